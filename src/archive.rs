@@ -0,0 +1,497 @@
+//! Virtual paths like `bundle.tar.gz//etc/app.conf`, which address one member of a tar archive.
+//!
+//! Members of archives inside archives can be addressed too, like `outer.tar//inner.tgz//file`.
+//! Compressed archives are decompressed and compressed again by running gzip, bzip2, xz, or zstd,
+//! which have to be installed. Editing a member rebuilds each archive around it with that
+//! member's new contents, and every other member is copied unchanged.
+
+use std::fs;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::thread;
+
+/// The size of tar headers, and the unit that member data is padded to.
+const BLOCK: usize = 512;
+
+/// The file name suffixes of archives, and the program for each one's compression.
+const SUFFIXES: [(&str, Option<&str>); 9] = [
+    (".tar", None),
+    (".tar.gz", Some("gzip")),
+    (".tgz", Some("gzip")),
+    (".tar.bz2", Some("bzip2")),
+    (".tbz2", Some("bzip2")),
+    (".tar.xz", Some("xz")),
+    (".txz", Some("xz")),
+    (".tar.zst", Some("zstd")),
+    (".tzst", Some("zstd")),
+];
+
+#[derive(Debug, thiserror::Error)]
+pub enum ArchiveError {
+    #[error("failed to read archive")]
+    Read(#[source] io::Error),
+    #[error("failed to run {0}")]
+    Spawn(&'static str, #[source] io::Error),
+    #[error("{0} failed: {1}")]
+    Filter(&'static str, String),
+    #[error("'{0}' isn't in the archive")]
+    NotFound(String),
+    #[error("'{0}' isn't a regular file")]
+    NotFile(String),
+    #[error("invalid tar header at offset {0}")]
+    Corrupt(usize),
+    #[error("the size of '{0}' is set by an extended header, which isn't supported")]
+    ExtendedSize(String),
+    #[error("'{0}' would be too large for a tar header")]
+    TooLarge(String),
+}
+
+/// One level of a virtual path: a member, and how the archive containing it is compressed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Layer {
+    compressor: Option<&'static str>,
+    member: String,
+}
+
+/// A path to a member of an archive file, possibly inside other archives in it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemberPath {
+    /// The archive file on disk
+    pub archive: PathBuf,
+    /// The members to open in turn, starting with the one in `archive`
+    layers: Vec<Layer>,
+}
+
+/// Get the compression of an archive from its name, or None if it isn't an archive.
+fn archive_kind(name: &str) -> Option<Option<&'static str>> {
+    SUFFIXES
+        .iter()
+        .find(|(suffix, _)| name.ends_with(suffix))
+        .map(|&(_, compressor)| compressor)
+}
+
+impl MemberPath {
+    /// Split a virtual path at each `//` which follows the name of an archive. Returns None for
+    /// ordinary paths, including ones which contain `//` but where the part before it isn't an
+    /// archive file.
+    pub fn parse(path: &Path) -> Option<MemberPath> {
+        let path = path.to_str()?;
+        let mut search = 0;
+        let (archive, rest) = loop {
+            let split = search + path[search..].find("//")?;
+            let archive = &path[..split];
+            if archive_kind(archive).is_some() && Path::new(archive).is_file() {
+                break (archive, &path[split + 2..]);
+            }
+            search = split + 1;
+        };
+
+        let mut layers = vec![];
+        let mut compressor = archive_kind(archive)?;
+        let mut members = rest.split("//").peekable();
+        while let Some(member) = members.next() {
+            if member.is_empty() {
+                return None;
+            }
+            layers.push(Layer {
+                compressor,
+                member: member.to_owned(),
+            });
+            if members.peek().is_some() {
+                compressor = archive_kind(member)?;
+            }
+        }
+        Some(MemberPath {
+            archive: archive.into(),
+            layers,
+        })
+    }
+
+    /// Read the contents of the member.
+    pub fn read(&self) -> Result<Vec<u8>, ArchiveError> {
+        let mut data = fs::read(&self.archive).map_err(ArchiveError::Read)?;
+        for layer in &self.layers {
+            let tar = decompress(layer.compressor, data)?;
+            let entry = find(&tar, &layer.member)?;
+            data = tar[entry.data].to_vec();
+        }
+        Ok(data)
+    }
+
+    /// Given `archive`, the contents of the archive file, call `edit` with the member's contents
+    /// and put what it returns in their place. Returns the new contents of the archive file, or
+    /// None if `edit` returns None to leave the member as it was.
+    pub fn update<F, E>(&self, archive: &[u8], edit: F) -> Result<Option<Vec<u8>>, E>
+    where
+        F: FnOnce(&[u8]) -> Result<Option<Vec<u8>>, E>,
+        E: From<ArchiveError>,
+    {
+        update_layers(&self.layers, archive, edit)
+    }
+}
+
+fn update_layers<F, E>(layers: &[Layer], container: &[u8], edit: F) -> Result<Option<Vec<u8>>, E>
+where
+    F: FnOnce(&[u8]) -> Result<Option<Vec<u8>>, E>,
+    E: From<ArchiveError>,
+{
+    let Some((layer, inner)) = layers.split_first() else {
+        return edit(container);
+    };
+    let tar = decompress(layer.compressor, container.to_vec())?;
+    let entry = find(&tar, &layer.member)?;
+    let Some(data) = update_layers(inner, &tar[entry.data.clone()], edit)? else {
+        return Ok(None);
+    };
+    let tar = replace_data(&tar, &entry, &data)?;
+    Ok(Some(compress(layer.compressor, tar)?))
+}
+
+fn decompress(compressor: Option<&'static str>, data: Vec<u8>) -> Result<Vec<u8>, ArchiveError> {
+    match compressor {
+        Some(program) => run_filter(program, &["-d", "-c"], &data),
+        None => Ok(data),
+    }
+}
+
+fn compress(compressor: Option<&'static str>, data: Vec<u8>) -> Result<Vec<u8>, ArchiveError> {
+    match compressor {
+        Some(program) => run_filter(program, &["-c"], &data),
+        None => Ok(data),
+    }
+}
+
+/// Run `program` with `input` on its stdin, and return what it writes to stdout.
+fn run_filter(program: &'static str, args: &[&str], input: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .map_err(|err| ArchiveError::Spawn(program, err))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let output = thread::scope(|scope| {
+        // write from another thread so that a full stdout pipe can't deadlock us, write errors
+        // mean the program exited early and show up in its exit status
+        scope.spawn(move || stdin.write_all(input));
+        child.wait_with_output()
+    })
+    .map_err(|err| ArchiveError::Spawn(program, err))?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(ArchiveError::Filter(program, stderr.trim().to_owned()));
+    }
+    Ok(output.stdout)
+}
+
+/// The location of a member in a tar archive.
+#[derive(Debug)]
+struct Entry {
+    /// The offset of the member's own header, after any extended headers for it
+    header: usize,
+    data: Range<usize>,
+}
+
+/// Find the last member of `tar` named `member`, which is the one tar would extract.
+fn find(tar: &[u8], member: &str) -> Result<Entry, ArchiveError> {
+    let want = normalize(member);
+    let mut found = None;
+    // names and sizes from GNU long name and pax extended headers, for the next member
+    let mut long_name = None;
+    let mut pax = Pax::default();
+    let mut pos = 0;
+    while pos + BLOCK <= tar.len() {
+        let header = &tar[pos..pos + BLOCK];
+        // the archive ends with blocks of zeros
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        if parse_number(&header[148..156]) != Some(checksum(header)) {
+            return Err(ArchiveError::Corrupt(pos));
+        }
+        let size = parse_number(&header[124..136]).ok_or(ArchiveError::Corrupt(pos))?;
+        let start = pos + BLOCK;
+        let data = start..start.saturating_add(size);
+        if data.end > tar.len() {
+            return Err(ArchiveError::Corrupt(pos));
+        }
+
+        match header[156] {
+            b'L' => long_name = Some(c_string(&tar[data.clone()])),
+            b'x' => pax = Pax::parse(&tar[data.clone()]),
+            // global pax headers don't set names
+            b'g' => (),
+            kind => {
+                let name = pax
+                    .path
+                    .take()
+                    .or(long_name.take())
+                    .unwrap_or_else(|| header_name(header));
+                if normalize(&name) == want {
+                    if !matches!(kind, b'0' | b'\0') {
+                        found = Some(Err(ArchiveError::NotFile(member.to_owned())));
+                    } else if pax.size {
+                        found = Some(Err(ArchiveError::ExtendedSize(member.to_owned())));
+                    } else {
+                        found = Some(Ok(Entry {
+                            header: pos,
+                            data: data.clone(),
+                        }));
+                    }
+                }
+                pax = Pax::default();
+            }
+        }
+        pos = start + padded(data.len());
+    }
+    found.unwrap_or_else(|| Err(ArchiveError::NotFound(member.to_owned())))
+}
+
+/// Rebuild `tar` with `data` as the contents of `entry`.
+fn replace_data(tar: &[u8], entry: &Entry, data: &[u8]) -> Result<Vec<u8>, ArchiveError> {
+    let mut header: [u8; BLOCK] = tar[entry.header..entry.header + BLOCK]
+        .try_into()
+        .expect("headers are one block");
+    if !write_octal(&mut header[124..136], data.len()) {
+        return Err(ArchiveError::TooLarge(header_name(&header)));
+    }
+    set_checksum(&mut header);
+
+    let rest = (entry.data.start + padded(entry.data.len())).min(tar.len());
+    let mut out = Vec::with_capacity(tar.len() - entry.data.len() + data.len() + BLOCK);
+    out.extend_from_slice(&tar[..entry.header]);
+    out.extend_from_slice(&header);
+    out.extend_from_slice(data);
+    out.resize(out.len() + padded(data.len()) - data.len(), 0);
+    out.extend_from_slice(&tar[rest..]);
+    Ok(out)
+}
+
+/// What a pax extended header says about the next member.
+#[derive(Debug, Default)]
+struct Pax {
+    path: Option<String>,
+    /// Whether it overrides the size
+    size: bool,
+}
+
+impl Pax {
+    /// Parse records like "19 path=etc/app.conf\n".
+    fn parse(mut data: &[u8]) -> Pax {
+        let mut pax = Pax::default();
+        while let Some(space) = data.iter().position(|&b| b == b' ') {
+            let len = std::str::from_utf8(&data[..space])
+                .ok()
+                .and_then(|len| len.parse::<usize>().ok());
+            let Some(len) = len.filter(|&len| len > space && len <= data.len()) else {
+                break;
+            };
+            let record = &data[space + 1..len];
+            let record = record.strip_suffix(b"\n").unwrap_or(record);
+            if let Some(path) = record.strip_prefix(b"path=") {
+                pax.path = Some(String::from_utf8_lossy(path).into_owned());
+            } else if record.starts_with(b"size=") {
+                pax.size = true;
+            }
+            data = &data[len..];
+        }
+        pax
+    }
+}
+
+/// Strip the leading "./" and "/" and trailing "/" which don't change which member a name refers
+/// to.
+fn normalize(name: &str) -> &str {
+    let mut name = name.trim_end_matches('/');
+    loop {
+        let trimmed = name.trim_start_matches('/').trim_start_matches("./");
+        if trimmed.len() == name.len() {
+            return name;
+        }
+        name = trimmed;
+    }
+}
+
+/// The name in a header, including the ustar prefix.
+fn header_name(header: &[u8]) -> String {
+    let name = c_string(&header[..100]);
+    let prefix = c_string(&header[345..500]);
+    if &header[257..262] == b"ustar" && !prefix.is_empty() {
+        format!("{prefix}/{name}")
+    } else {
+        name
+    }
+}
+
+/// The text before the first NUL.
+fn c_string(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+/// Parse a numeric header field, which is octal text or a big-endian GNU base-256 number.
+fn parse_number(field: &[u8]) -> Option<usize> {
+    if field.first().is_some_and(|&b| b & 0x80 != 0) {
+        let mut n = usize::from(field[0] & 0x7f);
+        for &b in &field[1..] {
+            n = n.checked_mul(256)?.checked_add(usize::from(b))?;
+        }
+        return Some(n);
+    }
+    let text = std::str::from_utf8(field).ok()?;
+    let text = text.trim_matches(|c| c == ' ' || c == '\0');
+    usize::from_str_radix(text, 8).ok()
+}
+
+/// Write `n` as NUL-terminated octal filling `field`, returning false if it doesn't fit.
+fn write_octal(field: &mut [u8], n: usize) -> bool {
+    let text = format!("{:0width$o}\0", n, width = field.len() - 1);
+    if text.len() != field.len() {
+        return false;
+    }
+    field.copy_from_slice(text.as_bytes());
+    true
+}
+
+/// The sum of the header's bytes, counting the checksum field as spaces.
+fn checksum(header: &[u8]) -> usize {
+    header
+        .iter()
+        .enumerate()
+        .map(|(i, &b)| if (148..156).contains(&i) { b' ' } else { b })
+        .map(usize::from)
+        .sum()
+}
+
+fn set_checksum(header: &mut [u8; BLOCK]) {
+    let sum = checksum(header);
+    header[148..156].copy_from_slice(format!("{sum:06o}\0 ").as_bytes());
+}
+
+/// Round a data length up to a whole number of blocks.
+fn padded(len: usize) -> usize {
+    len.div_ceil(BLOCK) * BLOCK
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Build an uncompressed tar archive with the given members.
+    fn tar(members: &[(&[u8], u8, &[u8])]) -> Vec<u8> {
+        let mut out = vec![];
+        for &(name, kind, data) in members {
+            let mut header = [0; BLOCK];
+            header[..name.len()].copy_from_slice(name);
+            write_octal(&mut header[100..108], 0o644);
+            write_octal(&mut header[124..136], data.len());
+            header[156] = kind;
+            header[257..263].copy_from_slice(b"ustar\0");
+            set_checksum(&mut header);
+            out.extend_from_slice(&header);
+            out.extend_from_slice(data);
+            out.resize(out.len() + padded(data.len()) - data.len(), 0);
+        }
+        out.resize(out.len() + 2 * BLOCK, 0);
+        out
+    }
+
+    fn member_path(archive: &str, members: &[&str]) -> MemberPath {
+        MemberPath {
+            archive: archive.into(),
+            layers: members
+                .iter()
+                .map(|&member| Layer {
+                    compressor: archive_kind(member).flatten(),
+                    member: member.to_owned(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = dir.path().join("bundle.tgz");
+        fs::write(&archive, "").unwrap();
+        let archive = archive.to_str().unwrap();
+
+        let path = MemberPath::parse(Path::new(&format!("{archive}//etc/app.conf"))).unwrap();
+        assert_eq!(path.archive, Path::new(archive));
+        assert_eq!(
+            path.layers,
+            [Layer {
+                compressor: Some("gzip"),
+                member: "etc/app.conf".to_owned()
+            }]
+        );
+        let path = MemberPath::parse(Path::new(&format!("{archive}//inner.tar.xz//a"))).unwrap();
+        assert_eq!(path.layers[1].compressor, Some("xz"));
+
+        // not archives, or not files
+        let dir = dir.path().to_str().unwrap();
+        assert_eq!(MemberPath::parse(Path::new(&format!("{dir}//a"))), None);
+        assert_eq!(
+            MemberPath::parse(Path::new(&format!("{dir}/x.tar//a"))),
+            None
+        );
+        assert_eq!(MemberPath::parse(Path::new(&format!("{archive}//"))), None);
+        assert_eq!(
+            MemberPath::parse(Path::new(&format!("{archive}//a//b"))),
+            None
+        );
+    }
+
+    #[test]
+    fn test_find() {
+        let long = format!("{}/file", "d".repeat(120));
+        let archive = tar(&[
+            (b"./a.txt", b'0', b"old a"),
+            (b"dir/", b'5', b""),
+            (b"././@LongLink", b'L', long.as_bytes()),
+            (b"truncated", b'0', b"long"),
+            (b"pax", b'x', b"14 path=p.txt\n"),
+            (b"ignored", b'0', b"pax"),
+            (b"a.txt", b'0', b"new a"),
+        ]);
+        let read = |name| find(&archive, name).map(|entry| archive[entry.data].to_vec());
+        // the last member with a name wins
+        assert_eq!(read("a.txt").unwrap(), b"new a");
+        assert_eq!(read(&long).unwrap(), b"long");
+        assert_eq!(read("/p.txt").unwrap(), b"pax");
+        assert!(matches!(read("dir"), Err(ArchiveError::NotFile(_))));
+        assert!(matches!(read("ignored"), Err(ArchiveError::NotFound(_))));
+
+        let mut corrupt = archive.clone();
+        corrupt[130] ^= 1;
+        assert!(matches!(
+            find(&corrupt, "a.txt"),
+            Err(ArchiveError::Corrupt(0))
+        ));
+    }
+
+    #[test]
+    fn test_update() {
+        let inner = tar(&[(b"x", b'0', b"inner x"), (b"y", b'0', b"y")]);
+        let outer = tar(&[(b"inner.tar", b'0', &inner), (b"z", b'0', b"z")]);
+        let path = member_path("outer.tar", &["inner.tar", "x"]);
+
+        let new = path
+            .update::<_, ArchiveError>(&outer, |data| {
+                assert_eq!(data, b"inner x");
+                Ok(Some(vec![b'!'; 600]))
+            })
+            .unwrap()
+            .unwrap();
+        let inner = &new[find(&new, "inner.tar").unwrap().data];
+        assert_eq!(&inner[find(inner, "x").unwrap().data], [b'!'; 600]);
+        assert_eq!(&inner[find(inner, "y").unwrap().data], b"y");
+        assert_eq!(&new[find(&new, "z").unwrap().data], b"z");
+
+        let unchanged = path.update::<_, ArchiveError>(&outer, |_| Ok(None));
+        assert_eq!(unchanged.unwrap(), None);
+    }
+}
@@ -0,0 +1,508 @@
+//! `--diff`: show what would change as a unified diff, without writing anything. Also
+//! `--diffstat`, which summarizes the changed lines of in-place edits.
+
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use rp::replace::{Edit, Line, Pattern, Replacer, StreamError};
+
+/// Number of unchanged lines shown around each change.
+const CONTEXT: usize = 3;
+
+/// The width of the diffstat, like git's default for a terminal.
+const DIFFSTAT_WIDTH: usize = 80;
+
+/// A run of original lines which were replaced by some new lines.
+#[derive(Debug, PartialEq, Eq)]
+struct Change {
+    /// Indexes of the original lines
+    old: Range<usize>,
+    /// Index of the first new line in the output
+    new_start: usize,
+    /// The new lines, including their terminators
+    new: Vec<Vec<u8>>,
+}
+
+/// The original lines of a file and the changes to them.
+#[derive(Debug, Default)]
+struct FileDiff {
+    old: Vec<Vec<u8>>,
+    changes: Vec<Change>,
+    /// Number of lines in the output so far
+    new_len: usize,
+    /// The first line and replaced text of a change which isn't finished yet
+    pending: Option<(usize, Vec<u8>)>,
+}
+
+impl FileDiff {
+    /// Add the next record of the file, which is a line unless in paragraph or multiline mode.
+    fn push(&mut self, line: &Line) {
+        let (old, new) = (line.old, line.new);
+        let base = self.old.len();
+        self.old.extend(split_lines(old));
+
+        let mut regions = changed_regions(line);
+        // a change continued from the previous record includes this record's first line
+        if self.pending.is_some() && regions.first().is_none_or(|(r, _)| r.start > 0) {
+            let end = line_end(old, 0);
+            regions.insert(0, (0..end, 0..end));
+        }
+
+        // end of the unchanged text before the next region
+        let mut old_pos = 0;
+        for (i, (old_range, new_range)) in regions.iter().enumerate() {
+            self.new_len += count_lines(&old[old_pos..old_range.start]);
+            old_pos = old_range.end;
+            let (start, mut text) = self
+                .pending
+                .take()
+                .unwrap_or_else(|| (base + count_lines(&old[..old_range.start]), vec![]));
+            text.extend_from_slice(&new[new_range.clone()]);
+
+            // A replacement which removed the record's final line break joins it with the next
+            // record, so the change continues until the new text ends a line too.
+            if i == regions.len() - 1
+                && old_range.end == old.len()
+                && old.ends_with(b"\n")
+                && !text.is_empty()
+                && !text.ends_with(b"\n")
+            {
+                self.pending = Some((start, text));
+                return;
+            }
+            self.add_change(start..(base + count_lines(&old[..old_range.end])), &text);
+        }
+        self.new_len += count_lines(&old[old_pos..]);
+    }
+
+    /// Record the pending change, if any.
+    fn finish(&mut self) {
+        if let Some((start, text)) = self.pending.take() {
+            self.add_change(start..self.old.len(), &text);
+        }
+    }
+
+    /// Record that the `old` lines were replaced by `text`, unless it's actually the same.
+    fn add_change(&mut self, old: Range<usize>, text: &[u8]) {
+        let new: Vec<Vec<u8>> = split_lines(text).collect();
+        let new_start = self.new_len;
+        self.new_len += new.len();
+        if self.old[old.clone()] != new[..] {
+            self.changes.push(Change {
+                old,
+                new_start,
+                new,
+            });
+        }
+    }
+
+    /// Write the diff with `name` as both the old and new file name. Nothing is written if there
+    /// are no changes.
+    fn write<W: Write + ?Sized>(&self, out: &mut W, name: &Path) -> io::Result<()> {
+        if self.changes.is_empty() {
+            return Ok(());
+        }
+        writeln!(out, "--- {}", name.display())?;
+        writeln!(out, "+++ {}", name.display())?;
+
+        let mut rest = &self.changes[..];
+        while !rest.is_empty() {
+            // changes which are close enough for their context to touch go in the same hunk
+            let len = 1 + rest
+                .windows(2)
+                .take_while(|pair| pair[1].old.start - pair[0].old.end <= 2 * CONTEXT)
+                .count();
+            let (hunk, after) = rest.split_at(len);
+            self.write_hunk(out, hunk)?;
+            rest = after;
+        }
+        Ok(())
+    }
+
+    fn write_hunk<W: Write + ?Sized>(&self, out: &mut W, hunk: &[Change]) -> io::Result<()> {
+        let (first, last) = (&hunk[0], &hunk[hunk.len() - 1]);
+        let old_start = first.old.start.saturating_sub(CONTEXT);
+        let old_end = (last.old.end + CONTEXT).min(self.old.len());
+        let new_start = first.new_start - (first.old.start - old_start);
+        let old_changed: usize = hunk.iter().map(|c| c.old.len()).sum();
+        let new_changed: usize = hunk.iter().map(|c| c.new.len()).sum();
+        let new_len = old_end - old_start - old_changed + new_changed;
+        writeln!(
+            out,
+            "@@ -{} +{} @@",
+            hunk_range(old_start, old_end - old_start),
+            hunk_range(new_start, new_len)
+        )?;
+
+        let mut pos = old_start;
+        for change in hunk {
+            for line in &self.old[pos..change.old.start] {
+                write_line(out, b' ', line)?;
+            }
+            for line in &self.old[change.old.clone()] {
+                write_line(out, b'-', line)?;
+            }
+            for line in &change.new {
+                write_line(out, b'+', line)?;
+            }
+            pos = change.old.end;
+        }
+        for line in &self.old[pos..old_end] {
+            write_line(out, b' ', line)?;
+        }
+        Ok(())
+    }
+}
+
+/// Get the byte ranges of `line.old` and `line.new` which differ, expanded to whole lines.
+/// Everything outside the regions is the same in both.
+fn changed_regions(line: &Line) -> Vec<(Range<usize>, Range<usize>)> {
+    let mut regions: Vec<(Range<usize>, Range<usize>)> = vec![];
+    for edit in line.edits {
+        let start = line_start(line.old, edit.old.start);
+        match regions.last_mut() {
+            // edits on the same line are one region
+            Some((old, new)) if start < old.end => (old.end, new.end) = region_end(line, edit),
+            _ => {
+                // the text before the edit on the same line is unchanged
+                let new_start = edit.new.start - (edit.old.start - start);
+                let (old_end, new_end) = region_end(line, edit);
+                regions.push((start..old_end, new_start..new_end));
+            }
+        }
+    }
+    regions
+}
+
+/// Get the end of the region whose last edit is `edit`, in the old and new text. This is only
+/// accurate if there are no more edits on the lines up to there.
+fn region_end(line: &Line, edit: &Edit) -> (usize, usize) {
+    let mut old_end = if edit.old.end > edit.old.start && line.old[edit.old.end - 1] == b'\n' {
+        edit.old.end
+    } else {
+        line_end(line.old, edit.old.end)
+    };
+    // the text after the edit on the same line is unchanged
+    let mut new_end = edit.new.end + (old_end - edit.old.end);
+    // A replacement which removed a line break joins the region with the following line. The
+    // region always starts a line in the new text, so this checks whether it ends one too.
+    while old_end < line.old.len()
+        && new_end > 0
+        && line.new.get(new_end - 1).is_some_and(|&b| b != b'\n')
+    {
+        let len = line_end(line.old, old_end) - old_end;
+        old_end += len;
+        new_end += len;
+    }
+    (old_end, new_end)
+}
+
+/// Get the offset of the start of the line containing `pos`.
+fn line_start(text: &[u8], pos: usize) -> usize {
+    memchr::memrchr(b'\n', &text[..pos]).map_or(0, |i| i + 1)
+}
+
+/// Get the offset just after the end of the line containing `pos`, including its terminator.
+fn line_end(text: &[u8], pos: usize) -> usize {
+    memchr::memchr(b'\n', &text[pos..]).map_or(text.len(), |i| pos + i + 1)
+}
+
+/// Split text into lines, keeping their terminators.
+fn split_lines(text: &[u8]) -> impl Iterator<Item = Vec<u8>> + '_ {
+    text.split_inclusive(|&b| b == b'\n').map(<[u8]>::to_vec)
+}
+
+/// Count the lines in some text, including a final one without a terminator.
+fn count_lines(text: &[u8]) -> usize {
+    text.split_inclusive(|&b| b == b'\n').count()
+}
+
+/// Format the range of a hunk header, where `start` counts from 0.
+fn hunk_range(start: usize, len: usize) -> String {
+    match len {
+        // an empty range is numbered by the line before it
+        0 => format!("{start},0"),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+fn write_line<W: Write + ?Sized>(out: &mut W, prefix: u8, line: &[u8]) -> io::Result<()> {
+    out.write_all(&[prefix])?;
+    out.write_all(line)?;
+    if !line.ends_with(b"\n") {
+        out.write_all(b"\n\\ No newline at end of file\n")?;
+    }
+    Ok(())
+}
+
+/// Make replacements on each line of `input` and write a unified diff of the changes, labelled
+/// with `name`. The whole input is kept in memory. Returns the number of replacements.
+pub fn write_diff<P, R, W>(
+    replacer: &Replacer<P>,
+    input: &mut R,
+    output: &mut W,
+    name: &Path,
+) -> Result<usize, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+    W: Write + ?Sized,
+{
+    let mut diff = FileDiff::default();
+    let stats = replacer.for_each_line(input, |line| {
+        diff.push(line);
+        Ok(())
+    })?;
+    diff.finish();
+    diff.write(output, name).map_err(StreamError::Write)?;
+    Ok(stats.replacements)
+}
+
+/// Counts the lines inserted and deleted in one file, for --diffstat. Like `write_diff`, the whole
+/// file is kept in memory.
+#[derive(Debug, Default)]
+pub struct DiffStat {
+    diff: FileDiff,
+}
+
+impl DiffStat {
+    /// Add the next record of the file.
+    pub fn push(&mut self, line: &Line) {
+        self.diff.push(line);
+    }
+
+    /// Finish the file and get the numbers of inserted and deleted lines.
+    pub fn finish(mut self) -> (usize, usize) {
+        self.diff.finish();
+        let changes = &self.diff.changes;
+        (
+            changes.iter().map(|change| change.new.len()).sum(),
+            changes.iter().map(|change| change.old.len()).sum(),
+        )
+    }
+}
+
+/// Write a diffstat like git's, with a line for each file and its numbers of inserted and deleted
+/// lines, followed by the totals. Nothing is written if there are no files.
+pub fn write_diffstat<W: Write + ?Sized>(
+    out: &mut W,
+    files: &[(PathBuf, usize, usize)],
+    color: bool,
+) -> io::Result<()> {
+    if files.is_empty() {
+        return Ok(());
+    }
+    let names: Vec<String> = files
+        .iter()
+        .map(|(path, ..)| path.display().to_string())
+        .collect();
+    let name_width = names
+        .iter()
+        .map(|name| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    let max_change = files
+        .iter()
+        .map(|(_, ins, del)| ins + del)
+        .max()
+        .unwrap_or(0);
+    let num_width = max_change.to_string().len();
+    // " name | num " comes before the bar
+    let bar_width = DIFFSTAT_WIDTH
+        .saturating_sub(name_width + num_width + 5)
+        .max(10);
+
+    let (plus, minus) = if color {
+        (
+            anstyle::AnsiColor::Green.on_default(),
+            anstyle::AnsiColor::Red.on_default(),
+        )
+    } else {
+        (anstyle::Style::new(), anstyle::Style::new())
+    };
+    for ((_, ins, del), name) in files.iter().zip(&names) {
+        let (mut ins_bar, mut del_bar) = (*ins, *del);
+        if max_change > bar_width {
+            ins_bar = scale(ins_bar, max_change, bar_width);
+            del_bar = scale(del_bar, max_change, bar_width);
+        }
+        writeln!(
+            out,
+            " {name:<name_width$} | {:>num_width$} {}{}{}{}{}{}",
+            ins + del,
+            plus.render(),
+            "+".repeat(ins_bar),
+            plus.render_reset(),
+            minus.render(),
+            "-".repeat(del_bar),
+            minus.render_reset(),
+        )?;
+    }
+
+    let plural = |n: usize, one, many| if n == 1 { one } else { many };
+    let (ins, del) = files
+        .iter()
+        .fold((0, 0), |(ins, del), (_, i, d)| (ins + i, del + d));
+    write!(
+        out,
+        " {} {} changed",
+        files.len(),
+        plural(files.len(), "file", "files")
+    )?;
+    if ins > 0 || del == 0 {
+        write!(out, ", {ins} {}(+)", plural(ins, "insertion", "insertions"))?;
+    }
+    if del > 0 || ins == 0 {
+        write!(out, ", {del} {}(-)", plural(del, "deletion", "deletions"))?;
+    }
+    writeln!(out)
+}
+
+/// Scale a count of changed lines to the width of the bar, keeping nonzero counts visible.
+fn scale(n: usize, max: usize, width: usize) -> usize {
+    match n {
+        0 => 0,
+        _ => 1 + n * (width - 1) / max,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::bytes::Regex;
+
+    use rp::replace::{Records, ReplaceOptions};
+    use rp::template::Template;
+
+    #[track_caller]
+    fn check(pattern: &str, replacement: &str, input: &str, expected: &str) {
+        let replacer = Replacer::new(Regex::new(pattern).unwrap(), Template::parse(replacement));
+        let mut out = vec![];
+        write_diff(&replacer, &mut input.as_bytes(), &mut out, Path::new("f")).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+
+    #[test]
+    fn test_diff() {
+        let input = "1\n2\n3\nfoo\n5\n6\n7\n8\n9\n10\nfoo\n12\n13\n14\n15\n16\n17\n18\nfoo\n";
+        check(
+            "foo",
+            "bar",
+            input,
+            "--- f\n+++ f\n\
+             @@ -1,14 +1,14 @@\n 1\n 2\n 3\n-foo\n+bar\n 5\n 6\n 7\n 8\n 9\n 10\n-foo\n+bar\n \
+             12\n 13\n 14\n\
+             @@ -16,4 +16,4 @@\n 16\n 17\n 18\n-foo\n+bar\n",
+        );
+        check("nope", "x", input, "");
+    }
+
+    #[test]
+    fn test_diffstat() {
+        let replacer = Replacer::new(Regex::new("o\n").unwrap(), Template::parse("0\n\n"));
+        let mut stat = DiffStat::default();
+        replacer
+            .for_each_line(&mut &b"foo\nbar\nboo\n"[..], |line| {
+                stat.push(line);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(stat.finish(), (4, 2));
+
+        let files = [
+            (PathBuf::from("a.txt"), 3, 1),
+            (PathBuf::from("dir/long.txt"), 0, 2),
+        ];
+        let mut out = vec![];
+        write_diffstat(&mut out, &files, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            " a.txt        | 4 +++-\n dir/long.txt | 2 --\n \
+             2 files changed, 3 insertions(+), 3 deletions(-)\n"
+        );
+
+        // long bars are scaled down
+        let mut out = vec![];
+        write_diffstat(&mut out, &[(PathBuf::from("f"), 1000, 1)], false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let line = out.lines().next().unwrap();
+        assert_eq!(line, format!(" f | 1001 {}-", "+".repeat(69)));
+        assert_eq!(line.len(), DIFFSTAT_WIDTH);
+    }
+
+    #[test]
+    fn test_diff_line_breaks() {
+        // adding and removing lines
+        check(
+            "^b\n",
+            "",
+            "a\nb\nc\n",
+            "--- f\n+++ f\n@@ -1,3 +1,2 @@\n a\n-b\n c\n",
+        );
+        check(
+            "b",
+            "b\nB",
+            "a\nb\nc\n",
+            "--- f\n+++ f\n@@ -1,3 +1,4 @@\n a\n-b\n+b\n+B\n c\n",
+        );
+        // joining a line with the next one
+        check(
+            r"b\n",
+            "b ",
+            "a\nb\nc\nd\n",
+            "--- f\n+++ f\n@@ -1,4 +1,3 @@\n a\n-b\n-c\n+b c\n d\n",
+        );
+        // no newline at the end of the file
+        check(
+            "c",
+            "C",
+            "a\nc",
+            "--- f\n+++ f\n@@ -1,2 +1,2 @@\n a\n-c\n\\ No newline at end of file\n\
+             +C\n\\ No newline at end of file\n",
+        );
+    }
+
+    #[test]
+    fn test_diff_records() {
+        #[track_caller]
+        fn check_records(
+            records: Records,
+            pattern: &str,
+            replacement: &str,
+            input: &str,
+        ) -> String {
+            let opts = ReplaceOptions {
+                records,
+                replace_all: true,
+                ..Default::default()
+            };
+            let replacer = opts.build(Regex::new(pattern).unwrap(), Template::parse(replacement));
+            let mut out = vec![];
+            write_diff(&replacer, &mut input.as_bytes(), &mut out, Path::new("f")).unwrap();
+            String::from_utf8(out).unwrap()
+        }
+
+        // only the changed lines of a paragraph are shown
+        assert_eq!(
+            check_records(Records::Paragraphs, "b", "B", "a\nb\nc\n\nd\n"),
+            "--- f\n+++ f\n@@ -1,5 +1,5 @@\n a\n-b\n+B\n c\n \n d\n"
+        );
+        // distant changes in multiline mode are separate hunks
+        let input = "a\nb\nc\n1\n2\n3\n4\n5\n6\n7\n8\na\nb\nc\n";
+        assert_eq!(
+            check_records(Records::Whole, "b\nc", "bc", input),
+            "--- f\n+++ f\n@@ -1,6 +1,5 @@\n a\n-b\n-c\n+bc\n 1\n 2\n 3\n\
+             @@ -10,5 +9,4 @@\n 7\n 8\n a\n-b\n-c\n+bc\n"
+        );
+    }
+
+    #[test]
+    fn test_hunk_range() {
+        assert_eq!(hunk_range(0, 0), "0,0");
+        assert_eq!(hunk_range(4, 1), "5");
+        assert_eq!(hunk_range(4, 3), "5,3");
+    }
+}
@@ -0,0 +1,252 @@
+//! `rp doctor`: print information about the environment rp is running in, to help debug why it
+//! behaves differently on different machines.
+
+use std::convert::Infallible;
+use std::io::{self, IsTerminal, Write};
+
+use clap::ValueEnum;
+use regex_syntax::ast::{self, Ast, ClassSetItem, Span, Visitor};
+
+use rp::replace::{Engine, ReplacerBuilder};
+
+/// Print the detected environment and settings, to debug unexpected behavior.
+///
+/// If PATTERN is given, also show which engine would be used to search for it, and with
+/// --classes, whether the Unicode classes it uses are available.
+#[derive(Debug, clap::Args)]
+pub struct DoctorArgs {
+    /// A pattern to check the engine selection for.
+    pattern: Option<String>,
+
+    /// Treat PATTERN as a literal string, like the main -F option.
+    #[arg(short = 'F', long)]
+    fixed_strings: bool,
+
+    /// Case-insensitive search, like the main -I option.
+    #[arg(short = 'I', long)]
+    ignore_case: bool,
+
+    /// Which engine to request, like the main --engine option.
+    #[arg(long, value_enum, value_name = "ENGINE", default_value_t)]
+    engine: Engine,
+
+    /// List the Unicode classes used in PATTERN, like \p{Greek} or \w, and check that each one
+    /// is supported by this build of rp.
+    #[arg(long, requires = "pattern")]
+    classes: bool,
+}
+
+/// Get the name of an engine as it's spelled on the command line.
+fn engine_name(engine: Engine) -> String {
+    // unwrap is ok because no engines are skipped
+    engine.to_possible_value().unwrap().get_name().to_owned()
+}
+
+fn yes_no(b: bool) -> &'static str {
+    if b {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+/// Collects the source text of every Unicode and Perl class in a pattern.
+struct ClassCollector<'a> {
+    pattern: &'a str,
+    classes: Vec<&'a str>,
+}
+
+impl<'a> ClassCollector<'a> {
+    fn push(&mut self, span: &Span) {
+        self.classes
+            .push(&self.pattern[span.start.offset..span.end.offset]);
+    }
+}
+
+impl<'a> Visitor for ClassCollector<'a> {
+    type Output = Vec<&'a str>;
+    type Err = Infallible;
+
+    fn finish(self) -> Result<Self::Output, Self::Err> {
+        Ok(self.classes)
+    }
+
+    fn visit_pre(&mut self, ast: &Ast) -> Result<(), Self::Err> {
+        match ast {
+            Ast::Class(ast::Class::Unicode(class)) => self.push(&class.span),
+            Ast::Class(ast::Class::Perl(class)) => self.push(&class.span),
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn visit_class_set_item_pre(&mut self, item: &ClassSetItem) -> Result<(), Self::Err> {
+        match item {
+            ClassSetItem::Unicode(class) => self.push(&class.span),
+            ClassSetItem::Perl(class) => self.push(&class.span),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+/// Find the Unicode and Perl classes in a regex pattern, as they're written in it. Returns an
+/// error message if the pattern can't be parsed.
+fn unicode_classes(pattern: &str) -> Result<Vec<&str>, String> {
+    let collector = ClassCollector {
+        pattern,
+        classes: vec![],
+    };
+    let ast = ast::parse::Parser::new()
+        .parse(pattern)
+        .map_err(|err| err.kind().to_string())?;
+    let Ok(classes) = ast::visit(&ast, collector);
+    Ok(classes)
+}
+
+/// Check whether a class can be used with Unicode mode enabled, returning the error message if
+/// not. Classes like \w are also available in ASCII-only form with (?-u), even when this fails.
+fn check_class(class: &str) -> Result<(), String> {
+    match regex_syntax::Parser::new().parse(class) {
+        Ok(_) => Ok(()),
+        Err(regex_syntax::Error::Translate(err)) => Err(err.kind().to_string()),
+        Err(err) => Err(err.to_string()),
+    }
+}
+
+/// Write the report to `out`, returning false if any of the classes checked with --classes can't
+/// be used.
+fn report(args: &DoctorArgs, out: &mut impl Write) -> anyhow::Result<bool> {
+    let mut ok = true;
+    writeln!(out, "rp {}", clap::crate_version!())?;
+    writeln!(out, "config files: none, rp doesn't read any")?;
+    writeln!(
+        out,
+        "stdin is a terminal: {}",
+        yes_no(io::stdin().is_terminal())
+    )?;
+    writeln!(
+        out,
+        "stdout is a terminal: {}",
+        yes_no(io::stdout().is_terminal())
+    )?;
+    writeln!(
+        out,
+        "stderr is a terminal: {}",
+        yes_no(io::stderr().is_terminal())
+    )?;
+    writeln!(out, "unicode tables: {}", yes_no(cfg!(feature = "unicode")))?;
+
+    if let Some(pattern) = &args.pattern {
+        let builder = ReplacerBuilder {
+            fixed_strings: args.fixed_strings,
+            ignore_case: args.ignore_case,
+            engine: args.engine,
+            ..Default::default()
+        };
+        let engine = match builder.build(pattern, "") {
+            Ok(replacer) => engine_name(replacer.pattern().engine()),
+            Err(err) => format!("error: {:#}", anyhow::Error::from(err)),
+        };
+        writeln!(out, "engine: {engine}")?;
+
+        if args.classes {
+            match unicode_classes(pattern) {
+                Ok(classes) if classes.is_empty() => writeln!(out, "classes: none")?,
+                Ok(classes) => {
+                    for class in classes {
+                        match check_class(class) {
+                            Ok(()) => writeln!(out, "class {class}: ok")?,
+                            Err(err) => {
+                                writeln!(out, "class {class}: error: {err}")?;
+                                ok = false;
+                            }
+                        }
+                    }
+                }
+                Err(err) => {
+                    writeln!(out, "classes: error: {err}")?;
+                    ok = false;
+                }
+            }
+        }
+    }
+    Ok(ok)
+}
+
+pub fn run(args: DoctorArgs) -> anyhow::Result<()> {
+    if !report(&args, &mut io::stdout().lock())? {
+        anyhow::bail!("PATTERN uses classes which aren't available");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Debug, Parser)]
+    struct Cli {
+        #[command(flatten)]
+        args: DoctorArgs,
+    }
+
+    fn run_doctor(argv: &[&str]) -> (String, bool) {
+        let cli = Cli::parse_from(std::iter::once("doctor").chain(argv.iter().copied()));
+        let mut out = vec![];
+        let ok = report(&cli.args, &mut out).unwrap();
+        (String::from_utf8(out).unwrap(), ok)
+    }
+
+    fn doctor(argv: &[&str]) -> String {
+        run_doctor(argv).0
+    }
+
+    #[track_caller]
+    fn engine_line(argv: &[&str]) -> String {
+        doctor(argv).lines().last().unwrap().to_owned()
+    }
+
+    #[test]
+    fn test_engine_selection() {
+        assert_eq!(engine_line(&[r"a\.b"]), "engine: literal");
+        assert_eq!(engine_line(&["a.b"]), "engine: regex");
+        assert_eq!(engine_line(&["-F", "a.b"]), "engine: literal");
+        assert_eq!(
+            engine_line(&["--engine", "aho-corasick", "-I", "a"]),
+            "engine: aho-corasick"
+        );
+        assert_eq!(
+            engine_line(&["-F", "-I", "a"]),
+            "engine: error: fixed_strings can't be used with ignore_case"
+        );
+        assert!(engine_line(&[]).starts_with("unicode tables"));
+    }
+
+    #[test]
+    fn test_classes() {
+        assert_eq!(
+            unicode_classes(r"\p{Greek}+[a\d\PL]|(?-u:\w)x").unwrap(),
+            [r"\p{Greek}", r"\d", r"\PL", r"\w"]
+        );
+        assert!(unicode_classes("(").is_err());
+
+        let (out, ok) = run_doctor(&["--classes", r"\pL \p{Nope}"]);
+        assert!(!ok);
+        let lines: Vec<_> = out
+            .lines()
+            .skip_while(|l| !l.starts_with("class"))
+            .collect();
+        assert_eq!(
+            lines,
+            [
+                r"class \pL: ok",
+                r"class \p{Nope}: error: Unicode property not found",
+            ]
+        );
+        assert!(doctor(&["--classes", "abc"]).ends_with("classes: none\n"));
+        assert!(run_doctor(&["--classes", r"\pL\w"]).1);
+        assert!(!run_doctor(&["--classes", "("]).1);
+    }
+}
@@ -0,0 +1,292 @@
+//! `--encoding`: convert inputs to UTF-8 before searching them, and the replaced text back.
+//!
+//! Only a few encodings are supported, which are simple enough to convert by hand: Latin-1, where
+//! every byte is the character with the same code point, and UTF-16 in either byte order. A
+//! byte order mark is kept as a U+FEFF character, so it's written back out unchanged.
+
+use std::io::{self, BufReader, Read, Write};
+
+use rp::replace::StreamError;
+
+/// How many bytes to read from the input at a time.
+const CHUNK_SIZE: usize = 8192;
+
+/// Encodings which inputs can be converted from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Encoding {
+    /// ISO-8859-1, where each byte is one character
+    #[value(name = "latin1", alias = "iso-8859-1")]
+    Latin1,
+    /// UTF-16, little endian, as used by Windows
+    #[value(name = "utf-16le")]
+    Utf16Le,
+    /// UTF-16, big endian
+    #[value(name = "utf-16be")]
+    Utf16Be,
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+impl Encoding {
+    /// Decode as much of `raw` as possible, appending the UTF-8 to `out`, and return how many bytes
+    /// were used. The rest are part of a character which continues in the next chunk, which is an
+    /// error at the end of the input.
+    fn decode(self, raw: &[u8], out: &mut Vec<u8>, eof: bool) -> io::Result<usize> {
+        let unit = |bytes: &[u8]| {
+            let bytes = [bytes[0], bytes[1]];
+            match self {
+                Encoding::Utf16Be => u16::from_be_bytes(bytes),
+                _ => u16::from_le_bytes(bytes),
+            }
+        };
+        let mut push = |c: char| out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+
+        if self == Encoding::Latin1 {
+            raw.iter().for_each(|&b| push(b as char));
+            return Ok(raw.len());
+        }
+
+        let mut used = 0;
+        while used + 2 <= raw.len() {
+            let first = unit(&raw[used..]);
+            let (c, len) = match first {
+                0xd800..=0xdbff => {
+                    if used + 4 > raw.len() {
+                        break;
+                    }
+                    let second = unit(&raw[used + 2..]);
+                    if !(0xdc00..=0xdfff).contains(&second) {
+                        return Err(invalid(format!("unpaired UTF-16 surrogate {first:#06x}")));
+                    }
+                    let c = 0x10000 + ((first as u32 - 0xd800) << 10 | (second as u32 - 0xdc00));
+                    (c, 4)
+                }
+                0xdc00..=0xdfff => {
+                    return Err(invalid(format!("unpaired UTF-16 surrogate {first:#06x}")));
+                }
+                _ => (first as u32, 2),
+            };
+            push(char::from_u32(c).expect("valid UTF-16 is a valid char"));
+            used += len;
+        }
+        if eof && used < raw.len() {
+            return Err(invalid(
+                "input ends in the middle of a UTF-16 character".to_owned(),
+            ));
+        }
+        Ok(used)
+    }
+
+    /// Encode UTF-8 text, appending it to `out`.
+    fn encode(self, text: &str, out: &mut Vec<u8>) -> io::Result<()> {
+        match self {
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    let b = u8::try_from(c)
+                        .map_err(|_| invalid(format!("{c:?} can't be encoded as latin1")))?;
+                    out.push(b);
+                }
+            }
+            Encoding::Utf16Le => text
+                .encode_utf16()
+                .for_each(|unit| out.extend_from_slice(&unit.to_le_bytes())),
+            Encoding::Utf16Be => text
+                .encode_utf16()
+                .for_each(|unit| out.extend_from_slice(&unit.to_be_bytes())),
+        }
+        Ok(())
+    }
+}
+
+/// A reader which converts its input from an encoding to UTF-8.
+pub struct Decoder<R> {
+    inner: R,
+    encoding: Encoding,
+    /// Bytes read from `inner` which haven't been decoded yet
+    raw: Vec<u8>,
+    /// Decoded text, starting at `pos`, which hasn't been returned yet
+    decoded: Vec<u8>,
+    pos: usize,
+}
+
+impl<R: Read> Decoder<R> {
+    pub fn new(inner: R, encoding: Encoding) -> Self {
+        Decoder {
+            inner,
+            encoding,
+            raw: vec![],
+            decoded: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl<R: Read> Read for Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.pos == self.decoded.len() {
+            self.decoded.clear();
+            self.pos = 0;
+            let len = self.raw.len();
+            self.raw.resize(len + CHUNK_SIZE, 0);
+            let count = match self.inner.read(&mut self.raw[len..]) {
+                Ok(count) => count,
+                Err(err) => {
+                    self.raw.truncate(len);
+                    return Err(err);
+                }
+            };
+            self.raw.truncate(len + count);
+            let eof = count == 0;
+            let used = self.encoding.decode(&self.raw, &mut self.decoded, eof)?;
+            self.raw.drain(..used);
+            if eof {
+                break;
+            }
+        }
+        let count = buf.len().min(self.decoded.len() - self.pos);
+        buf[..count].copy_from_slice(&self.decoded[self.pos..self.pos + count]);
+        self.pos += count;
+        Ok(count)
+    }
+}
+
+/// A writer which converts UTF-8 text to an encoding. Call `finish` once everything's been
+/// written, to check that the text didn't end in the middle of a character.
+pub struct Encoder<W> {
+    inner: W,
+    encoding: Encoding,
+    /// The start of a UTF-8 character which continues in the next write
+    pending: Vec<u8>,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Encoder<W> {
+    pub fn new(inner: W, encoding: Encoding) -> Self {
+        Encoder {
+            inner,
+            encoding,
+            pending: vec![],
+            buf: vec![],
+        }
+    }
+
+    pub fn finish(&mut self) -> io::Result<()> {
+        if !self.pending.is_empty() {
+            return Err(invalid("the replaced text isn't valid UTF-8".to_owned()));
+        }
+        self.inner.flush()
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.pending.extend_from_slice(data);
+        let text = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text,
+            // the last character is incomplete, keep it for next time
+            Err(err) if err.error_len().is_none() => {
+                std::str::from_utf8(&self.pending[..err.valid_up_to()]).unwrap()
+            }
+            Err(_) => {
+                self.pending.clear();
+                return Err(invalid("the replaced text isn't valid UTF-8".to_owned()));
+            }
+        };
+        self.buf.clear();
+        let result = self.encoding.encode(text, &mut self.buf);
+        let used = text.len();
+        self.pending.drain(..used);
+        result?;
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Call `replace` with `input` converted to UTF-8, converting what it writes to `output` back to
+/// `encoding`.
+pub fn transcode<R, T, F>(
+    encoding: Encoding,
+    input: R,
+    output: &mut dyn Write,
+    replace: F,
+) -> Result<T, StreamError>
+where
+    R: Read,
+    F: FnOnce(&mut BufReader<Decoder<R>>, &mut dyn Write) -> Result<T, StreamError>,
+{
+    let mut input = BufReader::new(Decoder::new(input, encoding));
+    let mut output = Encoder::new(output, encoding);
+    let ret = replace(&mut input, &mut output)?;
+    output.finish().map_err(StreamError::Write)?;
+    Ok(ret)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A reader which returns one byte at a time, to split characters across chunks.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let count = self.0.len().min(buf.len()).min(1);
+            buf[..count].copy_from_slice(&self.0[..count]);
+            self.0 = &self.0[count..];
+            Ok(count)
+        }
+    }
+
+    fn decode(raw: &[u8], encoding: Encoding) -> io::Result<String> {
+        let mut text = String::new();
+        Decoder::new(Trickle(raw), encoding).read_to_string(&mut text)?;
+        Ok(text)
+    }
+
+    fn encode(text: &[u8], encoding: Encoding) -> io::Result<Vec<u8>> {
+        let mut out = vec![];
+        let mut encoder = Encoder::new(&mut out, encoding);
+        for b in text {
+            encoder.write_all(&[*b])?;
+        }
+        encoder.finish()?;
+        Ok(out)
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(decode(b"caf\xe9", Encoding::Latin1).unwrap(), "café");
+        let le = b"\xff\xfea\x00\xe9\x00=\xd8\x00\xde\n\x00";
+        assert_eq!(decode(le, Encoding::Utf16Le).unwrap(), "\u{feff}aé😀\n");
+        let be = b"\xfe\xff\x00a\xd8=\xde\x00";
+        assert_eq!(decode(be, Encoding::Utf16Be).unwrap(), "\u{feff}a😀");
+        assert!(decode(b"a\x00b", Encoding::Utf16Le).is_err());
+        assert!(decode(b"=\xd8a\x00", Encoding::Utf16Le).is_err());
+        assert!(decode(b"\x00\xde", Encoding::Utf16Le).is_err());
+    }
+
+    #[test]
+    fn test_encode() {
+        assert_eq!(
+            encode("café".as_bytes(), Encoding::Latin1).unwrap(),
+            b"caf\xe9"
+        );
+        assert_eq!(
+            encode("\u{feff}aé😀\n".as_bytes(), Encoding::Utf16Le).unwrap(),
+            b"\xff\xfea\x00\xe9\x00=\xd8\x00\xde\n\x00"
+        );
+        assert_eq!(
+            encode("a😀".as_bytes(), Encoding::Utf16Be).unwrap(),
+            b"\x00a\xd8=\xde\x00"
+        );
+        assert!(encode("€".as_bytes(), Encoding::Latin1).is_err());
+        assert!(encode(b"\xff", Encoding::Utf16Le).is_err());
+        assert!(encode(b"\xc3", Encoding::Utf16Le).is_err());
+    }
+}
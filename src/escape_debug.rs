@@ -0,0 +1,37 @@
+//! `rp escape` and `rp unescape`: show what an escaped string means to rp, to untangle shell
+//! quoting from rp's own escape sequences.
+
+use std::io::{self, Write};
+
+use crate::hexdump::write_hexdump;
+use rp::unescape::{escape_bytes, unescape_bytes};
+
+/// Show the bytes STRING contains after --escape processing, as a hexdump.
+#[derive(Debug, clap::Args)]
+pub struct UnescapeArgs {
+    /// The string to unescape, as it would be passed with --escape.
+    string: String,
+}
+
+/// Print STRING with escape sequences for --escape, then a hexdump of its bytes.
+///
+/// Backslashes, control characters, and invalid UTF-8 are escaped, so the result gives the same
+/// bytes back when used with --escape.
+#[derive(Debug, clap::Args)]
+pub struct EscapeArgs {
+    /// The string to escape.
+    string: String,
+}
+
+pub fn run_unescape(args: UnescapeArgs) -> anyhow::Result<()> {
+    let bytes = unescape_bytes(&args.string)?;
+    write_hexdump(&mut io::stdout().lock(), &bytes)?;
+    Ok(())
+}
+
+pub fn run_escape(args: EscapeArgs) -> anyhow::Result<()> {
+    let mut stdout = io::stdout().lock();
+    writeln!(stdout, "{}", escape_bytes(args.string.as_bytes()))?;
+    write_hexdump(&mut stdout, args.string.as_bytes())?;
+    Ok(())
+}
@@ -0,0 +1,211 @@
+//! `--glob`: select the files to process while walking directories.
+
+use std::path::Path;
+
+use regex::Regex;
+
+#[derive(Debug, thiserror::Error)]
+pub enum GlobError {
+    #[error("unterminated character class")]
+    UnterminatedClass,
+    #[error("unterminated alternation")]
+    UnterminatedAlternation,
+    #[error("unmatched '}}'")]
+    UnmatchedBrace,
+    #[error(transparent)]
+    Regex(#[from] regex::Error),
+}
+
+/// Translate a glob into a regex which matches whole paths, with '/' as the separator.
+///
+/// `*` and `?` match any characters except '/', `**` as a whole path component matches any
+/// number of directories, `[...]` is a character class negated by a leading `!` or `^`, `{a,b}`
+/// matches either alternative, and a backslash escapes the next character. A glob without a '/'
+/// matches the file name at any depth, and a leading '/' is ignored.
+pub fn translate(glob: &str) -> Result<String, GlobError> {
+    translate_impl(glob, true)
+}
+
+/// Translate a .gitignore pattern like `translate`, except that '{' and '}' are literals.
+pub fn translate_gitignore(glob: &str) -> Result<String, GlobError> {
+    translate_impl(glob, false)
+}
+
+fn translate_impl(glob: &str, alternation: bool) -> Result<String, GlobError> {
+    let mut out = String::from("^");
+    if !glob.contains('/') {
+        out.push_str("(?:.*/)?");
+    }
+    let chars: Vec<char> = glob.strip_prefix('/').unwrap_or(glob).chars().collect();
+    let mut i = 0;
+    let mut depth = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        i += 1;
+        match c {
+            '*' if chars.get(i) == Some(&'*') => {
+                i += 1;
+                let component_start = i == 2 || chars[i - 3] == '/';
+                match chars.get(i) {
+                    Some('/') if component_start => {
+                        out.push_str("(?:.*/)?");
+                        i += 1;
+                    }
+                    None if component_start => out.push_str(".*"),
+                    _ => out.push_str("[^/]*"),
+                }
+            }
+            '*' => out.push_str("[^/]*"),
+            '?' => out.push_str("[^/]"),
+            '[' => i = translate_class(&chars, i, &mut out)?,
+            '{' if alternation => {
+                depth += 1;
+                out.push_str("(?:");
+            }
+            ',' if depth > 0 => out.push('|'),
+            '}' if depth > 0 => {
+                depth -= 1;
+                out.push(')');
+            }
+            '}' if alternation => return Err(GlobError::UnmatchedBrace),
+            '\\' if i < chars.len() => {
+                push_literal(&mut out, chars[i]);
+                i += 1;
+            }
+            _ => push_literal(&mut out, c),
+        }
+    }
+    if depth > 0 {
+        return Err(GlobError::UnterminatedAlternation);
+    }
+    out.push('$');
+    Ok(out)
+}
+
+/// Translate a character class starting just after its '['. Returns the index after its ']'.
+fn translate_class(chars: &[char], mut i: usize, out: &mut String) -> Result<usize, GlobError> {
+    out.push('[');
+    if let Some('!' | '^') = chars.get(i) {
+        out.push('^');
+        i += 1;
+    }
+    let start = i;
+    loop {
+        let Some(&c) = chars.get(i) else {
+            return Err(GlobError::UnterminatedClass);
+        };
+        i += 1;
+        match c {
+            // a ']' right at the start is a literal
+            ']' if i - 1 > start => break,
+            // a '-' between two characters is a range
+            '-' if i - 1 > start && chars.get(i).is_some_and(|&c| c != ']') => out.push('-'),
+            _ => {
+                if regex_syntax::is_meta_character(c) {
+                    out.push('\\');
+                }
+                out.push(c);
+            }
+        }
+    }
+    out.push(']');
+    Ok(i)
+}
+
+fn push_literal(out: &mut String, c: char) {
+    regex_syntax::escape_into(c.encode_utf8(&mut [0; 4]), out);
+}
+
+/// A set of globs for including and excluding paths. Globs starting with '!' exclude.
+#[derive(Debug, Default)]
+pub struct GlobSet {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl GlobSet {
+    /// Add a glob to the set, which excludes paths if it starts with '!'.
+    pub fn add(&mut self, glob: &str) -> Result<(), GlobError> {
+        match glob.strip_prefix('!') {
+            Some(glob) => self.exclude.push(Regex::new(&translate(glob)?)?),
+            None => self.include.push(Regex::new(&translate(glob)?)?),
+        }
+        Ok(())
+    }
+
+    /// Check whether a path, relative to the directory being walked, should be used.
+    ///
+    /// Paths matching any exclusion are never used. Directories are used otherwise so that
+    /// they're searched, and files only if they match an inclusion or there aren't any.
+    pub fn is_match(&self, path: &Path, is_dir: bool) -> bool {
+        let path = path.to_string_lossy();
+        #[cfg(windows)]
+        let path = path.replace('\\', "/");
+        if self.exclude.iter().any(|re| re.is_match(&path)) {
+            return false;
+        }
+        is_dir || self.include.is_empty() || self.include.iter().any(|re| re.is_match(&path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn glob_match(glob: &str, path: &str) -> bool {
+        Regex::new(&translate(glob).unwrap())
+            .unwrap()
+            .is_match(path)
+    }
+
+    #[test]
+    fn test_translate() {
+        assert!(glob_match("*.rs", "main.rs"));
+        assert!(glob_match("*.rs", "src/bin/main.rs"));
+        assert!(!glob_match("*.rs", "main.rs.orig"));
+        assert!(glob_match("src/*.rs", "src/main.rs"));
+        assert!(!glob_match("src/*.rs", "src/bin/main.rs"));
+        assert!(!glob_match("src/*.rs", "lib/src/main.rs"));
+        assert!(glob_match("/src/*.rs", "src/main.rs"));
+        assert!(glob_match("**/*.rs", "main.rs"));
+        assert!(glob_match("**/*.rs", "a/b/main.rs"));
+        assert!(glob_match("src/**", "src/a/b"));
+        assert!(glob_match("a/**/b", "a/b"));
+        assert!(glob_match("a/**/b", "a/x/y/b"));
+        assert!(!glob_match("a**b/c", "a/x/b/c"));
+        assert!(glob_match("?.[ch]", "x.h"));
+        assert!(!glob_match("?.[!ch]", "x.h"));
+        assert!(glob_match("[]a-c]", "]"));
+        assert!(glob_match("[]a-c]", "b"));
+        assert!(glob_match("[a-]", "-"));
+        assert!(glob_match("*.{rs,toml}", "Cargo.toml"));
+        assert!(glob_match("{a,b{c,d}}.txt", "bd.txt"));
+        assert!(glob_match(r"\*.(x)", "*.(x)"));
+
+        assert!(matches!(
+            translate("[abc"),
+            Err(GlobError::UnterminatedClass)
+        ));
+        assert!(matches!(
+            translate("{a,b"),
+            Err(GlobError::UnterminatedAlternation)
+        ));
+        assert!(matches!(translate("a}"), Err(GlobError::UnmatchedBrace)));
+    }
+
+    #[test]
+    fn test_glob_set() {
+        let mut globs = GlobSet::default();
+        assert!(globs.is_match(Path::new("anything"), false));
+        globs.add("!target").unwrap();
+        globs.add("!*.bak").unwrap();
+        assert!(globs.is_match(Path::new("foo.txt"), false));
+        assert!(!globs.is_match(Path::new("sub/target"), true));
+        assert!(!globs.is_match(Path::new("foo.rs.bak"), false));
+        globs.add("*.rs").unwrap();
+        assert!(!globs.is_match(Path::new("foo.txt"), false));
+        assert!(globs.is_match(Path::new("src"), true));
+        assert!(globs.is_match(Path::new("src/main.rs"), false));
+    }
+}
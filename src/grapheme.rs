@@ -0,0 +1,227 @@
+//! Extended grapheme cluster boundaries from Unicode Standard Annex #29, so that replacements can
+//! avoid splitting user-perceived characters apart.
+//!
+//! The character properties come from the regex crate's Unicode tables. Without the `unicode`
+//! feature, only UTF-8 sequences and CRLF are kept together.
+
+use std::ops::Range;
+use std::sync::OnceLock;
+
+use regex::RegexSet;
+
+/// Values of the Grapheme_Cluster_Break property which the boundary rules care about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Break {
+    CR,
+    LF,
+    Control,
+    Extend,
+    Zwj,
+    RegionalIndicator,
+    Prepend,
+    SpacingMark,
+    L,
+    V,
+    T,
+    Lv,
+    Lvt,
+    Other,
+}
+
+const BREAKS: [(Break, &str); 13] = [
+    (Break::CR, "CR"),
+    (Break::LF, "LF"),
+    (Break::Control, "Control"),
+    (Break::Extend, "Extend"),
+    (Break::Zwj, "ZWJ"),
+    (Break::RegionalIndicator, "Regional_Indicator"),
+    (Break::Prepend, "Prepend"),
+    (Break::SpacingMark, "SpacingMark"),
+    (Break::L, "L"),
+    (Break::V, "V"),
+    (Break::T, "T"),
+    (Break::Lv, "LV"),
+    (Break::Lvt, "LVT"),
+];
+
+/// The boundary properties of one character.
+#[derive(Debug, Clone, Copy)]
+struct Props {
+    brk: Break,
+    pictographic: bool,
+}
+
+/// A set with one regex for each of `BREAKS` and a last one for Extended_Pictographic, or None if
+/// the Unicode tables aren't available.
+fn property_set() -> Option<&'static RegexSet> {
+    static SET: OnceLock<Option<RegexSet>> = OnceLock::new();
+    SET.get_or_init(|| {
+        let classes = BREAKS
+            .iter()
+            .map(|(_, name)| format!(r"\A\p{{Grapheme_Cluster_Break={name}}}\z"))
+            .chain([r"\A\p{Extended_Pictographic}\z".to_owned()]);
+        RegexSet::new(classes).ok()
+    })
+    .as_ref()
+}
+
+fn props(c: char) -> Props {
+    let Some(set) = property_set() else {
+        let brk = match c {
+            '\r' => Break::CR,
+            '\n' => Break::LF,
+            _ if c.is_control() => Break::Control,
+            _ => Break::Other,
+        };
+        return Props {
+            brk,
+            pictographic: false,
+        };
+    };
+    let matches = set.matches(c.encode_utf8(&mut [0; 4]));
+    Props {
+        brk: BREAKS
+            .iter()
+            .enumerate()
+            .find(|&(i, _)| matches.matched(i))
+            .map_or(Break::Other, |(_, &(brk, _))| brk),
+        pictographic: matches.matched(BREAKS.len()),
+    }
+}
+
+/// Decode the character which ends at `pos`, if `text` is valid UTF-8 there.
+fn char_before(text: &[u8], pos: usize) -> Option<char> {
+    (1..=pos.min(4)).find_map(|len| single_char(&text[pos - len..pos]))
+}
+
+/// Decode the character which starts at `pos`, if `text` is valid UTF-8 there.
+fn char_after(text: &[u8], pos: usize) -> Option<char> {
+    (1..=(text.len() - pos).min(4)).find_map(|len| single_char(&text[pos..pos + len]))
+}
+
+fn single_char(bytes: &[u8]) -> Option<char> {
+    let mut chars = std::str::from_utf8(bytes).ok()?.chars();
+    chars.next().filter(|_| chars.as_str().is_empty())
+}
+
+/// Whether `pos` is inside a valid multi-byte UTF-8 sequence.
+fn splits_char(text: &[u8], pos: usize) -> bool {
+    (1..=pos.min(3)).any(|back| {
+        let start = pos - back;
+        (back + 1..=4).any(|len| {
+            start + len <= text.len() && single_char(&text[start..start + len]).is_some()
+        })
+    })
+}
+
+/// Check whether `pos` is a grapheme cluster boundary in `text`.
+///
+/// Positions which aren't valid UTF-8 on either side are boundaries, unless they're in the middle
+/// of a valid UTF-8 sequence, since there's no character there to split.
+pub fn is_boundary(text: &[u8], pos: usize) -> bool {
+    if pos == 0 || pos >= text.len() {
+        return true;
+    }
+    if splits_char(text, pos) {
+        return false;
+    }
+    let (Some(before), Some(after)) = (char_before(text, pos), char_after(text, pos)) else {
+        return true;
+    };
+    let (prev, next) = (props(before), props(after));
+    use Break::*;
+    match (prev.brk, next.brk) {
+        // GB3, GB4, GB5
+        (CR, LF) => false,
+        (CR | LF | Control, _) | (_, CR | LF | Control) => true,
+        // GB6, GB7, GB8: Hangul syllable sequences
+        (L, L | V | Lv | Lvt) | (Lv | V, V | T) | (Lvt | T, T) => false,
+        // GB9, GB9a, GB9b
+        (_, Extend | Zwj | SpacingMark) | (Prepend, _) => false,
+        // GB11: emoji Zwj sequences
+        (Zwj, _) if next.pictographic => !follows_pictographic(text, pos - before.len_utf8()),
+        // GB12, GB13: regional indicators pair up from the start of a run of them
+        (RegionalIndicator, RegionalIndicator) => {
+            regional_indicators_before(text, pos).is_multiple_of(2)
+        }
+        // GB999
+        _ => true,
+    }
+}
+
+/// Whether the text before `pos` ends with an Extended_Pictographic character followed by any
+/// number of Extend characters.
+fn follows_pictographic(text: &[u8], mut pos: usize) -> bool {
+    while let Some(c) = char_before(text, pos) {
+        let props = props(c);
+        if props.pictographic {
+            return true;
+        } else if props.brk != Break::Extend {
+            return false;
+        }
+        pos -= c.len_utf8();
+    }
+    false
+}
+
+/// Count the regional indicator characters immediately before `pos`.
+fn regional_indicators_before(text: &[u8], mut pos: usize) -> usize {
+    let mut count = 0;
+    while let Some(c) = char_before(text, pos).filter(|&c| props(c).brk == Break::RegionalIndicator)
+    {
+        count += 1;
+        pos -= c.len_utf8();
+    }
+    count
+}
+
+/// Get the range of the grapheme cluster containing `pos`, for reporting a split.
+pub fn cluster_around(text: &[u8], pos: usize) -> Range<usize> {
+    let start = (0..=pos).rev().find(|&i| is_boundary(text, i)).unwrap_or(0);
+    let end = (pos + 1..=text.len())
+        .find(|&i| is_boundary(text, i))
+        .unwrap_or(text.len());
+    start..end
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boundaries(text: &str) -> Vec<usize> {
+        (0..=text.len())
+            .filter(|&i| is_boundary(text.as_bytes(), i))
+            .collect()
+    }
+
+    #[test]
+    fn test_is_boundary() {
+        assert_eq!(boundaries("ab"), [0, 1, 2]);
+        assert_eq!(boundaries("a\r\nb"), [0, 1, 3, 4]);
+        // a multi-byte character is never split
+        assert_eq!(boundaries("é"), [0, 2]);
+        assert_eq!(cluster_around("xéx".as_bytes(), 2), 1..3);
+        assert!(is_boundary(b"\xff\xfe", 1));
+
+        if cfg!(feature = "unicode") {
+            // e + combining acute accent
+            assert_eq!(boundaries("e\u{301}x"), [0, 3, 4]);
+            // woman + Zwj + laptop
+            assert_eq!(boundaries("\u{1f469}\u{200d}\u{1f4bb}"), [0, 11]);
+            // a Zwj after something that isn't an emoji doesn't join
+            assert_eq!(boundaries("a\u{200d}\u{1f4bb}"), [0, 4, 8]);
+            // thumbs up with a skin tone modifier
+            assert_eq!(boundaries("\u{1f44d}\u{1f3fd}"), [0, 8]);
+            // two flags, each a pair of regional indicators
+            assert_eq!(
+                boundaries("\u{1f1ec}\u{1f1e7}\u{1f1eb}\u{1f1f7}"),
+                [0, 8, 16]
+            );
+            // Hangul jamo L + V + T
+            assert_eq!(boundaries("\u{1100}\u{1161}\u{11a8}"), [0, 9]);
+            // Devanagari KA + vowel sign I, a spacing mark
+            assert_eq!(boundaries("\u{915}\u{93f}"), [0, 6]);
+            assert_eq!(cluster_around("ae\u{301}".as_bytes(), 2), 1..4);
+        }
+    }
+}
@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+
+/// Number of bytes shown on each row, for each side.
+const ROW_LEN: usize = 8;
+
+/// Write a side-by-side hexdump of `old` and `new`.
+///
+/// Each row shows the offset, then the hex bytes and ASCII of `old` followed by the same for
+/// `new`. Rows where the two sides differ are marked with a '!'. Non-printable bytes are shown as
+/// '.' in the ASCII columns.
+pub fn write_hex_diff<W: Write + ?Sized>(out: &mut W, old: &[u8], new: &[u8]) -> io::Result<()> {
+    let rows = old.len().max(new.len()).div_ceil(ROW_LEN);
+    for row in 0..rows {
+        let offset = row * ROW_LEN;
+        let old_chunk = chunk(old, offset);
+        let new_chunk = chunk(new, offset);
+        let mark = if old_chunk == new_chunk { ' ' } else { '!' };
+
+        write!(out, "{mark} {offset:04x}  ")?;
+        write_row(out, old_chunk)?;
+        write!(out, " | ")?;
+        write_row(out, new_chunk)?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Write a hexdump of `data`, with the offset, hex bytes, and ASCII on each row.
+pub fn write_hexdump<W: Write + ?Sized>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    for offset in (0..data.len()).step_by(ROW_LEN) {
+        write!(out, "{offset:04x}  ")?;
+        write_row(out, chunk(data, offset))?;
+        writeln!(out)?;
+    }
+    Ok(())
+}
+
+/// Get the row of `data` starting at `offset`, which is empty if past the end.
+fn chunk(data: &[u8], offset: usize) -> &[u8] {
+    let start = offset.min(data.len());
+    let end = (offset + ROW_LEN).min(data.len());
+    &data[start..end]
+}
+
+/// Write one padded row of hex and ASCII.
+fn write_row<W: Write + ?Sized>(out: &mut W, data: &[u8]) -> io::Result<()> {
+    for b in data {
+        write!(out, "{b:02x} ")?;
+    }
+    for _ in data.len()..ROW_LEN {
+        write!(out, "   ")?;
+    }
+    write!(out, " ")?;
+    for &b in data {
+        let c = if b.is_ascii_graphic() || b == b' ' {
+            b as char
+        } else {
+            '.'
+        };
+        write!(out, "{c}")?;
+    }
+    for _ in data.len()..ROW_LEN {
+        write!(out, " ")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hex_diff() {
+        let mut out = vec![];
+        write_hex_diff(&mut out, b"hello world\n", b"hello\tworld!\n").unwrap();
+        let expected = concat!(
+            "! 0000  68 65 6c 6c 6f 20 77 6f  hello wo | 68 65 6c 6c 6f 09 77 6f  hello.wo\n",
+            "! 0008  72 6c 64 0a              rld.     | 72 6c 64 21 0a           rld!.   \n",
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+
+        let mut out = vec![];
+        write_hex_diff(&mut out, b"", b"").unwrap();
+        assert!(out.is_empty());
+
+        let mut out = vec![];
+        write_hex_diff(&mut out, b"same", b"same").unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "  0000  73 61 6d 65              same     | 73 61 6d 65              same    \n"
+        );
+    }
+
+    #[test]
+    fn test_hexdump() {
+        let mut out = vec![];
+        write_hexdump(
+            &mut out,
+            b"hello	world
+",
+        )
+        .unwrap();
+        let expected = concat!(
+            "0000  68 65 6c 6c 6f 09 77 6f  hello.wo\n",
+            "0008  72 6c 64 0a              rld.    \n",
+        );
+        assert_eq!(String::from_utf8(out).unwrap(), expected);
+    }
+}
@@ -0,0 +1,117 @@
+//! Skip the files listed in `.gitignore` and `.ignore` files during `-r`.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use regex::Regex;
+
+use crate::glob;
+
+/// The files read from each directory, in increasing order of precedence.
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".ignore"];
+
+#[derive(Debug)]
+struct Rule {
+    regex: Regex,
+    /// Whether this rule un-ignores paths, from a leading '!'
+    negated: bool,
+    /// Whether this rule only matches directories, from a trailing '/'
+    dir_only: bool,
+}
+
+/// The ignore rules from one directory, which apply to the paths beneath it.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    /// Read the ignore files in `dir`. Missing files are fine and just have no rules.
+    pub fn from_dir(dir: &Path) -> Result<IgnoreRules, (io::Error, &'static str)> {
+        let mut rules = IgnoreRules::default();
+        for name in IGNORE_FILES {
+            match fs::read_to_string(dir.join(name)) {
+                Ok(text) => rules.add_lines(&text),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err((err, name)),
+            }
+        }
+        Ok(rules)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Add the rules from the text of an ignore file, using the .gitignore syntax. Lines which
+    /// aren't valid patterns are skipped, like git does.
+    pub fn add_lines(&mut self, text: &str) {
+        for line in text.lines() {
+            // trailing spaces are removed unless they're escaped
+            let trimmed = line.trim_end_matches(' ');
+            let line = if trimmed.ends_with('\\') && trimmed.len() < line.len() {
+                &line[..trimmed.len() + 1]
+            } else {
+                trimmed
+            };
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (line, negated) = match line.strip_prefix('!') {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            let (line, dir_only) = match line.strip_suffix('/') {
+                Some(line) => (line, true),
+                None => (line, false),
+            };
+            if let Ok(Ok(regex)) = glob::translate_gitignore(line).map(|re| Regex::new(&re)) {
+                self.rules.push(Rule {
+                    regex,
+                    negated,
+                    dir_only,
+                });
+            }
+        }
+    }
+
+    /// Check a path relative to the directory these rules came from. Returns whether it's
+    /// ignored, or None if no rule matches it. The last matching rule wins.
+    pub fn matched(&self, path: &Path, is_dir: bool) -> Option<bool> {
+        let path = path.to_string_lossy();
+        #[cfg(windows)]
+        let path = path.replace('\\', "/");
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.dir_only) && rule.regex.is_match(&path))
+            .map(|rule| !rule.negated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ignore_rules() {
+        let mut rules = IgnoreRules::default();
+        rules.add_lines(
+            "# comment\n\n*.o\n!keep.o\nbuild/\n/top.txt\ndocs/*.html  \nspace\\ \n[bad\n",
+        );
+        let check = |path: &str, is_dir| rules.matched(Path::new(path), is_dir);
+        assert_eq!(check("main.o", false), Some(true));
+        assert_eq!(check("sub/main.o", false), Some(true));
+        assert_eq!(check("keep.o", false), Some(false));
+        assert_eq!(check("build", true), Some(true));
+        assert_eq!(check("sub/build", true), Some(true));
+        assert_eq!(check("build", false), None);
+        assert_eq!(check("top.txt", false), Some(true));
+        assert_eq!(check("sub/top.txt", false), None);
+        assert_eq!(check("docs/index.html", false), Some(true));
+        assert_eq!(check("main.c", false), None);
+        assert_eq!(check("space ", false), Some(true));
+        assert_eq!(rules.rules.len(), 6);
+    }
+}
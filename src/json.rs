@@ -0,0 +1,472 @@
+//! Just enough JSON to read requests and write reports, without pulling in serde.
+
+use std::fmt;
+
+/// A parsed JSON value. Object keys keep their original order.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    /// Build an object from a list of keys and values.
+    pub fn object<K, I>(fields: I) -> Value
+    where
+        K: Into<String>,
+        I: IntoIterator<Item = (K, Value)>,
+    {
+        Value::Object(fields.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    /// Look up a key in an object. Returns None if this isn't an object or the key is missing.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(fields) => fields.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Get a number if it's a non-negative integer.
+    pub fn as_usize(&self) -> Option<usize> {
+        match *self {
+            Value::Number(n) if n >= 0.0 && n.fract() == 0.0 && n <= usize::MAX as f64 => {
+                Some(n as usize)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Value::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&[Value]> {
+        match self {
+            Value::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Value {
+        Value::Bool(b)
+    }
+}
+
+impl From<usize> for Value {
+    fn from(n: usize) -> Value {
+        Value::Number(n as f64)
+    }
+}
+
+impl From<f64> for Value {
+    fn from(n: f64) -> Value {
+        Value::Number(n)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(s: &str) -> Value {
+        Value::String(s.to_owned())
+    }
+}
+
+impl From<String> for Value {
+    fn from(s: String) -> Value {
+        Value::String(s)
+    }
+}
+
+impl<T: Into<Value>> From<Vec<T>> for Value {
+    fn from(items: Vec<T>) -> Value {
+        Value::Array(items.into_iter().map(Into::into).collect())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(opt: Option<T>) -> Value {
+        opt.map_or(Value::Null, Into::into)
+    }
+}
+
+/// Values are formatted as compact JSON, all on one line.
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Null => f.write_str("null"),
+            Value::Bool(b) => write!(f, "{b}"),
+            // JSON has no NaN or infinity
+            Value::Number(n) if !n.is_finite() => f.write_str("null"),
+            Value::Number(n) => write!(f, "{n}"),
+            Value::String(s) => write_string(f, s),
+            Value::Array(items) => {
+                f.write_str("[")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                f.write_str("]")
+            }
+            Value::Object(fields) => {
+                f.write_str("{")?;
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i != 0 {
+                        f.write_str(",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{value}")?;
+                }
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter, s: &str) -> fmt::Result {
+    f.write_str("\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => f.write_str("\\\"")?,
+            '\\' => f.write_str("\\\\")?,
+            '\n' => f.write_str("\\n")?,
+            '\r' => f.write_str("\\r")?,
+            '\t' => f.write_str("\\t")?,
+            c if c.is_control() => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{c}")?,
+        }
+    }
+    f.write_str("\"")
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("invalid JSON at position {pos}: {msg}")]
+pub struct Error {
+    pos: usize,
+    msg: &'static str,
+}
+
+/// How deeply arrays and objects can be nested, to avoid overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+/// Parse a JSON document. Whitespace around the value is allowed but nothing else.
+pub fn parse(text: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        text: text.as_bytes(),
+        pos: 0,
+    };
+    let value = parser.value(0)?;
+    parser.skip_whitespace();
+    if parser.pos != parser.text.len() {
+        return Err(parser.error("trailing characters"));
+    }
+    Ok(value)
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn error(&self, msg: &'static str) -> Error {
+        Error { pos: self.pos, msg }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while matches!(self.peek(), Some(b' ' | b'\t' | b'\n' | b'\r')) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `expected` if it's next, otherwise fail.
+    fn expect(&mut self, expected: &[u8], msg: &'static str) -> Result<(), Error> {
+        if self.text[self.pos..].starts_with(expected) {
+            self.pos += expected.len();
+            Ok(())
+        } else {
+            Err(self.error(msg))
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Value, Error> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("nested too deeply"));
+        }
+
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.expect(b"null", "expected null").map(|_| Value::Null),
+            Some(b't') => self
+                .expect(b"true", "expected true")
+                .map(|_| Value::Bool(true)),
+            Some(b'f') => self
+                .expect(b"false", "expected false")
+                .map(|_| Value::Bool(false)),
+            Some(b'"') => self.string().map(Value::String),
+            Some(b'[') => self.array(depth),
+            Some(b'{') => self.object(depth),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn array(&mut self, depth: usize) -> Result<Value, Error> {
+        self.pos += 1; // '['
+        let mut items = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(items));
+        }
+        loop {
+            items.push(self.value(depth + 1)?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(items));
+                }
+                _ => return Err(self.error("expected ',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self, depth: usize) -> Result<Value, Error> {
+        self.pos += 1; // '{'
+        let mut fields = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(fields));
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.error("expected string key"));
+            }
+            let key = self.string()?;
+            self.skip_whitespace();
+            self.expect(b":", "expected ':'")?;
+            let value = self.value(depth + 1)?;
+            fields.push((key, value));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(fields));
+                }
+                _ => return Err(self.error("expected ',' or '}'")),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let start = p.pos;
+            while matches!(p.peek(), Some(b'0'..=b'9')) {
+                p.pos += 1;
+            }
+            p.pos > start
+        };
+
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if self.peek() == Some(b'0') {
+            self.pos += 1;
+        } else if !digits(self) {
+            return Err(self.error("invalid number"));
+        }
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            if !digits(self) {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if matches!(self.peek(), Some(b'e' | b'E')) {
+            self.pos += 1;
+            if matches!(self.peek(), Some(b'+' | b'-')) {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.error("invalid number"));
+            }
+        }
+
+        // unwraps are ok because we only consumed ASCII characters in valid float syntax
+        let s = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        Ok(Value::Number(s.parse().unwrap()))
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.pos += 1; // opening quote
+        let mut out = vec![];
+        loop {
+            let Some(b) = self.peek() else {
+                return Err(self.error("unterminated string"));
+            };
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let esc = self
+                        .peek()
+                        .ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match esc {
+                        b'"' | b'\\' | b'/' => out.push(esc),
+                        b'b' => out.push(0x08),
+                        b'f' => out.push(0x0c),
+                        b'n' => out.push(b'\n'),
+                        b'r' => out.push(b'\r'),
+                        b't' => out.push(b'\t'),
+                        b'u' => {
+                            let c = self.unicode_escape()?;
+                            out.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                0..=0x1f => return Err(self.error("control character in string")),
+                _ => out.push(b),
+            }
+        }
+        // the input was a str and escapes produce valid UTF-8, so this can't fail
+        String::from_utf8(out).map_err(|_| self.error("invalid UTF-8"))
+    }
+
+    /// Parse the XXXX part of a \uXXXX escape, including a following low surrogate if needed.
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let hi = self.hex4()?;
+        let code = if (0xD800..0xDC00).contains(&hi) {
+            self.expect(b"\\u", "unpaired surrogate")?;
+            let lo = self.hex4()?;
+            if !(0xDC00..0xE000).contains(&lo) {
+                return Err(self.error("unpaired surrogate"));
+            }
+            0x10000 + ((hi - 0xD800) << 10) + (lo - 0xDC00)
+        } else {
+            hi
+        };
+        char::from_u32(code).ok_or_else(|| self.error("invalid unicode escape"))
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let digits = self
+            .text
+            .get(self.pos..(self.pos + 4))
+            .and_then(|d| std::str::from_utf8(d).ok())
+            .filter(|d| d.bytes().all(|b| b.is_ascii_hexdigit()))
+            .ok_or_else(|| self.error("invalid unicode escape"))?;
+        self.pos += 4;
+        Ok(u32::from_str_radix(digits, 16).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(parse("null").unwrap(), Value::Null);
+        assert_eq!(parse(" true ").unwrap(), Value::Bool(true));
+        assert_eq!(parse("-12.5e1").unwrap(), Value::Number(-125.0));
+        assert_eq!(parse("0").unwrap(), Value::Number(0.0));
+        assert_eq!(
+            parse(r#""a\"b\\c\n\u00e9\ud83d\ude00""#).unwrap(),
+            Value::from("a\"b\\c\né😀")
+        );
+        assert_eq!(
+            parse(r#"{"a": [1, "x", {}], "b": {"c": null}}"#).unwrap(),
+            Value::object([
+                (
+                    "a",
+                    Value::Array(vec![
+                        1usize.into(),
+                        "x".into(),
+                        Value::object::<&str, _>([])
+                    ])
+                ),
+                ("b", Value::object([("c", Value::Null)])),
+            ])
+        );
+
+        for bad in [
+            "",
+            "nul",
+            "[1,]",
+            "{\"a\" 1}",
+            "{a: 1}",
+            "01",
+            "1.",
+            "\"abc",
+            "\"\\x\"",
+            "[1] 2",
+            "\"\\ud800\"",
+            "\"a\tb\"",
+        ] {
+            assert!(parse(bad).is_err(), "{bad:?} should fail");
+        }
+        assert!(parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+    }
+
+    #[test]
+    fn test_display() {
+        let value = Value::object([
+            ("s", Value::from("quote\" tab\t ctrl\x01 é")),
+            ("n", 3usize.into()),
+            ("f", 1.5.into()),
+            ("a", vec![true, false].into()),
+            ("none", None::<usize>.into()),
+        ]);
+        let text = value.to_string();
+        assert_eq!(
+            text,
+            r#"{"s":"quote\" tab\t ctrl\u0001 é","n":3,"f":1.5,"a":[true,false],"none":null}"#
+        );
+        assert_eq!(parse(&text).unwrap(), value);
+    }
+
+    #[test]
+    fn test_accessors() {
+        let value = parse(r#"{"s": "x", "b": false, "a": [1]}"#).unwrap();
+        assert_eq!(value.get("s").and_then(Value::as_str), Some("x"));
+        assert_eq!(value.get("b").and_then(Value::as_bool), Some(false));
+        assert_eq!(value.get("s").and_then(Value::as_bool), None);
+        assert_eq!(value.get("missing"), None);
+        assert_eq!(parse("12").unwrap().as_usize(), Some(12));
+        assert_eq!(parse("1.5").unwrap().as_usize(), None);
+        assert_eq!(parse("-1").unwrap().as_usize(), None);
+        assert_eq!(
+            value.get("a").and_then(Value::as_array),
+            Some(&[Value::Number(1.0)][..])
+        );
+    }
+}
@@ -0,0 +1,61 @@
+//! The stream replacement engine behind the `rp` command line tool, for embedding in other tools
+//! without shelling out.
+//!
+//! A [`Replacer`] pairs a [`Pattern`] to search for with a [`Template`] for the replacement text,
+//! and makes replacements line by line with [`Replacer::replace_stream`]. The easiest way to get
+//! one is from [`ReplaceOptions`], which holds the same settings as rp's command line flags:
+//!
+//! ```
+//! use rp::{Engine, ReplaceOptions, UnicodeMode};
+//!
+//! let opts = ReplaceOptions {
+//!     replace_all: true,
+//!     ..Default::default()
+//! };
+//! let replacer = opts.build_engine(
+//!     Engine::Auto,
+//!     r"(\w+)@example\.com",
+//!     "$1@example.org",
+//!     false,
+//!     UnicodeMode::Auto,
+//! )?;
+//!
+//! let mut output = vec![];
+//! let stats = replacer.replace_stream(&mut &b"alice@example.com bob@example.com\n"[..], &mut output)?;
+//! assert_eq!(output, b"alice@example.org bob@example.org\n");
+//! assert_eq!(stats.replacements, 2);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+//!
+//! [`ReplacerBuilder`] takes the command line flags themselves and checks them the same way rp
+//! does, and [`MultiPattern`] searches for several regexes at once, each with its own replacement.
+//!
+//! Replacement strings given on the command line usually go through [`unescape_bytes`] first, so
+//! that `\n` and `\x00` work.
+//!
+//! Building a replacer compiles its pattern, so build it once and reuse it. Replacers aren't
+//! modified while they're used, and are `Send` and `Sync` with the built-in patterns, so a server
+//! can share one between its worker threads by reference or by cheap clones.
+//!
+//! The items exported from the crate root are the stable API, whose signatures only change in a
+//! new major version. The modules are public so that the `rp` binary can use them too, but
+//! anything which is only reachable through them may change at any time.
+
+/// Grapheme cluster boundaries, for `grapheme_safe`.
+pub mod grapheme;
+/// Scratch buffers which are reused between lines.
+mod pool;
+/// Patterns, replacers, and the options for building them.
+pub mod replace;
+/// Parsing and expanding replacement strings.
+pub mod template;
+/// Escape sequences in replacement strings and output.
+pub mod unescape;
+
+pub use replace::{
+    Address, Anchor, BuildError, Candidate, Captures, Edit, EmptyMatches, Engine, EnginePattern,
+    Line, LineRange, MatchKind, MultiPattern, Pattern, Records, ReplaceError, ReplaceOptions,
+    Replacer, ReplacerBuilder, Stats, StreamError, UnicodeMode,
+};
+pub use template::{GroupRef, Template, TemplateSyntax};
+pub use unescape::unescape_bytes;
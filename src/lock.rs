@@ -0,0 +1,96 @@
+//! `--lock`: take advisory locks on files while editing them in-place.
+
+use std::fs::{File, TryLockError};
+use std::io;
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long to sleep between attempts to take a lock.
+const RETRY_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Debug, thiserror::Error)]
+pub enum LockError {
+    #[error("failed to open")]
+    Open(#[source] io::Error),
+    #[error("failed to lock")]
+    Lock(#[source] io::Error),
+    #[error("timed out waiting for another process to unlock it")]
+    Timeout,
+}
+
+/// Open `path` for reading and take an exclusive advisory lock on it, waiting up to `timeout`
+/// for other processes to release theirs, or forever if it's None.
+///
+/// In-place edits replace the file with a new one, so a process which was waiting for the lock
+/// may end up holding a lock on the old file. To avoid that, the lock is only returned once the
+/// locked file is still the one at `path`, and the new file is locked otherwise.
+pub fn open_locked(path: &Path, timeout: Option<Duration>) -> Result<File, LockError> {
+    let start = Instant::now();
+    loop {
+        let file = File::open(path).map_err(LockError::Open)?;
+        loop {
+            match file.try_lock() {
+                Ok(()) => break,
+                Err(TryLockError::WouldBlock) => {
+                    if timeout.is_some_and(|timeout| start.elapsed() >= timeout) {
+                        return Err(LockError::Timeout);
+                    }
+                    thread::sleep(RETRY_INTERVAL);
+                }
+                Err(TryLockError::Error(err)) => return Err(LockError::Lock(err)),
+            }
+        }
+        if is_same_file(&file, path).map_err(LockError::Open)? {
+            return Ok(file);
+        }
+    }
+}
+
+/// Check whether `file` is still the file at `path`.
+#[cfg(unix)]
+fn is_same_file(file: &File, path: &Path) -> io::Result<bool> {
+    use std::os::unix::fs::MetadataExt;
+    let (a, b) = (file.metadata()?, path.metadata()?);
+    Ok(a.dev() == b.dev() && a.ino() == b.ino())
+}
+
+#[cfg(not(unix))]
+fn is_same_file(_file: &File, _path: &Path) -> io::Result<bool> {
+    // files can't be replaced while they're open on Windows
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_open_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file");
+        std::fs::write(&path, "old").unwrap();
+
+        let locked = open_locked(&path, None).unwrap();
+        assert!(matches!(
+            open_locked(&path, Some(Duration::ZERO)),
+            Err(LockError::Timeout)
+        ));
+
+        // replacing the file while someone waits for the lock gives them the new file
+        let replacement = dir.path().join("new");
+        std::fs::write(&replacement, "new").unwrap();
+        let waiter = thread::spawn({
+            let path = path.clone();
+            move || open_locked(&path, None).map(|file| io::read_to_string(file).unwrap())
+        });
+        thread::sleep(RETRY_INTERVAL * 2);
+        std::fs::rename(&replacement, &path).unwrap();
+        drop(locked);
+        assert_eq!(waiter.join().unwrap().unwrap(), "new");
+        assert!(matches!(
+            open_locked(&dir.path().join("missing"), None),
+            Err(LockError::Open(_))
+        ));
+    }
+}
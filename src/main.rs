@@ -1,15 +1,83 @@
-use std::fs::File;
-use std::io::{self, BufReader, BufWriter};
-use std::path::{Path, PathBuf};
+use std::collections::BTreeMap;
+use std::fs::{self, File, Metadata, OpenOptions};
+use std::io::{self, BufRead, BufReader, BufWriter, IsTerminal, Seek, Write};
+use std::ops::Range;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant};
 
 use anyhow::Context;
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use rp::replace::{
+    Address, Anchor, EmptyMatches, Engine, Line, LineRange, MatchKind, Pattern, Records, Replacer,
+    ReplacerBuilder, Stats, StreamError, UnicodeMode,
+};
+use rp::template::TemplateSyntax;
+use rp::unescape::{escape_nonprintable_into, unescape_bytes};
 use tempfile::NamedTempFile;
 
-mod replace;
-use replace::{Pattern, ReplaceOptions, Replacer};
-mod unescape;
-use unescape::unescape_bytes;
+mod archive;
+mod diff;
+mod doctor;
+mod encoding;
+mod escape_debug;
+mod glob;
+mod hexdump;
+mod ignore;
+mod json;
+mod lock;
+mod matches;
+mod plugin;
+use plugin::PluginPattern;
+mod metrics;
+use metrics::{Metrics, METRICS};
+mod posix;
+mod preserve;
+mod presets;
+mod prompt;
+use prompt::Prompter;
+mod rename;
+mod repl;
+mod rules;
+mod sample;
+use sample::Sampler;
+mod sandbox;
+use sandbox::Sandbox;
+mod server;
+mod spans;
+mod state;
+use state::StateFile;
+mod verify;
+use verify::Verifier;
+mod vim;
+mod walk;
+mod workspace_edit;
+
+/// Exit status when at least one replacement was made and nothing failed.
+const EXIT_SUCCESS: i32 = 0;
+/// Exit status when nothing failed but there were no replacements.
+const EXIT_NO_MATCH: i32 = 1;
+/// Exit status for errors before any file was processed, like bad arguments or patterns.
+const EXIT_USAGE: i32 = 2;
+/// Exit status when some files failed but not all of them.
+const EXIT_PARTIAL: i32 = 3;
+/// Exit status when every file failed.
+const EXIT_FAILED: i32 = 4;
+
+const EXIT_STATUS_HELP: &str = "\
+Exit status:
+  0  at least one replacement was made and nothing failed
+  1  no replacements were made
+  2  usage error, or an error before any file was processed
+  3  some files failed
+  4  every file failed
+
+With --status=grep, 3 and 4 are 2 instead, like grep's exit status.";
+
+/// How to report failures in the exit status, from --status. Set once the arguments are parsed.
+static STATUS_STYLE: OnceLock<StatusStyle> = OnceLock::new();
 
 /// rp: A line-oriented stream replacer
 #[derive(Debug, Parser)]
@@ -19,13 +87,91 @@ use unescape::unescape_bytes;
     // enable custom styles
     styles = clap_styles(),
     // don't style the usage string, it looks ugly
-    override_usage = "rp [OPTIONS] PATTERN REPLACEMENT [FILES]...",
+    override_usage = "rp [OPTIONS] PATTERN REPLACEMENT [FILES]...\n       rp repl [OPTIONS] [FILE]\n       rp doctor [OPTIONS] [PATTERN]\n       rp rename [OPTIONS] OLD NEW [FILES]...\n       rp escape|unescape STRING\n       rp --spans-from FORMAT -i [OPTIONS] REPLACEMENT\n       rp --apply-matches FILE -i [OPTIONS]\n       rp --server",
+    // subcommands replace the normal PATTERN and REPLACEMENT arguments
+    args_conflicts_with_subcommands = true,
+    subcommand_negates_reqs = true,
+    // don't steal "help" as a subcommand, it's more likely to be a pattern
+    disable_help_subcommand = true,
+    after_long_help = EXIT_STATUS_HELP,
 )]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// Modify files in-place rather than printing to stdout
-    #[arg(short, long, requires = "files")]
+    #[arg(short, long)]
     in_place: bool,
 
+    /// Show each replacement and ask whether to make it, like 'git add -p' (in-place mode only).
+    ///
+    /// For each match, the line is shown before and after the replacement, and the answer can be
+    /// 'y' or 'n' for this match, 'a' or 'd' for this and the rest of the file, or 'q' to stop
+    /// without replacing anything else. Files are processed one at a time and the questions are
+    /// asked on the terminal, so stdin can't be used.
+    #[arg(
+        short,
+        long,
+        requires = "in_place",
+        conflicts_with_all = ["diff", "count", "spans_from"]
+    )]
+    prompt: bool,
+
+    /// Process every regular file in the directories given in FILES, recursively.
+    ///
+    /// Directories are walked in sorted order. Symlinks inside them aren't followed, and binary
+    /// files are skipped unless --binary or --text is used. Hidden files and directories are
+    /// skipped, as is anything listed in a .gitignore or .ignore file in one of the directories
+    /// walked. With no FILES, the current directory is used.
+    #[arg(short, long, conflicts_with = "spans_from")]
+    recursive: bool,
+
+    /// Only process files whose path matches GLOB while walking directories, which implies -r.
+    ///
+    /// This can be given more than once, and files matching any of the GLOBs are used. A GLOB
+    /// starting with '!' excludes the files and directories matching it instead. Paths are
+    /// matched relative to the directory being walked, and a GLOB without a '/' matches the file
+    /// name at any depth. '*' and '?' don't match '/', '**/' matches any number of directories,
+    /// and '[abc]' and '{foo,bar}' are supported. Files given directly in FILES are always used.
+    #[arg(long, value_name = "GLOB", conflicts_with = "spans_from")]
+    glob: Vec<String>,
+
+    /// Read the list of input files from FILE, one per line, or from stdin if FILE is '-'.
+    ///
+    /// This avoids argument length limits, e.g. with `find . -name '*.c' | rp --files-from - -i
+    /// PATTERN REPLACEMENT`. The files are used after any given in FILES, and blank lines are
+    /// ignored. An empty list means there's nothing to do, rather than reading stdin.
+    #[arg(long, value_name = "FILE", conflicts_with = "spans_from")]
+    files_from: Option<PathBuf>,
+
+    /// Separate the paths in --files-from with NUL bytes instead of newlines, like `find -print0`.
+    #[arg(short = '0', long, requires = "files_from")]
+    null: bool,
+
+    /// Also process hidden files and directories, whose names start with '.', with -r.
+    #[arg(long)]
+    hidden: bool,
+
+    /// Don't skip files listed in .gitignore and .ignore files with -r.
+    ///
+    /// These use the same syntax as .gitignore, and a directory's .ignore file takes precedence
+    /// over its .gitignore. Only the ignore files inside the directories being walked are used.
+    #[arg(long)]
+    no_ignore: bool,
+
+    /// Process binary files rather than skipping them, but don't print their replaced contents.
+    ///
+    /// Files with a NUL byte near the start are binary. With -r or more than one input, they're
+    /// skipped with a notice. With this, they're edited by -i like any other file, and when
+    /// printing the replaced text only a message saying how many replacements there were is
+    /// printed for each binary input, like grep.
+    #[arg(long, conflicts_with = "text")]
+    binary: bool,
+
+    /// Treat every input as text, so binary files are processed and printed like any other.
+    #[arg(short = 'a', long)]
+    text: bool,
+
     /// PATTERN and REPLACEMENT are literal strings, not regular expressions.
     #[arg(short = 'F', long)]
     fixed_strings: bool,
@@ -34,6 +180,49 @@ struct Args {
     #[arg(short = 'I', long, conflicts_with = "fixed_strings")]
     ignore_case: bool,
 
+    /// Only match whole words, so 'rp -w cat dog' leaves "concatenate" alone.
+    ///
+    /// A regex PATTERN is wrapped in '\b' word boundaries. With -F, each match has to start and
+    /// end next to something that isn't a letter, digit, or underscore.
+    #[arg(
+        short = 'w',
+        long,
+        conflicts_with_all = ["rules_file", "preset", "spans_from", "plugin", "vim_regex"]
+    )]
+    word_regexp: bool,
+
+    /// Only replace matches of the whole line, not counting its terminator, like 'grep -x'.
+    ///
+    /// This is the same as '--at only', and works the same way with -F and every engine, so
+    /// 'rp -xF "debug = true" "debug = false"' only rewrites lines which are exactly that.
+    #[arg(short = 'x', long, conflicts_with_all = ["at", "vim_regex"])]
+    line_regexp: bool,
+
+    /// Make each replacement follow the case of the text it replaces.
+    ///
+    /// If the matched text is all uppercase then so is the replacement, so replacing 'foo' with
+    /// 'bar' turns 'FOO' into 'BAR'. If only its first letter is uppercase then so is the
+    /// replacement's, so 'Foo' becomes 'Bar'. Otherwise the replacement is used as written. The
+    /// search is case-insensitive like -I, so one rule handles every casing, but with -F only
+    /// ASCII letters match regardless of case.
+    #[arg(long)]
+    preserve_case: bool,
+
+    /// Disable Unicode mode in regexes (regex mode only).
+    ///
+    /// Character classes like \w, \d, and \s and the '.' wildcard will match only ASCII bytes
+    /// rather than UTF-8 encoded codepoints. This is useful for binary-ish data and can be
+    /// significantly faster on large inputs.
+    ///
+    /// By default, Unicode mode is disabled automatically for patterns where it makes no
+    /// difference, such as patterns containing only ASCII literals and classes.
+    #[arg(long, conflicts_with = "fixed_strings")]
+    no_unicode: bool,
+
+    /// Always compile regexes with Unicode mode, even if the pattern is ASCII-only.
+    #[arg(long, conflicts_with_all = ["fixed_strings", "no_unicode"])]
+    strict_unicode: bool,
+
     /// Enable escape-sequence interpretation in REPLACEMENT.
     ///
     /// We support the same set of escape sequences as Rust string literals. Additionally non-ASCII
@@ -45,6 +234,105 @@ struct Args {
     #[arg(short, long, verbatim_doc_comment)]
     escape: bool,
 
+    /// With -F, interpret escape sequences in PATTERN like --escape does in REPLACEMENT.
+    ///
+    /// This allows searching for tabs, NUL bytes, or bytes which aren't valid UTF-8, like
+    /// '-F --escape-pattern "\x00"'. Regex patterns already support escapes like \t and \x00.
+    #[arg(long, requires = "fixed_strings", conflicts_with = "rules_file")]
+    escape_pattern: bool,
+
+    /// Don't expand capture groups in REPLACEMENT, every '$' is a literal dollar sign.
+    ///
+    /// Useful for replacements containing Makefile or shell snippets (regex mode only).
+    #[arg(long, conflicts_with = "fixed_strings")]
+    no_expand: bool,
+
+    /// Read REPLACEMENT from FILE instead of the command line, to avoid quoting large snippets.
+    ///
+    /// The file is used exactly as it is, including any final newline, and can span several lines
+    /// or contain binary data. Capture groups are expanded as usual unless -F or --no-expand is
+    /// used, and with -e escape sequences are processed too, which needs the file to be UTF-8. No
+    /// REPLACEMENT argument is used in this mode.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "branch_replacements", "rules_file", "preset", "expr", "spans_from", "delete",
+        ]
+    )]
+    replacement_file: Option<PathBuf>,
+
+    /// Which engine to search for PATTERN with.
+    ///
+    /// 'auto' uses a plain substring search when PATTERN has no regex syntax besides escaped
+    /// characters, and 'regex' otherwise. 'literal' and 'aho-corasick' search for PATTERN as-is
+    /// without parsing it as a regex, like -F, but REPLACEMENT can still use $0 for the match.
+    /// Only 'aho-corasick' supports --ignore-case, and only for ASCII letters.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ENGINE",
+        default_value_t,
+        conflicts_with_all = ["fixed_strings", "posix", "vim_regex", "branch_replacements"]
+    )]
+    engine: Engine,
+
+    /// Search using the plugin program at PATH, which is given PATTERN as its argument.
+    ///
+    /// The plugin talks to rp with one JSON object per line over its stdin and stdout. It first
+    /// writes {"version": 1, "groups": N}, where N is its number of capture groups. Then for each
+    /// search rp writes {"text": TEXT, "start": OFFSET}, and the plugin replies with the leftmost
+    /// match at or after OFFSET as {"match": [START, END], "groups": [[START, END] or null, ...]},
+    /// or {"match": null}. Offsets are in bytes. Lines which aren't valid UTF-8 aren't searched.
+    /// Replacements, in-place editing, and the other options work like with the built-in engines.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "fixed_strings", "ignore_case", "engine", "no_unicode", "strict_unicode", "posix",
+            "vim_regex", "branch_replacements",
+        ]
+    )]
+    plugin: Option<PathBuf>,
+
+    /// Interpret PATTERN as a POSIX basic (bre) or extended (ere) regular expression.
+    ///
+    /// The pattern is translated to the regex crate's syntax, so that patterns from sed and grep
+    /// can be used as-is. For example, in BRE mode \(a*\) is a group and (a*) matches literal
+    /// parentheses. Classes like [[:alpha:]] work in either mode. Backreferences aren't supported.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "DIALECT",
+        conflicts_with = "fixed_strings"
+    )]
+    posix: Option<posix::Dialect>,
+
+    /// Interpret PATTERN as a Vim regular expression.
+    ///
+    /// The pattern is translated to the regex crate's syntax. Vim's default "magic" syntax is
+    /// used, so \(a\|b\) is a group, and \v, \m, \M, and \V in the pattern switch magic
+    /// levels like in Vim. \zs and \ze set the start and end of the part of the match which is
+    /// replaced. Use --compat=sed to also accept Vim-style \1 and & in REPLACEMENT.
+    #[arg(
+        long,
+        conflicts_with_all = ["fixed_strings", "posix", "branch_replacements"]
+    )]
+    vim_regex: bool,
+
+    /// Accept replacement syntax from another tool (regex mode only).
+    ///
+    /// With 'sed', \1 through \9 and \0 in REPLACEMENT refer to capture groups like $1, & is the
+    /// whole match, and \n and \t are a newline and tab. Use \\ and \& for a literal backslash or
+    /// ampersand. $ references still work.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "MODE",
+        conflicts_with_all = ["fixed_strings", "no_expand", "escape", "branch_replacements"]
+    )]
+    compat: Option<Compat>,
+
     /// Replace all occurrences on each line rather than just the first match.
     #[arg(short = 'g', long)]
     replace_all: bool,
@@ -53,20 +341,750 @@ struct Args {
     #[arg(short = 'n', long)]
     only_matches: bool,
 
+    /// Prefix each output line with its line number, like 'grep -n'.
+    ///
+    /// With --paragraph or --multiline, each paragraph or input gets the number of its first line.
+    #[arg(
+        short = 'N',
+        long,
+        conflicts_with_all = [
+            "in_place", "diff", "hex_diff", "count", "summary", "sample", "format", "byte_range",
+        ]
+    )]
+    line_number: bool,
+
+    /// Prefix each output line with the name of its file, like 'grep -H'.
+    #[arg(
+        short = 'H',
+        long,
+        conflicts_with_all = ["in_place", "diff", "hex_diff", "count", "summary", "sample", "format"]
+    )]
+    with_filename: bool,
+
+    /// Print a '==> FILE <==' line before the output of each file, like 'tail' does.
+    ///
+    /// This keeps the combined output of several files readable without -H on every line. Files
+    /// which produce no output, like those without matches with -n, get no header.
+    #[arg(
+        long,
+        conflicts_with_all = ["in_place", "diff", "hex_diff", "count", "summary", "sample", "format"]
+    )]
+    file_headers: bool,
+
+    /// Replace only the first match in each file, and copy the rest of the file unchanged.
+    ///
+    /// Once a file has had its replacement, the pattern isn't searched for in the remaining
+    /// lines. Useful for updating a header or version line which may appear again later.
+    #[arg(long, conflicts_with = "replace_all")]
+    only_first_match_per_file: bool,
+
+    /// Replace at most N matches on each line, like -g but with a limit.
+    #[arg(long, value_name = "N")]
+    max: Option<usize>,
+
+    /// Replace at most N matches in each file, and copy the rest of the file unchanged.
+    ///
+    /// This is a generalization of --only-first-match-per-file, and once a file has N
+    /// replacements the pattern isn't searched for in the remaining lines.
+    #[arg(long, value_name = "N")]
+    max_per_file: Option<usize>,
+
+    /// Only make replacements in the lines in RANGE, like '10-20', '5', or '30-'.
+    ///
+    /// Line numbers start at 1, and other lines are passed through unchanged. With --paragraph,
+    /// a paragraph is in RANGE if its first line is.
+    #[arg(long, value_name = "RANGE", value_parser = parse_line_range)]
+    lines: Option<LineRange>,
+
+    /// Only make replacements in the bytes from offset START up to END, like '1000..2000'.
+    ///
+    /// Either offset can be left out to mean the start or end of the input. The rest of the input
+    /// is copied through unchanged without being searched, and the range is searched as if it
+    /// were the whole input, so its first and last lines may be partial lines.
+    #[arg(
+        long,
+        value_name = "START..END",
+        value_parser = parse_byte_range,
+        conflicts_with_all = [
+            "diff", "hex_diff", "count", "summary", "sample", "format", "only_matches", "prompt",
+        ]
+    )]
+    byte_range: Option<Range<u64>>,
+
+    /// Only make replacements from each line matching REGEX through the next line matching
+    /// --to-regex, like a sed '/from/,/to/' address.
+    ///
+    /// Without --to-regex, replacements are made from the first line matching REGEX to the end
+    /// of the input. Without --from-regex, they're made from the first line through the first
+    /// line matching --to-regex. Combined with --lines, lines must be selected by both.
+    #[arg(long, value_name = "REGEX")]
+    from_regex: Option<String>,
+
+    /// Stop making replacements after the next line matching REGEX. See --from-regex.
+    #[arg(long, value_name = "REGEX")]
+    to_regex: Option<String>,
+
+    /// Search each paragraph at once rather than each line, like awk's RS="".
+    ///
+    /// A paragraph is a run of non-blank lines, so that patterns can match across the line breaks
+    /// inside it, for example to replace a whole section of a config file. Use (?s) for '.' to
+    /// match newlines, and ^ and $ match at the start and end of each line. Blank lines between
+    /// paragraphs are searched one at a time like normal.
+    #[arg(long, conflicts_with = "output_delimiter")]
+    paragraph: bool,
+
+    /// Search the whole input at once, so that patterns can match across lines.
+    ///
+    /// Each file is read into memory. Use \n to match line breaks, and (?s) for '.' to match them
+    /// too. ^ and $ still match at the start and end of each line. Without -g, only the first
+    /// match in each file is replaced.
+    #[arg(short = 'm', long, conflicts_with_all = ["paragraph", "output_delimiter"])]
+    multiline: bool,
+
+    /// Only replace matches at POSITION in the line: 'start' or 'end', or 'only' for a match of
+    /// the whole line.
+    ///
+    /// This works the same way with -F and every engine, so it's like adding ^ or $ to a regex
+    /// without having to escape the pattern. The line terminator isn't part of the line.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "POSITION",
+        conflicts_with = "vim_regex"
+    )]
+    at: Option<Anchor>,
+
+    /// Lines end with NUL bytes rather than newlines, like `sed -z`, e.g. for `find -print0`.
+    ///
+    /// Lines are still written with the NUL byte at the end, unless --output-delimiter changes
+    /// it. ^ and $ match next to NUL bytes rather than newlines, and '.' matches newlines.
+    #[arg(short = 'z', long)]
+    null_data: bool,
+
+    /// Convert inputs from ENCODING to UTF-8 before searching them, and the replaced text back.
+    ///
+    /// This allows editing files like UTF-16 ones from Windows, which would otherwise look binary.
+    /// PATTERN and REPLACEMENT are matched against the UTF-8 text. A byte order mark is kept as it
+    /// is. With --diff, --hex-diff, --count, or --summary, the output is UTF-8. Characters which
+    /// can't be converted back are an error.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ENCODING",
+        conflicts_with_all = [
+            "byte_range", "append_safe", "prompt", "diffstat", "verify", "format", "sample",
+            "export_matches", "spans_from", "apply_matches",
+        ]
+    )]
+    encoding: Option<encoding::Encoding>,
+
+    /// Fail if a single expanded replacement is larger than SIZE bytes.
+    ///
+    /// SIZE may have a K, M, or G suffix for KiB, MiB, or GiB. When the limit is exceeded, the
+    /// current file is aborted with an error and is not modified.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_replacement_size: Option<usize>,
+
+    /// Fail if the output for a single line grows larger than SIZE bytes.
+    ///
+    /// This applies to every line, including ones without any replacements. It guards against
+    /// patterns with many empty matches or huge replacements using up all memory, especially with
+    /// -g. SIZE may have a K, M, or G suffix like for --max-replacement-size. When the limit is
+    /// exceeded, the current file is aborted with an error and is not modified.
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    max_output_line: Option<usize>,
+
+    /// Fail if a match would split a grapheme cluster, such as a letter and its combining accent
+    /// or an emoji ZWJ sequence.
+    ///
+    /// Replacing only part of a user-perceived character can silently corrupt text, like turning
+    /// 'é' written as 'e' plus U+0301 into 'E' plus a stray accent. When a match starts or ends
+    /// inside a grapheme cluster, the current file is aborted with an error and is not modified.
+    #[arg(long)]
+    grapheme_safe: bool,
+
+    /// What to do when PATTERN matches the empty string, like 'a*' does before every character
+    /// which isn't an 'a'.
+    ///
+    /// 'advance' replaces empty matches like most regex tools, inserting REPLACEMENT there, except
+    /// directly after another match. 'skip' leaves them alone, so 'a*' only replaces runs of at
+    /// least one 'a'. 'error' aborts the current file, which is left unmodified. Every engine
+    /// handles empty matches the same way.
+    #[arg(long, value_enum, value_name = "MODE", default_value_t)]
+    empty_matches: EmptyMatches,
+
+    /// Only change files with at least N replacements.
+    ///
+    /// Files with fewer are left alone. In-place edits don't modify them, and otherwise they're
+    /// printed unchanged, or not at all with --only-matches or --hex-diff. This is useful for
+    /// handling the files which use a pattern the most first. When printing to stdout, each file
+    /// is read into memory so that it can be counted before any output is written.
+    #[arg(long, value_name = "N")]
+    min_matches: Option<usize>,
+
+    /// Give each branch of PATTERN's top-level alternation its own replacement (regex mode only).
+    ///
+    /// LIST is a comma-separated list of literal replacements, one for each branch of the
+    /// alternation. For example, 'cat|dog' or '(cat|dog)' with a LIST of 'dog,cat' swaps the two
+    /// words. No REPLACEMENT argument is used in this mode. Capture groups are not expanded, but
+    /// escape sequences are when --escape is used.
+    #[arg(
+        long,
+        value_name = "LIST",
+        conflicts_with_all = ["fixed_strings", "no_expand"]
+    )]
+    branch_replacements: Option<String>,
+
+    /// Check that PATTERN has a named group for each of NAMES, a comma-separated list, and that
+    /// REPLACEMENT uses each of them.
+    ///
+    /// This catches a pattern and replacement which have drifted apart, like in a long-lived
+    /// script where the pattern was edited but the replacement wasn't. A group used by its number
+    /// rather than its name counts too.
+    #[arg(
+        long,
+        value_name = "NAMES",
+        value_delimiter = ',',
+        conflicts_with_all = [
+            "rules_file", "preset", "expr", "spans_from", "branch_replacements", "vim_regex",
+            "plugin", "fixed_strings", "no_expand",
+        ]
+    )]
+    require_groups: Vec<String>,
+
+    /// Remove each line which matches PATTERN, rather than replacing the match, like 'sed
+    /// /PATTERN/d'.
+    ///
+    /// This works like 'grep -v' as a filter, and with -i it removes the lines from the files.
+    /// No REPLACEMENT argument is used in this mode. Each removed line counts as one replacement.
+    #[arg(
+        short = 'd',
+        long,
+        conflicts_with_all = [
+            "branch_replacements", "rules_file", "preset", "expr", "spans_from", "prompt",
+            "multiline", "require_groups",
+        ]
+    )]
+    delete: bool,
+
+    /// Escape control characters and invalid UTF-8 in the output when stdout is a terminal.
+    ///
+    /// Non-printable bytes are shown using the same syntax as --escape, like \x1B or \xFF, so
+    /// that binary-ish input doesn't garble the terminal. Has no effect when stdout is redirected.
+    #[arg(long, conflicts_with = "in_place")]
+    escape_output: bool,
+
+    /// Also write the replaced text printed to stdout into FILE, like 'tee'.
+    ///
+    /// FILE gets the same text as stdout, except that --escape-output never applies to it and
+    /// --color=auto doesn't highlight anything.
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["in_place", "format"])]
+    also_write: Option<PathBuf>,
+
+    /// Highlight the replaced text when printing to stdout: 'auto', 'always', or 'never'.
+    ///
+    /// 'auto' highlights when stdout is a terminal and the NO_COLOR environment variable isn't
+    /// set. Only the normal text output is highlighted, and not when --escape-output applies.
+    #[arg(long, value_enum, value_name = "WHEN", default_value_t)]
+    color: clap::ColorChoice,
+
+    /// Show what would change as a unified diff, instead of printing the replaced text or
+    /// modifying files.
+    ///
+    /// Each file with changes gets its own '---' and '+++' header followed by hunks with three
+    /// lines of context, which can be applied later with 'patch -p0'. This also works with -i to
+    /// preview an in-place edit.
+    #[arg(long, visible_alias = "dry-run", conflicts_with_all = ["hex_diff", "format"])]
+    diff: bool,
+
+    /// Instead of the normal output, print the number of replacements in each file and how many
+    /// lines they were on, like 'file.txt: 42 replacements on 17 lines'.
+    ///
+    /// This also works with -i to see what an in-place edit would do without modifying anything.
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["diff", "hex_diff", "format", "only_matches", "spans_from"]
+    )]
+    count: bool,
+
+    /// Instead of the normal output, print a summary of the replacements grouped by KIND.
+    ///
+    /// With 'dirs', each directory containing files with replacements gets a line like
+    /// 'src/: 3 files, 12 replacements', followed by the total. Like --count, this also works with
+    /// -i to see where an in-place edit would land without modifying anything.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "KIND",
+        conflicts_with_all = ["count", "diff", "hex_diff", "format", "only_matches", "spans_from"]
+    )]
+    summary: Option<Summary>,
+
+    /// Instead of the normal output, show N randomly chosen replacements from all the inputs.
+    ///
+    /// Each one is shown as the line before and after that replacement, followed by how many
+    /// replacements were found in total. Nothing is modified, and only the sample is kept in
+    /// memory, so this is a quick way to check what a pattern would do to a huge set of files.
+    #[arg(
+        long,
+        value_name = "N",
+        conflicts_with_all = [
+            "in_place", "diff", "count", "hex_diff", "format", "only_matches", "also_write",
+            "summary",
+        ]
+    )]
+    sample: Option<usize>,
+
+    /// Instead of the normal output, show a side-by-side hexdump of each changed line.
+    ///
+    /// Useful when the replacement involves escape sequences or invisible characters.
+    #[arg(long, conflicts_with = "in_place")]
+    hex_diff: bool,
+
+    /// Output format to use instead of the replaced text.
+    ///
+    /// 'workspace-edit' prints a single LSP WorkspaceEdit JSON object listing the range and new
+    /// text of every replacement in each file, so that editor plugins can apply the changes
+    /// themselves. Files are never modified.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        default_value_t,
+        conflicts_with_all = ["in_place", "hex_diff"]
+    )]
+    format: OutputFormat,
+
+    /// Write every match to PATH as JSON lines instead of printing the replaced text, or '-' for
+    /// stdout. Files are never modified.
+    ///
+    /// Each line is an object with the "path", the "line" number, the "start" and "end" byte
+    /// offsets of the match in the file, the "match" text, its "replacement", and the
+    /// "captures", a list of each group's text starting with the whole match, or null for groups
+    /// which didn't match. Text which isn't UTF-8 is {"bytes": BASE64} rather than a string. This
+    /// is meant for review tools.
+    #[arg(
+        long,
+        value_name = "PATH",
+        conflicts_with_all = [
+            "in_place", "diff", "hex_diff", "count", "summary", "sample", "format", "prompt",
+            "expr", "delete", "branch_replacements", "vim_regex", "spans_from", "byte_range",
+        ]
+    )]
+    export_matches: Option<PathBuf>,
+
+    /// Terminate output lines with BYTES instead of a newline.
+    ///
+    /// Escape sequences in BYTES are always interpreted, e.g. '\0' for NUL-separated output which
+    /// is safe to pass to 'xargs -0'. Lines which had no newline in the input won't get one.
+    // fully-qualified Vec so that clap doesn't treat this as a list of u8 values
+    #[arg(long, value_name = "BYTES", value_parser = parse_bytes)]
+    output_delimiter: Option<std::vec::Vec<u8>>,
+
+    /// Process up to N files at once, or 0 for one per CPU.
+    ///
+    /// Output printed to stdout is always in the same order as the input files. When a file
+    /// finishes before the ones listed ahead of it, its output is held in memory until they're
+    /// done.
+    ///
+    /// Each thread reuses up to 8 spare scratch buffers of each kind between lines and files. Set
+    /// the RP_BUFFER_POOL_SIZE environment variable to change how many, or 0 to turn this off.
+    #[arg(short = 'j', long, value_name = "N", default_value_t = 1)]
+    threads: usize,
+
+    /// Record each file in FILE once it's been modified (in-place mode only).
+    ///
+    /// Combined with --resume, this lets a large batch of in-place edits be interrupted and then
+    /// continued without processing any file twice. Files are matched by the path given on the
+    /// command line, so use the same paths when resuming.
+    #[arg(long, value_name = "FILE", requires = "in_place")]
+    state: Option<PathBuf>,
+
+    /// Create temporary files for in-place edits in DIR rather than next to each file.
+    ///
+    /// By default the temporary file is created in the same directory as the file being edited
+    /// so that it can be renamed over the original, falling back to the system temp directory
+    /// (TMPDIR) if that fails. Use this when the files' directories are read-only or short on
+    /// quota. If DIR is on a different filesystem then the temporary file can't be renamed, so it
+    /// is copied into place instead.
+    #[arg(long, value_name = "DIR", requires = "in_place")]
+    temp_dir: Option<PathBuf>,
+
+    /// Set the permissions of in-place edited files to MODE instead of keeping the original ones.
+    ///
+    /// MODE is an octal number like 644 or 0755. On platforms without Unix permissions, only
+    /// whether MODE has any write bits is used, to set the file read-only or not.
+    #[arg(long, value_name = "MODE", value_parser = parse_mode, requires = "in_place")]
+    chmod: Option<u32>,
+
+    /// Keep these attributes of in-place edited files, as a comma-separated list.
+    ///
+    /// Edited files are replaced by new ones, which keep the original permissions but otherwise
+    /// belong to the current user and have no extended attributes. 'ownership' keeps the owner and
+    /// group, which needs root unless they're the current user's. 'xattr' keeps extended
+    /// attributes like SELinux labels and ACLs, and is only supported on Linux. 'timestamps' keeps
+    /// the access and modification times. A file is left alone if these can't be kept.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "ATTRS",
+        value_delimiter = ',',
+        requires = "in_place",
+        conflicts_with = "append_safe"
+    )]
+    preserve: Vec<preserve::Attribute>,
+
+    /// When a file edited in-place is a symlink, edit the file it points to.
+    ///
+    /// By default, like 'sed -i', the symlink itself is replaced by a regular file with the edited
+    /// contents, and the file it pointed to is left alone. With this, the link is kept and its
+    /// target is edited, along with the target of any link it points to in turn. Backups and
+    /// temporary files go next to the target, and with --sandbox the target has to be one of the
+    /// FILES too. Symlinks inside directories walked by -r are still skipped.
+    #[arg(long, requires = "in_place")]
+    follow_symlinks: bool,
+
+    /// Refuse to modify any files other than the FILES given, as they're resolved at startup.
+    ///
+    /// Each file's directory is resolved before any work is done and again right before the file
+    /// is replaced, and rp stops with an error if it has changed, for example because a directory
+    /// was replaced by a symlink. Temporary files are only created next to the files being
+    /// edited. With --spans-from, the files are the ones listed on stdin.
+    #[arg(long, requires = "in_place", conflicts_with_all = ["temp_dir", "state"])]
+    sandbox: bool,
+
+    /// Save a copy of each edited file with SUFFIX added to its name, '.bak' by default.
+    ///
+    /// Like 'sed -i.bak', the backup has the original contents of the file. An existing backup
+    /// is overwritten. Files which aren't changed don't get a backup.
+    #[arg(
+        short = 'b',
+        long,
+        value_name = "SUFFIX",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = ".bak",
+        requires = "in_place",
+        conflicts_with = "sandbox"
+    )]
+    backup: Option<String>,
+
+    /// Save backups of edited files in DIR rather than next to them.
+    ///
+    /// The backup's path inside DIR is the file's path as given, without any leading '/' or
+    /// '..', plus the --backup suffix if there is one. Directories are created as needed.
+    #[arg(
+        long,
+        value_name = "DIR",
+        requires = "in_place",
+        conflicts_with = "sandbox"
+    )]
+    backup_dir: Option<PathBuf>,
+
+    /// Lock each file while editing it, so that concurrent runs of 'rp -i' don't lose changes.
+    ///
+    /// An exclusive advisory lock (flock on Unix) is held on each file from before it's read until
+    /// the updated version is in place. If another process holds the lock, rp waits for it, up to
+    /// --lock-timeout. Only programs which also lock the file are kept out.
+    #[arg(long, requires = "in_place")]
+    lock: bool,
+
+    /// Give up on a file if its --lock can't be taken within SECONDS, rather than waiting forever.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_seconds, requires = "lock")]
+    lock_timeout: Option<Duration>,
+
+    /// Before saving each edited file, check that replacing INVERSE_PATTERN with
+    /// INVERSE_REPLACEMENT in the new contents gives back the original, and leave the file alone
+    /// if it doesn't.
+    ///
+    /// This is a safety check for replacements which should be reversible, like renaming a token
+    /// which catches files where the new name was already used. The inverse is interpreted with
+    /// the same flags as PATTERN and REPLACEMENT, except for --posix and --vim-regex.
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["INVERSE_PATTERN", "INVERSE_REPLACEMENT"],
+        requires = "in_place",
+        conflicts_with_all = ["diff", "count", "summary", "sample", "spans_from"]
+    )]
+    verify: Option<Vec<String>>,
+
+    /// Before saving each edited file, flush it to disk, read it back, and check that it's
+    /// exactly what repeating the replacement on the original gives, with the same number of
+    /// replacements. Files which fail the check are left alone.
+    ///
+    /// This catches a full disk, faulty storage, or a bug in rp before the original is replaced,
+    /// at the cost of reading each file twice.
+    #[arg(long, requires = "in_place", conflicts_with = "prompt")]
+    paranoid: bool,
+
+    /// Edit files by overwriting only the bytes which changed, rather than replacing them with a
+    /// new file, so they keep their inode and are never truncated.
+    ///
+    /// This is for files which other processes keep open, like logs which are being appended to
+    /// or tailed. Every replacement has to be the same length as the text it replaces, and a file
+    /// where one isn't is left alone. Anything appended while the file is being edited is kept.
+    #[arg(
+        long,
+        requires = "in_place",
+        conflicts_with_all = [
+            "diff", "count", "summary", "sample", "prompt", "diffstat", "paranoid", "verify",
+            "byte_range", "backup", "backup_dir", "temp_dir", "chmod",
+        ]
+    )]
+    append_safe: bool,
+
+    /// After editing files in-place, print how many lines changed in each one, like 'git diff
+    /// --stat'.
+    ///
+    /// Each changed file gets a line with its number of changed lines and a bar of '+' and '-' for
+    /// the inserted and deleted ones, followed by the totals. Each file is kept in memory while
+    /// it's edited to count its changes.
+    #[arg(
+        long,
+        requires = "in_place",
+        conflicts_with_all = ["diff", "count", "summary", "sample", "prompt", "byte_range"]
+    )]
+    diffstat: bool,
+
+    /// Skip the files already recorded in the --state file by a previous run.
+    #[arg(long, requires = "state")]
+    resume: bool,
+
+    /// Replace the spans listed on stdin in FORMAT, rather than searching for a PATTERN.
+    ///
+    /// With 'rg-json', stdin is the output of 'rg --json', so ripgrep's file filtering and regex
+    /// features decide what to replace. There's no PATTERN argument in this mode, only
+    /// REPLACEMENT, where $0 is the text of each span. Files are edited in-place and no FILES are
+    /// given. A file is left alone if its spans no longer contain the text that was found.
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        requires = "in_place",
+        conflicts_with_all = [
+            "fixed_strings", "ignore_case", "engine", "posix", "vim_regex", "branch_replacements",
+        ]
+    )]
+    spans_from: Option<spans::SpanFormat>,
+
+    /// Make exactly the edits listed in FILE, written by --export-matches, or read them from stdin
+    /// if FILE is '-'.
+    ///
+    /// This splits reviewing the changes from making them: export the matches, remove any which
+    /// shouldn't be replaced or edit their replacements, and apply what's left later. There's no
+    /// PATTERN, REPLACEMENT, or FILES in this mode, and files are edited in-place. A file is left
+    /// alone if any of its matches no longer contain the text that was exported.
+    #[arg(
+        long,
+        value_name = "FILE",
+        requires = "in_place",
+        conflicts_with_all = [
+            "fixed_strings", "ignore_case", "engine", "posix", "vim_regex", "branch_replacements",
+            "spans_from", "export_matches", "rules_file", "preset", "expr", "delete",
+            "replacement_file", "files_from", "recursive", "glob", "prompt", "append_safe",
+            "byte_range",
+        ]
+    )]
+    apply_matches: Option<PathBuf>,
+
+    /// Write statistics about the run to PATH when finished, for monitoring batch jobs.
+    ///
+    /// The file is in the Prometheus text format, for use with the node exporter's textfile
+    /// collector. It has the number of files processed, files changed, replacements, and errors,
+    /// whether the run succeeded, and its duration. It's written even if the run fails.
+    #[arg(long, value_name = "PATH")]
+    metrics_file: Option<PathBuf>,
+
+    /// Print an error if no replacements were made in any of the input files.
+    ///
+    /// The exit status is 1 when nothing was replaced either way, this just makes it noisy.
+    #[arg(long)]
+    fail_if_no_match: bool,
+
+    /// Treat files skipped because they're binary, or by -r because they're unreadable, as failed
+    /// files.
+    ///
+    /// Normally binary files are skipped with a notice and unreadable ones with a warning, and neither
+    /// affects the exit status. With this they're reported as errors and the exit status is 3 or 4.
+    #[arg(long)]
+    strict_errors: bool,
+
+    /// Print a JSON line to stderr for each input which was skipped, instead of the usual notices.
+    ///
+    /// Each line is an object with the "path", a "reason" code, and a "message" to show people.
+    /// The reasons are 'binary', 'permission-denied', and 'unreadable', and with -r also 'hidden',
+    /// 'ignored', 'excluded' by --glob, 'symlink', and 'special' for things like sockets, which are
+    /// otherwise left out silently. Wrappers can use this to tell a file where nothing matched
+    /// from one which was never searched.
+    #[arg(long)]
+    report_skipped: bool,
+
+    /// How to report errors in the exit status.
+    ///
+    /// Either way the exit status is 0 when something was replaced and 1 when nothing was. With
+    /// 'detailed', runs where some or all of the files failed exit with 3 or 4. With 'grep', any
+    /// error exits with 2, for scripts which expect grep's exit status.
+    #[arg(long, value_enum, value_name = "STYLE", default_value_t)]
+    status: StatusStyle,
+
+    /// Refer to stdin as NAME rather than '-' in messages, filename headers, and JSON output.
+    #[arg(long, value_name = "NAME")]
+    stdin_name: Option<PathBuf>,
+
+    /// Read JSON replacement requests from stdin, one per line, and write JSON responses.
+    ///
+    /// Each request is an object with "pattern", "replacement", either "text" or "file", and
+    /// optionally "id" and "options". Options are the long names of flags like "replace_all" or
+    /// "fixed_strings" with boolean values. Each response has "ok" and either "replacements" and
+    /// "text" or "error". A "rules" array of objects with "pattern" and "replacement" can be given
+    /// instead, to make several kinds of replacement in one pass. This avoids starting a new
+    /// process for each replacement in editor integrations and other tools.
+    #[arg(long, exclusive = true)]
+    server: bool,
+
+    /// Make every replacement listed in FILE in one pass, rather than a single PATTERN and
+    /// REPLACEMENT.
+    ///
+    /// FILE has a '[name]' line for each rule, followed by 'pattern = ...' and 'replacement = ...'
+    /// lines. Values are TOML strings: single-quoted ones are used as-is, which suits regexes, and
+    /// double-quoted ones support escapes like \n. Lines starting with '#' are comments.
+    ///
+    /// At each position in the input, the rules are tried in the order they're listed and the
+    /// first one that matches is used, so one rule's replacement is never matched by another.
+    /// Capture groups in each replacement refer to its own pattern. Flags like -F, -I, -g, and -e
+    /// apply to every rule. No PATTERN or REPLACEMENT arguments are given in this mode.
+    #[arg(
+        long,
+        value_name = "FILE",
+        conflicts_with_all = [
+            "spans_from", "branch_replacements", "posix", "vim_regex", "plugin", "engine",
+        ]
+    )]
+    rules_file: Option<PathBuf>,
+
+    /// Make the replacements of the built-in cleanup NAME, rather than a PATTERN and REPLACEMENT.
+    ///
+    /// See --list-presets for the available ones. This can be given more than once to combine
+    /// several presets, which then work like a --rules-file with each preset's rules in turn.
+    /// Every match is replaced as if with -g, and presets which need to match across lines read
+    /// each file into memory like -m.
+    #[arg(
+        long,
+        value_name = "NAME",
+        value_parser = clap::builder::PossibleValuesParser::new(
+            presets::PRESETS
+                .iter()
+                .map(|preset| clap::builder::PossibleValue::new(preset.name).help(preset.about)),
+        ),
+        conflicts_with_all = [
+            "rules_file", "expr", "spans_from", "branch_replacements", "posix", "vim_regex",
+            "plugin", "engine", "fixed_strings",
+        ]
+    )]
+    preset: Vec<String>,
+
+    /// List the built-in presets for --preset.
+    #[arg(long, exclusive = true)]
+    list_presets: bool,
+
+    /// Replace PATTERN with REPLACEMENT, and can be given more than once to chain several
+    /// substitutions like sed's -e.
+    ///
+    /// The substitutions are made on each line in order, so each one sees the text after the ones
+    /// before it have been made, and 'rp --expr foo bar --expr bar baz' turns 'foo' into 'baz'.
+    /// Every flag applies to each substitution. No PATTERN or REPLACEMENT arguments are given in
+    /// this mode.
+    #[arg(
+        long,
+        num_args = 2,
+        value_names = ["PATTERN", "REPLACEMENT"],
+        conflicts_with_all = [
+            "rules_file", "spans_from", "branch_replacements", "posix", "vim_regex", "plugin",
+            "prompt",
+        ]
+    )]
+    expr: Vec<String>,
+
     /// The pattern (regex or literal string) to search for
-    pattern: String,
+    #[arg(required_unless_present_any = [
+        "apply_matches", "expr", "list_presets", "preset", "rules_file", "server",
+    ])]
+    pattern: Option<String>,
 
     /// The replacement text.
     ///
     /// In regex mode, capture groups are specified using '$', e.g. $0 for the full match, $1 for
     /// the first group, or $name for a named capture group. Curly braces like ${1} or ${name} can
-    /// also be used. Use $$ for a literal dollar sign.
-    replacement: String,
+    /// also be used. Use $$ for a literal dollar sign. Referring to a group which doesn't exist in
+    /// the pattern is an error.
+    ///
+    /// A group's text can be re-cased as an identifier with ${camel(1)} for fooBar, ${pascal(1)}
+    /// for FooBar, ${snake(1)} for foo_bar, or ${title(1)} for Foo Bar. Words are split at '_',
+    /// '-', spaces, and changes of case.
+    ///
+    /// ${before:N} and ${after:N} expand to up to N bytes of the line before and after the match,
+    /// e.g. to quote where a change was made in a generated comment. ${LINE} expands to the whole
+    /// original line without its line break, e.g. to keep the old version in a comment next to
+    /// the new one.
+    ///
+    /// Omit when using --branch-replacements, --delete, or --replacement-file.
+    #[arg(required_unless_present_any = [
+        "apply_matches", "branch_replacements", "delete", "expr", "list_presets", "preset", "replacement_file",
+        "rules_file", "server", "spans_from",
+    ])]
+    replacement: Option<String>,
 
     /// List of input files. Omit or use '-' for stdin.
+    ///
+    /// A member of a tar archive can be given as ARCHIVE//MEMBER, like bundle.tar.gz//etc/app.conf,
+    /// or inner.tar//file inside it with bundle.tar.gz//inner.tar//file. With -i, the archive is
+    /// rebuilt with the member's new contents. Archives compressed with gzip, bzip2, xz, or zstd
+    /// need that program to be installed.
     files: Vec<PathBuf>,
 }
 
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum OutputFormat {
+    /// The input text with replacements made
+    #[default]
+    Text,
+    /// LSP WorkspaceEdit JSON
+    WorkspaceEdit,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Summary {
+    /// The number of files with replacements and replacements in each directory
+    Dirs,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+enum StatusStyle {
+    /// Separate exit statuses for errors before any file, some files failing, and every file
+    /// failing
+    #[default]
+    Detailed,
+    /// Exit status 2 for every error
+    Grep,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Compat {
+    /// sed-style \N backreferences and & for the whole match
+    Sed,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    Doctor(doctor::DoctorArgs),
+    Escape(escape_debug::EscapeArgs),
+    Rename(rename::RenameArgs),
+    Repl(repl::ReplArgs),
+    Unescape(escape_debug::UnescapeArgs),
+}
+
 /// Get the set of text styles to use in clap help/error text.
 fn clap_styles() -> clap::builder::Styles {
     use anstyle::{AnsiColor, Style};
@@ -83,141 +1101,2465 @@ fn clap_styles() -> clap::builder::Styles {
         .placeholder(value)
 }
 
-fn do_replace_stdout<P: Pattern>(replacer: Replacer<P>, files: &[PathBuf]) -> anyhow::Result<()> {
-    let mut failed = false;
-    for path in files {
-        let ret = if let Some("-") = path.to_str() {
-            // reading from stdin
-            replacer.replace_stream(&mut io::stdin().lock(), &mut io::stdout().lock())
-        } else {
-            let mut file = BufReader::new(
-                File::open(path).with_context(|| format!("unable to open '{}'", path.display()))?,
-            );
-            replacer.replace_stream(&mut file, &mut io::stdout().lock())
-        };
+/// Parse a size in bytes, with an optional K, M, or G suffix.
+fn parse_size(s: &str) -> Result<usize, String> {
+    let (num, shift) = match s.as_bytes().last() {
+        Some(b'k' | b'K') => (&s[..s.len() - 1], 10),
+        Some(b'm' | b'M') => (&s[..s.len() - 1], 20),
+        Some(b'g' | b'G') => (&s[..s.len() - 1], 30),
+        _ => (s, 0),
+    };
+    let num: usize = num.parse().map_err(|_| format!("invalid size '{s}'"))?;
+    num.checked_mul(1 << shift)
+        .ok_or_else(|| format!("size '{s}' is too large"))
+}
 
-        if let Err(err) = ret {
-            // Ignore EPIPE, that's somewhat common when paging output
-            if !err.is_broken_pipe() {
-                eprintln!("Error on '{}': {}", path.display(), err);
-                failed = true;
-            }
-        }
+/// Writer adapter which escapes non-printable characters in everything written to it.
+///
+/// Each write is escaped independently, so a multi-byte UTF-8 character split between two writes
+/// will be shown as invalid. That's fine because `Replacer` writes one line at a time.
+struct EscapeWriter<W> {
+    inner: W,
+    buf: Vec<u8>,
+}
+
+impl<W: Write> Write for EscapeWriter<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.clear();
+        escape_nonprintable_into(&mut self.buf, data);
+        self.inner.write_all(&self.buf)?;
+        Ok(data.len())
     }
 
-    if failed {
-        Err(anyhow::anyhow!("failed processing one or more files"))
-    } else {
-        Ok(())
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
     }
 }
 
-fn replace_one_inplace<P: Pattern>(replacer: &Replacer<P>, path: &Path) -> anyhow::Result<()> {
-    // open input first to make sure that the file exists
-    let infile = File::open(path).context("failed to open")?;
-    let dir = match path.parent() {
-        Some(dir) => {
-            if dir.as_os_str().is_empty() {
-                Path::new(".")
-            } else if !dir.is_dir() {
-                // this shouldn't actually happen
-                anyhow::bail!("parent '{}' isn't a directory", dir.display())
-            } else {
-                dir
-            }
-        }
-        None => anyhow::bail!("unable to get parent directory"),
-    };
+/// Writer adapter which copies everything written to it to a second writer, like 'tee'.
+struct TeeWriter<A, B> {
+    first: A,
+    second: B,
+}
 
-    // get input metadata, we'll need its permissions later
-    let infile_meta = infile.metadata().context("failed to get file metadata")?;
-    // now we can buffer the input
-    let mut infile = BufReader::new(infile);
+impl<A: Write, B: Write> Write for TeeWriter<A, B> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.first.write_all(data)?;
+        self.second.write_all(data)?;
+        Ok(data.len())
+    }
 
-    let mut outfile =
-        BufWriter::new(NamedTempFile::new_in(dir).context("failed to open temporary output file")?);
-    replacer.replace_stream(&mut infile, &mut outfile)?;
+    fn flush(&mut self) -> io::Result<()> {
+        self.first.flush()?;
+        self.second.flush()
+    }
+}
 
-    // Close the input first before we rename over it
-    drop(infile);
+/// Writer adapter which writes a header before the first data written to it, for --file-headers.
+struct HeaderWriter<'a> {
+    inner: &'a mut dyn Write,
+    /// The header, until it's been written
+    header: Option<Vec<u8>>,
+}
 
-    // get the tempfile out of the BufWriter, this will flush the remaining buffer
-    let outfile = outfile.into_inner().context("write error")?;
-    // atomically rename to replace the file
-    let new_outfile = outfile
-        .persist(path)
-        .context("failed to save updated file")?;
+impl Write for HeaderWriter<'_> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        if let Some(header) = self.header.take() {
+            self.inner.write_all(&header)?;
+        }
+        self.inner.write(data)
+    }
 
-    // set the same permissions as the input
-    new_outfile
-        .set_permissions(infile_meta.permissions())
-        .context("failed to set permissions on udpated file")?;
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
 
-    Ok(())
+/// Format the --file-headers line for a file, with a blank line to separate it from the output
+/// of the previous file unless it's the first.
+fn file_header(path: &Path, first: bool, args: &Args) -> Vec<u8> {
+    let style = match args.color {
+        clap::ColorChoice::Always => anstyle::AnsiColor::Magenta.on_default(),
+        _ => anstyle::Style::new(),
+    };
+    format!(
+        "{}==> {}{}{} <==\n",
+        if first { "" } else { "\n" },
+        style.render(),
+        display_path(path, args).display(),
+        style.render_reset()
+    )
+    .into_bytes()
 }
 
-fn do_replace_inplace<P: Pattern>(replacer: Replacer<P>, files: &[PathBuf]) -> anyhow::Result<()> {
-    for file in files {
-        replace_one_inplace(&replacer, file).with_context(|| file.display().to_string())?;
+/// Get the name to show for an input path, which is the --stdin-name if this is stdin.
+fn display_path<'a>(path: &'a Path, args: &'a Args) -> &'a Path {
+    match (path.to_str(), &args.stdin_name) {
+        (Some("-"), Some(name)) => name,
+        _ => path,
     }
-    Ok(())
 }
 
-fn run() -> anyhow::Result<()> {
-    let args = Args::parse();
-
-    let files = if args.files.is_empty() {
-        vec![PathBuf::from("-")]
-    } else {
-        args.files
+/// Make replacements in one input and write the result to `output` in the format chosen by `args`.
+///
+/// With --min-matches, the input is read into memory and only replaced if it has enough matches.
+fn replace_to_output<P, R>(
+    replacer: &Replacer<P>,
+    input: &mut R,
+    output: &mut dyn Write,
+    path: &Path,
+    args: &Args,
+) -> Result<usize, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+{
+    let Some(min) = args.min_matches else {
+        return write_replaced(replacer, input, output, path, args);
     };
 
-    // Quick check that stdin isn't specified twice in the files list. We can't be completely sure
-    // because of /proc/self/fd/0 or /dev/stdout or other such paths.
-    let stdin_arg_count = files
-        .iter()
-        .filter(|p| matches!(p.to_str(), Some("-")))
-        .count();
+    let mut text = vec![];
+    input.read_to_end(&mut text).map_err(StreamError::Read)?;
+    let mut buf = vec![];
+    let count = write_replaced(replacer, &mut &text[..], &mut buf, path, args)?;
+    if count >= min {
+        output.write_all(&buf).map_err(StreamError::Write)?;
+        Ok(count)
+    } else {
+        if args.count {
+            write_count(output, path, args, Stats::default()).map_err(StreamError::Write)?;
+        } else if !(args.only_matches || args.hex_diff || args.diff || args.summary.is_some()) {
+            output.write_all(&text).map_err(StreamError::Write)?;
+        }
+        Ok(0)
+    }
+}
 
-    if stdin_arg_count > 1 {
-        anyhow::bail!("stdin '-' argument specified more than once");
-    } else if args.in_place && stdin_arg_count > 0 {
-        anyhow::bail!("stdin can't be used with in-place replacement");
+/// Write the replaced text of one input, highlighted with --color, or its changes with --diff or
+/// --hex-diff, or its counts with --count.
+fn write_replaced<P, R>(
+    replacer: &Replacer<P>,
+    input: &mut R,
+    output: &mut dyn Write,
+    path: &Path,
+    args: &Args,
+) -> Result<usize, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+{
+    if args.diff {
+        diff::write_diff(replacer, input, output, display_path(path, args))
+    } else if args.hex_diff {
+        replacer
+            .for_each_line(input, |line| {
+                if line.count != 0 {
+                    writeln!(
+                        output,
+                        "{}:{}:",
+                        display_path(path, args).display(),
+                        line.number
+                    )?;
+                    hexdump::write_hex_diff(output, line.old, line.new)?;
+                }
+                Ok(())
+            })
+            .map(|stats| stats.replacements)
+    } else if args.count {
+        let stats = replacer.for_each_line(input, |_| Ok(()))?;
+        write_count(output, path, args, stats).map_err(StreamError::Write)?;
+        Ok(stats.replacements)
+    } else if args.summary.is_some() {
+        // the counts are summarized once all the files are done
+        Ok(replacer.for_each_line(input, |_| Ok(()))?.replacements)
+    } else if let Some(range) = &args.byte_range {
+        in_byte_range(range, input, output, |input, output| {
+            write_text(replacer, input, output, path, args)
+        })
+    } else {
+        write_text(replacer, input, output, path, args)
     }
+}
 
-    let opts = ReplaceOptions {
-        replace_all: args.replace_all,
+/// Write the replaced text of one input, highlighted with --color and prefixed with the file
+/// name and line number with -H and -N. With --binary, binary inputs are only summarized.
+fn write_text<P, R>(
+    replacer: &Replacer<P>,
+    input: &mut R,
+    output: &mut dyn Write,
+    path: &Path,
+    args: &Args,
+) -> Result<usize, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+{
+    if args.binary && looks_binary(input).map_err(StreamError::Read)? {
+        // like grep, don't dump binary data, just say that there were replacements
+        let count = replacer.for_each_line(input, |_| Ok(()))?.replacements;
+        if count > 0 {
+            let plural = if count == 1 { "" } else { "s" };
+            writeln!(
+                output,
+                "Binary file '{}' has {count} replacement{plural}",
+                display_path(path, args).display()
+            )
+            .map_err(StreamError::Write)?;
+        }
+        return Ok(count);
+    }
+    let color = args.color == clap::ColorChoice::Always;
+    if color || args.line_number || args.with_filename {
+        let mut buf = vec![];
+        replacer
+            .for_each_line(input, |line| {
+                if !replacer.writes_line(line) {
+                    return Ok(());
+                }
+                write_prefix(output, path, line.number, args)?;
+                let new = if color {
+                    buf.clear();
+                    highlight_edits(&mut buf, line);
+                    &buf
+                } else {
+                    line.new
+                };
+                replacer.write_line(line, new, output)
+            })
+            .map(|stats| stats.replacements)
+    } else {
+        replacer
+            .replace_stream(input, output)
+            .map(|stats| stats.replacements)
+    }
+}
+
+/// Copy `input` to `output`, except that the bytes in `range` go through `replace` instead, for
+/// --byte-range.
+fn in_byte_range<R, W, F>(
+    range: &Range<u64>,
+    input: &mut R,
+    output: &mut W,
+    replace: F,
+) -> Result<usize, StreamError>
+where
+    R: BufRead,
+    W: Write + ?Sized,
+    F: FnOnce(&mut io::Take<&mut R>, &mut W) -> Result<usize, StreamError>,
+{
+    copy_bytes(&mut io::Read::take(&mut *input, range.start), output)?;
+    let count = replace(
+        &mut io::Read::take(&mut *input, range.end - range.start),
+        output,
+    )?;
+    copy_bytes(input, output)?;
+    Ok(count)
+}
+
+/// Copy all of `input` to `output`, keeping read and write errors apart unlike `io::copy`.
+fn copy_bytes<R, W>(input: &mut R, output: &mut W) -> Result<(), StreamError>
+where
+    R: BufRead,
+    W: Write + ?Sized,
+{
+    loop {
+        let buf = input.fill_buf().map_err(StreamError::Read)?;
+        if buf.is_empty() {
+            return Ok(());
+        }
+        output.write_all(buf).map_err(StreamError::Write)?;
+        let len = buf.len();
+        input.consume(len);
+    }
+}
+
+/// Check whether an input looks binary from a NUL byte at the start of it, without consuming it.
+fn looks_binary<R: BufRead + ?Sized>(input: &mut R) -> io::Result<bool> {
+    Ok(memchr::memchr(0, input.fill_buf()?).is_some())
+}
+
+/// Write the -H and -N prefix for an output line, colored like grep with --color.
+fn write_prefix(output: &mut dyn Write, path: &Path, number: usize, args: &Args) -> io::Result<()> {
+    let style = |color: anstyle::AnsiColor| match args.color {
+        clap::ColorChoice::Always => color.on_default(),
+        _ => anstyle::Style::new(),
+    };
+    let (name, num, sep) = (
+        style(anstyle::AnsiColor::Magenta),
+        style(anstyle::AnsiColor::Green),
+        style(anstyle::AnsiColor::Cyan),
+    );
+    let mut field = |style: anstyle::Style, text: &dyn std::fmt::Display| {
+        write!(
+            output,
+            "{}{text}{}{}:{}",
+            style.render(),
+            style.render_reset(),
+            sep.render(),
+            sep.render_reset()
+        )
+    };
+    if args.with_filename {
+        field(name, &display_path(path, args).display())?;
+    }
+    if args.line_number {
+        field(num, &number)?;
+    }
+    Ok(())
+}
+
+/// Copy the replaced text of `line` into `buf`, with each replacement highlighted for --color.
+fn highlight_edits(buf: &mut Vec<u8>, line: &Line) {
+    let style = anstyle::Style::new()
+        .bold()
+        .fg_color(Some(anstyle::AnsiColor::Green.into()));
+    let mut last = 0;
+    for edit in line
+        .edits
+        .iter()
+        .filter(|edit| edit.new.end > edit.new.start)
+    {
+        buf.extend_from_slice(&line.new[last..edit.new.start]);
+        // unwraps are ok because writing to a Vec can't fail
+        write!(buf, "{}", style.render()).unwrap();
+        buf.extend_from_slice(&line.new[edit.new.clone()]);
+        write!(buf, "{}", style.render_reset()).unwrap();
+        last = edit.new.end;
+    }
+    buf.extend_from_slice(&line.new[last..]);
+}
+
+/// Write the --count line for one input.
+fn write_count(output: &mut dyn Write, path: &Path, args: &Args, stats: Stats) -> io::Result<()> {
+    let plural = |n| if n == 1 { "" } else { "s" };
+    writeln!(
+        output,
+        "{}: {} replacement{} on {} line{}",
+        display_path(path, args).display(),
+        stats.replacements,
+        plural(stats.replacements),
+        stats.lines,
+        plural(stats.lines),
+    )
+}
+
+/// Write the --summary=dirs lines from the number of files with replacements and replacements
+/// in each directory.
+fn write_dir_summary(
+    output: &mut dyn Write,
+    dirs: &BTreeMap<PathBuf, (usize, usize)>,
+) -> io::Result<()> {
+    let plural = |n| if n == 1 { "" } else { "s" };
+    let mut line = |name: &str, files, replacements| {
+        writeln!(
+            output,
+            "{name}: {files} file{}, {replacements} replacement{}",
+            plural(files),
+            plural(replacements),
+        )
+    };
+    for (dir, &(files, replacements)) in dirs {
+        let dir = match dir.to_string_lossy() {
+            dir if dir.is_empty() => "./".into(),
+            dir if dir.ends_with('/') => dir,
+            dir => format!("{dir}/").into(),
+        };
+        line(&dir, files, replacements)?;
+    }
+    let (files, replacements) = dirs
+        .values()
+        .fold((0, 0), |(f, r), &(files, reps)| (f + files, r + reps));
+    line("total", files, replacements)
+}
+
+/// Parse an octal file permission mode.
+fn parse_mode(s: &str) -> Result<u32, String> {
+    if s.is_empty() || !s.bytes().all(|b| matches!(b, b'0'..=b'7')) {
+        return Err(format!("invalid octal mode '{s}'"));
+    }
+    match u32::from_str_radix(s, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("mode '{s}' is out of range")),
+    }
+}
+
+/// Parse a range of line numbers for --lines.
+fn parse_line_range(s: &str) -> Result<LineRange, String> {
+    let invalid = || format!("invalid line range '{s}'");
+    let number = |n: &str| match n.parse() {
+        Ok(0) | Err(_) => Err(invalid()),
+        Ok(n) => Ok(n),
+    };
+    let range = match s.split_once('-') {
+        None => LineRange {
+            start: number(s)?,
+            end: Some(number(s)?),
+        },
+        Some((start, "")) => LineRange {
+            start: number(start)?,
+            end: None,
+        },
+        Some((start, end)) => LineRange {
+            start: number(start)?,
+            end: Some(number(end)?),
+        },
+    };
+    match range.end {
+        Some(end) if end < range.start => Err(invalid()),
+        _ => Ok(range),
+    }
+}
+
+/// Parse a range of byte offsets for --byte-range.
+fn parse_byte_range(s: &str) -> Result<Range<u64>, String> {
+    let invalid = || format!("invalid byte range '{s}', expected START..END");
+    let (start, end) = s.split_once("..").ok_or_else(invalid)?;
+    let offset = |n: &str, default| match n {
+        "" => Ok(default),
+        n => n.parse().map_err(|_| invalid()),
+    };
+    let range = offset(start, 0)?..offset(end, u64::MAX)?;
+    if range.start > range.end {
+        return Err(invalid());
+    }
+    Ok(range)
+}
+
+/// Parse a non-negative number of seconds, which may have a fractional part.
+fn parse_seconds(s: &str) -> Result<Duration, String> {
+    s.parse()
+        .ok()
+        .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+        .ok_or_else(|| format!("invalid number of seconds '{s}'"))
+}
+
+/// Parse a string containing escape sequences into bytes.
+fn parse_bytes(s: &str) -> Result<Vec<u8>, String> {
+    unescape_bytes(s).map_err(|err| err.to_string())
+}
+
+/// Make replacements in one input file, or stdin for "-", and write the result to `output`.
+fn replace_file_to_output<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    output: &mut dyn Write,
+    args: &Args,
+) -> Result<usize, StreamError> {
+    if let Some(encoding) = args.encoding {
+        return replace_file_decoded(replacer, path, output, args, encoding);
+    }
+    if let Some("-") = path.to_str() {
+        // reading from stdin
+        replace_to_output(replacer, &mut io::stdin().lock(), output, path, args)
+    } else if let Some(member) = archive::MemberPath::parse(path) {
+        let data = member
+            .read()
+            .map_err(|err| StreamError::Open(io::Error::other(err)))?;
+        replace_to_output(replacer, &mut &data[..], output, path, args)
+    } else {
+        let mut file = BufReader::new(File::open(path).map_err(StreamError::Open)?);
+        replace_to_output(replacer, &mut file, output, path, args)
+    }
+}
+
+/// Like `replace_file_to_output`, but for --encoding. The replaced text is converted back to the
+/// input's encoding, while diffs and counts are written as UTF-8.
+fn replace_file_decoded<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    output: &mut dyn Write,
+    args: &Args,
+    encoding: encoding::Encoding,
+) -> Result<usize, StreamError> {
+    let input: Box<dyn io::Read> = if let Some("-") = path.to_str() {
+        Box::new(io::stdin().lock())
+    } else if let Some(member) = archive::MemberPath::parse(path) {
+        let data = member
+            .read()
+            .map_err(|err| StreamError::Open(io::Error::other(err)))?;
+        Box::new(io::Cursor::new(data))
+    } else {
+        Box::new(File::open(path).map_err(StreamError::Open)?)
+    };
+    if args.diff || args.hex_diff || args.count || args.summary.is_some() {
+        let mut input = BufReader::new(encoding::Decoder::new(input, encoding));
+        replace_to_output(replacer, &mut input, output, path, args)
+    } else {
+        encoding::transcode(encoding, input, output, |input, output| {
+            replace_to_output(replacer, input, output, path, args)
+        })
+    }
+}
+
+/// Call `work` on each file using up to `threads` threads, then pass the results to `finish` on
+/// the calling thread in the same order as `files`. Stops early if `finish` returns an error.
+///
+/// Results which finish out of order are buffered until all the files before them are done.
+fn for_each_parallel<T, W, F>(
+    files: &[PathBuf],
+    threads: usize,
+    work: W,
+    mut finish: F,
+) -> anyhow::Result<()>
+where
+    T: Send,
+    W: Fn(&Path) -> T + Sync,
+    F: FnMut(&Path, T) -> anyhow::Result<()>,
+{
+    let next_file = AtomicUsize::new(0);
+    let (tx, rx) = mpsc::channel();
+
+    thread::scope(|scope| {
+        for _ in 0..threads.min(files.len()) {
+            let tx = tx.clone();
+            let (next_file, work) = (&next_file, &work);
+            scope.spawn(move || loop {
+                let index = next_file.fetch_add(1, Ordering::Relaxed);
+                let Some(path) = files.get(index) else {
+                    break;
+                };
+                // a send error means that finish failed and we should stop
+                if tx.send((index, work(path))).is_err() {
+                    break;
+                }
+            });
+        }
+        // drop our sender so that the loop below ends when all the threads are done
+        drop(tx);
+
+        let mut pending = BTreeMap::new();
+        let mut next_result = 0;
+        for (index, result) in rx {
+            pending.insert(index, result);
+            while let Some(result) = pending.remove(&next_result) {
+                finish(&files[next_result], result)?;
+                next_result += 1;
+            }
+        }
+        Ok(())
+    })
+}
+
+/// Get the number of threads to use, where 0 means one per CPU.
+fn thread_count(threads: usize) -> usize {
+    match threads {
+        0 => thread::available_parallelism().map_or(1, |n| n.get()),
+        n => n,
+    }
+}
+
+fn do_replace_stdout<P: Pattern + Sync>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    args: &Args,
+) -> anyhow::Result<usize> {
+    let mut also_write = match &args.also_write {
+        Some(path) => {
+            Some(BufWriter::new(File::create(path).with_context(|| {
+                format!("unable to create '{}'", path.display())
+            })?))
+        }
+        None => None,
+    };
+    let stdout = io::stdout().lock();
+    let mut output: Box<dyn Write + '_> = if args.escape_output && stdout.is_terminal() {
+        Box::new(EscapeWriter {
+            inner: stdout,
+            buf: vec![],
+        })
+    } else {
+        Box::new(stdout)
+    };
+    if let Some(file) = &mut also_write {
+        output = Box::new(TeeWriter {
+            first: output,
+            second: file,
+        });
+    }
+
+    let mut failed = false;
+    let mut total = 0;
+    // for --summary=dirs, the number of files with replacements and replacements in each
+    let mut dirs = BTreeMap::<PathBuf, (usize, usize)>::new();
+    let mut check_result = |path: &Path, ret: Result<usize, StreamError>| match ret {
+        Ok(count) => {
+            METRICS.record::<()>(&Ok(count));
+            total += count;
+            if count > 0 && args.summary.is_some() {
+                let dir = display_path(path, args).parent().unwrap_or(Path::new(""));
+                let (files, replacements) = dirs.entry(dir.to_owned()).or_default();
+                *files += 1;
+                *replacements += count;
+            }
+        }
+        // Ignore EPIPE, that's somewhat common when paging output
+        Err(err) if err.is_broken_pipe() => (),
+        Err(err) => {
+            METRICS.record(&Err(()));
+            eprintln!("Error on '{}': {}", display_path(path, args).display(), err);
+            failed = true;
+        }
+    };
+
+    // whether a --file-headers line hasn't been printed yet
+    let mut first_header = true;
+    let threads = thread_count(args.threads);
+    if threads > 1 {
+        // buffer each file's output so that it's printed in order rather than interleaved
+        let work = |path: &Path| {
+            let mut buf = vec![];
+            let ret = replace_file_to_output(&replacer, path, &mut buf, args);
+            (buf, ret)
+        };
+        for_each_parallel(files, threads, work, |path, (buf, ret)| {
+            let ret = ret.and_then(|count| {
+                if args.file_headers && !buf.is_empty() {
+                    let header = file_header(path, first_header, args);
+                    output.write_all(&header).map_err(StreamError::Write)?;
+                    first_header = false;
+                }
+                output.write_all(&buf).map_err(StreamError::Write)?;
+                Ok(count)
+            });
+            check_result(path, ret);
+            Ok(())
+        })?;
+    } else {
+        for path in files {
+            let ret = if args.file_headers {
+                let mut output = HeaderWriter {
+                    inner: &mut output,
+                    header: Some(file_header(path, first_header, args)),
+                };
+                let ret = replace_file_to_output(&replacer, path, &mut output, args);
+                first_header &= output.header.is_some();
+                ret
+            } else {
+                replace_file_to_output(&replacer, path, &mut output, args)
+            };
+            check_result(path, ret);
+        }
+    }
+
+    if args.summary == Some(Summary::Dirs) {
+        match write_dir_summary(&mut output, &dirs) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(err).context("failed to write output")
+            }
+            _ => (),
+        }
+    }
+
+    drop(output);
+    if let (Some(file), Some(path)) = (&mut also_write, &args.also_write) {
+        file.flush()
+            .with_context(|| format!("failed to write '{}'", path.display()))?;
+    }
+
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Settings for how in-place edits are saved.
+#[derive(Debug, Default)]
+struct InPlaceOptions {
+    /// Create temporary files here instead of in the edited file's directory.
+    temp_dir: Option<PathBuf>,
+    /// Give edited files this mode rather than their original permissions.
+    mode: Option<u32>,
+    /// Only allow modifying the files in this sandbox.
+    sandbox: Option<Sandbox>,
+    /// Leave files with fewer than this many replacements unmodified.
+    min_matches: Option<usize>,
+    /// Save the original contents of edited files with this suffix.
+    backup_suffix: Option<String>,
+    /// Save backups in this directory rather than next to the edited files.
+    backup_dir: Option<PathBuf>,
+    /// Lock files while editing them.
+    lock: bool,
+    /// Give up waiting for a lock after this long.
+    lock_timeout: Option<Duration>,
+    /// Only make replacements in this range of bytes.
+    byte_range: Option<Range<u64>>,
+    /// Convert files from this encoding and back.
+    encoding: Option<encoding::Encoding>,
+    /// Give edited files these attributes of the originals.
+    preserve: Vec<preserve::Attribute>,
+    /// Edit the targets of symlinks rather than replacing the links.
+    follow_symlinks: bool,
+    /// Check that the edit can be undone before saving it.
+    verify: Option<Verifier>,
+    /// Read back and check the new contents before saving them.
+    paranoid: bool,
+}
+
+impl InPlaceOptions {
+    /// Get the in-place options from the command line flags. With --sandbox, this pins `files`
+    /// as the only ones which may be modified, so it should be called before doing any work.
+    fn from_args<P: AsRef<Path>>(args: &Args, files: &[P]) -> anyhow::Result<InPlaceOptions> {
+        let sandbox = if args.sandbox {
+            Some(Sandbox::new(files)?)
+        } else {
+            None
+        };
+        Ok(InPlaceOptions {
+            temp_dir: args.temp_dir.clone(),
+            mode: args.chmod,
+            sandbox,
+            min_matches: args.min_matches,
+            // --backup-dir without a suffix keeps the file's name
+            backup_suffix: match (&args.backup, &args.backup_dir) {
+                (None, Some(_)) => Some(String::new()),
+                (suffix, _) => suffix.clone(),
+            },
+            backup_dir: args.backup_dir.clone(),
+            lock: args.lock,
+            lock_timeout: args.lock_timeout,
+            byte_range: args.byte_range.clone(),
+            encoding: args.encoding,
+            preserve: args.preserve.clone(),
+            follow_symlinks: args.follow_symlinks,
+            verify: match args.verify.as_deref() {
+                Some([pattern, replacement]) => Some(Verifier::new(
+                    replacer_builder(args)?
+                        .build(pattern, replacement)
+                        .context("invalid --verify pattern")?,
+                )),
+                _ => None,
+            },
+            paranoid: args.paranoid,
+        })
+    }
+
+    /// Get where to save the backup of `path`, if backups are enabled.
+    fn backup_path(&self, path: &Path) -> Option<PathBuf> {
+        let suffix = self.backup_suffix.as_ref()?;
+        let mut backup = match &self.backup_dir {
+            Some(dir) => {
+                let mut backup = dir.clone();
+                backup.extend(path.components().filter_map(|c| match c {
+                    Component::Normal(name) => Some(name),
+                    _ => None,
+                }));
+                backup
+            }
+            None => path.to_owned(),
+        }
+        .into_os_string();
+        backup.push(suffix);
+        Some(backup.into())
+    }
+}
+
+/// Save a copy of the file at `path` as `backup`, replacing any existing file there.
+///
+/// With `link`, a hard link is used when possible so that the backup keeps the original's
+/// metadata, since the edited file is about to get a new inode anyway. Otherwise the file is
+/// copied.
+fn save_backup(path: &Path, backup: &Path, link: bool) -> anyhow::Result<()> {
+    let context = || format!("failed to save backup '{}'", backup.display());
+    if let Some(parent) = backup.parent() {
+        fs::create_dir_all(parent).with_context(context)?;
+    }
+    match fs::remove_file(backup) {
+        Ok(()) => (),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err).with_context(context),
+    }
+    if !link || fs::hard_link(path, backup).is_err() {
+        fs::copy(path, backup).with_context(context)?;
+    }
+    Ok(())
+}
+
+/// Get the permissions for an edited file whose original permissions were `orig`.
+fn output_permissions(orig: fs::Permissions, opts: &InPlaceOptions) -> fs::Permissions {
+    let Some(mode) = opts.mode else {
+        return orig;
+    };
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::Permissions::from_mode(mode)
+    }
+    #[cfg(not(unix))]
+    {
+        let mut perms = orig;
+        perms.set_readonly(mode & 0o222 == 0);
+        perms
+    }
+}
+
+/// Create the temporary file which will replace a file in directory `dir`.
+fn create_temp_file(dir: &Path, opts: &InPlaceOptions) -> anyhow::Result<NamedTempFile> {
+    if let Some(temp_dir) = &opts.temp_dir {
+        return NamedTempFile::new_in(temp_dir).with_context(|| {
+            format!(
+                "failed to open temporary output file in '{}'",
+                temp_dir.display()
+            )
+        });
+    }
+
+    match NamedTempFile::new_in(dir) {
+        Ok(file) => Ok(file),
+        // the sandbox doesn't allow writing anywhere else
+        Err(err) if opts.sandbox.is_some() => {
+            Err(err).context("failed to open temporary output file")
+        }
+        // The directory may be read-only or out of quota even though the file itself is
+        // writable, so try the system temp directory but report the original error if that fails.
+        Err(err) => NamedTempFile::new()
+            .map_err(|_| err)
+            .context("failed to open temporary output file"),
+    }
+}
+
+/// Replace `path` with the contents of `temp` when they're on different filesystems, so that
+/// `temp` can't simply be renamed. Returns the updated file.
+///
+/// If possible, `temp` is copied to a new temporary file in `dir` which is then renamed, which is
+/// still atomic. Otherwise `path` is overwritten directly. That isn't atomic, so first check that
+/// `path` hasn't changed since `orig_meta` was read, and if the copy fails partway through then
+/// keep `temp` and report where it is so that the updated contents aren't lost. The `backup` of
+/// `path` may be a hard link to it, so it's replaced by a copy before `path` is overwritten.
+fn copy_into_place(
+    temp: NamedTempFile,
+    path: &Path,
+    dir: &Path,
+    orig_meta: &Metadata,
+    backup: Option<&Path>,
+) -> anyhow::Result<File> {
+    let mut src = temp.reopen().context("failed to reopen temporary file")?;
+
+    if let Ok(mut sibling) = NamedTempFile::new_in(dir) {
+        io::copy(&mut src, &mut sibling).context("failed to copy temporary file")?;
+        match sibling.persist(path) {
+            Ok(file) => return Ok(file),
+            // a bind-mounted file can't be renamed over even from its own directory
+            Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+                src.rewind().context("failed to rewind temporary file")?
+            }
+            Err(err) => return Err(err).context("failed to save updated file"),
+        }
+    }
+
+    let meta = fs::metadata(path).context("failed to get file metadata")?;
+    if meta.len() != orig_meta.len() || meta.modified().ok() != orig_meta.modified().ok() {
+        let (_, kept) = temp.keep().context("failed to keep temporary file")?;
+        anyhow::bail!(
+            "file changed while it was being edited, the updated version was saved to '{}'",
+            kept.display()
+        );
+    }
+
+    if let Some(backup) = backup {
+        save_backup(path, backup, false)?;
+    }
+    let mut dest = OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .context("failed to open file for writing")?;
+    if let Err(err) = io::copy(&mut src, &mut dest).and_then(|_| dest.sync_all()) {
+        let (_, kept) = temp.keep().context("failed to keep temporary file")?;
+        return Err(err).with_context(|| {
+            format!(
+                "failed to overwrite file, the updated version was saved to '{}'",
+                kept.display()
+            )
+        });
+    }
+    Ok(dest)
+}
+
+fn replace_one_inplace<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    opts: &InPlaceOptions,
+) -> anyhow::Result<usize> {
+    let replace = |input: &mut BufReader<File>, output: &mut dyn Write| match &opts.byte_range {
+        Some(range) => in_byte_range(range, input, output, |input, output| {
+            Ok(replacer.replace_stream(input, output)?.replacements)
+        }),
+        None => match opts.encoding {
+            Some(encoding) => encoding::transcode(encoding, input, output, |input, output| {
+                Ok(replacer.replace_stream(input, output)?.replacements)
+            }),
+            None => Ok(replacer.replace_stream(input, output)?.replacements),
+        },
+    };
+    rewrite_inplace(path, opts, |infile, outfile| {
+        let count = replace(infile, outfile)?;
+        if opts.paranoid {
+            recheck_inplace(infile, outfile, count, replace)?;
+        }
+        Ok(count)
+    })
+}
+
+/// For --paranoid, read back the new contents written to `outfile` and check that they're
+/// exactly what `replace` writes when given the original contents in `infile` again, with the
+/// same `count` of replacements.
+fn recheck_inplace<F>(
+    infile: &mut BufReader<File>,
+    outfile: &mut BufWriter<NamedTempFile>,
+    count: usize,
+    replace: F,
+) -> anyhow::Result<()>
+where
+    F: FnOnce(&mut BufReader<File>, &mut dyn Write) -> Result<usize, StreamError>,
+{
+    outfile.flush().context("write error")?;
+    let temp = outfile.get_ref();
+    temp.as_file().sync_data().context("write error")?;
+    let mut saved = temp.reopen().context("failed to reopen temporary file")?;
+    infile.rewind().context("failed to rewind file")?;
+    verify::check_saved(&mut saved, count, |output| replace(infile, output))?;
+    Ok(())
+}
+
+/// Edit a file for --append-safe by writing the changed bytes over the original ones, so that
+/// the file is never replaced or truncated. Nothing is written unless every replacement is the
+/// same length as its match.
+fn replace_one_append_safe<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    opts: &InPlaceOptions,
+) -> anyhow::Result<usize> {
+    if let Some(sandbox) = &opts.sandbox {
+        sandbox.check(path)?;
+    }
+    let _lock = if opts.lock {
+        Some(lock::open_locked(path, opts.lock_timeout)?)
+    } else {
+        None
+    };
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)
+        .context("failed to open")?;
+    // only edit what's there now, and leave anything appended while we work alone
+    let len = file
+        .metadata()
+        .context("failed to get file metadata")?
+        .len();
+
+    // the changed bytes and where they go in the file
+    let mut writes = vec![];
+    let mut resized = None;
+    let mut offset = 0;
+    let mut input = BufReader::new(io::Read::take(&mut file, len));
+    let stats = replacer.for_each_line(&mut input, |line| {
+        for edit in line.edits {
+            let (old, new) = (&line.old[edit.old.clone()], &line.new[edit.new.clone()]);
+            if old.len() != new.len() {
+                resized.get_or_insert(line.number);
+            } else if old != new {
+                writes.push((offset + edit.old.start as u64, new.to_vec()));
+            }
+        }
+        offset += line.old.len() as u64;
+        Ok(())
+    })?;
+    drop(input);
+    if let Some(number) = resized {
+        anyhow::bail!(
+            "a replacement on line {number} changes the length of the text, which can't be done \
+             with --append-safe"
+        );
+    }
+    if opts.min_matches.is_some_and(|min| stats.replacements < min) {
+        return Ok(0);
+    }
+
+    // check again in case the file was swapped out while we were working
+    if let Some(sandbox) = &opts.sandbox {
+        sandbox.check(path)?;
+    }
+    for (pos, bytes) in writes {
+        file.seek(io::SeekFrom::Start(pos))
+            .and_then(|_| file.write_all(&bytes))
+            .context("write error")?;
+    }
+    file.sync_data().context("write error")?;
+    Ok(stats.replacements)
+}
+
+/// Edit a member of an archive in-place, rebuilding the archive file around it.
+fn replace_one_member<P: Pattern>(
+    replacer: &Replacer<P>,
+    member: &archive::MemberPath,
+    opts: &InPlaceOptions,
+) -> anyhow::Result<usize> {
+    if opts.byte_range.is_some()
+        || opts.verify.is_some()
+        || opts.paranoid
+        || opts.encoding.is_some()
+    {
+        anyhow::bail!(
+            "archive members can't be edited with --byte-range, --verify, --paranoid, or \
+             --encoding"
+        );
+    }
+    rewrite_inplace(&member.archive, opts, |infile, outfile| {
+        let mut archive = vec![];
+        io::Read::read_to_end(infile, &mut archive).context("read error")?;
+        let mut count = 0;
+        let updated = member.update(&archive, |data| {
+            let mut replaced = vec![];
+            count = replacer
+                .replace_stream(&mut &data[..], &mut replaced)?
+                .replacements;
+            anyhow::Ok((count > 0).then_some(replaced))
+        })?;
+        outfile
+            .write_all(updated.as_deref().unwrap_or(&archive))
+            .context("write error")?;
+        Ok(count)
+    })
+}
+
+/// Like `replace_one_inplace`, but also count the lines inserted and deleted for --diffstat.
+fn replace_one_diffstat<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    opts: &InPlaceOptions,
+) -> anyhow::Result<(usize, (usize, usize))> {
+    let mut stat = diff::DiffStat::default();
+    let count = rewrite_inplace(path, opts, |infile, outfile| {
+        let stats = replacer.for_each_line(infile, |line| {
+            stat.push(line);
+            replacer.write_line(line, line.new, outfile)
+        })?;
+        if opts.paranoid {
+            recheck_inplace(infile, outfile, stats.replacements, |input, output| {
+                Ok(replacer.replace_stream(input, output)?.replacements)
+            })?;
+        }
+        Ok(stats.replacements)
+    })?;
+    // files left alone because of --min-matches didn't change
+    let stat = if count > 0 { stat.finish() } else { (0, 0) };
+    Ok((count, stat))
+}
+
+/// Like `replace_one_inplace`, but ask before making each replacement.
+fn replace_one_prompt<P: Pattern>(
+    replacer: &Replacer<P>,
+    path: &Path,
+    opts: &InPlaceOptions,
+    prompter: &mut Prompter<BufReader<File>, File>,
+) -> anyhow::Result<usize> {
+    prompter.start_file();
+    rewrite_inplace(path, opts, |infile, outfile| {
+        let mut error = None;
+        let stats = replacer.for_each_line_confirm(
+            infile,
+            |candidate| {
+                prompter.confirm(path, candidate).unwrap_or_else(|err| {
+                    error.get_or_insert(err);
+                    false
+                })
+            },
+            |line| replacer.write_line(line, line.new, outfile),
+        )?;
+        if let Some(err) = error {
+            return Err(err).context("failed to prompt on the terminal");
+        }
+        Ok(stats.replacements)
+    })
+}
+
+/// Most symlinks followed by --follow-symlinks to get to a file, the same limit as Linux has.
+const MAX_SYMLINKS: usize = 40;
+
+/// For --follow-symlinks, follow `path` through symlinks to the file they point to. Only the final
+/// component is resolved, so the result is relative if the links are.
+fn resolve_symlinks(path: &Path) -> anyhow::Result<PathBuf> {
+    let mut path = path.to_owned();
+    for _ in 0..MAX_SYMLINKS {
+        let meta = fs::symlink_metadata(&path)
+            .with_context(|| format!("failed to resolve symlink to '{}'", path.display()))?;
+        if !meta.file_type().is_symlink() {
+            return Ok(path);
+        }
+        let link = fs::read_link(&path).context("failed to read symlink")?;
+        // a relative target is relative to the link's directory
+        path = match path.parent() {
+            Some(dir) => dir.join(link),
+            None => link,
+        };
+    }
+    anyhow::bail!("too many levels of symlinks")
+}
+
+/// Safely rewrite a file in-place. `rewrite` reads the original contents and writes the new
+/// contents, which atomically replace the file if it succeeds. Returns whatever `rewrite` does,
+/// or 0 if the file was left alone because of `opts.min_matches`.
+fn rewrite_inplace<F>(path: &Path, opts: &InPlaceOptions, rewrite: F) -> anyhow::Result<usize>
+where
+    F: FnOnce(&mut BufReader<File>, &mut BufWriter<NamedTempFile>) -> anyhow::Result<usize>,
+{
+    let target;
+    let path = if opts.follow_symlinks {
+        target = resolve_symlinks(path)?;
+        &target
+    } else {
+        path
+    };
+    if let Some(sandbox) = &opts.sandbox {
+        sandbox.check(path)?;
+    }
+    // open input first to make sure that the file exists
+    let infile = if opts.lock {
+        lock::open_locked(path, opts.lock_timeout)?
+    } else {
+        File::open(path).context("failed to open")?
+    };
+    // the lock lasts until every handle is closed, so keep one until the new file is in place
+    let _lock = if opts.lock {
+        Some(infile.try_clone().context("failed to open")?)
+    } else {
+        None
+    };
+    let dir = match path.parent() {
+        Some(dir) => {
+            if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else if !dir.is_dir() {
+                // this shouldn't actually happen
+                anyhow::bail!("parent '{}' isn't a directory", dir.display())
+            } else {
+                dir
+            }
+        }
+        None => anyhow::bail!("unable to get parent directory"),
+    };
+
+    // get input metadata, we'll need its permissions later
+    let infile_meta = infile.metadata().context("failed to get file metadata")?;
+    let saved = preserve::save(&infile, &infile_meta, &opts.preserve)?;
+    // now we can buffer the input
+    let mut infile = BufReader::new(infile);
+
+    let mut outfile = BufWriter::new(create_temp_file(dir, opts)?);
+    let count = rewrite(&mut infile, &mut outfile)?;
+
+    if opts.min_matches.is_some_and(|min| count < min) {
+        // dropping the temp file deletes it
+        return Ok(0);
+    }
+
+    // get the tempfile out of the BufWriter, this will flush the remaining buffer
+    let outfile = outfile.into_inner().context("write error")?;
+    if let Some(verifier) = &opts.verify {
+        infile.rewind().context("failed to rewind file")?;
+        let mut replaced = BufReader::new(
+            outfile
+                .reopen()
+                .context("failed to reopen temporary file")?,
+        );
+        verifier.check(&mut infile, &mut replaced)?;
+    }
+
+    // set these before the rename, so that the file is left alone if they can't be
+    preserve::restore(&saved, outfile.as_file())?;
+
+    // Close the input first before we rename over it
+    drop(infile);
+    // check again in case the directory was swapped out while we were working
+    if let Some(sandbox) = &opts.sandbox {
+        sandbox.check(path)?;
+    }
+    let backup = opts.backup_path(path);
+    if let Some(backup) = &backup {
+        save_backup(path, backup, true)?;
+    }
+    // atomically rename to replace the file, unless the temp file is on another filesystem
+    let new_outfile = match outfile.persist(path) {
+        Ok(file) => file,
+        Err(err) if err.error.kind() == io::ErrorKind::CrossesDevices => {
+            let file = copy_into_place(err.file, path, dir, &infile_meta, backup.as_deref())?;
+            // the copy is a new file again, or the original was overwritten
+            preserve::restore(&saved, &file)?;
+            file
+        }
+        Err(err) => return Err(err).context("failed to save updated file"),
+    };
+
+    // set the same permissions as the input, or the ones from --chmod
+    new_outfile
+        .set_permissions(output_permissions(infile_meta.permissions(), opts))
+        .context("failed to set permissions on udpated file")?;
+
+    Ok(count)
+}
+
+fn do_replace_inplace<P: Pattern + Sync>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    args: &Args,
+) -> anyhow::Result<usize> {
+    let state = match &args.state {
+        Some(path) => Some(
+            StateFile::open(path, args.resume)
+                .with_context(|| format!("failed to open state file '{}'", path.display()))?,
+        ),
+        None => None,
+    };
+    let files: Vec<PathBuf> = match &state {
+        Some(state) => files
+            .iter()
+            .filter(|f| !state.is_done(f))
+            .cloned()
+            .collect(),
+        None => files.to_vec(),
+    };
+
+    // archive members are edited by rewriting the archive file
+    let edited: Vec<PathBuf> = files
+        .iter()
+        .map(|file| archive::MemberPath::parse(file).map_or_else(|| file.clone(), |m| m.archive))
+        .collect();
+    let inplace_opts = InPlaceOptions::from_args(args, &edited)?;
+    let prompter = if args.prompt {
+        let color = args.color == clap::ColorChoice::Always;
+        let prompter = Prompter::open_tty(color).context("unable to open the terminal")?;
+        Some(std::sync::Mutex::new(prompter))
+    } else {
+        None
+    };
+    // The outer error is for failing to update the state file, which stops processing entirely.
+    // The inner error is for this file only, after which we move on to the next one.
+    let work = |file: &Path| {
+        // the numbers of lines inserted and deleted, for --diffstat
+        let mut stat = (0, 0);
+        let count = match (&prompter, archive::MemberPath::parse(file)) {
+            (None, Some(member)) if !(args.diffstat || args.append_safe) => {
+                replace_one_member(&replacer, &member, &inplace_opts)
+            }
+            (_, Some(_)) => Err(anyhow::anyhow!(
+                "archive members can't be edited with --prompt, --diffstat, or --append-safe"
+            )),
+            (Some(prompter), None) => replace_one_prompt(
+                &replacer,
+                file,
+                &inplace_opts,
+                &mut prompter.lock().unwrap(),
+            ),
+            (None, None) if args.diffstat => replace_one_diffstat(&replacer, file, &inplace_opts)
+                .map(|(count, file_stat)| {
+                    stat = file_stat;
+                    count
+                }),
+            (None, None) if args.append_safe => {
+                replace_one_append_safe(&replacer, file, &inplace_opts)
+            }
+            (None, None) => replace_one_inplace(&replacer, file, &inplace_opts),
+        };
+        METRICS.record(&count);
+        if count.is_ok() {
+            if let Some(state) = &state {
+                state
+                    .mark_done(file)
+                    .context("failed to update state file")?;
+            }
+        }
+        anyhow::Ok(count.map(|count| (count, stat)))
+    };
+
+    let mut failed = false;
+    let mut total = 0;
+    let mut diffstat = vec![];
+    let mut check_result = |path: &Path, ret: anyhow::Result<(usize, (usize, usize))>| match ret {
+        Ok((count, (ins, del))) => {
+            total += count;
+            if ins + del > 0 {
+                diffstat.push((display_path(path, args).to_owned(), ins, del));
+            }
+        }
+        Err(err) => {
+            eprintln!("Error on '{}': {:#}", path.display(), err);
+            failed = true;
+        }
+    };
+
+    // prompts for different files can't be mixed together
+    let threads = if args.prompt {
+        1
+    } else {
+        thread_count(args.threads)
+    };
+    if threads > 1 {
+        for_each_parallel(&files, threads, work, |path, ret| {
+            check_result(path, ret?);
+            Ok(())
+        })?;
+    } else {
+        for file in &files {
+            check_result(file, work(file)?);
+            if prompter.as_ref().is_some_and(|p| p.lock().unwrap().quit()) {
+                break;
+            }
+        }
+    }
+
+    if args.diffstat {
+        let color = args.color == clap::ColorChoice::Always;
+        match diff::write_diffstat(&mut io::stdout().lock(), &diffstat, color) {
+            Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                return Err(err).context("failed to write output")
+            }
+            _ => (),
+        }
+    }
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Print the edits for every file as a single LSP WorkspaceEdit JSON object.
+fn do_workspace_edit<P: Pattern + Sync>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    args: &Args,
+) -> anyhow::Result<usize> {
+    let work = |path: &Path| -> anyhow::Result<(String, Vec<json::Value>)> {
+        let name = display_path(path, args);
+        let uri = workspace_edit::file_uri(name)
+            .with_context(|| format!("unable to get URI for '{}'", name.display()))?;
+        let edits = if let Some("-") = path.to_str() {
+            workspace_edit::text_edits(&replacer, &mut io::stdin().lock())
+        } else {
+            let file =
+                File::open(path).with_context(|| format!("unable to open '{}'", path.display()))?;
+            workspace_edit::text_edits(&replacer, &mut BufReader::new(file))
+        }
+        .map(|edits| match args.min_matches {
+            Some(min) if edits.len() < min => vec![],
+            _ => edits,
+        })
+        .with_context(|| format!("error on '{}'", name.display()));
+        METRICS.record(&edits.as_ref().map(Vec::len));
+        Ok((uri, edits?))
+    };
+
+    let mut total = 0;
+    let mut changes = vec![];
+    for_each_parallel(files, thread_count(args.threads), work, |_, ret| {
+        let (uri, edits) = ret?;
+        if !edits.is_empty() {
+            total += edits.len();
+            changes.push((uri, json::Value::Array(edits)));
+        }
+        Ok(())
+    })?;
+
+    let workspace_edit = json::Value::object([("changes", json::Value::Object(changes))]);
+    println!("{workspace_edit}");
+    Ok(total)
+}
+
+/// Write every match in all the files to `path` as JSON lines, for --export-matches.
+fn do_export_matches<P: Pattern + Sync>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    path: &Path,
+    args: &Args,
+) -> anyhow::Result<usize> {
+    let mut output: Box<dyn Write> = if path.as_os_str() == "-" {
+        Box::new(io::stdout().lock())
+    } else {
+        let file =
+            File::create(path).with_context(|| format!("unable to create '{}'", path.display()))?;
+        Box::new(BufWriter::new(file))
+    };
+
+    let work = |path: &Path| {
+        let name = display_path(path, args);
+        let ret = if let Some("-") = path.to_str() {
+            matches::export(&replacer, &mut io::stdin().lock(), name)
+        } else {
+            File::open(path)
+                .map_err(StreamError::Open)
+                .and_then(|file| matches::export(&replacer, &mut BufReader::new(file), name))
+        }
+        .map(|matches| match args.min_matches {
+            Some(min) if matches.len() < min => vec![],
+            _ => matches,
+        });
+        METRICS.record(&ret.as_ref().map(Vec::len));
+        ret
+    };
+
+    let mut failed = false;
+    let mut total = 0;
+    for_each_parallel(files, thread_count(args.threads), work, |path, ret| {
+        match ret {
+            Ok(matches) => {
+                total += matches.len();
+                for m in matches {
+                    match writeln!(output, "{m}") {
+                        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+                            return Err(err).context("failed to write output")
+                        }
+                        _ => (),
+                    }
+                }
+            }
+            Err(err) => {
+                eprintln!("Error on '{}': {}", display_path(path, args).display(), err);
+                failed = true;
+            }
+        }
+        Ok(())
+    })?;
+    match output.flush() {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+            return Err(err).context("failed to write output")
+        }
+        _ => (),
+    }
+
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Print a random sample of the replacements in all the files.
+fn do_sample<P: Pattern>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    size: usize,
+    args: &Args,
+) -> anyhow::Result<usize> {
+    let mut sampler = Sampler::new(size);
+    let mut failed = false;
+    let mut total = 0;
+    for path in files {
+        let name = display_path(path, args);
+        let ret = if let Some("-") = path.to_str() {
+            sampler.add_input(&replacer, &mut io::stdin().lock(), name)
+        } else {
+            File::open(path)
+                .map_err(StreamError::Open)
+                .and_then(|file| sampler.add_input(&replacer, &mut BufReader::new(file), name))
+        };
+        METRICS.record(&ret);
+        match ret {
+            Ok(count) => total += count,
+            Err(err) => {
+                eprintln!("Error on '{}': {}", name.display(), err);
+                failed = true;
+            }
+        }
+    }
+
+    let color = args.color == clap::ColorChoice::Always;
+    match sampler.write(&mut io::stdout().lock(), color) {
+        Err(err) if err.kind() != io::ErrorKind::BrokenPipe => {
+            return Err(err).context("failed to write output")
+        }
+        _ => (),
+    }
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Make replacements in all the files, in the mode and output format chosen by `args`.
+fn do_replace<P: Pattern + Sync>(
+    replacer: Replacer<P>,
+    files: &[PathBuf],
+    args: &Args,
+) -> anyhow::Result<usize> {
+    if let Some(size) = args.sample {
+        do_sample(replacer, files, size, args)
+    } else if args.in_place && !(args.diff || args.count || args.summary.is_some()) {
+        do_replace_inplace(replacer, files, args)
+    } else if let Some(path) = &args.export_matches {
+        do_export_matches(replacer, files, path, args)
+    } else if args.format == OutputFormat::WorkspaceEdit {
+        do_workspace_edit(replacer, files, args)
+    } else {
+        do_replace_stdout(replacer, files, args)
+    }
+}
+
+/// Get the settings for building a replacer from the command line flags.
+fn replacer_builder(args: &Args) -> anyhow::Result<ReplacerBuilder> {
+    if args.grapheme_safe && !cfg!(feature = "unicode") {
+        anyhow::bail!("--grapheme-safe needs Unicode tables, and rp was built without them");
+    }
+    Ok(ReplacerBuilder {
+        fixed_strings: args.fixed_strings,
+        ignore_case: args.ignore_case,
+        escape: args.escape,
+        escape_pattern: args.escape_pattern,
+        no_expand: args.no_expand,
+        replace_all: args.replace_all,
         only_matches: args.only_matches,
+        first_match_only: args.only_first_match_per_file,
+        max_per_line: args.max,
+        max_per_file: args.max_per_file,
+        address: Address::new(
+            args.lines,
+            args.from_regex.as_deref(),
+            args.to_regex.as_deref(),
+        )
+        .context("invalid --from-regex or --to-regex")?,
+        records: if args.multiline {
+            Records::Whole
+        } else if args.paragraph {
+            Records::Paragraphs
+        } else {
+            Records::Lines
+        },
+        null_data: args.null_data,
+        anchor: if args.line_regexp {
+            Some(Anchor::Only)
+        } else {
+            args.at
+        },
+        max_replacement_size: args.max_replacement_size,
+        max_output_line: args.max_output_line,
+        grapheme_safe: args.grapheme_safe,
+        empty_matches: args.empty_matches,
+        word_regexp: args.word_regexp,
+        delete_lines: args.delete,
+        preserve_case: args.preserve_case,
+        output_delimiter: args.output_delimiter.clone(),
+        engine: args.engine,
+        unicode: if args.no_unicode {
+            UnicodeMode::Never
+        } else if args.strict_unicode {
+            UnicodeMode::Always
+        } else {
+            UnicodeMode::Auto
+        },
+        template_syntax: match args.compat {
+            Some(Compat::Sed) => TemplateSyntax::Sed,
+            None => TemplateSyntax::Regex,
+        },
+    })
+}
+
+/// Replace the spans read from stdin, for --spans-from.
+fn do_replace_spans(
+    format: spans::SpanFormat,
+    replacement: &str,
+    args: &Args,
+) -> anyhow::Result<usize> {
+    if args.replacement.is_some() || !args.files.is_empty() {
+        anyhow::bail!("FILES can't be used with --spans-from, the files to edit come from stdin");
+    }
+
+    let builder = replacer_builder(args)?;
+    let opts = builder.options();
+    let replacement = builder.unescape(replacement)?;
+    spans::check_replacement(&opts, &replacement)?;
+
+    let files = spans::read_spans(&mut io::stdin().lock(), format)?;
+    let paths: Vec<&PathBuf> = files.keys().collect();
+    let inplace_opts = InPlaceOptions::from_args(args, &paths)?;
+    let mut failed = false;
+    let mut total = 0;
+    for (path, spans) in &files {
+        let count = spans::replace_file_spans(&opts, &replacement, path, spans, &inplace_opts);
+        METRICS.record(&count);
+        match count {
+            Ok(count) => total += count,
+            Err(err) => {
+                eprintln!("Error on '{}': {:#}", path.display(), err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Make the edits from a file written by --export-matches, for --apply-matches.
+fn do_apply_matches(list: &Path, args: &Args) -> anyhow::Result<usize> {
+    if args.pattern.is_some() || args.replacement.is_some() || !args.files.is_empty() {
+        anyhow::bail!(
+            "PATTERN, REPLACEMENT, and FILES can't be used with --apply-matches, the edits come \
+             from the match file"
+        );
+    }
+
+    let files = if list.as_os_str() == "-" {
+        matches::read(&mut io::stdin().lock())
+    } else {
+        File::open(list)
+            .context("unable to open")
+            .and_then(|file| matches::read(&mut BufReader::new(file)))
+    }
+    .with_context(|| format!("invalid match file '{}'", list.display()))?;
+    let paths: Vec<&PathBuf> = files.keys().collect();
+    let inplace_opts = InPlaceOptions::from_args(args, &paths)?;
+    let mut failed = false;
+    let mut total = 0;
+    for (path, edits) in &files {
+        let count = matches::apply_file(path, edits, &inplace_opts);
+        METRICS.record(&count);
+        match count {
+            Ok(count) => total += count,
+            Err(err) => {
+                eprintln!("Error on '{}': {:#}", path.display(), err);
+                failed = true;
+            }
+        }
+    }
+    if failed {
+        Err(anyhow::anyhow!("failed processing one or more files"))
+    } else {
+        Ok(total)
+    }
+}
+
+/// Run rp, returning the exit status.
+fn run() -> anyhow::Result<i32> {
+    let mut args = Args::parse();
+    let style = *STATUS_STYLE.get_or_init(|| args.status);
+    if let Some(command) = args.command.take() {
+        let result = match command {
+            Command::Doctor(doctor_args) => doctor::run(doctor_args),
+            Command::Escape(escape_args) => escape_debug::run_escape(escape_args),
+            Command::Rename(rename_args) => return rename::run(rename_args),
+            Command::Repl(repl_args) => repl::run(repl_args),
+            Command::Unescape(unescape_args) => escape_debug::run_unescape(unescape_args),
+        };
+        return result.map(|()| EXIT_SUCCESS);
+    }
+
+    if args.list_presets {
+        presets::write_list(&mut io::stdout().lock()).context("failed to write output")?;
+        return Ok(EXIT_SUCCESS);
+    }
+    if args.server {
+        return server::run().map(|()| EXIT_SUCCESS);
+    }
+
+    let start = Instant::now();
+    let metrics_file = args.metrics_file.take();
+    let result = run_replace(args);
+    if let Some(path) = metrics_file {
+        METRICS
+            .write_file(&path, start.elapsed(), result.is_ok())
+            .with_context(|| format!("failed to write metrics file '{}'", path.display()))?;
+    }
+    result.map(|()| exit_status(&METRICS, true, style))
+}
+
+/// Make replacements as specified by the command line, when not running a subcommand or server.
+fn run_replace(mut args: Args) -> anyhow::Result<()> {
+    if let Some(list) = &args.apply_matches {
+        let count = do_apply_matches(list, &args)?;
+        if args.fail_if_no_match && count == 0 {
+            eprintln!("Error: no matches were applied");
+        }
+        return Ok(());
+    }
+
+    // With --rules-file or --preset there's no PATTERN or REPLACEMENT, so those positionals are
+    // really files
+    let rules = if let Some(path) = &args.rules_file {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("unable to read rules file '{}'", path.display()))?;
+        let rules = rules::parse(&text)
+            .with_context(|| format!("invalid rules file '{}'", path.display()))?;
+        Some(rules)
+    } else if !args.preset.is_empty() {
+        let presets: Vec<_> = args.preset.iter().filter_map(|p| presets::get(p)).collect();
+        if let Some(preset) = presets.iter().find(|preset| preset.multiline) {
+            if args.paragraph || args.output_delimiter.is_some() {
+                anyhow::bail!(
+                    "preset '{}' can't be used with --paragraph or --output-delimiter",
+                    preset.name
+                );
+            }
+            args.multiline = true;
+        }
+        args.replace_all = true;
+        Some(presets.iter().flat_map(|preset| preset.rules()).collect())
+    } else {
+        None
+    };
+    if rules.is_some() {
+        let files = [args.pattern.take(), args.replacement.take()];
+        args.files
+            .splice(0..0, files.into_iter().flatten().map(PathBuf::from));
+    }
+
+    // With --expr, the positionals are files too, and the first expression takes their place
+    let mut exprs = vec![];
+    if !args.expr.is_empty() {
+        let files = [args.pattern.take(), args.replacement.take()];
+        args.files
+            .splice(0..0, files.into_iter().flatten().map(PathBuf::from));
+        let mut pairs = std::mem::take(&mut args.expr).into_iter();
+        args.pattern = pairs.next();
+        args.replacement = pairs.next();
+        while let (Some(pattern), Some(replacement)) = (pairs.next(), pairs.next()) {
+            exprs.push((pattern, replacement));
+        }
+    }
+
+    // clap requires PATTERN whenever there's no subcommand, --server, --expr, --rules-file, or
+    // --preset
+    let pattern = args.pattern.take().unwrap_or_default();
+
+    // With --spans-from there's no PATTERN, so the first positional is really REPLACEMENT
+    if let Some(format) = args.spans_from {
+        let count = do_replace_spans(format, &pattern, &args)?;
+        if args.fail_if_no_match && count == 0 {
+            eprintln!("Error: no spans were replaced");
+        }
+        return Ok(());
+    }
+
+    // With --branch-replacements, --delete, or --replacement-file there's no REPLACEMENT, so the
+    // first positional is really a file
+    if args.branch_replacements.is_some() || args.delete || args.replacement_file.is_some() {
+        if let Some(file) = args.replacement.take() {
+            args.files.insert(0, file.into());
+        }
+    }
+
+    let mut walk_opts = walk::WalkOptions {
+        hidden: args.hidden,
+        no_ignore: args.no_ignore,
+        report_filtered: args.report_skipped,
+        ..Default::default()
     };
+    for glob in &args.glob {
+        walk_opts
+            .globs
+            .add(glob)
+            .with_context(|| format!("invalid glob '{glob}'"))?;
+    }
+    args.recursive |= !args.glob.is_empty();
+
+    if let Some(list) = &args.files_from {
+        let delimiter = if args.null { 0 } else { b'\n' };
+        let files = if list.as_os_str() == "-" {
+            walk::read_list(&mut io::stdin().lock(), delimiter)
+        } else {
+            File::open(list).and_then(|file| walk::read_list(&mut BufReader::new(file), delimiter))
+        }
+        .with_context(|| format!("unable to read file list '{}'", list.display()))?;
+        if list.as_os_str() == "-"
+            && files
+                .iter()
+                .chain(&args.files)
+                .any(|p| p.as_os_str() == "-")
+        {
+            anyhow::bail!("stdin can't be used as an input with --files-from -");
+        }
+        args.files.extend(files);
+    }
+
+    if args.recursive && args.files.is_empty() && args.files_from.is_none() {
+        args.files.push(PathBuf::from("."));
+    }
+
+    if args.in_place && args.files.is_empty() && args.files_from.is_none() {
+        anyhow::bail!("in-place replacement requires at least one file");
+    }
 
-    let replacement = if args.escape {
-        unescape_bytes(args.replacement.as_bytes())?
+    let files = if args.files.is_empty() && args.files_from.is_none() {
+        vec![PathBuf::from("-")]
     } else {
-        args.replacement.into_bytes()
+        std::mem::take(&mut args.files)
     };
 
-    if args.fixed_strings {
-        let replacer = opts.build_literal(args.pattern, replacement);
-        if args.in_place {
-            do_replace_inplace(replacer, &files)
+    // Quick check that stdin isn't specified twice in the files list. We can't be completely sure
+    // because of /proc/self/fd/0 or /dev/stdout or other such paths.
+    let stdin_arg_count = files
+        .iter()
+        .filter(|p| matches!(p.to_str(), Some("-")))
+        .count();
+
+    if stdin_arg_count > 1 {
+        anyhow::bail!("stdin '-' argument specified more than once");
+    } else if args.in_place && stdin_arg_count > 0 {
+        anyhow::bail!("stdin can't be used with in-place replacement");
+    }
+
+    // binary files are only skipped when there's more than one input
+    // UTF-16 files are full of NUL bytes, so --encoding implies --text
+    let skip_binary = !(args.binary || args.text || args.encoding.is_some())
+        && (args.recursive || files.len() > 1);
+    walk_opts.binary = !skip_binary;
+    let mut skipped = vec![];
+    let files = if skip_binary {
+        walk::skip_binary(files, &mut skipped)
+    } else {
+        files
+    };
+    let files = if args.recursive {
+        walk::expand(&files, &walk_opts, &mut skipped)
+    } else {
+        files
+    };
+    for skip in skipped {
+        if args.strict_errors && !skip.reason.is_filtered() {
+            METRICS.record(&Err(()));
+        }
+        if args.report_skipped {
+            let notice = json::Value::object([
+                ("path", skip.path.to_string_lossy().into_owned().into()),
+                ("reason", skip.reason.code().into()),
+                ("message", skip.reason.to_string().into()),
+            ]);
+            eprintln!("{notice}");
+        } else if args.strict_errors {
+            eprintln!("Error on '{}': {}", skip.path.display(), skip.reason);
+        } else if matches!(skip.reason, walk::SkipReason::Binary) {
+            eprintln!(
+                "Notice: skipped binary file '{}', use --binary or --text to process it",
+                skip.path.display()
+            );
         } else {
-            do_replace_stdout(replacer, &files)
+            eprintln!(
+                "Warning: skipped '{}': {}",
+                skip.path.display(),
+                skip.reason
+            );
+        }
+    }
+
+    // decide on color now, so that buffered output for -j is highlighted the same way
+    let terminal = io::stdout().is_terminal();
+    args.color = match args.color {
+        _ if args.escape_output && terminal => clap::ColorChoice::Never,
+        clap::ColorChoice::Auto
+            if terminal
+                && args.also_write.is_none()
+                && std::env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) =>
+        {
+            clap::ColorChoice::Always
+        }
+        clap::ColorChoice::Auto => clap::ColorChoice::Never,
+        color => color,
+    };
+
+    let builder = replacer_builder(&args)?;
+    let opts = builder.options();
+    let replacement = match &args.replacement_file {
+        Some(path) => {
+            let text = fs::read(path)
+                .with_context(|| format!("unable to read replacement file '{}'", path.display()))?;
+            if builder.escape {
+                let text = std::str::from_utf8(&text).with_context(|| {
+                    format!("replacement file '{}' isn't UTF-8", path.display())
+                })?;
+                builder.unescape(text)?
+            } else {
+                text
+            }
+        }
+        None => builder.unescape(args.replacement.as_deref().unwrap_or_default())?,
+    };
+
+    // whether only part of each match is replaced, from Vim's \zs and \ze
+    let mut submatch = false;
+    let translated = if let Some(dialect) = args.posix {
+        posix::translate(&pattern, dialect)
+            .with_context(|| format!("unable to translate POSIX pattern '{pattern}'"))?
+    } else if args.vim_regex {
+        let vim = vim::translate(&pattern)
+            .with_context(|| format!("unable to translate Vim pattern '{pattern}'"))?;
+        submatch = vim.submatch;
+        vim.pattern
+    } else {
+        pattern.clone()
+    };
+
+    let count = if let Some(rules) = &rules {
+        let ignore_case = builder.search_ignore_case();
+        let mut pairs = vec![];
+        for rule in rules {
+            let pattern = if args.fixed_strings {
+                regex::escape(&rule.pattern)
+            } else {
+                rule.pattern.clone()
+            };
+            let replacement = builder.unescape(&rule.replacement)?;
+            // build each rule by itself first so that errors say which rule is wrong
+            opts.build_engine(
+                Engine::Regex,
+                &pattern,
+                &replacement,
+                ignore_case,
+                builder.unicode,
+            )
+            .with_context(|| format!("rule '{}'", rule.name))?;
+            pairs.push((pattern, replacement));
         }
+        let replacer = opts.build_multi(
+            &pairs,
+            MatchKind::LeftmostFirst,
+            ignore_case,
+            builder.unicode,
+        )?;
+        do_replace(replacer, &files, &args)
+    } else if submatch {
+        let replacer = opts.build_submatch_regex(
+            &translated,
+            vim::MATCH_GROUP,
+            &replacement,
+            builder.search_ignore_case(),
+            builder.unicode,
+        )?;
+        do_replace(replacer, &files, &args)
+    } else if let Some(plugin) = &args.plugin {
+        let plugin = PluginPattern::spawn(plugin, &pattern)
+            .with_context(|| format!("plugin '{}'", plugin.display()))?;
+        let replacer = opts.build_template(plugin, &replacement)?;
+        do_replace(replacer, &files, &args)
+    } else if let Some(list) = &args.branch_replacements {
+        let reps = list
+            .split(',')
+            .map(|rep| builder.unescape(rep))
+            .collect::<Result<Vec<_>, _>>()?;
+        let replacer = opts.build_regex_branches(
+            &translated,
+            &reps,
+            builder.search_ignore_case(),
+            builder.unicode,
+        )?;
+        do_replace(replacer, &files, &args)
     } else {
-        let replacer = opts
-            .build_regex(&args.pattern, replacement, args.ignore_case)
-            .context("invalid pattern regex")?;
-        if args.in_place {
-            do_replace_inplace(replacer, &files)
+        let mut replacer = builder.build_unescaped(&translated, replacement)?;
+        replacer.require_groups(&args.require_groups)?;
+        for (pattern, replacement) in &exprs {
+            replacer = replacer.then(
+                builder
+                    .build(pattern, replacement)
+                    .with_context(|| format!("invalid --expr pattern '{pattern}'"))?,
+            );
+        }
+        do_replace(replacer, &files, &args)
+    }?;
+
+    if args.fail_if_no_match && count == 0 {
+        match &args.rules_file {
+            Some(path) => eprintln!(
+                "Error: no matches found for the rules in '{}'",
+                path.display()
+            ),
+            None if !args.preset.is_empty() => {
+                eprintln!(
+                    "Error: no matches found for --preset {}",
+                    args.preset.join(", ")
+                )
+            }
+            None if !exprs.is_empty() => {
+                eprintln!("Error: no matches found for any --expr pattern")
+            }
+            None => eprintln!("Error: no matches found for pattern '{pattern}'"),
+        }
+    }
+    Ok(())
+}
+
+/// Pick the exit status from the files processed so far and whether the run succeeded. See
+/// EXIT_STATUS_HELP.
+fn exit_status(metrics: &Metrics, success: bool, style: StatusStyle) -> i32 {
+    let files = metrics.files();
+    let errors = metrics.errors();
+    if success && errors == 0 {
+        if metrics.replacements() > 0 {
+            EXIT_SUCCESS
         } else {
-            do_replace_stdout(replacer, &files)
+            EXIT_NO_MATCH
         }
+    } else if files == 0 || style == StatusStyle::Grep {
+        EXIT_USAGE
+    } else if errors < files {
+        EXIT_PARTIAL
+    } else {
+        EXIT_FAILED
     }
 }
 
 fn main() {
-    if let Err(err) = run() {
-        eprintln!("Error: {err:#}");
-        std::process::exit(1);
+    let status = match run() {
+        Ok(status) => status,
+        Err(err) => {
+            eprintln!("Error: {err:#}");
+            // errors parsing the arguments exit from clap, so the style has been set
+            let style = STATUS_STYLE.get().copied().unwrap_or_default();
+            exit_status(&METRICS, false, style)
+        }
+    };
+    std::process::exit(status);
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn test_parse_size() {
+        use super::parse_size;
+
+        assert_eq!(parse_size("0"), Ok(0));
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("4k"), Ok(4096));
+        assert_eq!(parse_size("2M"), Ok(2 << 20));
+        assert_eq!(parse_size("1G"), Ok(1 << 30));
+
+        assert!(parse_size("").is_err());
+        assert!(parse_size("K").is_err());
+        assert!(parse_size("-1").is_err());
+        assert!(parse_size("1.5M").is_err());
+        assert!(parse_size("1T").is_err());
+    }
+
+    #[test]
+    fn test_parse_line_range() {
+        use super::parse_line_range;
+        use rp::replace::LineRange;
+
+        let range = |start, end| Ok(LineRange { start, end });
+        assert_eq!(parse_line_range("5"), range(5, Some(5)));
+        assert_eq!(parse_line_range("10-20"), range(10, Some(20)));
+        assert_eq!(parse_line_range("30-"), range(30, None));
+        assert!(parse_line_range("0").is_err());
+        assert!(parse_line_range("-5").is_err());
+        assert!(parse_line_range("20-10").is_err());
+        assert!(parse_line_range("x").is_err());
+    }
+
+    #[test]
+    fn test_parse_byte_range() {
+        use super::parse_byte_range;
+
+        assert_eq!(parse_byte_range("10..20"), Ok(10..20));
+        assert_eq!(parse_byte_range("10.."), Ok(10..u64::MAX));
+        assert_eq!(parse_byte_range("..20"), Ok(0..20));
+        assert_eq!(parse_byte_range("5..5"), Ok(5..5));
+        assert!(parse_byte_range("20..10").is_err());
+        assert!(parse_byte_range("10").is_err());
+        assert!(parse_byte_range("a..b").is_err());
+    }
+
+    #[test]
+    fn test_in_byte_range() {
+        use super::in_byte_range;
+        use regex::bytes::Regex;
+        use rp::replace::ReplaceOptions;
+        use rp::template::Template;
+
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = opts.build(Regex::new("o").unwrap(), Template::literal("0"));
+        let mut out = vec![];
+        let count = in_byte_range(&(2..9), &mut &b"foo\nfoo\nfoo\n"[..], &mut out, |i, o| {
+            Ok(replacer.replace_stream(i, o)?.replacements)
+        })
+        .unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(out, b"fo0\nf00\nfoo\n");
+    }
+
+    #[test]
+    fn test_parse_mode() {
+        use super::parse_mode;
+
+        assert_eq!(parse_mode("644"), Ok(0o644));
+        assert_eq!(parse_mode("0755"), Ok(0o755));
+        assert_eq!(parse_mode("4755"), Ok(0o4755));
+        assert_eq!(parse_mode("0"), Ok(0));
+
+        assert!(parse_mode("").is_err());
+        assert!(parse_mode("+644").is_err());
+        assert!(parse_mode("648").is_err());
+        assert!(parse_mode("u+x").is_err());
+        assert!(parse_mode("17777").is_err());
+    }
+
+    #[test]
+    fn test_rewrite_inplace_min_matches() {
+        use super::{rewrite_inplace, InPlaceOptions};
+        use std::fs;
+        use std::io::Write;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "old\n").unwrap();
+        let opts = InPlaceOptions {
+            min_matches: Some(2),
+            ..Default::default()
+        };
+        let rewrite = |count| {
+            move |_: &mut _, out: &mut std::io::BufWriter<_>| {
+                out.write_all(b"new\n")?;
+                Ok(count)
+            }
+        };
+
+        assert_eq!(rewrite_inplace(&path, &opts, rewrite(1)).unwrap(), 0);
+        assert_eq!(fs::read(&path).unwrap(), b"old\n");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+        assert_eq!(rewrite_inplace(&path, &opts, rewrite(2)).unwrap(), 2);
+        assert_eq!(fs::read(&path).unwrap(), b"new\n");
+    }
+
+    #[test]
+    fn test_replace_one_append_safe() {
+        use super::{replace_one_append_safe, InPlaceOptions};
+        use regex::bytes::Regex;
+        use rp::replace::Replacer;
+        use rp::template::Template;
+        use std::fs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("log");
+        fs::write(&path, "ERROR a\nok\nERROR b\n").unwrap();
+        // a second handle sees the edits, since the file isn't replaced
+        let open = fs::File::open(&path).unwrap();
+        let opts = InPlaceOptions::default();
+
+        let replacer = Replacer::new(Regex::new("ERROR").unwrap(), Template::literal("WARN!"));
+        assert_eq!(replace_one_append_safe(&replacer, &path, &opts).unwrap(), 2);
+        assert_eq!(
+            std::io::read_to_string(&open).unwrap(),
+            "WARN! a\nok\nWARN! b\n"
+        );
+
+        let replacer = Replacer::new(Regex::new("ok").unwrap(), Template::literal("okay"));
+        let err = replace_one_append_safe(&replacer, &path, &opts).unwrap_err();
+        assert!(err.to_string().contains("line 2"));
+        assert_eq!(fs::read(&path).unwrap(), b"WARN! a\nok\nWARN! b\n");
+    }
+
+    #[test]
+    fn test_replace_one_inplace_encoding() {
+        use super::{encoding::Encoding, replace_one_inplace, InPlaceOptions};
+        use regex::bytes::Regex;
+        use rp::replace::Replacer;
+        use rp::template::Template;
+        use std::fs;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, b"\xff\xfec\x00a\x00f\x00\xe9\x00\n\x00").unwrap();
+        let opts = InPlaceOptions {
+            encoding: Some(Encoding::Utf16Le),
+            ..Default::default()
+        };
+
+        let replacer = Replacer::new(Regex::new("é").unwrap(), Template::literal("e\u{1f600}"));
+        assert_eq!(replace_one_inplace(&replacer, &path, &opts).unwrap(), 1);
+        assert_eq!(
+            fs::read(&path).unwrap(),
+            b"\xff\xfec\x00a\x00f\x00e\x00=\xd8\x00\xde\n\x00"
+        );
+
+        let opts = InPlaceOptions {
+            encoding: Some(Encoding::Latin1),
+            ..Default::default()
+        };
+        assert!(replace_one_inplace(&replacer, &path, &opts).is_ok());
+        let replacer = Replacer::new(Regex::new("c").unwrap(), Template::literal("\u{20ac}"));
+        assert!(replace_one_inplace(&replacer, &path, &opts).is_err());
+    }
+
+    #[test]
+    fn test_backup() {
+        use super::{rewrite_inplace, InPlaceOptions};
+        use std::fs;
+        use std::io::Write;
+        use std::path::{Path, PathBuf};
+
+        let opts = InPlaceOptions {
+            backup_suffix: Some(".orig".to_owned()),
+            backup_dir: Some(PathBuf::from("/backups")),
+            ..Default::default()
+        };
+        assert_eq!(
+            opts.backup_path(Path::new("../a/./b.txt")),
+            Some(PathBuf::from("/backups/a/b.txt.orig"))
+        );
+        assert_eq!(InPlaceOptions::default().backup_path(Path::new("a")), None);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        let backup = dir.path().join("file.txt.bak");
+        fs::write(&path, "old\n").unwrap();
+        fs::write(&backup, "stale\n").unwrap();
+        let opts = InPlaceOptions {
+            backup_suffix: Some(".bak".to_owned()),
+            ..Default::default()
+        };
+        rewrite_inplace(&path, &opts, |_, out| {
+            out.write_all(b"new\n")?;
+            Ok(1)
+        })
+        .unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new\n");
+        assert_eq!(fs::read(&backup).unwrap(), b"old\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_follow_symlinks() {
+        use super::{resolve_symlinks, rewrite_inplace, InPlaceOptions};
+        use std::fs;
+        use std::io::Write;
+        use std::os::unix::fs::symlink;
+
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        let target = dir.path().join("sub/target");
+        let link = dir.path().join("link");
+        let link2 = dir.path().join("link2");
+        fs::write(&target, "old\n").unwrap();
+        symlink("sub/target", &link).unwrap();
+        symlink("link", &link2).unwrap();
+        assert_eq!(resolve_symlinks(&link2).unwrap(), target);
+        assert_eq!(resolve_symlinks(&target).unwrap(), target);
+
+        let rewrite = |out: &mut std::io::BufWriter<_>| {
+            out.write_all(b"new\n")?;
+            Ok(1)
+        };
+        let opts = InPlaceOptions {
+            follow_symlinks: true,
+            backup_suffix: Some(".bak".to_owned()),
+            ..Default::default()
+        };
+        rewrite_inplace(&link2, &opts, |_, out| rewrite(out)).unwrap();
+        assert!(fs::symlink_metadata(&link2).unwrap().is_symlink());
+        assert_eq!(fs::read(&link2).unwrap(), b"new\n");
+        assert_eq!(
+            fs::read(dir.path().join("sub/target.bak")).unwrap(),
+            b"old\n"
+        );
+
+        // by default the link is replaced and the target is left alone
+        fs::write(&target, "old\n").unwrap();
+        rewrite_inplace(&link, &InPlaceOptions::default(), |_, out| rewrite(out)).unwrap();
+        assert!(fs::symlink_metadata(&link).unwrap().is_file());
+        assert_eq!(fs::read(&link).unwrap(), b"new\n");
+        assert_eq!(fs::read(&target).unwrap(), b"old\n");
+
+        let looped = dir.path().join("loop");
+        symlink("loop", &looped).unwrap();
+        assert!(resolve_symlinks(&looped).is_err());
+        assert!(resolve_symlinks(&dir.path().join("missing")).is_err());
+    }
+
+    #[test]
+    fn test_exit_status() {
+        use super::*;
+
+        let detailed = |metrics, success| exit_status(metrics, success, StatusStyle::Detailed);
+        let metrics = Metrics::new();
+        assert_eq!(detailed(&metrics, false), EXIT_USAGE);
+        metrics.record::<()>(&Ok(0));
+        assert_eq!(detailed(&metrics, true), EXIT_NO_MATCH);
+        metrics.record::<()>(&Ok(2));
+        assert_eq!(detailed(&metrics, true), EXIT_SUCCESS);
+        // a fatal error partway through, or a failed file, is a partial failure
+        assert_eq!(detailed(&metrics, false), EXIT_PARTIAL);
+        assert_eq!(exit_status(&metrics, false, StatusStyle::Grep), EXIT_USAGE);
+        metrics.record(&Err(()));
+        assert_eq!(detailed(&metrics, true), EXIT_PARTIAL);
+
+        let metrics = Metrics::new();
+        metrics.record(&Err(()));
+        metrics.record(&Err(()));
+        assert_eq!(detailed(&metrics, false), EXIT_FAILED);
+        assert_eq!(exit_status(&metrics, false, StatusStyle::Grep), EXIT_USAGE);
+    }
+
+    #[test]
+    fn test_for_each_parallel_order() {
+        use super::*;
+        use regex::bytes::Regex;
+        use rp::template::Template;
+        use std::sync::Mutex;
+
+        let dir = tempfile::tempdir().unwrap();
+        let files: Vec<PathBuf> = (0..4)
+            .map(|i| {
+                let path = dir.path().join(format!("{i}"));
+                fs::write(&path, format!("{i}a\n").repeat(1000 * (4 - i))).unwrap();
+                path
+            })
+            .collect();
+        let args = Args::parse_from(["rp", "a", "b"]);
+        let replacer = Replacer::new(Regex::new("a").unwrap(), Template::literal("b"));
+
+        // the earlier files take longer, so they finish last
+        let done = Mutex::new(vec![]);
+        let work = |path: &Path| {
+            let index: u64 = path.file_name().unwrap().to_str().unwrap().parse().unwrap();
+            thread::sleep(Duration::from_millis(50 * (4 - index)));
+            let mut buf = vec![];
+            let ret = replace_file_to_output(&replacer, path, &mut buf, &args);
+            done.lock().unwrap().push(index);
+            (buf, ret)
+        };
+        let mut output = vec![];
+        let mut counts = vec![];
+        for_each_parallel(&files, 4, work, |_, (buf, ret)| {
+            output.extend_from_slice(&buf);
+            counts.push(ret?);
+            Ok(())
+        })
+        .unwrap();
+
+        assert_ne!(*done.lock().unwrap(), [0, 1, 2, 3]);
+        assert_eq!(counts, [4000, 3000, 2000, 1000]);
+        let expected: String = (0..4)
+            .map(|i| format!("{i}b\n").repeat(1000 * (4 - i)))
+            .collect();
+        assert_eq!(output, expected.as_bytes());
+    }
+
+    #[test]
+    fn test_header_writer() {
+        use super::HeaderWriter;
+        use std::io::Write;
+
+        let mut out = vec![];
+        let mut writer = HeaderWriter {
+            inner: &mut out,
+            header: Some(b"==> a <==\n".to_vec()),
+        };
+        writer.write_all(b"").unwrap();
+        assert!(writer.header.is_some());
+        writer.write_all(b"x\n").unwrap();
+        writer.write_all(b"y\n").unwrap();
+        assert!(writer.header.is_none());
+        assert_eq!(out, b"==> a <==\nx\ny\n");
+    }
+
+    #[test]
+    fn test_write_dir_summary() {
+        use super::write_dir_summary;
+        use std::collections::BTreeMap;
+        use std::path::PathBuf;
+
+        let dirs = BTreeMap::from([
+            (PathBuf::new(), (1, 1)),
+            (PathBuf::from("src"), (2, 5)),
+            (PathBuf::from("/"), (1, 2)),
+        ]);
+        let mut out = vec![];
+        write_dir_summary(&mut out, &dirs).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "./: 1 file, 1 replacement\n\
+             /: 1 file, 2 replacements\n\
+             src/: 2 files, 5 replacements\n\
+             total: 4 files, 8 replacements\n"
+        );
+    }
+
+    #[test]
+    fn test_highlight_edits() {
+        use super::highlight_edits;
+        use rp::replace::{Edit, Line};
+
+        let edits = [
+            Edit {
+                old: 0..1,
+                new: 0..2,
+            },
+            Edit {
+                old: 2..3,
+                new: 3..3,
+            },
+            Edit {
+                old: 4..5,
+                new: 4..5,
+            },
+        ];
+        let line = Line {
+            number: 1,
+            old: b"a b c\n",
+            new: b"AA  C\n",
+            count: 3,
+            edits: &edits,
+        };
+        let mut buf = vec![];
+        highlight_edits(&mut buf, &line);
+        assert_eq!(buf, b"\x1b[1m\x1b[32mAA\x1b[0m  \x1b[1m\x1b[32mC\x1b[0m\n");
+    }
+
+    #[test]
+    fn test_copy_into_place() {
+        use super::{copy_into_place, save_backup};
+        use std::fs;
+        use std::io::Write;
+        use std::path::Path;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("file.txt");
+        fs::write(&path, "old\n").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        let new_temp = || {
+            let mut temp = tempfile::NamedTempFile::new_in(dir.path()).unwrap();
+            temp.write_all(b"new\n").unwrap();
+            temp
+        };
+
+        // copy to a sibling temp file and rename it
+        copy_into_place(new_temp(), &path, dir.path(), &meta, None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new\n");
+
+        // no usable directory for a sibling, so overwrite the file directly
+        fs::write(&path, "old\n").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        let missing = dir.path().join("missing");
+        copy_into_place(new_temp(), &path, &missing, &meta, None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new\n");
+
+        // a hard-linked backup isn't overwritten along with the file
+        fs::write(&path, "old\n").unwrap();
+        let meta = fs::metadata(&path).unwrap();
+        let backup = dir.path().join("file.txt.bak");
+        save_backup(&path, &backup, true).unwrap();
+        #[cfg(unix)]
+        assert_eq!(
+            std::os::unix::fs::MetadataExt::nlink(&fs::metadata(&path).unwrap()),
+            2
+        );
+        copy_into_place(new_temp(), &path, &missing, &meta, Some(&backup)).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new\n");
+        assert_eq!(fs::read(&backup).unwrap(), b"old\n");
+
+        // refuse to overwrite if the file changed, but keep the new contents
+        fs::write(&path, "changed\n").unwrap();
+        let err = copy_into_place(new_temp(), &path, &missing, &meta, None).unwrap_err();
+        assert!(err.to_string().starts_with("file changed"), "{err}");
+        assert_eq!(fs::read(&path).unwrap(), b"changed\n");
+        let kept = err.to_string().rsplit('\'').nth(1).unwrap().to_owned();
+        assert_eq!(fs::read(Path::new(&kept)).unwrap(), b"new\n");
     }
 }
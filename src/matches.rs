@@ -0,0 +1,319 @@
+//! `--export-matches`: save every match and its proposed replacement as JSON lines, for review
+//! tools to read without anything being modified.
+//!
+//! Each line is one match:
+//!
+//! ```json
+//! {"path":"a.txt","line":2,"start":10,"end":17,"match":"2024-05","replacement":"05/2024",
+//!  "captures":["2024-05","2024","05"]}
+//! ```
+//!
+//! Lines count from 1, and `start` and `end` are byte offsets from the start of the file.
+//! `captures` has the text of each capture group, starting with group 0 for the whole match, or
+//! null for groups which didn't participate. Text which isn't valid UTF-8 is written as
+//! `{"bytes":BASE64}` rather than a string, like `rg --json` does.
+//!
+//! `--apply-matches` reads the same format back and makes exactly those edits, so the file can be
+//! reviewed, and matches which shouldn't be replaced removed from it, before anything is changed.
+//! Only "path", "start", "end", "match", and "replacement" are used.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::json::{self, Value};
+use crate::spans::{self, Region};
+use crate::InPlaceOptions;
+use rp::replace::{Captures, Pattern, Replacer, StreamError};
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Make replacements on each line of `input` without writing them anywhere, and describe each
+/// match. `path` is the name to give the input.
+pub fn export<P, R>(
+    replacer: &Replacer<P>,
+    input: &mut R,
+    path: &Path,
+) -> Result<Vec<Value>, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+{
+    let path = path.to_string_lossy().into_owned();
+    let mut matches = vec![];
+    let mut caps = Captures::default();
+    let mut offset = 0;
+    replacer.for_each_line(input, |line| {
+        for edit in line.edits {
+            // search again from the start of the match to get its groups
+            replacer
+                .pattern()
+                .captures_at(line.old, edit.old.start, &mut caps);
+            let captures = (0..replacer.pattern().captures_len())
+                .map(|index| caps.get(index).map_or(Value::Null, |m| text(&line.old[m])))
+                .collect();
+            let number =
+                line.number + memchr::memchr_iter(b'\n', &line.old[..edit.old.start]).count();
+            matches.push(Value::object([
+                ("path", path.as_str().into()),
+                ("line", number.into()),
+                ("start", (offset + edit.old.start).into()),
+                ("end", (offset + edit.old.end).into()),
+                ("match", text(&line.old[edit.old.clone()])),
+                ("replacement", text(&line.new[edit.new.clone()])),
+                ("captures", Value::Array(captures)),
+            ]));
+        }
+        offset += line.old.len();
+        Ok(())
+    })?;
+    Ok(matches)
+}
+
+/// One edit read from a match file: `range` should still contain `expected`, and is replaced by
+/// `replacement`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    pub range: Range<usize>,
+    pub expected: Vec<u8>,
+    pub replacement: Vec<u8>,
+}
+
+impl Region for Edit {
+    const NAME: &'static str = "match";
+
+    fn range(&self) -> &Range<usize> {
+        &self.range
+    }
+
+    fn expected(&self) -> Option<&[u8]> {
+        Some(&self.expected)
+    }
+}
+
+/// Read the edits from a match file, grouped by file. Each file's edits are sorted and don't
+/// overlap.
+pub fn read<R: BufRead>(input: &mut R) -> anyhow::Result<BTreeMap<PathBuf, Vec<Edit>>> {
+    let mut files: BTreeMap<PathBuf, Vec<Edit>> = BTreeMap::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("failed to read matches")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = json::parse(&line).with_context(|| format!("line {}", index + 1))?;
+        let (path, edit) = parse_edit(&message).with_context(|| format!("line {}", index + 1))?;
+        files.entry(path).or_default().push(edit);
+    }
+    spans::sort_regions(&mut files)?;
+    Ok(files)
+}
+
+/// Get the path and edit from one line of a match file.
+fn parse_edit(message: &Value) -> anyhow::Result<(PathBuf, Edit)> {
+    let path = message
+        .get("path")
+        .and_then(Value::as_str)
+        .context("missing \"path\"")?;
+    let start = message.get("start").and_then(Value::as_usize);
+    let end = message.get("end").and_then(Value::as_usize);
+    let (Some(start), Some(end)) = (start, end) else {
+        anyhow::bail!("missing \"start\" or \"end\"");
+    };
+    if start > end {
+        anyhow::bail!("\"start\" is after \"end\"");
+    }
+    let field = |name| {
+        message
+            .get(name)
+            .and_then(from_text)
+            .with_context(|| format!("missing \"{name}\""))
+    };
+    let edit = Edit {
+        range: start..end,
+        expected: field("match")?,
+        replacement: field("replacement")?,
+    };
+    Ok((PathBuf::from(path), edit))
+}
+
+/// Make the given edits to `text`, after checking that every one still contains what was
+/// expected. Returns the number of edits.
+fn apply_edits(edits: &[Edit], text: &[u8], out: &mut Vec<u8>) -> anyhow::Result<usize> {
+    spans::check_regions(edits, text)?;
+    let mut last = 0;
+    for edit in edits {
+        out.extend_from_slice(&text[last..edit.range.start]);
+        out.extend_from_slice(&edit.replacement);
+        last = edit.range.end;
+    }
+    out.extend_from_slice(&text[last..]);
+    Ok(edits.len())
+}
+
+/// Make the given edits to the file at `path` in-place. The file is left alone if any match
+/// doesn't contain the text that was exported.
+pub fn apply_file(
+    path: &Path,
+    edits: &[Edit],
+    inplace_opts: &InPlaceOptions,
+) -> anyhow::Result<usize> {
+    crate::rewrite_inplace(path, inplace_opts, |input, output| {
+        let mut text = vec![];
+        input.read_to_end(&mut text).context("failed to read")?;
+        let mut buf = vec![];
+        let count = apply_edits(edits, &text, &mut buf)?;
+        output.write_all(&buf).context("failed to write")?;
+        Ok(count)
+    })
+}
+
+/// Convert text to a JSON string, or `{"bytes":BASE64}` if it isn't valid UTF-8.
+fn text(bytes: &[u8]) -> Value {
+    match std::str::from_utf8(bytes) {
+        Ok(s) => s.into(),
+        Err(_) => Value::object([("bytes", base64(bytes).into())]),
+    }
+}
+
+/// Encode bytes as standard base64, with padding.
+fn base64(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Convert a JSON string or `{"bytes":BASE64}` back to text.
+fn from_text(value: &Value) -> Option<Vec<u8>> {
+    match value.as_str() {
+        Some(s) => Some(s.as_bytes().to_vec()),
+        None => from_base64(value.get("bytes")?.as_str()?),
+    }
+}
+
+/// Decode standard base64, with or without padding.
+fn from_base64(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim_end_matches('=').as_bytes();
+    let mut out = Vec::with_capacity(text.len() * 3 / 4);
+    for chunk in text.chunks(4) {
+        if chunk.len() == 1 {
+            return None;
+        }
+        let mut n = 0u32;
+        for (i, &c) in chunk.iter().enumerate() {
+            let digit = BASE64.iter().position(|&b| b == c)? as u32;
+            n |= digit << (18 - 6 * i);
+        }
+        out.extend_from_slice(&n.to_be_bytes()[1..chunk.len()]);
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use regex::bytes::Regex;
+    use rp::replace::ReplaceOptions;
+    use rp::template::Template;
+
+    #[test]
+    fn test_export() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let re = Regex::new(r"(?<year>\d{4})-(\d\d)?").unwrap();
+        let replacer = opts.build(re, Template::parse("[$year]"));
+        let text = b"x\n2024-05 1999-\n\xff2000-01\n";
+        let matches = export(&replacer, &mut &text[..], Path::new("a.txt")).unwrap();
+        let lines: Vec<String> = matches.iter().map(Value::to_string).collect();
+        assert_eq!(
+            lines,
+            [
+                r#"{"path":"a.txt","line":2,"start":2,"end":9,"match":"2024-05","replacement":"[2024]","captures":["2024-05","2024","05"]}"#,
+                r#"{"path":"a.txt","line":2,"start":10,"end":15,"match":"1999-","replacement":"[1999]","captures":["1999-","1999",null]}"#,
+                r#"{"path":"a.txt","line":3,"start":17,"end":24,"match":"2000-01","replacement":"[2000]","captures":["2000-01","2000","01"]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(text(b"a\"b"), Value::from("a\"b"));
+        assert_eq!(text(b"\xff"), Value::object([("bytes", "/w==".into())]));
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"foob"), "Zm9vYg==");
+        assert_eq!(base64(b"fooba"), "Zm9vYmE=");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"\xff\x00\x80\x7f"] {
+            assert_eq!(from_base64(&base64(bytes)).as_deref(), Some(bytes));
+            assert_eq!(from_text(&text(bytes)).as_deref(), Some(bytes));
+        }
+        assert_eq!(from_base64("Zm9vYg"), Some(b"foob".to_vec()));
+        assert_eq!(from_base64("Z"), None);
+        assert_eq!(from_base64("Zm9v!"), None);
+    }
+
+    #[test]
+    fn test_apply() {
+        let text = r#"
+{"path":"a.txt","line":2,"start":10,"end":15,"match":"1999-","replacement":"[1999]","captures":[]}
+{"path":"a.txt","line":2,"start":2,"end":9,"match":"2024-05","replacement":{"bytes":"/w=="}}
+
+{"path":"b.txt","start":0,"end":1,"match":{"bytes":"eA=="},"replacement":""}
+"#;
+        let files = read(&mut text.as_bytes()).unwrap();
+        let edit = |range, expected: &[u8], replacement: &[u8]| Edit {
+            range,
+            expected: expected.to_vec(),
+            replacement: replacement.to_vec(),
+        };
+        assert_eq!(
+            files.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    PathBuf::from("a.txt"),
+                    vec![
+                        edit(2..9, b"2024-05", b"\xff"),
+                        edit(10..15, b"1999-", b"[1999]"),
+                    ]
+                ),
+                (PathBuf::from("b.txt"), vec![edit(0..1, b"x", b"")]),
+            ]
+        );
+
+        let edits = [edit(2..9, b"2024-05", b"May"), edit(10..15, b"1999-", b"")];
+        let mut out = vec![];
+        let count = apply_edits(&edits, b"x\n2024-05 1999-\n", &mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(out, b"x\nMay \n");
+        assert!(apply_edits(&edits, b"x\n2024-06 1999-\n", &mut vec![]).is_err());
+        assert!(apply_edits(&edits, b"x\n2024-05 19", &mut vec![]).is_err());
+
+        let error = |text: &str| read(&mut text.as_bytes()).unwrap_err().to_string();
+        assert_eq!(
+            error(
+                r#"{"path":"a","start":0,"end":3,"match":"foo","replacement":""}
+{"path":"a","start":2,"end":4,"match":"oo","replacement":""}"#
+            ),
+            "a: match 0..3 overlaps 2..4"
+        );
+        assert_eq!(error(r#"{"path":"a","start":0,"end":3}"#), "line 1");
+        assert!(read(&mut "{".as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,180 @@
+//! `--metrics-file`: write statistics about a run for the Prometheus node exporter's textfile
+//! collector.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use tempfile::NamedTempFile;
+
+/// Counters for the current run, updated as files are processed.
+pub static METRICS: Metrics = Metrics::new();
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    files: AtomicUsize,
+    changed: AtomicUsize,
+    replacements: AtomicUsize,
+    errors: AtomicUsize,
+}
+
+impl Metrics {
+    pub const fn new() -> Metrics {
+        Metrics {
+            files: AtomicUsize::new(0),
+            changed: AtomicUsize::new(0),
+            replacements: AtomicUsize::new(0),
+            errors: AtomicUsize::new(0),
+        }
+    }
+
+    /// Record a file which was processed and had `count` replacements.
+    fn file_done(&self, count: usize) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        if count > 0 {
+            self.changed.fetch_add(1, Ordering::Relaxed);
+        }
+        self.replacements.fetch_add(count, Ordering::Relaxed);
+    }
+
+    /// Record a file which failed.
+    fn file_failed(&self) {
+        self.files.fetch_add(1, Ordering::Relaxed);
+        self.errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record the result of processing a file.
+    pub fn record<E>(&self, result: &Result<usize, E>) {
+        match result {
+            Ok(count) => self.file_done(*count),
+            Err(_) => self.file_failed(),
+        }
+    }
+
+    /// The number of files processed, including failed ones.
+    pub fn files(&self) -> usize {
+        self.files.load(Ordering::Relaxed)
+    }
+
+    /// The number of files which failed.
+    pub fn errors(&self) -> usize {
+        self.errors.load(Ordering::Relaxed)
+    }
+
+    /// The total number of replacements.
+    pub fn replacements(&self) -> usize {
+        self.replacements.load(Ordering::Relaxed)
+    }
+
+    /// Write the metrics in the Prometheus text format. `success` is whether the whole run
+    /// succeeded. If it failed without any file errors, that counts as one error.
+    pub fn write_to<W: Write>(
+        &self,
+        out: &mut W,
+        duration: Duration,
+        success: bool,
+    ) -> io::Result<()> {
+        let mut errors = self.errors.load(Ordering::Relaxed);
+        if !success && errors == 0 {
+            errors = 1;
+        }
+        let metrics = [
+            (
+                "files_processed",
+                "Number of files processed.",
+                self.files.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "files_changed",
+                "Number of files with at least one replacement.",
+                self.changed.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "replacements",
+                "Total number of replacements made.",
+                self.replacements.load(Ordering::Relaxed) as f64,
+            ),
+            ("errors", "Number of errors.", errors as f64),
+            (
+                "success",
+                "Whether the run succeeded.",
+                if success { 1.0 } else { 0.0 },
+            ),
+            (
+                "duration_seconds",
+                "How long the run took.",
+                duration.as_secs_f64(),
+            ),
+        ];
+        for (name, help, value) in metrics {
+            writeln!(out, "# HELP rp_{name} {help}")?;
+            writeln!(out, "# TYPE rp_{name} gauge")?;
+            writeln!(out, "rp_{name} {value}")?;
+        }
+        Ok(())
+    }
+
+    /// Write the metrics to `path`, atomically replacing it so that the collector never sees a
+    /// partial file.
+    pub fn write_file(&self, path: &Path, duration: Duration, success: bool) -> io::Result<()> {
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut file = NamedTempFile::new_in(dir)?;
+        // temp files are only readable by us, but the collector usually runs as another user
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            file.as_file()
+                .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+        }
+        self.write_to(&mut file, duration, success)?;
+        file.persist(path)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Metrics::new();
+        metrics.file_done(0);
+        metrics.file_done(3);
+        metrics.record::<()>(&Ok(2));
+        metrics.record(&Err(()));
+
+        let mut out = vec![];
+        metrics
+            .write_to(&mut out, Duration::from_millis(1500), false)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let values: Vec<&str> = out.lines().filter(|l| !l.starts_with('#')).collect();
+        assert_eq!(
+            values,
+            [
+                "rp_files_processed 4",
+                "rp_files_changed 2",
+                "rp_replacements 5",
+                "rp_errors 1",
+                "rp_success 0",
+                "rp_duration_seconds 1.5",
+            ]
+        );
+        assert!(out.starts_with(
+            "# HELP rp_files_processed Number of files processed.\n\
+             # TYPE rp_files_processed gauge\n"
+        ));
+
+        // a failed run counts as an error even if no files failed
+        let mut out = vec![];
+        Metrics::new()
+            .write_to(&mut out, Duration::ZERO, false)
+            .unwrap();
+        assert!(String::from_utf8(out).unwrap().contains("rp_errors 1\n"));
+    }
+}
@@ -0,0 +1,272 @@
+//! `--plugin`: search for matches using an external program.
+//!
+//! The plugin is started as `PLUGIN PATTERN` and talks to rp with newline-delimited JSON over its
+//! stdin and stdout. Its stderr is passed through. First the plugin writes a hello message:
+//!
+//! ```text
+//! {"version": 1, "groups": 2}
+//! ```
+//!
+//! "groups" is the number of capture groups, not counting group 0 for the whole match, and may be
+//! omitted if there are none. Then for each search, rp writes a request and the plugin answers:
+//!
+//! ```text
+//! {"text": "foo bar\n", "start": 0}
+//! {"match": [4, 7], "groups": [[4, 5], null]}
+//! ```
+//!
+//! The plugin should return the leftmost match at or after byte offset "start" of "text", which is
+//! usually a single line, or `{"match": null}` if there isn't one. Offsets are in bytes, and the
+//! optional "groups" gives the span of each capture group or null for groups which didn't match.
+//! Text which isn't valid UTF-8 is never sent to the plugin and has no matches.
+
+use std::io::{self, BufRead, BufReader, Write};
+use std::ops::Range;
+use std::path::Path;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+
+use crate::json::{self, Value};
+use rp::replace::{Captures, Pattern};
+
+/// The only protocol version so far.
+const VERSION: usize = 1;
+
+#[derive(Debug, thiserror::Error)]
+pub enum PluginError {
+    #[error("failed to start plugin")]
+    Spawn(#[source] io::Error),
+    #[error("failed to communicate with plugin")]
+    Io(#[from] io::Error),
+    #[error("plugin exited unexpectedly")]
+    Exited,
+    #[error("invalid message from plugin")]
+    Json(#[from] json::Error),
+    #[error("invalid message from plugin: {0}")]
+    Protocol(&'static str),
+    #[error("plugin uses protocol version {0}, but only version {VERSION} is supported")]
+    Version(usize),
+}
+
+/// The pipes to talk to a plugin.
+#[derive(Debug)]
+struct Channel<W, R> {
+    writer: W,
+    reader: R,
+}
+
+impl<W: Write, R: BufRead> Channel<W, R> {
+    /// Read one message.
+    fn receive(&mut self) -> Result<Value, PluginError> {
+        let mut line = String::new();
+        if self.reader.read_line(&mut line)? == 0 {
+            return Err(PluginError::Exited);
+        }
+        Ok(json::parse(&line)?)
+    }
+
+    /// Read the hello message and return the number of capture groups, including group 0.
+    fn hello(&mut self) -> Result<usize, PluginError> {
+        let hello = self.receive()?;
+        match hello.get("version").and_then(Value::as_usize) {
+            Some(VERSION) => (),
+            Some(version) => return Err(PluginError::Version(version)),
+            None => return Err(PluginError::Protocol("missing \"version\"")),
+        }
+        match hello.get("groups") {
+            None => Ok(1),
+            Some(groups) => groups
+                .as_usize()
+                .map(|groups| groups + 1)
+                .ok_or(PluginError::Protocol("invalid \"groups\"")),
+        }
+    }
+
+    /// Ask for the next match and save its capture groups into `caps`.
+    fn search(
+        &mut self,
+        text: &str,
+        start: usize,
+        caps: &mut Captures,
+    ) -> Result<Option<Range<usize>>, PluginError> {
+        let request = Value::object([("text", text.into()), ("start", start.into())]);
+        writeln!(self.writer, "{request}")?;
+        self.writer.flush()?;
+
+        let response = self.receive()?;
+        let m = match response.get("match") {
+            Some(Value::Null) => return Ok(None),
+            Some(m) => span(m, text.len()).ok_or(PluginError::Protocol("invalid \"match\""))?,
+            None => return Err(PluginError::Protocol("missing \"match\"")),
+        };
+        if m.start < start {
+            return Err(PluginError::Protocol("match is before \"start\""));
+        }
+
+        let groups = match response.get("groups") {
+            None => &[][..],
+            Some(groups) => groups
+                .as_array()
+                .ok_or(PluginError::Protocol("invalid \"groups\""))?,
+        };
+        let groups = groups
+            .iter()
+            .map(|group| match group {
+                Value::Null => Ok(None),
+                group => span(group, text.len())
+                    .map(Some)
+                    .ok_or(PluginError::Protocol("invalid group in \"groups\"")),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        caps.set(std::iter::once(Some(m.clone())).chain(groups));
+        Ok(Some(m))
+    }
+}
+
+/// Parse a `[start, end]` span which must fit in text of length `len`.
+fn span(value: &Value, len: usize) -> Option<Range<usize>> {
+    match value.as_array()? {
+        [start, end] => {
+            let (start, end) = (start.as_usize()?, end.as_usize()?);
+            (start <= end && end <= len).then_some(start..end)
+        }
+        _ => None,
+    }
+}
+
+/// A pattern whose matches are found by a plugin process.
+///
+/// Searches can't fail through the `Pattern` interface, so the first error is saved and every
+/// search after it finds no match. Callers must check `Pattern::error` after searching.
+#[derive(Debug)]
+pub struct PluginPattern {
+    child: Child,
+    /// This is only None after being dropped
+    channel: Mutex<Option<Channel<ChildStdin, BufReader<ChildStdout>>>>,
+    captures_len: usize,
+    error: Mutex<Option<String>>,
+}
+
+impl PluginPattern {
+    /// Start the plugin at `path` for `pattern` and wait for its hello message.
+    pub fn spawn(path: &Path, pattern: &str) -> Result<PluginPattern, PluginError> {
+        let mut child = Command::new(path)
+            .arg(pattern)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .map_err(PluginError::Spawn)?;
+        // unwraps are ok because both were piped
+        let mut channel = Channel {
+            writer: child.stdin.take().unwrap(),
+            reader: BufReader::new(child.stdout.take().unwrap()),
+        };
+        let captures_len = match channel.hello() {
+            Ok(len) => len,
+            Err(err) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(err);
+            }
+        };
+        Ok(PluginPattern {
+            child,
+            channel: Mutex::new(Some(channel)),
+            captures_len,
+            error: Mutex::new(None),
+        })
+    }
+}
+
+impl Pattern for PluginPattern {
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        self.captures_at(text, start, &mut Captures::default())
+    }
+
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        let mut error = self.error.lock().unwrap();
+        if error.is_some() {
+            return None;
+        }
+        let text = std::str::from_utf8(text).ok()?;
+        let mut channel = self.channel.lock().unwrap();
+        match channel.as_mut()?.search(text, start, caps) {
+            Ok(m) => m,
+            Err(err) => {
+                *error = Some(format!("{:#}", anyhow::Error::from(err)));
+                None
+            }
+        }
+    }
+
+    fn captures_len(&self) -> usize {
+        self.captures_len
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.lock().unwrap().clone()
+    }
+}
+
+impl Drop for PluginPattern {
+    fn drop(&mut self) {
+        // closing stdin tells the plugin to exit
+        if let Ok(channel) = self.channel.get_mut() {
+            channel.take();
+        }
+        let _ = self.child.wait();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channel(responses: &str) -> Channel<Vec<u8>, &[u8]> {
+        Channel {
+            writer: vec![],
+            reader: responses.as_bytes(),
+        }
+    }
+
+    #[test]
+    fn test_hello() {
+        assert_eq!(channel(r#"{"version": 1}"#).hello().unwrap(), 1);
+        assert_eq!(
+            channel(r#"{"version": 1, "groups": 2}"#).hello().unwrap(),
+            3
+        );
+        assert!(matches!(
+            channel(r#"{"version": 2}"#).hello(),
+            Err(PluginError::Version(2))
+        ));
+        assert!(matches!(channel("").hello(), Err(PluginError::Exited)));
+    }
+
+    #[test]
+    fn test_search() {
+        let mut ch = channel(concat!(
+            "{\"match\": [4, 7], \"groups\": [[4, 5], null]}\n",
+            "{\"match\": null}\n",
+            "{\"match\": [1, 9]}\n",
+        ));
+        let mut caps = Captures::default();
+        assert_eq!(ch.search("foo bar\n", 2, &mut caps).unwrap(), Some(4..7));
+        assert_eq!(caps.get(0), Some(4..7));
+        assert_eq!(caps.get(1), Some(4..5));
+        assert_eq!(caps.get(2), None);
+        assert_eq!(ch.search("x", 0, &mut caps).unwrap(), None);
+        assert!(matches!(
+            ch.search("short", 0, &mut caps),
+            Err(PluginError::Protocol(_))
+        ));
+        assert_eq!(
+            String::from_utf8(ch.writer).unwrap(),
+            concat!(
+                "{\"text\":\"foo bar\\n\",\"start\":2}\n",
+                "{\"text\":\"x\",\"start\":0}\n",
+                "{\"text\":\"short\",\"start\":0}\n",
+            )
+        );
+    }
+}
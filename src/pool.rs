@@ -0,0 +1,139 @@
+//! Spare scratch buffers kept by each thread, so that making replacements line by line doesn't
+//! allocate new buffers for every line, or for every file when many are processed in parallel.
+//!
+//! Each thread keeps up to 8 spare buffers of each kind. The `RP_BUFFER_POOL_SIZE` environment
+//! variable changes that, and 0 turns the pool off.
+
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut};
+use std::sync::OnceLock;
+use std::thread::LocalKey;
+
+/// The environment variable which sets how many spare buffers of each kind a thread keeps.
+pub const POOL_SIZE_VAR: &str = "RP_BUFFER_POOL_SIZE";
+
+const DEFAULT_POOL_SIZE: usize = 8;
+
+/// Buffers which have grown bigger than this many bytes are freed rather than kept, so that one
+/// huge line doesn't hold on to memory for the rest of the run.
+pub(crate) const MAX_KEPT_BYTES: usize = 1 << 20;
+
+/// How many spare buffers of each kind to keep, from `RP_BUFFER_POOL_SIZE`.
+fn pool_size() -> usize {
+    static SIZE: OnceLock<usize> = OnceLock::new();
+    *SIZE.get_or_init(|| {
+        std::env::var(POOL_SIZE_VAR)
+            .ok()
+            .and_then(|size| size.trim().parse().ok())
+            .unwrap_or(DEFAULT_POOL_SIZE)
+    })
+}
+
+/// A kind of buffer which can be pooled.
+pub(crate) trait Reuse: Default + 'static {
+    /// The current thread's spare buffers of this kind.
+    fn pool() -> &'static LocalKey<RefCell<Vec<Self>>>;
+
+    /// Empty the buffer so that it can be used again, returning false if it's too big to keep.
+    fn reset(&mut self) -> bool;
+}
+
+impl Reuse for Vec<u8> {
+    fn pool() -> &'static LocalKey<RefCell<Vec<Self>>> {
+        thread_local!(static POOL: RefCell<Vec<Vec<u8>>> = const { RefCell::new(Vec::new()) });
+        &POOL
+    }
+
+    fn reset(&mut self) -> bool {
+        self.clear();
+        self.capacity() <= MAX_KEPT_BYTES
+    }
+}
+
+/// A buffer taken from the current thread's pool, which goes back into the pool when it's
+/// dropped.
+pub(crate) struct Pooled<T: Reuse>(Option<T>);
+
+impl<T: Reuse> Pooled<T> {
+    /// Take a spare buffer, or make a new one if there aren't any. It's always empty.
+    pub(crate) fn take() -> Pooled<T> {
+        // with the pool turned off, don't pay for looking at it either
+        if pool_size() == 0 {
+            return Pooled(Some(T::default()));
+        }
+        // the pool is gone while the thread is shutting down
+        let spare = T::pool()
+            .try_with(|pool| pool.borrow_mut().pop())
+            .ok()
+            .flatten();
+        Pooled(Some(spare.unwrap_or_default()))
+    }
+}
+
+impl<T: Reuse> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.0.as_ref().expect("only taken when dropped")
+    }
+}
+
+impl<T: Reuse> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.0.as_mut().expect("only taken when dropped")
+    }
+}
+
+impl<T: Reuse> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if pool_size() == 0 {
+            return;
+        }
+        let Some(mut buf) = self.0.take() else {
+            return;
+        };
+        if buf.reset() {
+            let _ = T::pool().try_with(|pool| {
+                let mut pool = pool.borrow_mut();
+                if pool.len() < pool_size() {
+                    pool.push(buf);
+                }
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pooled() {
+        // RP_BUFFER_POOL_SIZE=0 in the environment turns the pool off
+        if pool_size() == 0 {
+            return;
+        }
+        let mut buf = Pooled::<Vec<u8>>::take();
+        buf.extend_from_slice(b"abc");
+        let ptr = buf.as_ptr();
+        drop(buf);
+
+        // the same allocation comes back, emptied
+        let buf = Pooled::<Vec<u8>>::take();
+        assert!(buf.is_empty());
+        assert_eq!(buf.as_ptr(), ptr);
+        drop(buf);
+
+        // huge buffers aren't kept
+        let mut buf = Pooled::<Vec<u8>>::take();
+        buf.reserve(MAX_KEPT_BYTES + 1);
+        drop(buf);
+        let buf = Pooled::<Vec<u8>>::take();
+        assert!(buf.capacity() <= MAX_KEPT_BYTES);
+
+        // other threads have their own pools
+        std::thread::spawn(|| assert_eq!(Pooled::<Vec<u8>>::take().capacity(), 0))
+            .join()
+            .unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+//! Translate POSIX basic and extended regular expressions into the regex crate's syntax.
+
+/// Which flavor of POSIX regular expression to translate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Dialect {
+    /// Basic regular expressions, as used by sed and grep
+    Bre,
+    /// Extended regular expressions, as used by sed -E and grep -E
+    Ere,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum PosixError {
+    #[error("backreferences like '\\{0}' aren't supported")]
+    Backreference(char),
+    #[error("'{0}' isn't supported")]
+    Unsupported(String),
+    #[error("unterminated bracket expression")]
+    UnterminatedBracket,
+}
+
+/// Translate a POSIX regular expression into an equivalent regex crate pattern.
+///
+/// Besides the standard syntax, the common GNU extensions `\+`, `\?`, and `\|` in BREs, `\w`,
+/// `\W`, `\s`, `\S`, `\b`, `\B`, `\n`, `\t`, and the buffer anchors `` \` `` and `\'` are
+/// supported. Backreferences, `\<` and `\>` word boundaries, and multi-character collating
+/// elements can't be translated and return an error.
+pub fn translate(pattern: &str, dialect: Dialect) -> Result<String, PosixError> {
+    let bre = dialect == Dialect::Bre;
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut out = String::with_capacity(pattern.len());
+    let mut i = 0;
+    // At the start of the pattern or a group or alternative, where '*' is literal and a BRE '^'
+    // is an anchor
+    let mut at_start = true;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let was_start = std::mem::replace(&mut at_start, false);
+        i += 1;
+
+        match c {
+            '[' => i = translate_bracket(&chars, i, &mut out)?,
+            '\\' => {
+                let Some(&next) = chars.get(i) else {
+                    // a trailing backslash has no meaning, treat it as a literal
+                    out.push_str(r"\\");
+                    break;
+                };
+                i += 1;
+                match next {
+                    '1'..='9' => return Err(PosixError::Backreference(next)),
+                    '(' | '|' if bre => {
+                        out.push(next);
+                        at_start = true;
+                    }
+                    ')' | '{' | '}' | '+' | '?' if bre => out.push(next),
+                    '<' | '>' => return Err(PosixError::Unsupported(format!("\\{next}"))),
+                    '`' => out.push_str(r"\A"),
+                    '\'' => out.push_str(r"\z"),
+                    'w' | 'W' | 's' | 'S' | 'b' | 'B' | 'n' | 't' => {
+                        out.push('\\');
+                        out.push(next);
+                    }
+                    _ => push_literal(&mut out, next),
+                }
+            }
+            '*' if was_start => out.push_str(r"\*"),
+            '+' | '?' if was_start && !bre => push_literal(&mut out, c),
+            '^' if bre && !was_start => out.push_str(r"\^"),
+            '^' => {
+                out.push('^');
+                at_start = true;
+            }
+            '$' if bre && !is_bre_end(&chars[i..]) => out.push_str(r"\$"),
+            '(' | ')' | '|' | '+' | '?' | '{' | '}' if bre => push_literal(&mut out, c),
+            '(' | '|' => {
+                out.push(c);
+                at_start = true;
+            }
+            '{' => match interval_len(&chars[i..]) {
+                Some(len) => {
+                    out.push('{');
+                    out.extend(&chars[i..(i + len)]);
+                    i += len;
+                }
+                None => out.push_str(r"\{"),
+            },
+            '}' => out.push_str(r"\}"),
+            '.' | '*' | '+' | '?' | ')' | '$' => out.push(c),
+            _ => push_literal(&mut out, c),
+        }
+    }
+
+    Ok(out)
+}
+
+/// Push a character which should match literally, escaping it if needed.
+fn push_literal(out: &mut String, c: char) {
+    if regex_syntax::is_meta_character(c) {
+        out.push('\\');
+    }
+    out.push(c);
+}
+
+/// Check whether a BRE '$' followed by `rest` is an anchor, which is only the case at the end of
+/// the pattern, a group, or an alternative.
+fn is_bre_end(rest: &[char]) -> bool {
+    matches!(rest, [] | ['\\', ')' | '|', ..])
+}
+
+/// If `rest` (following a '{') is a valid ERE interval like `2}`, `2,}`, or `2,5}`, return its
+/// length including the closing brace.
+fn interval_len(rest: &[char]) -> Option<usize> {
+    let end = rest.iter().position(|c| *c == '}')?;
+    let body: String = rest[..end].iter().collect();
+    let (min, max) = body.split_once(',').unwrap_or((&body, "0"));
+    let is_num = |s: &str| !s.is_empty() && s.bytes().all(|b| b.is_ascii_digit());
+    (is_num(min) && (max.is_empty() || is_num(max))).then_some(end + 1)
+}
+
+/// Translate a bracket expression whose contents start at `chars[start]`, just after the '['.
+/// Returns the index just after the closing ']'.
+fn translate_bracket(chars: &[char], start: usize, out: &mut String) -> Result<usize, PosixError> {
+    let mut i = start;
+    out.push('[');
+    if chars.get(i) == Some(&'^') {
+        out.push('^');
+        i += 1;
+    }
+    // a ']' right at the start is a literal
+    if chars.get(i) == Some(&']') {
+        out.push_str(r"\]");
+        i += 1;
+    }
+
+    loop {
+        let Some(&c) = chars.get(i) else {
+            return Err(PosixError::UnterminatedBracket);
+        };
+        i += 1;
+        match c {
+            ']' => {
+                out.push(']');
+                return Ok(i);
+            }
+            '[' if matches!(chars.get(i), Some(':' | '=' | '.')) => {
+                let delim = chars[i];
+                let body_start = i + 1;
+                let len = chars[body_start..]
+                    .windows(2)
+                    .position(|w| w == [delim, ']'])
+                    .ok_or(PosixError::UnterminatedBracket)?;
+                let body = &chars[body_start..(body_start + len)];
+                i = body_start + len + 2;
+                match (delim, body) {
+                    (':', _) => {
+                        out.push_str("[:");
+                        out.extend(body);
+                        out.push_str(":]");
+                    }
+                    // equivalence classes and collating elements are just the character itself
+                    // in the C locale
+                    (_, ['-']) => out.push_str(r"\-"),
+                    (_, [c]) => push_bracket_literal(out, *c),
+                    _ => {
+                        let body: String = body.iter().collect();
+                        return Err(PosixError::Unsupported(format!("[{delim}{body}{delim}]")));
+                    }
+                }
+            }
+            _ => push_bracket_literal(out, c),
+        }
+    }
+}
+
+/// Push a character inside a bracket expression. Backslashes are literal in POSIX brackets, and
+/// the regex crate also gives special meaning to nested '[' and doubled '&' or '~'.
+fn push_bracket_literal(out: &mut String, c: char) {
+    if matches!(c, '\\' | '[' | '&' | '~') {
+        out.push('\\');
+    }
+    out.push(c);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_translate_bre() {
+        #[track_caller]
+        fn check(bre: &str, expected: &str) {
+            assert_eq!(translate(bre, Dialect::Bre).unwrap(), expected);
+            regex::Regex::new(expected).unwrap();
+        }
+
+        check(r"\(ab*\)\{2,3\}", r"(ab*){2,3}");
+        check(r"(a|b)+?{x}", r"\(a\|b\)\+\?\{x\}");
+        check(r"a\|b\+c\?", r"a|b+c?");
+        check(r"*a\(*b\)", r"\*a(\*b)");
+        check(r"^*x^y", r"^\*x\^y");
+        check(r"a$b$", r"a\$b$");
+        check(r"\(a$\|b$\)", r"(a$|b$)");
+        check(r"\.\*\[\]\\#&~-", r"\.\*\[\]\\\#\&\~\-");
+        check(r"\w\S\b\`\'", r"\w\S\b\A\z");
+        check(r"\/\n\t", r"/\n\t");
+        check(r"a\", r"a\\");
+    }
+
+    #[test]
+    fn test_translate_ere() {
+        #[track_caller]
+        fn check(ere: &str, expected: &str) {
+            assert_eq!(translate(ere, Dialect::Ere).unwrap(), expected);
+            regex::Regex::new(expected).unwrap();
+        }
+
+        check(r"(ab*){2,3}|c+d?", r"(ab*){2,3}|c+d?");
+        check(r"\(a\)\{", r"\(a\)\{");
+        check(r"a{x}b{,2}c{3,}", r"a\{x\}b\{,2\}c{3,}");
+        check(r"*a|+b(?c)", r"\*a|\+b(\?c)");
+        check(r"a^b$c", r"a^b$c");
+    }
+
+    #[test]
+    fn test_translate_bracket() {
+        #[track_caller]
+        fn check(pattern: &str, expected: &str) {
+            assert_eq!(translate(pattern, Dialect::Ere).unwrap(), expected);
+            regex::Regex::new(expected).unwrap();
+        }
+
+        check(r"[[:alpha:]_][[:digit:]]*", r"[[:alpha:]_][[:digit:]]*");
+        check(r"[]a]", r"[\]a]");
+        check(r"[^]a-z]", r"[^\]a-z]");
+        check(r"[\n]", r"[\\n]");
+        check(r"[a[b&&c~~]", r"[a\[b\&\&c\~\~]");
+        check(r"[[=e=][.-.]]", r"[e\-]");
+        check(r"[a-]", r"[a-]");
+    }
+
+    #[test]
+    fn test_translate_errors() {
+        assert!(matches!(
+            translate(r"\(a\)\1", Dialect::Bre),
+            Err(PosixError::Backreference('1'))
+        ));
+        assert!(matches!(
+            translate(r"\<word\>", Dialect::Ere),
+            Err(PosixError::Unsupported(_))
+        ));
+        assert!(matches!(
+            translate(r"[abc", Dialect::Bre),
+            Err(PosixError::UnterminatedBracket)
+        ));
+        assert!(matches!(
+            translate(r"[[:alpha]", Dialect::Bre),
+            Err(PosixError::UnterminatedBracket)
+        ));
+        assert!(matches!(
+            translate(r"[[.space.]]", Dialect::Bre),
+            Err(PosixError::Unsupported(_))
+        ));
+    }
+}
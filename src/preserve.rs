@@ -0,0 +1,231 @@
+//! `--preserve`: give files edited in-place their original owner, extended attributes, and
+//! timestamps, rather than those of the new file which replaces them.
+
+use std::fs::{File, FileTimes, Metadata};
+use std::io;
+
+use anyhow::Context;
+
+/// Attributes of an edited file which can be kept.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Attribute {
+    /// The owner and group, which needs root unless they're the current user's
+    Ownership,
+    /// Extended attributes, like SELinux labels and ACLs
+    Xattr,
+    /// The access and modification times
+    Timestamps,
+}
+
+/// The attributes of an original file, saved before it's replaced.
+#[derive(Debug, Default)]
+pub struct Saved {
+    owner: Option<(u32, u32)>,
+    xattrs: Option<Vec<(Vec<u8>, Vec<u8>)>>,
+    times: Option<FileTimes>,
+}
+
+/// Read the `attributes` of `file`, whose metadata is `meta`.
+pub fn save(file: &File, meta: &Metadata, attributes: &[Attribute]) -> anyhow::Result<Saved> {
+    let mut saved = Saved::default();
+    for attribute in attributes {
+        match attribute {
+            Attribute::Ownership => saved.owner = Some(owner(meta)?),
+            Attribute::Xattr => {
+                saved.xattrs = Some(xattr::list(file).context("failed to read xattrs")?);
+            }
+            Attribute::Timestamps => {
+                let times = FileTimes::new()
+                    .set_accessed(meta.accessed().context("failed to get access time")?)
+                    .set_modified(meta.modified().context("failed to get modification time")?);
+                saved.times = Some(times);
+            }
+        }
+    }
+    Ok(saved)
+}
+
+/// Give `file` the attributes which were saved. The timestamps are set last, since setting the
+/// others could change them.
+pub fn restore(saved: &Saved, file: &File) -> anyhow::Result<()> {
+    if let Some((uid, gid)) = saved.owner {
+        set_owner(file, uid, gid)
+            .with_context(|| format!("failed to restore owner {uid}:{gid}"))?;
+    }
+    if let Some(xattrs) = &saved.xattrs {
+        for (name, value) in xattrs {
+            xattr::set(file, name, value).with_context(|| {
+                format!(
+                    "failed to restore xattr '{}'",
+                    String::from_utf8_lossy(name)
+                )
+            })?;
+        }
+    }
+    if let Some(times) = saved.times {
+        file.set_times(times)
+            .context("failed to restore timestamps")?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn owner(meta: &Metadata) -> anyhow::Result<(u32, u32)> {
+    use std::os::unix::fs::MetadataExt;
+    Ok((meta.uid(), meta.gid()))
+}
+
+#[cfg(not(unix))]
+fn owner(_meta: &Metadata) -> anyhow::Result<(u32, u32)> {
+    anyhow::bail!("file ownership isn't supported on this platform")
+}
+
+/// Change the owner and group of `file`, unless they're already right. Only root can give a file
+/// to another user.
+#[cfg(unix)]
+fn set_owner(file: &File, uid: u32, gid: u32) -> io::Result<()> {
+    use std::os::unix::fs::MetadataExt;
+    let meta = file.metadata()?;
+    let uid = (meta.uid() != uid).then_some(uid);
+    let gid = (meta.gid() != gid).then_some(gid);
+    if uid.is_none() && gid.is_none() {
+        return Ok(());
+    }
+    std::os::unix::fs::fchown(file, uid, gid)
+}
+
+#[cfg(not(unix))]
+fn set_owner(_file: &File, _uid: u32, _gid: u32) -> io::Result<()> {
+    unreachable!("ownership can't be saved")
+}
+
+#[cfg(target_os = "linux")]
+mod xattr {
+    use std::fs::File;
+    use std::io;
+    use std::os::fd::AsRawFd;
+
+    /// Call `f` with a buffer to fill, growing it until it's big enough, like the xattr calls
+    /// which return the size they need when given an empty buffer.
+    fn read_sized(mut f: impl FnMut(&mut [u8]) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = f(&mut []);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0; size as usize];
+            let size = f(&mut buf);
+            if size >= 0 {
+                buf.truncate(size as usize);
+                return Ok(buf);
+            }
+            // the attributes changed in between, so try again
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::ERANGE) {
+                return Err(err);
+            }
+        }
+    }
+
+    /// Read the names and values of all of the extended attributes of `file`.
+    pub fn list(file: &File) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let fd = file.as_raw_fd();
+        // SAFETY: the buffer is valid for writing `buf.len()` bytes
+        let names =
+            read_sized(|buf| unsafe { libc::flistxattr(fd, buf.as_mut_ptr().cast(), buf.len()) })?;
+        // the names are each terminated by a NUL byte
+        names
+            .split_inclusive(|&b| b == 0)
+            .map(|name| {
+                // SAFETY: `name` is NUL-terminated, and the buffer is valid for writing
+                // `buf.len()` bytes
+                let value = read_sized(|buf| unsafe {
+                    libc::fgetxattr(fd, name.as_ptr().cast(), buf.as_mut_ptr().cast(), buf.len())
+                })?;
+                Ok((name[..name.len() - 1].to_vec(), value))
+            })
+            .collect()
+    }
+
+    /// Set one extended attribute of `file`.
+    pub fn set(file: &File, name: &[u8], value: &[u8]) -> io::Result<()> {
+        let name = std::ffi::CString::new(name)?;
+        // SAFETY: `name` is NUL-terminated, and `value` is valid for reading `value.len()` bytes
+        let ret = unsafe {
+            libc::fsetxattr(
+                file.as_raw_fd(),
+                name.as_ptr(),
+                value.as_ptr().cast(),
+                value.len(),
+                0,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+mod xattr {
+    use std::fs::File;
+    use std::io;
+
+    pub fn list(_file: &File) -> io::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "extended attributes are only supported on Linux",
+        ))
+    }
+
+    pub fn set(_file: &File, _name: &[u8], _value: &[u8]) -> io::Result<()> {
+        unreachable!("xattrs can't be saved")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::fs;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_preserve() {
+        let dir = tempfile::tempdir().unwrap();
+        let orig = dir.path().join("orig");
+        let new = dir.path().join("new");
+        fs::write(&orig, "a").unwrap();
+        fs::write(&new, "b").unwrap();
+
+        let mtime = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000);
+        let file = File::options().write(true).open(&orig).unwrap();
+        file.set_times(FileTimes::new().set_accessed(mtime).set_modified(mtime))
+            .unwrap();
+        // not every filesystem supports user xattrs
+        let has_xattr = cfg!(target_os = "linux") && xattr::set(&file, b"user.rp", b"x").is_ok();
+
+        let attributes = [Attribute::Ownership, Attribute::Timestamps];
+        let attributes = if has_xattr {
+            &[
+                Attribute::Ownership,
+                Attribute::Xattr,
+                Attribute::Timestamps,
+            ][..]
+        } else {
+            &attributes[..]
+        };
+        let saved = save(&file, &file.metadata().unwrap(), attributes).unwrap();
+        let file = File::options().write(true).open(&new).unwrap();
+        restore(&saved, &file).unwrap();
+
+        let meta = fs::metadata(&new).unwrap();
+        assert_eq!(meta.modified().unwrap(), mtime);
+        assert_eq!(meta.accessed().unwrap(), mtime);
+        if has_xattr {
+            let xattrs = xattr::list(&file).unwrap();
+            assert!(xattrs.contains(&(b"user.rp".to_vec(), b"x".to_vec())));
+        }
+    }
+}
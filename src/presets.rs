@@ -0,0 +1,132 @@
+//! `--preset`: common cleanups which are built in, so they don't need a rules file.
+
+use std::io::{self, Write};
+
+use crate::rules::Rule;
+
+/// A named set of rules.
+#[derive(Debug)]
+pub struct Preset {
+    pub name: &'static str,
+    pub about: &'static str,
+    /// Pattern and replacement pairs, tried in order like a rules file
+    rules: &'static [(&'static str, &'static str)],
+    /// Whether the whole input has to be searched at once, like -m
+    pub multiline: bool,
+}
+
+pub const PRESETS: &[Preset] = &[
+    Preset {
+        name: "strip-ansi",
+        about: "Remove ANSI escape sequences for colors and cursor movement",
+        rules: &[
+            // CSI sequences like colors, and OSC sequences like hyperlinks and window titles
+            (r"\x1b\[[0-?]*[ -/]*[@-~]", ""),
+            (r"\x1b\][^\x07\x1b]*(?:\x07|\x1b\\)", ""),
+        ],
+        multiline: false,
+    },
+    Preset {
+        name: "crlf-to-lf",
+        about: "Convert Windows line endings to Unix ones",
+        rules: &[(r"\r\n", "\n")],
+        multiline: false,
+    },
+    Preset {
+        name: "lf-to-crlf",
+        about: "Convert Unix line endings to Windows ones",
+        rules: &[(r"(^|[^\r])\n", "$1\r\n")],
+        multiline: false,
+    },
+    Preset {
+        name: "trailing-whitespace",
+        about: "Remove spaces and tabs at the end of lines",
+        rules: &[(r"[ \t]+(\r?)$", "$1")],
+        multiline: false,
+    },
+    Preset {
+        name: "collapse-blank-lines",
+        about: "Replace runs of blank lines with a single one",
+        rules: &[(r"(\r?\n)(?:[ \t]*\r?\n){2,}", "$1$1")],
+        multiline: true,
+    },
+];
+
+/// Find a preset by name.
+pub fn get(name: &str) -> Option<&'static Preset> {
+    PRESETS.iter().find(|preset| preset.name == name)
+}
+
+impl Preset {
+    /// The preset's rules, named after it.
+    pub fn rules(&self) -> impl Iterator<Item = Rule> + '_ {
+        self.rules.iter().map(|&(pattern, replacement)| Rule {
+            name: self.name.to_owned(),
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+        })
+    }
+}
+
+/// Print each preset's name and description, for `--list-presets`.
+pub fn write_list<W: Write + ?Sized>(out: &mut W) -> io::Result<()> {
+    let width = PRESETS.iter().map(|p| p.name.len()).max().unwrap_or(0);
+    for preset in PRESETS {
+        writeln!(out, "{:width$}  {}", preset.name, preset.about)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rp::replace::{MatchKind, Records, ReplaceOptions, UnicodeMode};
+
+    fn apply(name: &str, text: &str) -> String {
+        let preset = get(name).unwrap();
+        let opts = ReplaceOptions {
+            replace_all: true,
+            records: if preset.multiline {
+                Records::Whole
+            } else {
+                Records::Lines
+            },
+            ..Default::default()
+        };
+        let pairs: Vec<_> = preset
+            .rules()
+            .map(|rule| (rule.pattern, rule.replacement))
+            .collect();
+        let replacer = opts
+            .build_multi(&pairs, MatchKind::LeftmostFirst, false, UnicodeMode::Auto)
+            .unwrap();
+        let mut out = vec![];
+        replacer
+            .replace_stream(&mut text.as_bytes(), &mut out)
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_presets() {
+        assert_eq!(
+            apply(
+                "strip-ansi",
+                "\x1b[1;31mred\x1b[0m \x1b]8;;http://x\x1b\\link\x1b]8;;\x07\n"
+            ),
+            "red link\n"
+        );
+        assert_eq!(apply("crlf-to-lf", "a\r\n\r\nb\n"), "a\n\nb\n");
+        assert_eq!(apply("lf-to-crlf", "a\n\nb\r\nc"), "a\r\n\r\nb\r\nc");
+        assert_eq!(
+            apply("trailing-whitespace", "a \t\nb \r\n c\n"),
+            "a\nb\r\n c\n"
+        );
+        assert_eq!(
+            apply("collapse-blank-lines", "a\n\n\n \nb\n\nc\r\n\r\n\r\nd"),
+            "a\n\nb\n\nc\r\n\r\nd"
+        );
+        assert!(get("missing").is_none());
+    }
+}
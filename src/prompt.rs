@@ -0,0 +1,191 @@
+//! `-p/--prompt`: ask before making each replacement, like `git add -p`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use rp::replace::Candidate;
+use rp::unescape::escape_nonprintable_into;
+
+const HELP: &str = "\
+y - replace this match
+n - don't replace this match
+a - replace this and all later matches in the file
+d - don't replace this or any later matches in the file
+q - quit; don't replace this or any later matches
+? - print help
+";
+
+/// Asks the user about each candidate replacement.
+///
+/// Answers for the rest of a file last until `start_file` is called. After quitting, or after an
+/// error talking to the terminal, every later candidate is declined without asking.
+#[derive(Debug)]
+pub struct Prompter<R, W> {
+    input: R,
+    output: W,
+    color: bool,
+    /// The answer from 'a' or 'd' for the rest of the current file
+    rest_of_file: Option<bool>,
+    quit: bool,
+}
+
+impl Prompter<BufReader<File>, File> {
+    /// Prompt on the controlling terminal, so that stdin and stdout can still be redirected.
+    pub fn open_tty(color: bool) -> io::Result<Self> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        Ok(Prompter::new(BufReader::new(tty.try_clone()?), tty, color))
+    }
+}
+
+impl<R: BufRead, W: Write> Prompter<R, W> {
+    pub fn new(input: R, output: W, color: bool) -> Self {
+        Prompter {
+            input,
+            output,
+            color,
+            rest_of_file: None,
+            quit: false,
+        }
+    }
+
+    /// Forget the answer for the rest of the previous file.
+    pub fn start_file(&mut self) {
+        self.rest_of_file = None;
+    }
+
+    /// Whether the user quit, so no more files should be processed.
+    pub fn quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Show `candidate` from the file at `path` and ask whether to replace it.
+    pub fn confirm(&mut self, path: &Path, candidate: &Candidate) -> io::Result<bool> {
+        if self.quit {
+            return Ok(false);
+        }
+        if let Some(answer) = self.rest_of_file {
+            return Ok(answer);
+        }
+        let result = self.ask(path, candidate);
+        if result.is_err() {
+            self.quit = true;
+        }
+        result
+    }
+
+    fn ask(&mut self, path: &Path, candidate: &Candidate) -> io::Result<bool> {
+        write_candidate(&mut self.output, path, candidate, self.color)?;
+        loop {
+            write!(self.output, "Replace? [y,n,a,d,q,?] ")?;
+            self.output.flush()?;
+            let mut answer = String::new();
+            if self.input.read_line(&mut answer)? == 0 {
+                // treat EOF like 'q', but end the prompt's line first
+                writeln!(self.output)?;
+                self.quit = true;
+                return Ok(false);
+            }
+            match answer.trim() {
+                "y" => return Ok(true),
+                "n" => return Ok(false),
+                "a" => {
+                    self.rest_of_file = Some(true);
+                    return Ok(true);
+                }
+                "d" => {
+                    self.rest_of_file = Some(false);
+                    return Ok(false);
+                }
+                "q" => {
+                    self.quit = true;
+                    return Ok(false);
+                }
+                _ => self.output.write_all(HELP.as_bytes())?,
+            }
+        }
+    }
+}
+
+/// Print the line containing a candidate replacement before and after it's made, like a diff,
+/// with the match and its replacement highlighted if `color` is set.
+pub fn write_candidate<W: Write>(
+    output: &mut W,
+    path: &Path,
+    candidate: &Candidate,
+    color: bool,
+) -> io::Result<()> {
+    let text = candidate.text;
+    let end = text
+        .iter()
+        .rposition(|&b| !matches!(b, b'\n' | b'\r' | b'\0'))
+        .map_or(0, |i| i + 1)
+        .max(candidate.old.end);
+    let (before, after) = (&text[..candidate.old.start], &text[candidate.old.end..end]);
+
+    writeln!(output, "{}:{}:", path.display(), candidate.number)?;
+    let old = anstyle::AnsiColor::Red.on_default().bold();
+    let new = anstyle::AnsiColor::Green.on_default().bold();
+    for (sign, part, style) in [
+        ('-', &text[candidate.old.clone()], old),
+        ('+', candidate.new, new),
+    ] {
+        let mut buf = vec![];
+        write!(buf, "{sign}")?;
+        escape_nonprintable_into(&mut buf, before);
+        if color {
+            write!(buf, "{}", style.render())?;
+        }
+        escape_nonprintable_into(&mut buf, part);
+        if color {
+            write!(buf, "{}", style.render_reset())?;
+        }
+        escape_nonprintable_into(&mut buf, after);
+        buf.push(b'\n');
+        output.write_all(&buf)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidate(number: usize) -> Candidate<'static> {
+        Candidate {
+            number,
+            text: b"foo bar\n",
+            old: 4..7,
+            new: b"baz",
+        }
+    }
+
+    #[test]
+    fn test_prompt() {
+        let path = Path::new("file.txt");
+        let mut prompter = Prompter::new(&b"x\nn\ny\nd\n"[..], vec![], false);
+        assert!(!prompter.confirm(path, &candidate(1)).unwrap());
+        assert!(prompter.confirm(path, &candidate(2)).unwrap());
+        assert!(!prompter.confirm(path, &candidate(3)).unwrap());
+        // 'd' declines the rest of the file without asking
+        assert!(!prompter.confirm(path, &candidate(4)).unwrap());
+        prompter.start_file();
+        // EOF quits
+        assert!(!prompter.confirm(path, &candidate(1)).unwrap());
+        assert!(prompter.quit());
+
+        let output = String::from_utf8(prompter.output).unwrap();
+        let expected = format!(
+            "file.txt:1:\n-foo bar\n+foo baz\nReplace? [y,n,a,d,q,?] {HELP}\
+             Replace? [y,n,a,d,q,?] "
+        );
+        assert!(output.starts_with(&expected), "{output}");
+        assert_eq!(output.matches("Replace?").count(), 5);
+        assert!(!output.contains("file.txt:4:"));
+
+        let mut prompter = Prompter::new(&b"a\n"[..], vec![], false);
+        assert!(prompter.confirm(path, &candidate(1)).unwrap());
+        assert!(prompter.confirm(path, &candidate(2)).unwrap());
+        assert!(!prompter.quit());
+    }
+}
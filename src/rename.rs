@@ -0,0 +1,223 @@
+//! `rp rename`: rename an identifier in all of its casings at once.
+
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use rp::replace::{MatchKind, MultiPattern, ReplaceOptions, Replacer, UnicodeMode};
+use rp::template::split_words;
+
+use crate::walk::{self, WalkOptions};
+use crate::{diff, EXIT_FAILED, EXIT_NO_MATCH, EXIT_PARTIAL, EXIT_SUCCESS};
+
+/// Rename an identifier wherever it appears as a whole word, keeping the casing of each use.
+///
+/// Renaming foo_bar to baz_qux also renames FooBar to BazQux, fooBar to bazQux, FOO_BAR to
+/// BAZ_QUX, and foo-bar to baz-qux. OLD itself, as written, is always renamed to NEW as written.
+/// Words are split at '_', '-', spaces, and changes of case, like the ${snake(1)} replacement
+/// functions.
+///
+/// Each file in FILES is edited in-place, and directories are searched recursively like -r. With
+/// no FILES, stdin is renamed to stdout.
+#[derive(Debug, clap::Args)]
+pub struct RenameArgs {
+    /// The identifier to rename.
+    old: String,
+
+    /// Its new name.
+    new: String,
+
+    /// Files or directories to rename in.
+    files: Vec<PathBuf>,
+
+    /// Don't change anything, just list the lines where OLD appears, and exit with status 1 if
+    /// there are any.
+    #[arg(long, conflicts_with = "diff")]
+    check: bool,
+
+    /// Don't change anything, show what would change as a unified diff.
+    #[arg(long)]
+    diff: bool,
+}
+
+/// One way of writing an identifier, given its words.
+#[derive(Debug, Clone, Copy)]
+enum Style {
+    /// `foo_bar`
+    Snake,
+    /// `FOO_BAR`
+    ScreamingSnake,
+    /// `fooBar`
+    Camel,
+    /// `FooBar`
+    Pascal,
+    /// `foo-bar`
+    Kebab,
+}
+
+impl Style {
+    const ALL: [Style; 5] = [
+        Style::Snake,
+        Style::ScreamingSnake,
+        Style::Camel,
+        Style::Pascal,
+        Style::Kebab,
+    ];
+
+    fn join(self, words: &[&str]) -> String {
+        let mut out = String::new();
+        for (i, word) in words.iter().enumerate() {
+            match self {
+                Style::Snake | Style::ScreamingSnake if i > 0 => out.push('_'),
+                Style::Kebab if i > 0 => out.push('-'),
+                _ => (),
+            }
+            match (self, i) {
+                (Style::ScreamingSnake, _) => out.push_str(&word.to_uppercase()),
+                (Style::Pascal, _) | (Style::Camel, 1..) => {
+                    let mut chars = word.chars();
+                    if let Some(first) = chars.next() {
+                        out.extend(first.to_uppercase());
+                        out.push_str(&chars.as_str().to_lowercase());
+                    }
+                }
+                _ => out.push_str(&word.to_lowercase()),
+            }
+        }
+        out
+    }
+}
+
+/// List each casing of `old` and what it's renamed to, with `old` itself first. When two styles
+/// write `old` the same way, the first one wins.
+fn renames(old: &str, new: &str) -> anyhow::Result<Vec<(String, String)>> {
+    let old_words = split_words(old);
+    let new_words = split_words(new);
+    if old_words.is_empty() || new_words.is_empty() {
+        anyhow::bail!("OLD and NEW must contain at least one letter or digit");
+    }
+
+    let mut renames = vec![(old.to_owned(), new.to_owned())];
+    for style in Style::ALL {
+        let from = style.join(&old_words);
+        if !renames.iter().any(|(seen, _)| *seen == from) {
+            renames.push((from, style.join(&new_words)));
+        }
+    }
+    Ok(renames)
+}
+
+/// Build a replacer which renames every casing of `old` as a whole word.
+fn build_replacer(old: &str, new: &str) -> anyhow::Result<Replacer<MultiPattern>> {
+    let rules: Vec<(String, String)> = renames(old, new)?
+        .into_iter()
+        .map(|(from, to)| (format!(r"(?-u:\b){}(?-u:\b)", regex::escape(&from)), to))
+        .collect();
+    let opts = ReplaceOptions {
+        replace_all: true,
+        literal_replacement: true,
+        ..Default::default()
+    };
+    Ok(opts.build_multi(&rules, MatchKind::LeftmostFirst, false, UnicodeMode::Auto)?)
+}
+
+/// Run the rename, returning the exit status.
+pub fn run(args: RenameArgs) -> anyhow::Result<i32> {
+    let replacer = build_replacer(&args.old, &args.new)?;
+    let mut stdout = io::stdout().lock();
+    if args.files.is_empty() {
+        let count = replacer
+            .replace_stream(&mut io::stdin().lock(), &mut stdout)?
+            .replacements;
+        return Ok(if count > 0 {
+            EXIT_SUCCESS
+        } else {
+            EXIT_NO_MATCH
+        });
+    }
+
+    let files = walk::expand(&args.files, &WalkOptions::default(), &mut vec![]);
+    let mut total = 0;
+    let mut failed = 0;
+    for path in &files {
+        let count = if args.check || args.diff {
+            File::open(path)
+                .map_err(anyhow::Error::from)
+                .and_then(|file| {
+                    let mut input = BufReader::new(file);
+                    if args.diff {
+                        Ok(diff::write_diff(&replacer, &mut input, &mut stdout, path)?)
+                    } else {
+                        let stats = replacer.for_each_line(&mut input, |line| {
+                            if line.count != 0 {
+                                write!(stdout, "{}:{}:", path.display(), line.number)?;
+                                stdout.write_all(line.old)?;
+                            }
+                            Ok(())
+                        })?;
+                        Ok(stats.replacements)
+                    }
+                })
+        } else {
+            crate::replace_one_inplace(&replacer, path, &Default::default())
+        };
+        match count.with_context(|| path.display().to_string()) {
+            Ok(count) => total += count,
+            Err(err) => {
+                eprintln!("Error: {err:#}");
+                failed += 1;
+            }
+        }
+    }
+
+    Ok(match (failed, total) {
+        (0, 0) if args.check => EXIT_SUCCESS,
+        (0, _) if args.check => 1,
+        (0, 0) => EXIT_NO_MATCH,
+        (0, _) => EXIT_SUCCESS,
+        (failed, _) if failed < files.len() => EXIT_PARTIAL,
+        _ => EXIT_FAILED,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_renames() {
+        let list = renames("fooBar", "bazQux").unwrap();
+        let list: Vec<(&str, &str)> = list.iter().map(|(a, b)| (a.as_str(), b.as_str())).collect();
+        assert_eq!(
+            list,
+            [
+                ("fooBar", "bazQux"),
+                ("foo_bar", "baz_qux"),
+                ("FOO_BAR", "BAZ_QUX"),
+                ("FooBar", "BazQux"),
+                ("foo-bar", "baz-qux"),
+            ]
+        );
+
+        // a single word only has three casings
+        assert_eq!(renames("count", "total").unwrap().len(), 3);
+        assert!(renames("foo", "--").is_err());
+    }
+
+    #[test]
+    fn test_rename() {
+        let replacer = build_replacer("old_name", "newName").unwrap();
+        let mut out = vec![];
+        replacer
+            .replace_stream(
+                &mut &b"old_name OldName OLD_NAME oldName old-name\nmy_old_name OldNames\n"[..],
+                &mut out,
+            )
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "newName NewName NEW_NAME newName new-name\nmy_old_name OldNames\n"
+        );
+    }
+}
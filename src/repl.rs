@@ -0,0 +1,228 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+
+use rp::replace::{Pattern, Replacer, ReplacerBuilder};
+
+/// Interactively edit a pattern and replacement while previewing the results on sample lines.
+///
+/// Commands are read from stdin and the preview is printed to stderr. When finished, the
+/// equivalent rp command line is printed to stdout.
+#[derive(Debug, clap::Args)]
+pub struct ReplArgs {
+    /// Load sample lines from FILE. If omitted, add samples with the 'add' command.
+    file: Option<PathBuf>,
+
+    /// Maximum number of sample lines to load from FILE.
+    #[arg(short = 'n', long, value_name = "N", default_value_t = 20)]
+    lines: usize,
+}
+
+const HELP: &str = "\
+Commands:
+  p PATTERN      set the pattern (everything after the first space)
+  r REPLACEMENT  set the replacement (everything after the first space)
+  F, I, e, g     toggle the -F, -I, -e, or -g option
+  add TEXT       add a sample line
+  show           show the preview again
+  help           show this help
+  q              quit and print the final rp command line";
+
+#[derive(Debug, Default)]
+struct State {
+    samples: Vec<Vec<u8>>,
+    pattern: String,
+    replacement: String,
+    fixed_strings: bool,
+    ignore_case: bool,
+    escape: bool,
+    replace_all: bool,
+}
+
+impl State {
+    /// Get the rp command line for the current settings, quoted for a POSIX shell.
+    fn command_line(&self) -> String {
+        let mut cmd = String::from("rp");
+        for (enabled, flag) in [
+            (self.fixed_strings, " -F"),
+            (self.ignore_case, " -I"),
+            (self.escape, " -e"),
+            (self.replace_all, " -g"),
+        ] {
+            if enabled {
+                cmd.push_str(flag);
+            }
+        }
+        if self.pattern.starts_with('-') || self.replacement.starts_with('-') {
+            cmd.push_str(" --");
+        }
+        cmd.push(' ');
+        cmd.push_str(&shell_quote(&self.pattern));
+        cmd.push(' ');
+        cmd.push_str(&shell_quote(&self.replacement));
+        cmd
+    }
+
+    /// Apply the current settings to every sample line and print the results.
+    fn preview(&self, out: &mut impl Write) -> io::Result<()> {
+        writeln!(out, "{}", self.command_line())?;
+
+        let result = (|| -> anyhow::Result<Vec<u8>> {
+            let builder = ReplacerBuilder {
+                fixed_strings: self.fixed_strings,
+                ignore_case: self.ignore_case,
+                escape: self.escape,
+                replace_all: self.replace_all,
+                ..Default::default()
+            };
+            let replacer = builder.build(&self.pattern, &self.replacement)?;
+            preview_lines(&replacer, &self.samples)
+        })();
+
+        match result {
+            Ok(preview) => out.write_all(&preview),
+            Err(err) => writeln!(out, "error: {err:#}"),
+        }
+    }
+}
+
+/// Run a replacer on each sample line, showing the before and after of the changed ones.
+fn preview_lines<P: Pattern>(
+    replacer: &Replacer<P>,
+    samples: &[Vec<u8>],
+) -> anyhow::Result<Vec<u8>> {
+    let mut out = vec![];
+    let mut buf = vec![];
+    let mut changed = 0;
+    for line in samples {
+        buf.clear();
+        if replacer.replace_into(&mut buf, line)? == 0 {
+            out.extend_from_slice(b"  ");
+            out.extend_from_slice(line);
+            out.push(b'\n');
+        } else {
+            changed += 1;
+            for (prefix, text) in [(b"- ", line), (b"+ ", &buf)] {
+                out.extend_from_slice(prefix);
+                out.extend_from_slice(text);
+                out.push(b'\n');
+            }
+        }
+    }
+    writeln!(out, "({changed} of {} lines changed)", samples.len())?;
+    Ok(out)
+}
+
+/// Quote a string for a POSIX shell, if needed.
+fn shell_quote(s: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "%+,-./:=@^_".contains(c);
+    if !s.is_empty() && s.chars().all(is_safe) {
+        s.to_owned()
+    } else {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    }
+}
+
+pub fn run(args: ReplArgs) -> anyhow::Result<()> {
+    let mut state = State::default();
+    if let Some(path) = &args.file {
+        let file =
+            File::open(path).with_context(|| format!("unable to open '{}'", path.display()))?;
+        for line in BufReader::new(file).split(b'\n').take(args.lines) {
+            let mut line = line.with_context(|| format!("failed to read '{}'", path.display()))?;
+            if line.last() == Some(&b'\r') {
+                line.pop();
+            }
+            state.samples.push(line);
+        }
+    }
+
+    let mut stderr = io::stderr().lock();
+    writeln!(stderr, "{HELP}")?;
+
+    let mut input = String::new();
+    loop {
+        write!(stderr, "rp> ")?;
+        stderr.flush()?;
+        input.clear();
+        if io::stdin().lock().read_line(&mut input)? == 0 {
+            writeln!(stderr)?;
+            break;
+        }
+
+        let line = input.trim_end_matches(['\n', '\r']);
+        let (cmd, arg) = line.split_once(' ').unwrap_or((line, ""));
+        match cmd {
+            "p" | "pattern" => state.pattern = arg.to_owned(),
+            "r" | "replacement" => state.replacement = arg.to_owned(),
+            "F" => state.fixed_strings = !state.fixed_strings,
+            "I" => state.ignore_case = !state.ignore_case,
+            "e" => state.escape = !state.escape,
+            "g" => state.replace_all = !state.replace_all,
+            "add" => state.samples.push(arg.as_bytes().to_vec()),
+            "show" | "" => (),
+            "help" | "?" => {
+                writeln!(stderr, "{HELP}")?;
+                continue;
+            }
+            "q" | "quit" => break,
+            _ => {
+                writeln!(stderr, "unknown command '{cmd}', try 'help'")?;
+                continue;
+            }
+        }
+        state.preview(&mut stderr)?;
+    }
+
+    println!("{}", state.command_line());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_shell_quote() {
+        assert_eq!(shell_quote("foo"), "foo");
+        assert_eq!(shell_quote("a-b_c.d/e"), "a-b_c.d/e");
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("foo bar"), "'foo bar'");
+        assert_eq!(shell_quote(r"(\w+)$"), r"'(\w+)$'");
+        assert_eq!(shell_quote("it's"), r"'it'\''s'");
+    }
+
+    #[test]
+    fn test_command_line() {
+        let mut state = State {
+            pattern: r"(\w+), (\w+)".to_owned(),
+            replacement: "$2 $1".to_owned(),
+            ..Default::default()
+        };
+        assert_eq!(state.command_line(), r"rp '(\w+), (\w+)' '$2 $1'");
+
+        state.replace_all = true;
+        state.ignore_case = true;
+        state.pattern = "-x".to_owned();
+        state.replacement = "y".to_owned();
+        assert_eq!(state.command_line(), "rp -I -g -- -x y");
+    }
+
+    #[test]
+    fn test_preview() {
+        let state = State {
+            samples: vec![b"foo bar".to_vec(), b"baz".to_vec()],
+            pattern: "ba(.)".to_owned(),
+            replacement: "<$1>".to_owned(),
+            ..Default::default()
+        };
+        let mut out = vec![];
+        state.preview(&mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "rp 'ba(.)' '<$1>'\n- foo bar\n+ foo <r>\n- baz\n+ <z>\n(2 of 2 lines changed)\n"
+        );
+    }
+}
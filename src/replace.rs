@@ -1,224 +1,1844 @@
+use std::cell::RefCell;
 use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::sync::Arc;
+use std::thread::LocalKey;
 
-use regex::bytes::{Regex, RegexBuilder};
+use aho_corasick::AhoCorasick;
+use regex::bytes::{CaptureLocations, Regex, RegexBuilder, RegexSet, RegexSetBuilder};
 
+use crate::grapheme;
+use crate::pool::{self, Pooled, Reuse};
+use crate::template::{self, GroupRef, Template, TemplateSyntax};
+use crate::unescape::{self, unescape_bytes};
+
+/// Byte ranges of a match and its capture groups within the searched text.
+///
+/// Group 0 is always the whole match. Groups which didn't participate in the match are `None`.
+#[derive(Debug, Clone, Default)]
+pub struct Captures {
+    spans: Vec<Option<Range<usize>>>,
+    /// The regex crate's buffer for the last regex search, kept to be reused by the next one
+    locations: Option<CaptureLocations>,
+}
+
+impl Captures {
+    /// Get the span of the given capture group, if it matched.
+    pub fn get(&self, index: usize) -> Option<Range<usize>> {
+        self.spans.get(index).cloned().flatten()
+    }
+
+    /// Replace the saved capture groups.
+    pub fn set(&mut self, spans: impl IntoIterator<Item = Option<Range<usize>>>) {
+        self.spans.clear();
+        self.spans.extend(spans);
+    }
+
+    /// Take the buffer for searching with `re`, making a new one only if the last one doesn't
+    /// fit. It just holds a span for each group, so one from any regex with as many groups will
+    /// do. Put it back in `locations` afterwards.
+    fn take_locations(&mut self, re: &Regex) -> CaptureLocations {
+        match self.locations.take() {
+            Some(locs) if locs.len() == re.captures_len() => locs,
+            _ => re.capture_locations(),
+        }
+    }
+}
+
+impl Reuse for Captures {
+    fn pool() -> &'static LocalKey<RefCell<Vec<Self>>> {
+        thread_local!(static POOL: RefCell<Vec<Captures>> = const { RefCell::new(Vec::new()) });
+        &POOL
+    }
+
+    fn reset(&mut self) -> bool {
+        self.spans.clear();
+        self.spans.capacity() * std::mem::size_of::<Option<Range<usize>>>() <= pool::MAX_KEPT_BYTES
+    }
+}
+
+/// Something which can be searched for in text, like a regex or a literal string.
+///
+/// Only `find_at` is required. Patterns with capture groups should also implement `captures_at`,
+/// `group_index`, and `captures_len`, so that replacements can refer to their groups.
 pub trait Pattern {
-    /// Make replacements in the given input text and write the result to the provided buffer.
+    /// Find the leftmost match in `text` which starts at or after `start`, returning its range.
     ///
-    /// Returns the total number of replacements that were made.
+    /// `start` is an offset rather than a subslice so that anchors and word boundaries can look
+    /// at the text before it.
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>>;
+
+    /// Like `find_at`, but also save the spans of the capture groups into `caps`.
     ///
-    /// Args:
-    ///   * `buf`: the string with replacements applied will be saved into this buffer. This method
-    ///     will only append to `buf` and will not clear it.
-    ///   * `text`: the input text (byte string)
-    ///   * `rep`: the replacement to make
-    ///   * `all`: if false, replace only the first occurrence
-    fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8], rep: &[u8], all: bool) -> usize;
+    /// The default implementation is suitable for patterns which have no capture groups.
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        let m = self.find_at(text, start)?;
+        caps.set([Some(m.clone())]);
+        Some(m)
+    }
+
+    /// Look up the index of a named capture group.
+    fn group_index(&self, _name: &str) -> Option<usize> {
+        None
+    }
+
+    /// The number of capture groups, including group 0 for the whole match.
+    fn captures_len(&self) -> usize {
+        1
+    }
+
+    /// If a search failed, describe why. Searches which fail find no match, so this must be
+    /// checked for patterns which can fail, like plugins.
+    fn error(&self) -> Option<String> {
+        None
+    }
 }
 
 impl Pattern for Regex {
-    fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8], mut rep: &[u8], all: bool) -> usize {
-        // use the regex Replacer trait locally so it doesn't conflict with our own Replacer
-        // struct. Also the rep argument must be mut to work with Replacer, but it can still be
-        // a shared slice.
-        // This implementation is derived from Regex::bytes::Regex::replacen()
-        use regex::bytes::Replacer;
-
-        if let Some(rep) = rep.no_expansion() {
-            let mut it = self.find_iter(text).peekable();
-            if it.peek().is_none() {
-                buf.extend_from_slice(text);
-                return 0;
-            }
-            let mut last = 0;
-            let mut count = 0;
-            for m in it {
-                count += 1;
-                buf.extend_from_slice(&text[last..m.start()]);
-                buf.extend_from_slice(&rep);
-                last = m.end();
-                if !all {
-                    break;
-                }
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        Regex::find_at(self, text, start).map(|m| m.range())
+    }
+
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        let mut locs = caps.take_locations(self);
+        let m = self
+            .captures_read_at(&mut locs, text, start)
+            .map(|m| m.range());
+        if m.is_some() {
+            caps.set((0..locs.len()).map(|i| locs.get(i).map(|(s, e)| s..e)));
+        }
+        caps.locations = Some(locs);
+        m
+    }
+
+    fn group_index(&self, name: &str) -> Option<usize> {
+        self.capture_names().position(|n| n == Some(name))
+    }
+
+    fn captures_len(&self) -> usize {
+        Regex::captures_len(self)
+    }
+}
+
+/// A regex where only one capture group is replaced rather than the whole match, like Vim's `\zs`
+/// and `\ze`. The text around that group must still match but is left alone.
+///
+/// The group takes the place of group 0, and the other groups are numbered as if it didn't exist.
+#[derive(Debug, Clone)]
+pub struct SubmatchRegex {
+    regex: Regex,
+    group: usize,
+}
+
+impl SubmatchRegex {
+    /// Returns None if `regex` has no group named `group_name`. The group should always
+    /// participate in a match, otherwise matches without it are skipped.
+    pub fn new(regex: Regex, group_name: &str) -> Option<SubmatchRegex> {
+        let group = regex.group_index(group_name)?;
+        Some(SubmatchRegex { regex, group })
+    }
+}
+
+impl Pattern for SubmatchRegex {
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        self.captures_at(text, start, &mut Pooled::take())
+    }
+
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        let mut locs = caps.take_locations(&self.regex);
+        let mut start = start;
+        let group = loop {
+            let Some(m) = self.regex.captures_read_at(&mut locs, text, start) else {
+                break None;
+            };
+            if let Some((s, e)) = locs.get(self.group) {
+                break Some(s..e);
             }
-            buf.extend_from_slice(&text[last..]);
-            return count;
+            // try again after this match, making sure to move forward if it was empty
+            start = if m.is_empty() { m.end() + 1 } else { m.end() };
+            if start > text.len() {
+                break None;
+            }
+        };
+        if let Some(group) = &group {
+            let others = (1..locs.len())
+                .filter(|i| *i != self.group)
+                .map(|i| locs.get(i).map(|(s, e)| s..e));
+            caps.set(std::iter::once(Some(group.clone())).chain(others));
         }
+        caps.locations = Some(locs);
+        group
+    }
 
-        // The slower path, which we use if the replacement needs access to capture groups.
-        let mut it = self.captures_iter(text).peekable();
-        if it.peek().is_none() {
-            buf.extend_from_slice(text);
-            return 0;
+    fn group_index(&self, name: &str) -> Option<usize> {
+        match self.regex.group_index(name)? {
+            i if i < self.group => Some(i),
+            i if i > self.group => Some(i - 1),
+            _ => None,
         }
-        let mut last_match = 0;
-        let mut count = 0;
-        for cap in it {
-            count += 1;
-            // unwrap on 0 is OK because captures only reports matches
-            let m = cap.get(0).unwrap();
-            buf.extend_from_slice(&text[last_match..m.start()]);
-            rep.replace_append(&cap, buf);
-            last_match = m.end();
-            if !all {
-                break;
+    }
+
+    fn captures_len(&self) -> usize {
+        self.regex.captures_len() - 1
+    }
+}
+
+/// How input is split into the records which are searched one at a time.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Records {
+    /// Each line.
+    #[default]
+    Lines,
+    /// Each run of consecutive non-blank lines, with blank lines still handled one at a time.
+    Paragraphs,
+    /// The whole input at once.
+    Whole,
+}
+
+/// What to do when the pattern matches the empty string, like `a*` does before each character
+/// which isn't an `a`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum EmptyMatches {
+    /// Replace them like the regex crate's replace_all, except right after another match
+    #[default]
+    Advance,
+    /// Leave them alone and keep searching from the next byte
+    Skip,
+    /// Fail with an error
+    Error,
+}
+
+/// Where in the line a match must be for it to be replaced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum Anchor {
+    /// The match starts at the start of the line
+    Start,
+    /// The match ends at the end of the line, before its terminator
+    End,
+    /// The match is the whole line, not counting its terminator
+    Only,
+}
+
+impl Anchor {
+    /// Check whether the match `m` in `text` is in the right place, where `terminator` ends each
+    /// line. This agrees with where `^` and `$` match in our regexes.
+    fn allows(self, text: &[u8], m: &Range<usize>, terminator: u8) -> bool {
+        let at_start = m.start == 0 || text[m.start - 1] == terminator;
+        let at_end = m.end == text.len() || text[m.end] == terminator;
+        match self {
+            Anchor::Start => at_start,
+            Anchor::End => at_end,
+            Anchor::Only => at_start && at_end,
+        }
+    }
+
+    /// Add the equivalent anchors to a regex pattern.
+    fn wrap(self, pattern: &str) -> String {
+        match self {
+            Anchor::Start => format!("^(?:{pattern})"),
+            Anchor::End => format!("(?:{pattern})$"),
+            Anchor::Only => format!("^(?:{pattern})$"),
+        }
+    }
+}
+
+/// A range of line numbers, starting at 1, for `Address`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LineRange {
+    pub start: usize,
+    /// The last line in the range, or None to continue to the end of the input.
+    pub end: Option<usize>,
+}
+
+impl LineRange {
+    pub fn contains(&self, number: usize) -> bool {
+        number >= self.start && self.end.is_none_or(|end| number <= end)
+    }
+}
+
+/// Which lines to make replacements in, like a sed address. Other lines are passed through
+/// unchanged. The default selects every line.
+///
+/// With `from` or `to`, a range starts at each line matching `from`, or at the first line if
+/// there's no `from`, and continues through the next line matching `to`, or to the end of the
+/// input if there's no `to`. Lines must also be in `lines`, if it's set.
+#[derive(Debug, Clone, Default)]
+pub struct Address {
+    pub lines: Option<LineRange>,
+    pub from: Option<Regex>,
+    pub to: Option<Regex>,
+}
+
+impl Address {
+    /// Build an address, compiling `from` and `to` like other regexes where `^` and `$` match
+    /// at line breaks.
+    pub fn new(
+        lines: Option<LineRange>,
+        from: Option<&str>,
+        to: Option<&str>,
+    ) -> Result<Address, BuildError> {
+        let compile = |pattern| compile_regex(pattern, false, UnicodeMode::Auto, b'\n');
+        Ok(Address {
+            lines,
+            from: from.map(compile).transpose()?,
+            to: to.map(compile).transpose()?,
+        })
+    }
+
+    /// Check whether line `number` with contents `text` is selected. Lines must be checked in
+    /// order, and `in_range` tracks whether a `from`/`to` range is active, which starts out as
+    /// whether `from` is None.
+    fn selects(&self, in_range: &mut bool, number: usize, text: &[u8]) -> bool {
+        let by_regex = if self.from.is_none() && self.to.is_none() {
+            true
+        } else if *in_range {
+            if self.to.as_ref().is_some_and(|to| to.is_match(text)) {
+                *in_range = false;
+            }
+            true
+        } else if self.from.as_ref().is_some_and(|from| from.is_match(text)) {
+            *in_range = true;
+            true
+        } else {
+            false
+        };
+        by_regex && self.lines.is_none_or(|lines| lines.contains(number))
+    }
+}
+
+/// How `MultiPattern` chooses between patterns which match at the same position.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MatchKind {
+    /// Prefer the longest match, then the pattern which comes first.
+    #[default]
+    LeftmostLongest,
+    /// Prefer the pattern which comes first.
+    LeftmostFirst,
+}
+
+/// A set of regexes searched for together, where each match comes from whichever regex matches
+/// earliest in the text. Ties are broken according to the `MatchKind`.
+///
+/// The capture groups of every regex are numbered as one list: group 0 is the whole match, then
+/// each regex's groups follow in order, starting with its own group 0. Use
+/// `ReplaceOptions::build_multi` to pair each regex with its own replacement.
+#[derive(Debug, Clone)]
+pub struct MultiPattern {
+    set: RegexSet,
+    regexes: Vec<Regex>,
+    /// The index of each regex's group 0 among all the groups
+    offsets: Vec<usize>,
+    captures_len: usize,
+    kind: MatchKind,
+}
+
+impl MultiPattern {
+    pub fn new<S: AsRef<str>>(
+        patterns: &[S],
+        kind: MatchKind,
+        ignore_case: bool,
+        unicode: UnicodeMode,
+        terminator: u8,
+    ) -> Result<MultiPattern, BuildError> {
+        // every regex needs the same Unicode mode as the set so that they agree on what matches
+        let unicode = if patterns
+            .iter()
+            .any(|p| use_unicode(p.as_ref(), ignore_case, unicode))
+        {
+            UnicodeMode::Always
+        } else {
+            UnicodeMode::Never
+        };
+        let regexes = patterns
+            .iter()
+            .map(|p| compile_regex(p.as_ref(), ignore_case, unicode, terminator))
+            .collect::<Result<Vec<_>, _>>()?;
+        let set = RegexSetBuilder::new(patterns)
+            .multi_line(true)
+            .line_terminator(terminator)
+            .case_insensitive(ignore_case)
+            .unicode(unicode == UnicodeMode::Always)
+            .build()?;
+
+        let mut offsets = Vec::with_capacity(regexes.len());
+        let mut captures_len = 1;
+        for re in &regexes {
+            offsets.push(captures_len);
+            captures_len += re.captures_len();
+        }
+        Ok(MultiPattern {
+            set,
+            regexes,
+            offsets,
+            captures_len,
+            kind,
+        })
+    }
+
+    /// Check whether match `m` should be used rather than the best one found so far.
+    fn is_better(&self, m: &Range<usize>, best: Option<&Range<usize>>) -> bool {
+        match best {
+            None => true,
+            Some(b) => {
+                m.start < b.start
+                    || (m.start == b.start
+                        && self.kind == MatchKind::LeftmostLongest
+                        && m.end > b.end)
             }
         }
-        buf.extend_from_slice(&text[last_match..]);
-        count
     }
 }
 
-impl Pattern for &[u8] {
-    fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8], rep: &[u8], all: bool) -> usize {
-        let mut last = 0;
-        let mut count = 0;
-        for start in memchr::memmem::find_iter(text, &self) {
-            count += 1;
-            buf.extend_from_slice(&text[last..start]);
-            buf.extend_from_slice(rep);
-            last = start + self.len();
-            if !all {
-                break;
+impl Pattern for MultiPattern {
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        let mut best = None;
+        for index in self.set.matches_at(text, start).iter() {
+            let Some(m) = Pattern::find_at(&self.regexes[index], text, start) else {
+                continue;
+            };
+            if self.is_better(&m, best.as_ref()) {
+                best = Some(m);
             }
         }
-        buf.extend_from_slice(&text[last..]);
-        count
+        best
+    }
+
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        // search each regex which might match only once, keeping the groups of the best match
+        let mut best: Option<(usize, Range<usize>)> = None;
+        let mut regex_caps = Pooled::<Captures>::take();
+        let mut scratch = Pooled::<Captures>::take();
+        for index in self.set.matches_at(text, start).iter() {
+            let Some(m) = Pattern::captures_at(&self.regexes[index], text, start, &mut scratch)
+            else {
+                continue;
+            };
+            if self.is_better(&m, best.as_ref().map(|(_, b)| b)) {
+                std::mem::swap(&mut *regex_caps, &mut *scratch);
+                best = Some((index, m));
+            }
+        }
+        let (index, m) = best?;
+        let offset = self.offsets[index];
+        caps.set((0..self.captures_len).map(|i| match i {
+            0 => Some(m.clone()),
+            i if i >= offset => regex_caps.get(i - offset),
+            _ => None,
+        }));
+        Some(m)
+    }
+
+    fn captures_len(&self) -> usize {
+        self.captures_len
+    }
+}
+
+impl Pattern for &[u8] {
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        let pos = start + memchr::memmem::find(&text[start..], self)?;
+        Some(pos..(pos + self.len()))
     }
 }
 
 // can't be generic over AsRef<[u8]> so hard-code an impl for Vec
 impl Pattern for Vec<u8> {
     #[inline]
-    fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8], rep: &[u8], all: bool) -> usize {
-        self.as_slice().replace_into(buf, text, rep, all)
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        self.as_slice().find_at(text, start)
     }
 }
 
 // same for String
 impl Pattern for String {
     #[inline]
-    fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8], rep: &[u8], all: bool) -> usize {
-        self.as_bytes().replace_into(buf, text, rep, all)
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        self.as_bytes().find_at(text, start)
+    }
+}
+
+/// Which matching engine to search for the pattern with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum Engine {
+    /// Regex, or literal if the pattern has no regex syntax besides escapes
+    #[default]
+    Auto,
+    /// The regex crate
+    Regex,
+    /// Plain substring search, the pattern isn't parsed as a regex
+    Literal,
+    /// Substring search which can also ignore ASCII case, the pattern isn't parsed as a regex
+    AhoCorasick,
+}
+
+/// A pattern compiled for one of the available engines.
+///
+/// Cloning one shares the compiled regex or searcher instead of building it again.
+#[derive(Debug, Clone)]
+pub enum EnginePattern {
+    /// A regex, for `Engine::Regex`
+    Regex(Regex),
+    /// The exact bytes to search for, for `Engine::Literal`
+    Literal(Vec<u8>),
+    /// A single string searcher, for `Engine::AhoCorasick`
+    AhoCorasick(AhoCorasick),
+    /// A literal pattern which only matches whole words, for `word_regexp`
+    Word(Box<EnginePattern>),
+}
+
+impl EnginePattern {
+    /// Which engine this pattern uses. This is never `Engine::Auto`.
+    pub fn engine(&self) -> Engine {
+        match self {
+            EnginePattern::Regex(_) => Engine::Regex,
+            EnginePattern::Literal(_) => Engine::Literal,
+            EnginePattern::AhoCorasick(_) => Engine::AhoCorasick,
+            EnginePattern::Word(inner) => inner.engine(),
+        }
     }
 }
 
+impl Pattern for EnginePattern {
+    fn find_at(&self, text: &[u8], start: usize) -> Option<Range<usize>> {
+        match self {
+            EnginePattern::Regex(re) => Pattern::find_at(re, text, start),
+            EnginePattern::Literal(lit) => lit.find_at(text, start),
+            EnginePattern::AhoCorasick(ac) => {
+                let input = aho_corasick::Input::new(text).range(start..);
+                ac.find(input).map(|m| m.range())
+            }
+            EnginePattern::Word(inner) => {
+                let mut start = start;
+                loop {
+                    let m = inner.find_at(text, start)?;
+                    if is_word_boundary(text, m.start) && is_word_boundary(text, m.end) {
+                        return Some(m);
+                    }
+                    start = m.start + 1;
+                    // an empty match at the end of the text has nowhere left to go
+                    if start > text.len() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+
+    fn captures_at(&self, text: &[u8], start: usize, caps: &mut Captures) -> Option<Range<usize>> {
+        match self {
+            EnginePattern::Regex(re) => Pattern::captures_at(re, text, start, caps),
+            _ => {
+                let m = self.find_at(text, start)?;
+                caps.set([Some(m.clone())]);
+                Some(m)
+            }
+        }
+    }
+
+    fn group_index(&self, name: &str) -> Option<usize> {
+        match self {
+            EnginePattern::Regex(re) => Pattern::group_index(re, name),
+            _ => None,
+        }
+    }
+
+    fn captures_len(&self) -> usize {
+        match self {
+            EnginePattern::Regex(re) => Pattern::captures_len(re),
+            _ => 1,
+        }
+    }
+}
+
+/// Check whether `pos` is between a word character and something else, like `\b` in a regex.
+/// Word characters are Unicode ones if the tables are available, otherwise only ASCII ones, and
+/// bytes which aren't valid UTF-8 aren't word characters.
+fn is_word_boundary(text: &[u8], pos: usize) -> bool {
+    let is_word = |c: Option<char>| {
+        c.is_some_and(|c| {
+            regex_syntax::try_is_word_character(c).unwrap_or(c.is_ascii_alphanumeric() || c == '_')
+        })
+    };
+    let before = (1..=pos.min(4)).find_map(|len| single_char(&text[pos - len..pos]));
+    let after = (1..=(text.len() - pos).min(4)).find_map(|len| single_char(&text[pos..pos + len]));
+    is_word(before) != is_word(after)
+}
+
+fn single_char(bytes: &[u8]) -> Option<char> {
+    let mut chars = std::str::from_utf8(bytes).ok()?.chars();
+    chars.next().filter(|_| chars.as_str().is_empty())
+}
+
+/// How Unicode support should be configured when compiling a regex.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum UnicodeMode {
+    /// Disable Unicode mode if doing so doesn't change what the pattern can match.
+    #[default]
+    Auto,
+    /// Always enable Unicode mode.
+    Always,
+    /// Never enable Unicode mode, classes like \w and \d match only ASCII bytes.
+    Never,
+}
+
+/// Check whether a regex pattern would match exactly the same text with or without Unicode mode.
+///
+/// This is true when every literal, class, and word boundary in the pattern is ASCII-only, e.g.
+/// `foo[a-z]+` is ASCII-only but `\w+`, `.`, and `[^a]` are not. Case-insensitive matching is
+/// taken into account, since `(?i)k` also matches the Kelvin sign, unless the Unicode tables
+/// aren't available. Patterns which fail to parse are reported as not ASCII, so that the real
+/// regex compiler can report the error.
+fn is_ascii_pattern(pattern: &str, ignore_case: bool) -> bool {
+    use regex_syntax::hir::{Class, Hir, HirKind, Look};
+
+    fn walk(hir: &Hir) -> bool {
+        match hir.kind() {
+            HirKind::Empty => true,
+            HirKind::Literal(lit) => lit.0.is_ascii(),
+            HirKind::Class(Class::Unicode(cls)) => cls.is_ascii(),
+            HirKind::Class(Class::Bytes(cls)) => cls.is_ascii(),
+            HirKind::Look(look) => !matches!(look, Look::WordUnicode | Look::WordUnicodeNegate),
+            HirKind::Repetition(rep) => walk(&rep.sub),
+            HirKind::Capture(cap) => walk(&cap.sub),
+            HirKind::Concat(subs) | HirKind::Alternation(subs) => subs.iter().all(walk),
+        }
+    }
+
+    let parser = |unicode| {
+        regex_syntax::ParserBuilder::new()
+            .utf8(false)
+            .multi_line(true)
+            .case_insensitive(ignore_case)
+            .unicode(unicode)
+            .build()
+    };
+    match parser(true).parse(pattern) {
+        Ok(hir) => walk(&hir),
+        // Without the Unicode tables, case-insensitive patterns only parse with Unicode mode
+        // disabled, which is fine as long as they're ASCII-only.
+        Err(regex_syntax::Error::Translate(err))
+            if *err.kind() == regex_syntax::hir::ErrorKind::UnicodeCaseUnavailable =>
+        {
+            parser(false).parse(pattern).is_ok_and(|hir| walk(&hir))
+        }
+        Err(_) => false,
+    }
+}
+
+/// Settings for building a `Replacer`, which correspond to some of rp's command line flags.
+///
+/// Start from `ReplaceOptions::default()`, which replaces the first match on each line like rp
+/// does without any flags.
 #[derive(Debug, Clone, Default)]
 pub struct ReplaceOptions {
+    /// Replace every match on each line rather than just the first.
     pub replace_all: bool,
+    /// Only output lines where a replacement was made.
     pub only_matches: bool,
+    /// Stop replacing after the first match in each input.
+    pub first_match_only: bool,
+    /// Replace at most this many matches on each line, which implies `replace_all`.
+    pub max_per_line: Option<usize>,
+    /// Stop replacing after this many matches in each input.
+    pub max_per_file: Option<usize>,
+    /// Only replace in these lines.
+    pub address: Address,
+    /// How to split input into records to search.
+    pub records: Records,
+    /// Lines end with NUL bytes rather than newlines.
+    pub null_data: bool,
+    /// Only replace matches at this position in the line.
+    pub anchor: Option<Anchor>,
+    /// Fail if a single replacement expands to more than this many bytes.
+    pub max_replacement_size: Option<usize>,
+    /// Fail if the output for a single line grows larger than this many bytes.
+    pub max_output_line: Option<usize>,
+    /// Fail if a match starts or ends in the middle of a grapheme cluster.
+    pub grapheme_safe: bool,
+    /// What to do with empty matches. These are handled the same way by every engine.
+    pub empty_matches: EmptyMatches,
+    /// Only match whole words, like `grep -w`. Regexes are wrapped in `\b`, and literal patterns
+    /// check for word boundaries around each match. This doesn't apply to `build_multi` or
+    /// `build_submatch_regex`.
+    pub word_regexp: bool,
+    /// Remove each line with a match, terminator and all, rather than replacing the match. Each
+    /// removed line counts as one replacement.
+    pub delete_lines: bool,
+    /// Change the case of each replacement to follow the text it replaces.
+    pub preserve_case: bool,
+    /// Don't expand `$` capture group references in regex replacements.
+    pub literal_replacement: bool,
+    /// Terminate output lines with these bytes rather than a newline.
+    pub output_delimiter: Option<Vec<u8>>,
+    /// How to parse regex replacement strings.
+    pub template_syntax: TemplateSyntax,
 }
 
 impl ReplaceOptions {
-    pub fn build<P, R>(&self, pattern: P, replacement: R) -> Replacer<P>
+    /// Build a replacer for `pattern` and `template` with these options.
+    ///
+    /// Unlike `build_template`, this doesn't check that the groups which `template` refers to
+    /// exist. References to groups that don't exist expand to nothing.
+    pub fn build<P: Pattern>(&self, pattern: P, template: Template) -> Replacer<P> {
+        let mut replacer = Replacer::new(pattern, template);
+        replacer.replace_all = self.replace_all;
+        replacer.only_matches = self.only_matches;
+        replacer.first_match_only = self.first_match_only;
+        replacer.max_per_line = self.max_per_line;
+        replacer.max_per_file = self.max_per_file;
+        replacer.address = self.address.clone();
+        replacer.records = self.records;
+        replacer.terminator = self.terminator();
+        replacer.anchor = self.anchor;
+        replacer.max_replacement_size = self.max_replacement_size;
+        replacer.max_output_line = self.max_output_line;
+        replacer.grapheme_safe = self.grapheme_safe;
+        replacer.empty_matches = self.empty_matches;
+        replacer.delete_lines = self.delete_lines;
+        replacer.preserve_case = self.preserve_case;
+        replacer.output_delimiter = self.output_delimiter.clone();
+        replacer
+    }
+
+    /// The byte which ends each line.
+    fn terminator(&self) -> u8 {
+        if self.null_data {
+            b'\0'
+        } else {
+            b'\n'
+        }
+    }
+
+    /// Compile a regex with these options, where `^` and `$` match at line terminators.
+    ///
+    /// With `anchor`, the regex is anchored to match only where it's allowed. Matches of other
+    /// patterns are checked while replacing instead, which works for fixed strings but could miss
+    /// matches of regexes which are allowed but aren't leftmost-first. With `word_regexp`, the
+    /// regex is wrapped in word boundaries.
+    fn compile_regex(
+        &self,
+        pattern: &str,
+        ignore_case: bool,
+        unicode: UnicodeMode,
+    ) -> Result<Regex, BuildError> {
+        // always compile the pattern as-is so that errors are reported against what the user wrote
+        let re = compile_regex(pattern, ignore_case, unicode, self.terminator())?;
+        let mut wrapped = pattern.to_owned();
+        if self.word_regexp {
+            // Unicode word boundaries need the Unicode tables
+            let b = if cfg!(feature = "unicode") {
+                r"\b"
+            } else {
+                r"(?-u:\b)"
+            };
+            wrapped = format!("{b}(?:{wrapped}){b}");
+        }
+        if let Some(anchor) = self.anchor {
+            wrapped = anchor.wrap(&wrapped);
+        }
+        if wrapped == pattern {
+            return Ok(re);
+        }
+        compile_regex(&wrapped, ignore_case, unicode, self.terminator())
+    }
+
+    /// Make a literal pattern only match whole words if `word_regexp` is set.
+    fn literal_words(&self, pattern: EnginePattern) -> EnginePattern {
+        if self.word_regexp {
+            EnginePattern::Word(Box::new(pattern))
+        } else {
+            pattern
+        }
+    }
+
+    /// Build a replacer which searches for `pattern` using the given engine.
+    ///
+    /// With the literal engines, the pattern is searched for as-is and the replacement can only
+    /// refer to group 0, the whole match. The plain literal engine doesn't support `ignore_case`.
+    pub fn build_engine<R>(
+        &self,
+        engine: Engine,
+        pattern: &str,
+        replacement: R,
+        ignore_case: bool,
+        unicode: UnicodeMode,
+    ) -> Result<Replacer<EnginePattern>, BuildError>
+    where
+        R: AsRef<[u8]>,
+    {
+        // the literal word boundary checks don't know about UnicodeMode::Never
+        let literal = match unicode {
+            UnicodeMode::Never if self.word_regexp => None,
+            _ => literal_regex(pattern, ignore_case, unicode),
+        };
+        let pattern = match engine {
+            Engine::Auto => match literal {
+                Some(lit) => self.literal_words(EnginePattern::Literal(lit)),
+                None => EnginePattern::Regex(self.compile_regex(pattern, ignore_case, unicode)?),
+            },
+            Engine::Regex => {
+                EnginePattern::Regex(self.compile_regex(pattern, ignore_case, unicode)?)
+            }
+            Engine::Literal if ignore_case => {
+                return Err(BuildError::LiteralIgnoreCase);
+            }
+            Engine::Literal => {
+                self.literal_words(EnginePattern::Literal(pattern.as_bytes().to_vec()))
+            }
+            Engine::AhoCorasick => {
+                let ac = AhoCorasick::builder()
+                    .ascii_case_insensitive(ignore_case)
+                    .build([pattern])?;
+                self.literal_words(EnginePattern::AhoCorasick(ac))
+            }
+        };
+        self.build_template(pattern, replacement)
+    }
+
+    /// Build a replacer for a literal pattern, which unlike the patterns given to `build_engine`
+    /// doesn't have to be UTF-8. With `ignore_case`, ASCII letters match regardless of case.
+    pub fn build_literal<R>(
+        &self,
+        literal: Vec<u8>,
+        replacement: R,
+        ignore_case: bool,
+    ) -> Result<Replacer<EnginePattern>, BuildError>
+    where
+        R: AsRef<[u8]>,
+    {
+        let pattern = if ignore_case {
+            let ac = AhoCorasick::builder()
+                .ascii_case_insensitive(true)
+                .build([literal])?;
+            EnginePattern::AhoCorasick(ac)
+        } else {
+            EnginePattern::Literal(literal)
+        };
+        self.build_template(self.literal_words(pattern), replacement)
+    }
+
+    /// Build a regex replacer which replaces only the span of the capture group named `group`
+    /// rather than the whole match. See `SubmatchRegex`.
+    pub fn build_submatch_regex<R>(
+        &self,
+        pattern: &str,
+        group: &str,
+        replacement: R,
+        ignore_case: bool,
+        unicode: UnicodeMode,
+    ) -> Result<Replacer<SubmatchRegex>, BuildError>
+    where
+        R: AsRef<[u8]>,
+    {
+        // the anchor applies to the replaced part of the match, so it's checked while replacing
+        let re = compile_regex(pattern, ignore_case, unicode, self.terminator())?;
+        let re = SubmatchRegex::new(re, group)
+            .ok_or_else(|| BuildError::MissingGroup(GroupRef::Name(group.to_owned())))?;
+        self.build_template(re, replacement)
+    }
+
+    /// Build a replacer for several regexes at once, each paired with its own replacement.
+    /// Overlapping matches are resolved according to `kind`, see `MultiPattern`.
+    pub fn build_multi<S, R>(
+        &self,
+        rules: &[(S, R)],
+        kind: MatchKind,
+        ignore_case: bool,
+        unicode: UnicodeMode,
+    ) -> Result<Replacer<MultiPattern>, BuildError>
+    where
+        S: AsRef<str>,
+        R: AsRef<[u8]>,
+    {
+        let patterns: Vec<&str> = rules.iter().map(|(p, _)| p.as_ref()).collect();
+        let multi = MultiPattern::new(&patterns, kind, ignore_case, unicode, self.terminator())?;
+        let branches = rules
+            .iter()
+            .enumerate()
+            .map(|(i, (_, rep))| {
+                let re = &multi.regexes[i];
+                let mut template = self.parse_template(re, rep)?;
+                template.resolve(|name| re.group_index(name));
+                template.shift_groups(multi.offsets[i]);
+                Ok((multi.offsets[i], template))
+            })
+            .collect::<Result<Vec<_>, BuildError>>()?;
+        Ok(self.build(multi, Template::branches(branches)))
+    }
+
+    /// Parse `replacement` as a template for `pattern` and build a replacer, checking that every
+    /// group it refers to exists.
+    pub fn build_template<P, R>(
+        &self,
+        pattern: P,
+        replacement: R,
+    ) -> Result<Replacer<P>, BuildError>
     where
         P: Pattern,
-        R: Into<Vec<u8>>,
+        R: AsRef<[u8]>,
     {
-        Replacer {
-            pattern,
-            replacement: replacement.into(),
-            replace_all: self.replace_all,
-            only_matches: self.only_matches,
+        let template = self.parse_template(&pattern, replacement)?;
+        Ok(self.build(pattern, template))
+    }
+
+    /// Parse `replacement` as a template for `pattern`, checking that every group it refers to
+    /// exists.
+    fn parse_template<P, R>(&self, pattern: &P, replacement: R) -> Result<Template, BuildError>
+    where
+        P: Pattern,
+        R: AsRef<[u8]>,
+    {
+        if self.literal_replacement {
+            return Ok(Template::literal(replacement.as_ref()));
         }
+
+        // Catch references to groups that don't exist, which would otherwise silently expand to
+        // nothing. A common mistake is "$1a" which means the group named "1a", not "${1}a".
+        let template = match self.template_syntax {
+            TemplateSyntax::Regex => Template::parse(replacement),
+            TemplateSyntax::Sed => Template::parse_sed(replacement),
+        };
+        let missing = template.group_refs().find(|group| match group {
+            GroupRef::Index(index) => *index >= pattern.captures_len(),
+            GroupRef::Name(name) => pattern.group_index(name).is_none(),
+        });
+        if let Some(group) = missing {
+            return Err(BuildError::MissingGroup(group.clone()));
+        }
+
+        Ok(template)
     }
 
-    pub fn build_literal<P, R>(&self, pattern: P, replacement: R) -> Replacer<Vec<u8>>
+    /// Build a regex replacer where each branch of the pattern's top-level alternation has its own
+    /// literal replacement. For example, `foo|bar` or `(foo|bar)` with replacements `FOO` and
+    /// `BAR` replaces "foo" with "FOO" and "bar" with "BAR".
+    pub fn build_regex_branches<R>(
+        &self,
+        pattern: &str,
+        replacements: &[R],
+        ignore_case: bool,
+        unicode: UnicodeMode,
+    ) -> Result<Replacer<Regex>, BuildError>
     where
-        P: Into<Vec<u8>>,
-        R: Into<Vec<u8>>,
+        R: AsRef<[u8]>,
     {
-        self.build(pattern.into(), replacement)
+        // compile the pattern as-is first so that syntax errors are reported against what the user
+        // actually wrote rather than our modified version.
+        self.compile_regex(pattern, ignore_case, unicode)?;
+
+        let (pattern, count) = wrap_branches(pattern).ok_or(BuildError::NoAlternation)?;
+        if count != replacements.len() {
+            return Err(BuildError::BranchCountMismatch {
+                branches: count,
+                replacements: replacements.len(),
+            });
+        }
+
+        let re = self.compile_regex(&pattern, ignore_case, unicode)?;
+        let branches = replacements
+            .iter()
+            .enumerate()
+            .map(|(i, rep)| {
+                // unwrap is ok because wrap_branches created this group
+                let index = re.group_index(&branch_group_name(i)).unwrap();
+                (index, Template::literal(rep.as_ref()))
+            })
+            .collect();
+        Ok(self.build(re, Template::branches(branches)))
+    }
+}
+
+/// Builds a `Replacer` from the same settings as rp's command line flags, with the same
+/// validation, so that other front-ends behave exactly like the CLI.
+#[derive(Debug, Clone, Default)]
+pub struct ReplacerBuilder {
+    /// The pattern and replacement are literal strings (`-F`).
+    pub fixed_strings: bool,
+    /// Case-insensitive search (`-I`).
+    pub ignore_case: bool,
+    /// Interpret escape sequences in replacements (`-e`).
+    pub escape: bool,
+    /// Interpret escape sequences in fixed-string patterns (`--escape-pattern`). Regex patterns
+    /// have their own escapes, so this only applies with `fixed_strings`.
+    pub escape_pattern: bool,
+    /// Don't expand capture groups in replacements (`--no-expand`).
+    pub no_expand: bool,
+    /// Replace all matches on each line (`-g`).
+    pub replace_all: bool,
+    /// Only output lines with replacements (`-n`).
+    pub only_matches: bool,
+    /// Only replace the first match in each file (`--only-first-match-per-file`).
+    pub first_match_only: bool,
+    /// Replace at most this many matches on each line (`--max`).
+    pub max_per_line: Option<usize>,
+    /// Replace at most this many matches in each file (`--max-per-file`).
+    pub max_per_file: Option<usize>,
+    /// Only replace in these lines (`--lines`, `--from-regex`, and `--to-regex`).
+    pub address: Address,
+    /// Search paragraphs (`--paragraph`) or the whole input (`--multiline`) rather than lines.
+    pub records: Records,
+    /// Lines end with NUL bytes rather than newlines (`-z`).
+    pub null_data: bool,
+    /// Only replace matches at this position in the line (`--at`).
+    pub anchor: Option<Anchor>,
+    /// Fail if one replacement expands to more than this many bytes (`--max-replacement-size`).
+    pub max_replacement_size: Option<usize>,
+    /// Fail if the output for one line grows past this many bytes (`--max-output-line`).
+    pub max_output_line: Option<usize>,
+    /// Refuse to split grapheme clusters (`--grapheme-safe`).
+    pub grapheme_safe: bool,
+    /// What to do with empty matches (`--empty-matches`).
+    pub empty_matches: EmptyMatches,
+    /// Only match whole words (`-w`).
+    pub word_regexp: bool,
+    /// Remove lines with a match rather than replacing it (`-d`).
+    pub delete_lines: bool,
+    /// Make replacements follow the case of the matched text (`--preserve-case`), which also
+    /// makes the search case-insensitive.
+    pub preserve_case: bool,
+    /// Terminate output lines with these bytes rather than a newline (`--output-delimiter`).
+    pub output_delimiter: Option<Vec<u8>>,
+    /// Which engine searches for the pattern (`--engine`).
+    pub engine: Engine,
+    /// When regexes use Unicode mode (`--no-unicode` and `--strict-unicode`).
+    pub unicode: UnicodeMode,
+    /// How to parse regex replacement strings (`--compat=sed`).
+    pub template_syntax: TemplateSyntax,
+}
+
+impl ReplacerBuilder {
+    /// Check for combinations of settings which the CLI rejects.
+    pub fn validate(&self) -> Result<(), BuildError> {
+        let conflict = |a, b| Err(BuildError::Conflict(a, b));
+        if self.fixed_strings {
+            if self.ignore_case {
+                return conflict("fixed_strings", "ignore_case");
+            }
+            if self.no_expand {
+                return conflict("fixed_strings", "no_expand");
+            }
+            if self.engine != Engine::Auto {
+                return conflict("fixed_strings", "engine");
+            }
+            if self.unicode != UnicodeMode::Auto {
+                return conflict("fixed_strings", "unicode");
+            }
+        }
+        if self.replace_all && self.first_match_only {
+            return conflict("replace_all", "first_match_only");
+        }
+        if self.template_syntax == TemplateSyntax::Sed {
+            if self.fixed_strings {
+                return conflict("fixed_strings", "template_syntax");
+            }
+            if self.no_expand {
+                return conflict("no_expand", "template_syntax");
+            }
+            if self.escape {
+                return conflict("escape", "template_syntax");
+            }
+        }
+        Ok(())
+    }
+
+    /// The `ReplaceOptions` corresponding to these settings.
+    pub fn options(&self) -> ReplaceOptions {
+        ReplaceOptions {
+            replace_all: self.replace_all,
+            only_matches: self.only_matches,
+            first_match_only: self.first_match_only,
+            max_per_line: self.max_per_line,
+            max_per_file: self.max_per_file,
+            address: self.address.clone(),
+            records: self.records,
+            null_data: self.null_data,
+            anchor: self.anchor,
+            max_replacement_size: self.max_replacement_size,
+            max_output_line: self.max_output_line,
+            grapheme_safe: self.grapheme_safe,
+            empty_matches: self.empty_matches,
+            word_regexp: self.word_regexp,
+            delete_lines: self.delete_lines,
+            preserve_case: self.preserve_case,
+            literal_replacement: self.no_expand || self.fixed_strings,
+            output_delimiter: self.output_delimiter.clone(),
+            template_syntax: self.template_syntax,
+        }
+    }
+
+    /// Process escape sequences in a replacement string if enabled.
+    pub fn unescape(&self, replacement: &str) -> Result<Vec<u8>, BuildError> {
+        if self.escape {
+            Ok(unescape_bytes(replacement)?)
+        } else {
+            Ok(replacement.as_bytes().to_vec())
+        }
+    }
+
+    /// Whether to search case-insensitively. `preserve_case` implies it, so that one rule finds
+    /// every casing of the pattern.
+    pub fn search_ignore_case(&self) -> bool {
+        self.ignore_case || self.preserve_case
+    }
+
+    pub fn build(
+        &self,
+        pattern: &str,
+        replacement: &str,
+    ) -> Result<Replacer<EnginePattern>, BuildError> {
+        self.validate()?;
+        self.build_unescaped(pattern, self.unescape(replacement)?)
+    }
+
+    /// Like `build`, but escape sequences in `replacement` aren't processed, e.g. because that
+    /// was already done or it was read from a file which isn't UTF-8.
+    pub fn build_unescaped(
+        &self,
+        pattern: &str,
+        replacement: Vec<u8>,
+    ) -> Result<Replacer<EnginePattern>, BuildError> {
+        self.validate()?;
+        if self.fixed_strings && self.escape_pattern {
+            let literal = unescape_bytes(pattern).map_err(BuildError::UnescapePattern)?;
+            return self
+                .options()
+                .build_literal(literal, replacement, self.search_ignore_case());
+        }
+        let engine = match self.engine {
+            // the plain literal engine can't ignore case, Aho-Corasick can for ASCII letters
+            _ if self.fixed_strings && self.preserve_case => Engine::AhoCorasick,
+            _ if self.fixed_strings => Engine::Literal,
+            Engine::Literal if self.preserve_case => Engine::AhoCorasick,
+            engine => engine,
+        };
+        self.options().build_engine(
+            engine,
+            pattern,
+            replacement,
+            self.search_ignore_case(),
+            self.unicode,
+        )
+    }
+}
+
+/// Decide whether to enable Unicode mode for a regex.
+fn use_unicode(pattern: &str, ignore_case: bool, unicode: UnicodeMode) -> bool {
+    match unicode {
+        UnicodeMode::Auto => !is_ascii_pattern(pattern, ignore_case),
+        UnicodeMode::Always => true,
+        UnicodeMode::Never => false,
+    }
+}
+
+/// If a regex pattern only ever matches one exact string, e.g. `foo` or `a\.b`, return it.
+fn literal_regex(pattern: &str, ignore_case: bool, unicode: UnicodeMode) -> Option<Vec<u8>> {
+    use regex_syntax::hir::HirKind;
+
+    let hir = regex_syntax::ParserBuilder::new()
+        .utf8(false)
+        .multi_line(true)
+        .case_insensitive(ignore_case)
+        .unicode(use_unicode(pattern, ignore_case, unicode))
+        .build()
+        .parse(pattern)
+        .ok()?;
+    match hir.kind() {
+        HirKind::Empty => Some(vec![]),
+        HirKind::Literal(lit) => Some(lit.0.to_vec()),
+        _ => None,
+    }
+}
+
+/// Compile a regex with the options we always use.
+fn compile_regex(
+    pattern: &str,
+    ignore_case: bool,
+    unicode: UnicodeMode,
+    terminator: u8,
+) -> Result<Regex, BuildError> {
+    let unicode = use_unicode(pattern, ignore_case, unicode);
+    Ok(RegexBuilder::new(pattern)
+        .multi_line(true)
+        .line_terminator(terminator)
+        .case_insensitive(ignore_case)
+        .unicode(unicode)
+        .build()?)
+}
+
+fn branch_group_name(index: usize) -> String {
+    format!("__rp_branch{index}")
+}
+
+/// Wrap each branch of a pattern's top-level alternation in a named capture group, so that we can
+/// tell which branch matched. The alternation may also be the only thing inside a group, like
+/// `(foo|bar)`. Returns the new pattern and the number of branches, or None if the pattern doesn't
+/// have a top-level alternation.
+///
+/// Flags set in one branch, like the `(?i)` in `(?i)foo|bar`, apply to every later branch too, so
+/// they're repeated at the start of each later branch's group to keep the same meaning.
+fn wrap_branches(pattern: &str) -> Option<(String, usize)> {
+    use regex_syntax::ast::{parse::Parser, Ast};
+
+    let parsed = Parser::new().parse_with_comments(pattern).ok()?;
+    let ast = parsed.ast;
+    let alt = match &ast {
+        Ast::Alternation(alt) => alt,
+        Ast::Group(group) => match &*group.ast {
+            Ast::Alternation(alt) => alt,
+            _ => return None,
+        },
+        _ => return None,
+    };
+
+    let mut out = String::with_capacity(pattern.len() + 20 * alt.asts.len());
+    let mut flags = String::new();
+    out.push_str(&pattern[..alt.span.start.offset]);
+    for (i, branch) in alt.asts.iter().enumerate() {
+        if i != 0 {
+            out.push('|');
+        }
+        let span = branch.span();
+        out.push_str(&format!(
+            "(?P<{}>{}{}",
+            branch_group_name(i),
+            flags,
+            &pattern[span.start.offset..span.end.offset]
+        ));
+        // a comment at the end of the branch would hide the group's closing paren
+        if parsed
+            .comments
+            .iter()
+            .any(|comment| comment.span.end.offset == span.end.offset)
+        {
+            out.push('\n');
+        }
+        out.push(')');
+        let items = match branch {
+            Ast::Concat(concat) => &concat.asts[..],
+            ast => std::slice::from_ref(ast),
+        };
+        for item in items {
+            if let Ast::Flags(set) = item {
+                flags.push_str(&pattern[set.span.start.offset..set.span.end.offset]);
+            }
+        }
+    }
+    out.push_str(&pattern[alt.span.end.offset..]);
+    Some((out, alt.asts.len()))
+}
+
+/// An error from building a `Replacer`, because of an invalid pattern, replacement, or
+/// combination of options.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum BuildError {
+    #[error("invalid pattern regex")]
+    Regex(#[from] regex::Error),
+    #[error("replacement refers to capture group {0} which doesn't exist in the pattern")]
+    MissingGroup(GroupRef),
+    #[error("pattern doesn't have a top-level alternation")]
+    NoAlternation,
+    #[error("pattern has {branches} alternation branches but {replacements} replacements")]
+    BranchCountMismatch {
+        branches: usize,
+        replacements: usize,
+    },
+    #[error("invalid pattern")]
+    AhoCorasick(#[from] aho_corasick::BuildError),
+    #[error("the literal engine doesn't support case-insensitive matching")]
+    LiteralIgnoreCase,
+    #[error("invalid replacement")]
+    Unescape(#[from] unescape::Error),
+    #[error("invalid pattern")]
+    UnescapePattern(#[source] unescape::Error),
+    #[error("{0} can't be used with {1}")]
+    Conflict(&'static str, &'static str),
+    #[error("pattern doesn't have the required group '{0}'")]
+    RequiredGroupMissing(String),
+    #[error("replacement doesn't use the required group '{0}'")]
+    RequiredGroupUnused(String),
+}
+
+/// An error from making replacements in some text.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum ReplaceError {
+    #[error("replacement of {size} bytes exceeds the limit of {limit} bytes")]
+    ReplacementTooLarge { size: usize, limit: usize },
+    #[error("output line of {size} bytes exceeds the limit of {limit} bytes")]
+    OutputTooLarge { size: usize, limit: usize },
+    #[error("match would split the grapheme cluster {0:?}")]
+    SplitsGrapheme(String),
+    #[error("search failed: {0}")]
+    Search(String),
+    #[error("pattern matched the empty string at byte {0} of the line")]
+    EmptyMatch(usize),
+}
+
+/// An error from making replacements in a stream, which can also fail to read or write.
+#[derive(Debug, thiserror::Error)]
+#[non_exhaustive]
+pub enum StreamError {
+    #[error("unable to open: {0}")]
+    Open(#[source] io::Error),
+    #[error("read error: {0}")]
+    Read(#[source] io::Error),
+    #[error("write error: {0}")]
+    Write(#[source] io::Error),
+    #[error(transparent)]
+    Replace(#[from] ReplaceError),
+}
+
+impl StreamError {
+    /// Returns true if this is a write error caused by a broken pipe (EPIPE).
+    pub fn is_broken_pipe(&self) -> bool {
+        match self {
+            StreamError::Write(err) => err.kind() == io::ErrorKind::BrokenPipe,
+            _ => false,
+        }
+    }
+}
+
+/// Makes replacements of a `Pattern` using a `Template`, in a single buffer with `replace_into`
+/// or line by line in a stream with `replace_stream`.
+///
+/// Build one with `ReplaceOptions`, or with `Replacer::new` for the default options.
+///
+/// A replacer is never modified while it's used, so one can be shared between threads by
+/// reference, or cloned for each thread. It's `Send` and `Sync` whenever its pattern is, which
+/// includes `EnginePattern` and the regex patterns. Cloning is cheap for those patterns: the
+/// compiled regexes and the template are reference counted rather than copied, so clones don't
+/// compile anything again.
+#[derive(Debug, Clone)]
+pub struct Replacer<P> {
+    pattern: P,
+    template: Arc<Template>,
+    replace_all: bool,
+    only_matches: bool,
+    first_match_only: bool,
+    max_per_line: Option<usize>,
+    max_per_file: Option<usize>,
+    address: Address,
+    records: Records,
+    /// The byte which ends each line
+    terminator: u8,
+    anchor: Option<Anchor>,
+    max_replacement_size: Option<usize>,
+    max_output_line: Option<usize>,
+    grapheme_safe: bool,
+    empty_matches: EmptyMatches,
+    delete_lines: bool,
+    preserve_case: bool,
+    output_delimiter: Option<Vec<u8>>,
+    /// More replacers to apply in turn to the output of this one
+    then: Arc<Vec<Replacer<EnginePattern>>>,
+}
+
+// and pattern related methods are generic over Patterns only
+impl<P: Pattern> Replacer<P> {
+    /// Create a replacer with the default options, which replaces the first match on each line.
+    /// Named groups in `template` are resolved using `pattern`.
+    pub fn new(pattern: P, mut template: Template) -> Replacer<P> {
+        template.resolve(|name| pattern.group_index(name));
+        Replacer {
+            pattern,
+            template: Arc::new(template),
+            replace_all: false,
+            only_matches: false,
+            first_match_only: false,
+            max_per_line: None,
+            max_per_file: None,
+            address: Address::default(),
+            records: Records::Lines,
+            terminator: b'\n',
+            anchor: None,
+            max_replacement_size: None,
+            max_output_line: None,
+            grapheme_safe: false,
+            empty_matches: EmptyMatches::default(),
+            delete_lines: false,
+            preserve_case: false,
+            output_delimiter: None,
+            then: Arc::default(),
+        }
+    }
+
+    /// Check that the pattern has a group with each of `names`, and that the replacement refers
+    /// to it by name or by number, to catch a pattern and replacement which have drifted apart.
+    pub fn require_groups<S: AsRef<str>>(&self, names: &[S]) -> Result<(), BuildError> {
+        for name in names {
+            let name = name.as_ref();
+            let index = self
+                .pattern
+                .group_index(name)
+                .ok_or_else(|| BuildError::RequiredGroupMissing(name.to_owned()))?;
+            // named references were resolved to indexes by `new`
+            if !self
+                .template
+                .group_refs()
+                .any(|group| *group == GroupRef::Index(index))
+            {
+                return Err(BuildError::RequiredGroupUnused(name.to_owned()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Chain another replacer after this one, like a second `sed -e` expression. On each line,
+    /// `next` searches the text after this replacer and any chained before it have made their
+    /// replacements, and the replacements made by all of them are counted together.
+    ///
+    /// Only the per-line options of `next` are used, the stream options like `address` and
+    /// `max_per_file` come from this replacer. Since the edits made by chained replacers overlap,
+    /// lines they change get a single `Edit` covering everything which changed.
+    pub fn then(mut self, next: Replacer<EnginePattern>) -> Replacer<P> {
+        Arc::make_mut(&mut self.then).push(next);
+        self
+    }
+
+    /// The pattern this replacer searches for.
+    pub fn pattern(&self) -> &P {
+        &self.pattern
+    }
+
+    /// Make replacements in the given input text and write the result to the provided buffer.
+    ///
+    /// The result is appended to `buf`, which is not cleared first. Returns the total number of
+    /// replacements that were made, which is at most one unless `replace_all` or `max_per_line`
+    /// is set. `max_per_file` only applies when streaming.
+    pub fn replace_into(&self, buf: &mut Vec<u8>, text: &[u8]) -> Result<usize, ReplaceError> {
+        self.replace_into_with_edits(buf, text, &mut vec![])
+    }
+
+    /// Like `replace_into`, but also append an `Edit` to `edits` for every replacement made.
+    pub fn replace_into_with_edits(
+        &self,
+        buf: &mut Vec<u8>,
+        text: &[u8],
+        edits: &mut Vec<Edit>,
+    ) -> Result<usize, ReplaceError> {
+        self.replace_into_confirm(buf, text, edits, self.line_limit(), &mut |_, _| true)
+    }
+
+    /// The most replacements to make in each line, from `replace_all`, `max_per_line`, and
+    /// `first_match_only`.
+    fn line_limit(&self) -> usize {
+        match self.max_per_line {
+            _ if self.first_match_only => 1,
+            Some(max) => max,
+            None if self.replace_all => usize::MAX,
+            None => 1,
+        }
+    }
+
+    /// The most replacements to make in each input, from `max_per_file` and `first_match_only`.
+    fn file_limit(&self) -> Option<usize> {
+        match self.max_per_file {
+            _ if self.first_match_only => Some(1),
+            max => max,
+        }
+    }
+
+    /// Like `replace_into_with_edits`, but make at most `limit` replacements, and only the ones
+    /// which `confirm` accepts given the span of each match and what it would be replaced with.
+    /// Declined matches are left as they are and don't count towards `limit`.
+    fn replace_into_confirm(
+        &self,
+        buf: &mut Vec<u8>,
+        text: &[u8],
+        edits: &mut Vec<Edit>,
+        limit: usize,
+        confirm: &mut dyn FnMut(Range<usize>, &[u8]) -> bool,
+    ) -> Result<usize, ReplaceError> {
+        // when there's nothing to expand we can skip the slower capture group search
+        let literal = self.template.as_literal();
+        let buf_start = buf.len();
+        let check_output_size = |buf: &Vec<u8>| match self.max_output_line {
+            Some(limit) if buf.len() - buf_start > limit => Err(ReplaceError::OutputTooLarge {
+                size: buf.len() - buf_start,
+                limit,
+            }),
+            _ => Ok(()),
+        };
+        let mut caps = Pooled::<Captures>::take();
+        let first_edit = edits.len();
+
+        let mut count = 0;
+        // end of the input that's been copied to buf so far
+        let mut last = 0;
+        // where to start searching for the next match
+        let mut pos = 0;
+        let mut last_match_end = None;
+
+        while pos <= text.len() && count < limit {
+            let m = match literal {
+                Some(_) => self.pattern.find_at(text, pos),
+                None => self.pattern.captures_at(text, pos, &mut caps),
+            };
+            let Some(m) = m else {
+                break;
+            };
+
+            // The terminator isn't part of the line, so there's no empty string after it to
+            // match. Otherwise "^" would match there too, at what's really the next line.
+            if m.is_empty() && m.start == text.len() && text.last() == Some(&self.terminator) {
+                break;
+            }
+            // Like the regex crate, don't allow an empty match immediately after the previous
+            // match, otherwise "a*" could match "aaa" and then the empty string after it.
+            if m.is_empty() && last_match_end == Some(m.end) {
+                pos = m.end + 1;
+                continue;
+            }
+            if m.is_empty() {
+                match self.empty_matches {
+                    EmptyMatches::Advance => {}
+                    EmptyMatches::Skip => {
+                        pos = m.end + 1;
+                        continue;
+                    }
+                    EmptyMatches::Error => return Err(ReplaceError::EmptyMatch(m.start)),
+                }
+            }
+            if let Some(anchor) = self.anchor {
+                if !anchor.allows(text, &m, self.terminator) {
+                    pos = m.start + 1;
+                    continue;
+                }
+            }
+            if self.grapheme_safe {
+                if let Some(&split) = [m.start, m.end]
+                    .iter()
+                    .find(|&&i| !grapheme::is_boundary(text, i))
+                {
+                    let cluster = &text[grapheme::cluster_around(text, split)];
+                    return Err(ReplaceError::SplitsGrapheme(
+                        String::from_utf8_lossy(cluster).into_owned(),
+                    ));
+                }
+            }
+
+            buf.extend_from_slice(&text[last..m.start]);
+            let rep_start = buf.len();
+            match literal {
+                Some(rep) => buf.extend_from_slice(rep),
+                None => self.template.expand(text, &caps, buf),
+            }
+            if self.preserve_case {
+                template::follow_case(&text[m.clone()], buf, rep_start);
+            }
+            if let Some(limit) = self.max_replacement_size {
+                let size = buf.len() - rep_start;
+                if size > limit {
+                    return Err(ReplaceError::ReplacementTooLarge { size, limit });
+                }
+            }
+            check_output_size(buf)?;
+            if confirm(m.clone(), &buf[rep_start..]) {
+                edits.push(Edit {
+                    old: m.clone(),
+                    new: rep_start..buf.len(),
+                });
+                count += 1;
+            } else {
+                buf.truncate(rep_start);
+                buf.extend_from_slice(&text[m.clone()]);
+            }
+
+            last = m.end;
+            pos = m.end;
+            last_match_end = Some(m.end);
+        }
+
+        if let Some(err) = self.pattern.error() {
+            return Err(ReplaceError::Search(err));
+        }
+        buf.extend_from_slice(&text[last..]);
+        if !self.then.is_empty() {
+            count += self.replace_chained(buf, buf_start, text, edits, first_edit)?;
+        }
+        check_output_size(buf)?;
+        Ok(count)
+    }
+
+    /// Apply the `then` replacers in turn to the text written to `buf` after `start`. If they
+    /// replace anything, the edits to `text` after `first_edit` are merged into one which covers
+    /// everything that changed.
+    fn replace_chained(
+        &self,
+        buf: &mut Vec<u8>,
+        start: usize,
+        text: &[u8],
+        edits: &mut Vec<Edit>,
+        first_edit: usize,
+    ) -> Result<usize, ReplaceError> {
+        let mut count = 0;
+        let mut current = vec![];
+        for next in self.then.iter() {
+            current.clear();
+            current.extend_from_slice(&buf[start..]);
+            buf.truncate(start);
+            count += next.replace_into(buf, &current)?;
+        }
+        if count > 0 {
+            let new = &buf[start..];
+            let prefix = text.iter().zip(new).take_while(|(a, b)| a == b).count();
+            let suffix = text[prefix..]
+                .iter()
+                .rev()
+                .zip(new[prefix..].iter().rev())
+                .take_while(|(a, b)| a == b)
+                .count();
+            edits.truncate(first_edit);
+            edits.push(Edit {
+                old: prefix..text.len() - suffix,
+                new: start + prefix..buf.len() - suffix,
+            });
+        }
+        Ok(count)
     }
 
-    pub fn build_regex<R>(
-        &self,
-        pattern: &str,
-        replacement: R,
-        ignore_case: bool,
-    ) -> Result<Replacer<Regex>, regex::Error>
+    /// Make replacements on each line of `input`, writing the result to `output`.
+    pub fn replace_stream<R, W>(&self, input: &mut R, output: &mut W) -> Result<Stats, StreamError>
     where
-        R: Into<Vec<u8>>,
+        R: BufRead,
+        W: Write + ?Sized,
     {
-        let re = RegexBuilder::new(pattern)
-            .multi_line(true)
-            .case_insensitive(ignore_case)
-            .build()?;
-        Ok(self.build(re, replacement))
+        self.for_each_line(input, |line| self.write_line(line, line.new, output))
     }
-}
-
-#[derive(Debug, thiserror::Error)]
-pub enum StreamIOError {
-    #[error("read error: {0}")]
-    Read(#[source] io::Error),
-    #[error("write error: {0}")]
-    Write(#[source] io::Error),
-}
 
-impl StreamIOError {
-    /// Returns true if this is a write error caused by a broken pipe (EPIPE).
-    pub fn is_broken_pipe(&self) -> bool {
-        match self {
-            StreamIOError::Read(_) => false,
-            StreamIOError::Write(err) => err.kind() == io::ErrorKind::BrokenPipe,
+    /// Whether `write_line` writes anything for `line`, which it doesn't for lines without
+    /// replacements with `only_matches`, or for lines which were removed with `delete_lines`.
+    pub fn writes_line(&self, line: &Line) -> bool {
+        if self.delete_lines {
+            !self.only_matches && line.count == 0
+        } else {
+            !self.only_matches || line.count > 0
         }
     }
-}
-
-#[derive(Debug, Clone)]
-pub struct Replacer<P> {
-    pattern: P,
-    replacement: Vec<u8>,
-    replace_all: bool,
-    only_matches: bool,
-}
 
-// and pattern related methods are generic over Patterns only
-impl<P: Pattern> Replacer<P> {
-    #[allow(unused)]
-    pub fn new<R>(pattern: P, replacement: R) -> Replacer<P>
+    /// Write the replaced text of one line like `replace_stream` does, except that `new` is written
+    /// in place of `line.new`. This lets callers decorate the text, e.g. to highlight replacements.
+    pub fn write_line<W>(&self, line: &Line, new: &[u8], output: &mut W) -> io::Result<()>
     where
-        R: Into<Vec<u8>>,
+        W: Write + ?Sized,
     {
-        Replacer {
-            pattern,
-            replacement: replacement.into(),
-            replace_all: false,
-            only_matches: false,
+        if !self.writes_line(line) {
+            return Ok(());
+        }
+        match &self.output_delimiter {
+            // only swap the terminator if the replacement didn't change it
+            Some(delim)
+                if line.old.last() == Some(&self.terminator)
+                    && new.last() == Some(&self.terminator) =>
+            {
+                output.write_all(&new[..(new.len() - 1)])?;
+                output.write_all(delim)
+            }
+            _ => output.write_all(new),
         }
     }
 
-    pub fn replace_stream<R, W>(&self, input: &mut R, output: &mut W) -> Result<(), StreamIOError>
+    /// Make replacements on each line of `input`, passing the original and replaced text of each
+    /// line to the callback `f`. This lets callers use a different output format than
+    /// `replace_stream`. Errors returned by `f` are reported as write errors.
+    ///
+    /// If `records` isn't `Records::Lines`, each paragraph or the whole input is searched and
+    /// passed to `f` as a single line, so that patterns can match across the line breaks inside
+    /// it.
+    ///
+    /// Once `max_per_file` replacements have been made, or one with `first_match_only`, the
+    /// remaining lines aren't searched at all and are passed through unchanged, as are lines
+    /// outside the `address`.
+    pub fn for_each_line<R, F>(&self, input: &mut R, f: F) -> Result<Stats, StreamError>
     where
         R: BufRead,
-        W: Write,
+        F: FnMut(&Line) -> io::Result<()>,
     {
-        let mut buf = vec![];
-        let mut repbuf = vec![];
+        self.for_each_line_confirm(input, |_| true, f)
+    }
+
+    /// Like `for_each_line`, but ask `confirm` before making each replacement, for interactive
+    /// use. Matches it declines are left unchanged and don't count as replacements, so without
+    /// `replace_all`, declining the first match on a line moves on to the next one.
+    pub fn for_each_line_confirm<R, C, F>(
+        &self,
+        input: &mut R,
+        mut confirm: C,
+        mut f: F,
+    ) -> Result<Stats, StreamError>
+    where
+        R: BufRead,
+        C: FnMut(&Candidate) -> bool,
+        F: FnMut(&Line) -> io::Result<()>,
+    {
+        let mut buf = Pooled::<Vec<u8>>::take();
+        let mut repbuf = Pooled::<Vec<u8>>::take();
+        let mut edits = Pooled::<Vec<Edit>>::take();
+        // the line after a paragraph, which had to be read to find where the paragraph ends
+        let mut lookahead = Pooled::<Vec<u8>>::take();
+        let mut stats = Stats::default();
+        let mut number = 0;
+        let file_limit = self.file_limit();
+        let mut in_range = self.address.from.is_none();
         loop {
             // read some input
             buf.clear();
-            input
-                .read_until(b'\n', &mut buf)
-                .map_err(StreamIOError::Read)?;
+            std::mem::swap(&mut buf, &mut lookahead);
+            if buf.is_empty() {
+                input
+                    .read_until(self.terminator, &mut buf)
+                    .map_err(StreamError::Read)?;
+            }
             if buf.is_empty() {
                 break;
             }
+            number += 1;
+            let first_number = number;
+            match self.records {
+                Records::Lines => (),
+                Records::Paragraphs if self.is_blank_line(&buf) => (),
+                Records::Paragraphs => loop {
+                    input
+                        .read_until(self.terminator, &mut lookahead)
+                        .map_err(StreamError::Read)?;
+                    if lookahead.is_empty() || self.is_blank_line(&lookahead) {
+                        break;
+                    }
+                    buf.extend_from_slice(&lookahead);
+                    lookahead.clear();
+                    number += 1;
+                },
+                Records::Whole => {
+                    input.read_to_end(&mut buf).map_err(StreamError::Read)?;
+                }
+            }
 
             // do the replacement
-            repbuf.clear();
-            let rep_count =
-                self.pattern
-                    .replace_into(&mut repbuf, &buf, &self.replacement, self.replace_all);
-
-            // write the output (maybe)
-            if !self.only_matches || rep_count != 0 {
-                output.write_all(&repbuf).map_err(StreamIOError::Write)?;
+            edits.clear();
+            let remaining = if self.address.selects(&mut in_range, first_number, &buf) {
+                file_limit.map_or(usize::MAX, |max| max - stats.replacements)
+            } else {
+                0
+            };
+            let (new, count) = if remaining == 0 {
+                (&buf, 0)
+            } else {
+                repbuf.clear();
+                // one match is enough to remove a line
+                let limit = if self.delete_lines {
+                    1
+                } else {
+                    self.line_limit()
+                };
+                let count = self.replace_into_confirm(
+                    &mut repbuf,
+                    &buf,
+                    &mut edits,
+                    limit.min(remaining),
+                    &mut |old, new| {
+                        confirm(&Candidate {
+                            number: first_number,
+                            text: &buf,
+                            old,
+                            new,
+                        })
+                    },
+                )?;
+                if self.delete_lines && count > 0 {
+                    repbuf.clear();
+                    edits.clear();
+                    edits.push(Edit {
+                        old: 0..buf.len(),
+                        new: 0..0,
+                    });
+                }
+                (&repbuf, count)
+            };
+            stats.replacements += count;
+            if count > 0 {
+                stats.lines += 1;
             }
+
+            let line = Line {
+                number: first_number,
+                old: &buf,
+                new,
+                count,
+                edits: &edits,
+            };
+            f(&line).map_err(StreamError::Write)?;
         }
 
-        Ok(())
+        Ok(stats)
+    }
+
+    /// Check whether a line is empty except for its terminator.
+    fn is_blank_line(&self, line: &[u8]) -> bool {
+        match line {
+            [b] => *b == self.terminator,
+            b"\r\n" => self.terminator == b'\n',
+            _ => false,
+        }
+    }
+}
+
+/// Counts from making replacements in a stream.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// The total number of replacements.
+    pub replacements: usize,
+    /// The number of lines with at least one replacement. In paragraph or multiline mode, each
+    /// paragraph or the whole input counts as one line.
+    pub lines: usize,
+}
+
+/// A line of input and the result of making replacements in it. In paragraph or multiline mode,
+/// this may be several lines of input.
+#[derive(Debug)]
+pub struct Line<'a> {
+    /// The line number, starting at 1, or the number of the first line in a paragraph
+    pub number: usize,
+    /// The original text, including the line terminator
+    pub old: &'a [u8],
+    /// The text with replacements applied
+    pub new: &'a [u8],
+    /// The number of replacements made in this line
+    pub count: usize,
+    /// Where each replacement was made
+    pub edits: &'a [Edit],
+}
+
+/// A match which is about to be replaced, for `Replacer::for_each_line_confirm`.
+#[derive(Debug)]
+pub struct Candidate<'a> {
+    /// The number of the line the match is in, like `Line::number`
+    pub number: usize,
+    /// The whole line, before any replacements
+    pub text: &'a [u8],
+    /// The span of the match in `text`
+    pub old: Range<usize>,
+    /// What the match would be replaced with
+    pub new: &'a [u8],
+}
+
+/// The location of a single replacement.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Edit {
+    /// The span of the original text which was matched
+    pub old: Range<usize>,
+    /// The span of the replaced text which it was replaced with
+    pub new: Range<usize>,
+}
+
+impl Reuse for Vec<Edit> {
+    fn pool() -> &'static LocalKey<RefCell<Vec<Self>>> {
+        thread_local!(static POOL: RefCell<Vec<Vec<Edit>>> = const { RefCell::new(Vec::new()) });
+        &POOL
+    }
+
+    fn reset(&mut self) -> bool {
+        self.clear();
+        self.capacity() * std::mem::size_of::<Edit>() <= pool::MAX_KEPT_BYTES
     }
 }
 
@@ -227,6 +1847,27 @@ mod tests {
     use super::*;
     use regex::bytes::Regex;
 
+    #[test]
+    fn test_captures_reuse() {
+        let mut caps = Captures::default();
+        let a = Regex::new("(a)(b)?").unwrap();
+        let b = Regex::new("(?<x>c)(d)").unwrap();
+        assert_eq!(Pattern::captures_at(&a, b"xab", 0, &mut caps), Some(1..3));
+        assert_eq!((caps.get(1), caps.get(2)), (Some(1..2), Some(2..3)));
+        // the buffer from `a` is reused for `b`, which has as many groups
+        assert_eq!(Pattern::captures_at(&b, b"cd", 0, &mut caps), Some(0..2));
+        assert_eq!((caps.get(1), caps.get(2)), (Some(0..1), Some(1..2)));
+        assert_eq!(Pattern::captures_at(&a, b"a", 0, &mut caps), Some(0..1));
+        assert_eq!((caps.get(1), caps.get(2)), (Some(0..1), None));
+        // a failed search leaves the last groups alone
+        assert_eq!(Pattern::captures_at(&b, b"x", 0, &mut caps), None);
+        assert_eq!(caps.get(0), Some(0..1));
+
+        let c = Regex::new("(e)").unwrap();
+        assert_eq!(Pattern::captures_at(&c, b"e", 0, &mut caps), Some(0..1));
+        assert_eq!((caps.get(1), caps.get(2)), (Some(0..1), None));
+    }
+
     #[test]
     fn test_regex_replace_into() {
         let re = Regex::new(r"(\w+),\s*(\w+)").unwrap();
@@ -254,7 +1895,9 @@ mod tests {
 
         for (text, rep, all, excount, expected) in tests {
             buf.clear();
-            let count = re.replace_into(&mut buf, text.as_bytes(), rep.as_bytes(), all);
+            let mut replacer = Replacer::new(re.clone(), Template::parse(rep));
+            replacer.replace_all = all;
+            let count = replacer.replace_into(&mut buf, text.as_bytes()).unwrap();
             assert_eq!(count, excount);
             assert_eq!(&buf, expected.as_bytes());
         }
@@ -275,11 +1918,1059 @@ mod tests {
 
         for (text, rep, all, excount, expected) in tests {
             buf.clear();
-            let count = pat
-                .as_slice()
-                .replace_into(&mut buf, text.as_bytes(), rep.as_bytes(), all);
+            let mut replacer = Replacer::new(pat.as_slice(), Template::literal(rep));
+            replacer.replace_all = all;
+            let count = replacer.replace_into(&mut buf, text.as_bytes()).unwrap();
             assert_eq!(count, excount);
             assert_eq!(&buf, expected.as_bytes());
         }
     }
+
+    #[test]
+    fn test_replace_edits() {
+        let re = Regex::new(r"b+").unwrap();
+        let mut replacer = Replacer::new(re, Template::parse("<$0>"));
+        replacer.replace_all = true;
+
+        let mut buf = vec![];
+        let mut edits = vec![];
+        let count = replacer
+            .replace_into_with_edits(&mut buf, b"abbcb", &mut edits)
+            .unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(buf, b"a<bb>c<b>");
+        assert_eq!(
+            edits,
+            [
+                Edit {
+                    old: 1..3,
+                    new: 1..5
+                },
+                Edit {
+                    old: 4..5,
+                    new: 6..9
+                },
+            ]
+        );
+        assert_eq!(&buf[edits[1].new.clone()], b"<b>");
+    }
+
+    #[test]
+    fn test_build_regex_missing_group() {
+        let opts = ReplaceOptions::default();
+        let build = |pat, rep| opts.build_engine(Engine::Regex, pat, rep, false, UnicodeMode::Auto);
+
+        assert!(build(r"(\w+) (?P<x>\w+)", "$0 $1 $2 ${x} $$3").is_ok());
+        assert!(matches!(
+            build(r"(\w+)", "$2"),
+            Err(BuildError::MissingGroup(GroupRef::Index(2)))
+        ));
+        assert!(matches!(
+            build(r"(\w+)", "$1a"),
+            Err(BuildError::MissingGroup(GroupRef::Name(name))) if name == "1a"
+        ));
+        assert!(matches!(build(r"(\w+", "$1"), Err(BuildError::Regex(_))));
+
+        let opts = ReplaceOptions {
+            literal_replacement: true,
+            ..Default::default()
+        };
+        let replacer = opts
+            .build_engine(Engine::Regex, r"(\w+)", "$2 $$", false, UnicodeMode::Auto)
+            .unwrap();
+        let mut buf = vec![];
+        replacer.replace_into(&mut buf, b"foo").unwrap();
+        assert_eq!(buf, b"$2 $$");
+    }
+
+    #[test]
+    fn test_output_delimiter() {
+        let opts = ReplaceOptions {
+            output_delimiter: Some(b"\0".to_vec()),
+            ..Default::default()
+        };
+        let replacer = opts
+            .build_engine(Engine::Literal, "o", "0", false, UnicodeMode::Auto)
+            .unwrap();
+        let mut out = vec![];
+        replacer
+            .replace_stream(&mut &b"foo\nbar\nbaz"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"f0o\0bar\0baz");
+
+        let replacer = opts
+            .build_engine(Engine::Literal, "\n", ";", false, UnicodeMode::Auto)
+            .unwrap();
+        out.clear();
+        replacer
+            .replace_stream(&mut &b"foo\nbar\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"foo;bar;");
+    }
+
+    #[test]
+    fn test_wrap_branches() {
+        assert_eq!(
+            wrap_branches("foo|ba(r)").unwrap(),
+            (
+                "(?P<__rp_branch0>foo)|(?P<__rp_branch1>ba(r))".to_owned(),
+                2
+            )
+        );
+        assert_eq!(
+            wrap_branches(r"\b(a|b|c)\b").map(|(_, n)| n),
+            None,
+            "alternation inside a concatenation isn't top-level"
+        );
+        assert_eq!(
+            wrap_branches("(a|b|c)").unwrap(),
+            (
+                "((?P<__rp_branch0>a)|(?P<__rp_branch1>b)|(?P<__rp_branch2>c))".to_owned(),
+                3
+            )
+        );
+        assert_eq!(wrap_branches("foo"), None);
+        assert_eq!(
+            wrap_branches("(?i)foo|b(?-i)a(?s:.)|c").unwrap().0,
+            "(?P<__rp_branch0>(?i)foo)|(?P<__rp_branch1>(?i)b(?-i)a(?s:.))\
+             |(?P<__rp_branch2>(?i)(?-i)c)",
+            "flags carry over to later branches, but flag groups don't"
+        );
+        assert_eq!(
+            wrap_branches("(?x) foo | bar # c").unwrap().0,
+            "(?P<__rp_branch0>(?x) foo )|(?P<__rp_branch1>(?x) bar # c\n)"
+        );
+    }
+
+    #[test]
+    fn test_build_regex_branches() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = opts
+            .build_regex_branches(
+                "(cat|dog|fish)",
+                &["dog", "cat", ""],
+                false,
+                UnicodeMode::Auto,
+            )
+            .unwrap();
+        let mut buf = vec![];
+        replacer
+            .replace_into(&mut buf, b"cat chases dog, fish watches")
+            .unwrap();
+        assert_eq!(buf, b"dog chases cat,  watches");
+
+        assert!(matches!(
+            opts.build_regex_branches("a|b", &["x"], false, UnicodeMode::Auto),
+            Err(BuildError::BranchCountMismatch {
+                branches: 2,
+                replacements: 1
+            })
+        ));
+        assert!(matches!(
+            opts.build_regex_branches("ab", &["x"], false, UnicodeMode::Auto),
+            Err(BuildError::NoAlternation)
+        ));
+
+        let replacer = opts
+            .build_regex_branches("(?i)foo|bar", &["X", "Y"], false, UnicodeMode::Auto)
+            .unwrap();
+        let mut buf = vec![];
+        replacer.replace_into(&mut buf, b"foo BAR bar").unwrap();
+        assert_eq!(buf, b"X Y Y");
+    }
+
+    #[test]
+    fn test_empty_matches() {
+        // empty matches should behave the same as the regex crate's own replace_all
+        let mut buf = vec![];
+        for (pat, text) in [("a*", "baaac"), ("", "abc"), ("x*", ""), (r"\b", "foo bar")] {
+            let re = Regex::new(pat).unwrap();
+            let mut replacer = Replacer::new(re.clone(), Template::literal("-"));
+            replacer.replace_all = true;
+            buf.clear();
+            replacer.replace_into(&mut buf, text.as_bytes()).unwrap();
+            assert_eq!(buf, re.replace_all(text.as_bytes(), &b"-"[..]).as_ref());
+        }
+
+        let mut replacer = Replacer::new(&b""[..], Template::literal("-"));
+        replacer.replace_all = true;
+        buf.clear();
+        replacer.replace_into(&mut buf, b"ab").unwrap();
+        assert_eq!(buf, b"-a-b-");
+    }
+
+    #[test]
+    fn test_empty_match_modes() {
+        use EmptyMatches::{Advance, Error, Skip};
+
+        fn stream<P: Pattern>(replacer: &Replacer<P>, text: &str) -> Result<String, String> {
+            let mut out = vec![];
+            replacer
+                .replace_stream(&mut text.as_bytes(), &mut out)
+                .map_err(|err| err.to_string())?;
+            Ok(String::from_utf8(out).unwrap())
+        }
+        let opts = |empty_matches| ReplaceOptions {
+            replace_all: true,
+            empty_matches,
+            ..Default::default()
+        };
+        let run = |engine, pattern: &str, mode, text: &str| {
+            let replacer = opts(mode)
+                .build_engine(engine, pattern, "-", false, UnicodeMode::Auto)
+                .unwrap();
+            stream(&replacer, text)
+        };
+
+        let error = Err("pattern matched the empty string at byte 0 of the line".to_owned());
+        for engine in [
+            Engine::Auto,
+            Engine::Regex,
+            Engine::Literal,
+            Engine::AhoCorasick,
+        ] {
+            // nothing matches after the terminator, since it isn't part of the line
+            assert_eq!(
+                run(engine, "", Advance, "ab\n\nc"),
+                Ok("-a-b-\n-\n-c-".into())
+            );
+            assert_eq!(run(engine, "", Skip, "ab\n\nc"), Ok("ab\n\nc".into()));
+            assert_eq!(run(engine, "", Error, "ab\n"), error);
+            assert_eq!(run(engine, "", Error, ""), Ok("".into()));
+            for mode in [Advance, Skip, Error] {
+                assert_eq!(run(engine, "b", mode, "abcb\n"), Ok("a-c-\n".into()));
+            }
+        }
+
+        for (pattern, text, advance, skip) in [
+            ("a*", "baaac\n", "-b-c-\n", "b-c\n"),
+            ("a*", "aaa\n", "-\n", "-\n"),
+            ("^", "ab\ncd", "-ab\n-cd", "ab\ncd"),
+            ("$", "ab\ncd\n", "ab-\ncd-\n", "ab\ncd\n"),
+            (r"\b", "ab cd\n", "-ab- -cd-\n", "ab cd\n"),
+            ("x?", "\n", "-\n", "\n"),
+        ] {
+            for engine in [Engine::Auto, Engine::Regex] {
+                assert_eq!(run(engine, pattern, Advance, text).unwrap(), advance);
+                assert_eq!(run(engine, pattern, Skip, text).unwrap(), skip);
+                // only an error if an empty match would have been replaced
+                assert_eq!(run(engine, pattern, Error, text).is_err(), advance != skip);
+            }
+        }
+
+        let multi = |mode| {
+            opts(mode)
+                .build_multi(
+                    &[("b", "B"), ("a*", "-")],
+                    MatchKind::LeftmostFirst,
+                    false,
+                    UnicodeMode::Auto,
+                )
+                .unwrap()
+        };
+        assert_eq!(stream(&multi(Advance), "baac\n"), Ok("B-c-\n".into()));
+        assert_eq!(stream(&multi(Skip), "baac\n"), Ok("B-c\n".into()));
+        assert_eq!(
+            stream(&multi(Error), "baac\n"),
+            Err("pattern matched the empty string at byte 4 of the line".into())
+        );
+
+        let nul = ReplaceOptions {
+            null_data: true,
+            ..opts(Advance)
+        };
+        let replacer = nul.build(&b""[..], Template::literal("-"));
+        assert_eq!(stream(&replacer, "a\0b\n\0"), Ok("-a-\0-b-\n-\0".into()));
+    }
+
+    #[test]
+    fn test_submatch_regex() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = opts
+            .build_submatch_regex(
+                r"(\w+)=(?P<m>\d+)(;)",
+                "m",
+                "[$1 $2 $0]",
+                false,
+                UnicodeMode::Auto,
+            )
+            .unwrap();
+        let mut buf = vec![];
+        let count = replacer.replace_into(&mut buf, b"a=1; b=x; c=22;").unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(buf, b"a=[a ; 1]; b=x; c=[c ; 22];");
+
+        // the hidden group doesn't count, so there's no group 3
+        let err = opts
+            .build_submatch_regex(r"(a)(?P<m>b)(c)", "m", "$3", false, UnicodeMode::Auto)
+            .unwrap_err();
+        assert!(matches!(err, BuildError::MissingGroup(GroupRef::Index(3))));
+        assert!(opts
+            .build_submatch_regex("a", "m", "", false, UnicodeMode::Auto)
+            .is_err());
+    }
+
+    #[test]
+    fn test_build_engine() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        #[track_caller]
+        fn check(replacer: Replacer<EnginePattern>, input: &str, expected: &str) {
+            let mut buf = vec![];
+            replacer.replace_into(&mut buf, input.as_bytes()).unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        }
+        let build = |engine, pattern, replacement, ignore_case| {
+            opts.build_engine(engine, pattern, replacement, ignore_case, UnicodeMode::Auto)
+        };
+
+        let replacer = build(Engine::Auto, r"a\.b", "[$0]", false).unwrap();
+        assert!(matches!(replacer.pattern, EnginePattern::Literal(ref lit) if lit == b"a.b"));
+        check(replacer, "a.b axb", "[a.b] axb");
+
+        let replacer = build(Engine::Auto, r"a.(b)", "[$1]", false).unwrap();
+        assert!(matches!(replacer.pattern, EnginePattern::Regex(_)));
+        check(replacer, "a.b axb", "[b] [b]");
+
+        let replacer = build(Engine::Literal, "a.b", "[$0]", false).unwrap();
+        check(replacer, "a.b axb", "[a.b] axb");
+
+        let replacer = build(Engine::AhoCorasick, "a.B", "[$0]", true).unwrap();
+        check(replacer, "A.b a.b axb", "[A.b] [a.b] axb");
+
+        assert!(matches!(
+            build(Engine::Literal, "a", "", true),
+            Err(BuildError::LiteralIgnoreCase)
+        ));
+        assert!(matches!(
+            build(Engine::Literal, "a", "$1", false),
+            Err(BuildError::MissingGroup(GroupRef::Index(1)))
+        ));
+    }
+
+    #[test]
+    fn test_build_multi() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let rules = [("a", "1"), ("ab", "2"), (r"(?P<x>\w)(\d)", "<$2$x>")];
+        let check = |kind, input: &str, expected: &str| {
+            let replacer = opts
+                .build_multi(&rules, kind, false, UnicodeMode::Auto)
+                .unwrap();
+            let mut buf = vec![];
+            replacer.replace_into(&mut buf, input.as_bytes()).unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        };
+
+        check(MatchKind::LeftmostLongest, "ab a x9 a1", "2 1 <9x> <1a>");
+        check(MatchKind::LeftmostFirst, "ab a x9 a1", "1b 1 <9x> 11");
+
+        let err = opts
+            .build_multi(
+                &[("a", "$0"), ("(b)", "$2")],
+                MatchKind::default(),
+                false,
+                UnicodeMode::Auto,
+            )
+            .unwrap_err();
+        assert!(matches!(err, BuildError::MissingGroup(GroupRef::Index(2))));
+    }
+
+    #[test]
+    fn test_replacer_builder() {
+        #[track_caller]
+        fn check(builder: &ReplacerBuilder, pattern: &str, replacement: &str, expected: &str) {
+            let replacer = builder.build(pattern, replacement).unwrap();
+            let mut buf = vec![];
+            replacer.replace_into(&mut buf, b"a.b a.b axb").unwrap();
+            assert_eq!(String::from_utf8(buf).unwrap(), expected);
+        }
+
+        let mut builder = ReplacerBuilder::default();
+        check(&builder, "a.(b)", "[$1]", "[b] a.b axb");
+        builder.replace_all = true;
+        builder.escape = true;
+        check(&builder, "a.(b)", r"$1\t", "b\t b\t b\t");
+        builder.fixed_strings = true;
+        check(&builder, "a.b", r"$0\x21", "$0! $0! axb");
+        // the bytes given to build_unescaped are used as they are
+        let replacer = builder
+            .build_unescaped("a.b", b"\\x21\xff".to_vec())
+            .unwrap();
+        let mut buf = vec![];
+        replacer.replace_into(&mut buf, b"a.b").unwrap();
+        assert_eq!(buf, b"\\x21\xff");
+
+        builder.ignore_case = true;
+        assert!(matches!(
+            builder.build("a", ""),
+            Err(BuildError::Conflict("fixed_strings", "ignore_case"))
+        ));
+        builder.fixed_strings = false;
+        assert!(matches!(
+            builder.build("a", r"\q"),
+            Err(BuildError::Unescape(_))
+        ));
+        builder.escape = false;
+        builder.template_syntax = TemplateSyntax::Sed;
+        builder.no_expand = true;
+        assert!(matches!(
+            builder.build("a", ""),
+            Err(BuildError::Conflict("no_expand", "template_syntax"))
+        ));
+
+        let builder = ReplacerBuilder {
+            replace_all: true,
+            first_match_only: true,
+            ..Default::default()
+        };
+        assert!(matches!(
+            builder.validate(),
+            Err(BuildError::Conflict("replace_all", "first_match_only"))
+        ));
+    }
+
+    #[test]
+    fn test_max_replacement_size() {
+        let re = Regex::new(r"\w+").unwrap();
+        let mut replacer = Replacer::new(re, Template::parse("<$0>"));
+        replacer.replace_all = true;
+        replacer.max_replacement_size = Some(5);
+
+        let mut buf = vec![];
+        assert_eq!(replacer.replace_into(&mut buf, b"abc de").unwrap(), 2);
+        assert_eq!(buf, b"<abc> <de>");
+        assert!(matches!(
+            replacer.replace_into(&mut buf, b"abcd"),
+            Err(ReplaceError::ReplacementTooLarge { size: 6, limit: 5 })
+        ));
+    }
+
+    #[test]
+    fn test_grapheme_safe() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            grapheme_safe: true,
+            ..Default::default()
+        };
+        let replacer = opts.build(Regex::new("e").unwrap(), Template::literal("E"));
+        let mut buf = vec![];
+        assert_eq!(
+            replacer.replace_into(&mut buf, "hello".as_bytes()).unwrap(),
+            1
+        );
+        assert_eq!(buf, b"hEllo");
+        // 'é' as 'e' followed by a combining accent
+        let result = replacer.replace_into(&mut buf, "cafe\u{301}".as_bytes());
+        if cfg!(feature = "unicode") {
+            assert!(matches!(result, Err(ReplaceError::SplitsGrapheme(s)) if s == "e\u{301}"));
+        }
+    }
+
+    #[test]
+    fn test_preserve_case() {
+        for fixed_strings in [false, true] {
+            let builder = ReplacerBuilder {
+                fixed_strings,
+                replace_all: true,
+                preserve_case: true,
+                ..Default::default()
+            };
+            let replacer = builder.build("foo", "bar").unwrap();
+            let mut buf = vec![];
+            replacer.replace_into(&mut buf, b"foo Foo FOO fOo").unwrap();
+            assert_eq!(buf, b"bar Bar BAR bar");
+        }
+    }
+
+    #[test]
+    fn test_escape_pattern() {
+        let builder = ReplacerBuilder {
+            fixed_strings: true,
+            escape_pattern: true,
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = builder.build(r"\t\x00\xff", "|").unwrap();
+        let mut buf = vec![];
+        replacer
+            .replace_into(&mut buf, b"a\t\x00\xffb\t\x00")
+            .unwrap();
+        assert_eq!(buf, b"a|b\t\x00");
+        assert!(matches!(
+            builder.build(r"\q", ""),
+            Err(BuildError::UnescapePattern(_))
+        ));
+
+        // with preserve_case, ASCII letters still match in any case
+        let builder = ReplacerBuilder {
+            preserve_case: true,
+            ..builder
+        };
+        let mut buf = vec![];
+        builder
+            .build(r"x\x01", "y")
+            .unwrap()
+            .replace_into(&mut buf, b"X\x01")
+            .unwrap();
+        assert_eq!(buf, b"Y");
+    }
+
+    #[test]
+    fn test_require_groups() {
+        let build = |replacement| {
+            ReplacerBuilder::default()
+                .build(r"(?<year>\d{4})-(?<month>\d\d)", replacement)
+                .unwrap()
+        };
+        assert!(build("$month/$year")
+            .require_groups(&["year", "month"])
+            .is_ok());
+        assert!(build("$2/$1").require_groups(&["year", "month"]).is_ok());
+        assert!(build("x").require_groups::<&str>(&[]).is_ok());
+        assert!(matches!(
+            build("${month}").require_groups(&["year"]),
+            Err(BuildError::RequiredGroupUnused(name)) if name == "year"
+        ));
+        assert!(matches!(
+            build("$year").require_groups(&["day"]),
+            Err(BuildError::RequiredGroupMissing(name)) if name == "day"
+        ));
+    }
+
+    #[test]
+    fn test_word_regexp() {
+        let text = "cat concatenate cat_1 (cat) cat\n";
+        let replace = |builder: ReplacerBuilder, pattern: &str| {
+            let replacer = builder.build(pattern, "dog").unwrap();
+            let mut buf = vec![];
+            replacer.replace_into(&mut buf, text.as_bytes()).unwrap();
+            String::from_utf8(buf).unwrap()
+        };
+        let builder = ReplacerBuilder {
+            word_regexp: true,
+            replace_all: true,
+            ..Default::default()
+        };
+        for builder in [
+            builder.clone(),
+            ReplacerBuilder {
+                fixed_strings: true,
+                ..builder.clone()
+            },
+            ReplacerBuilder {
+                engine: Engine::AhoCorasick,
+                ..builder.clone()
+            },
+            ReplacerBuilder {
+                unicode: UnicodeMode::Never,
+                ..builder.clone()
+            },
+        ] {
+            assert_eq!(replace(builder, "cat"), "dog concatenate cat_1 (dog) dog\n");
+        }
+        assert_eq!(
+            replace(builder.clone(), "c[a-z]t"),
+            "dog concatenate cat_1 (dog) dog\n"
+        );
+        // the whole alternation is wrapped, not just its first and last branches
+        assert_eq!(
+            replace(builder, "cat|concat"),
+            "dog concatenate cat_1 (dog) dog\n"
+        );
+
+        assert_eq!(
+            is_word_boundary("aé".as_bytes(), 1),
+            !cfg!(feature = "unicode")
+        );
+        assert!(is_word_boundary(b"\xffa", 1));
+        assert!(!is_word_boundary(b"a_", 1));
+    }
+
+    #[test]
+    fn test_word_regexp_empty() {
+        let builder = ReplacerBuilder {
+            word_regexp: true,
+            replace_all: true,
+            ..Default::default()
+        };
+        for builder in [
+            builder.clone(),
+            ReplacerBuilder {
+                fixed_strings: true,
+                ..builder.clone()
+            },
+            ReplacerBuilder {
+                engine: Engine::AhoCorasick,
+                ..builder.clone()
+            },
+            ReplacerBuilder {
+                unicode: UnicodeMode::Never,
+                ..builder.clone()
+            },
+        ] {
+            let replacer = builder.build("", "X").unwrap();
+            for (text, expected) in [("a \n", "XaX \n"), ("  \n", "  \n"), ("a", "XaX")] {
+                let mut buf = vec![];
+                replacer.replace_into(&mut buf, text.as_bytes()).unwrap();
+                assert_eq!(String::from_utf8(buf).unwrap(), expected, "{builder:?}");
+            }
+        }
+        // a zero-width regex which isn't just the empty string
+        let replacer = builder.build("x?", "X").unwrap();
+        let mut buf = vec![];
+        replacer.replace_into(&mut buf, b"a \n").unwrap();
+        assert_eq!(buf, b"XaX \n");
+    }
+
+    #[test]
+    fn test_share_between_threads() {
+        fn assert_shareable<T: Send + Sync + Clone>() {}
+        assert_shareable::<Replacer<EnginePattern>>();
+        assert_shareable::<Replacer<Regex>>();
+        assert_shareable::<Replacer<MultiPattern>>();
+
+        let builder = ReplacerBuilder {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = builder
+            .build("(a+)", "<$1>")
+            .unwrap()
+            .then(builder.build("b", "c").unwrap());
+        let copy = replacer.clone();
+        assert!(Arc::ptr_eq(&replacer.template, &copy.template));
+        assert!(Arc::ptr_eq(&replacer.then, &copy.then));
+
+        std::thread::scope(|scope| {
+            let threads: Vec<_> = (0..4)
+                .map(|i| {
+                    let replacer = if i % 2 == 0 { &replacer } else { &copy };
+                    scope.spawn(move || {
+                        let mut out = vec![];
+                        let input = format!("{}b\n", "a".repeat(i + 1));
+                        replacer
+                            .replace_stream(&mut input.as_bytes(), &mut out)
+                            .unwrap();
+                        String::from_utf8(out).unwrap()
+                    })
+                })
+                .collect();
+            let outputs: Vec<_> = threads.into_iter().map(|t| t.join().unwrap()).collect();
+            assert_eq!(outputs, ["<a>c\n", "<aa>c\n", "<aaa>c\n", "<aaaa>c\n"]);
+        });
+
+        // chaining onto a clone doesn't change the original
+        let longer = copy.then(builder.build("c", "d").unwrap());
+        assert_eq!(replacer.then.len(), 1);
+        assert_eq!(longer.then.len(), 2);
+    }
+
+    #[test]
+    fn test_then() {
+        let builder = ReplacerBuilder {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = builder
+            .build("foo", "bar")
+            .unwrap()
+            .then(builder.build("bar", "baz").unwrap())
+            .then(builder.build("(a)z", "${1}y").unwrap());
+        let mut buf = b"> ".to_vec();
+        let mut edits = vec![];
+        let count = replacer
+            .replace_into_with_edits(&mut buf, b"foo bar-", &mut edits)
+            .unwrap();
+        // each replacer sees what the ones before it made
+        assert_eq!(count, 5);
+        assert_eq!(buf, b"> bay bay-");
+        assert_eq!(
+            edits,
+            [Edit {
+                old: 0..7,
+                new: 2..9
+            }]
+        );
+
+        // lines which only the chained replacers change still count
+        buf.clear();
+        assert_eq!(replacer.replace_into(&mut buf, b"baz").unwrap(), 1);
+        assert_eq!(buf, b"bay");
+    }
+
+    #[test]
+    fn test_anchor() {
+        let replace = |engine, pattern, anchor, text: &str| {
+            let opts = ReplaceOptions {
+                replace_all: true,
+                anchor: Some(anchor),
+                ..Default::default()
+            };
+            let replacer = opts
+                .build_engine(engine, pattern, "X", false, UnicodeMode::Auto)
+                .unwrap();
+            let mut out = vec![];
+            replacer
+                .replace_stream(&mut text.as_bytes(), &mut out)
+                .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        let text = "aa a\naa\na aa\n";
+        for engine in [Engine::Literal, Engine::AhoCorasick, Engine::Regex] {
+            assert_eq!(replace(engine, "aa", Anchor::Start, text), "X a\nX\na aa\n");
+            assert_eq!(replace(engine, "aa", Anchor::End, text), "aa a\nX\na X\n");
+            assert_eq!(replace(engine, "aa", Anchor::Only, text), "aa a\nX\na aa\n");
+        }
+        // the leftmost-first match "a" isn't at the end, but "ab" is
+        assert_eq!(replace(Engine::Regex, "a|ab", Anchor::End, "ab\n"), "X\n");
+        assert_eq!(replace(Engine::Auto, "b", Anchor::Only, "b\nbb"), "X\nbb");
+    }
+
+    #[test]
+    fn test_for_each_line_confirm() {
+        let re = Regex::new(r"o").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("0"));
+        replacer.replace_all = true;
+
+        let mut asked = vec![];
+        let mut out = vec![];
+        let stats = replacer
+            .for_each_line_confirm(
+                &mut &b"foo\nbob\n"[..],
+                |cand| {
+                    asked.push((cand.number, cand.old.clone()));
+                    assert_eq!(cand.new, b"0");
+                    asked.len() != 2
+                },
+                |line| {
+                    assert_eq!(line.edits.len(), line.count);
+                    out.extend_from_slice(line.new);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(asked, [(1, 1..2), (1, 2..3), (2, 1..2)]);
+        assert_eq!(out, b"f0o\nb0b\n");
+        assert_eq!(
+            stats,
+            Stats {
+                replacements: 2,
+                lines: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_stats() {
+        let re = Regex::new(r"o").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("0"));
+        replacer.replace_all = true;
+        let stats = replacer
+            .replace_stream(&mut &b"foo\nbar\nboo\n"[..], &mut io::sink())
+            .unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                replacements: 4,
+                lines: 2
+            }
+        );
+    }
+
+    #[test]
+    fn test_delete_lines() {
+        let re = Regex::new(r"o").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal(""));
+        replacer.delete_lines = true;
+        replacer.replace_all = true;
+
+        let mut out = vec![];
+        let stats = replacer
+            .replace_stream(&mut &b"foo\nbar\nboo\nbaz"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"bar\nbaz");
+        assert_eq!(
+            stats,
+            Stats {
+                replacements: 2,
+                lines: 2
+            }
+        );
+
+        let mut lines = vec![];
+        replacer
+            .for_each_line(&mut &b"bar\nfoo\n"[..], |line| {
+                lines.push((line.new.to_vec(), line.edits.to_vec()));
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            lines,
+            [
+                (b"bar\n".to_vec(), vec![]),
+                (
+                    vec![],
+                    vec![Edit {
+                        old: 0..4,
+                        new: 0..0
+                    }]
+                ),
+            ]
+        );
+
+        // with only_matches there's nothing left to print
+        replacer.only_matches = true;
+        out.clear();
+        replacer
+            .replace_stream(&mut &b"foo\nbar\n"[..], &mut out)
+            .unwrap();
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn test_first_match_only() {
+        let re = Regex::new(r"o").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("0"));
+        replacer.first_match_only = true;
+        replacer.replace_all = true;
+
+        let mut out = vec![];
+        let count = replacer
+            .replace_stream(&mut &b"abc\nfoo\nbob\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(
+            count,
+            Stats {
+                replacements: 1,
+                lines: 1
+            }
+        );
+        assert_eq!(out, b"abc\nf0o\nbob\n");
+
+        replacer.only_matches = true;
+        out.clear();
+        replacer
+            .replace_stream(&mut &b"foo\nbob\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"f0o\n");
+    }
+
+    #[test]
+    fn test_max_per_line_and_file() {
+        let re = Regex::new(r"o").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("0"));
+        replacer.max_per_line = Some(2);
+
+        let mut out = vec![];
+        replacer
+            .replace_stream(&mut &b"ooo\nfoo\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"00o\nf00\n");
+
+        replacer.max_per_file = Some(3);
+        out.clear();
+        let stats = replacer
+            .replace_stream(&mut &b"ooo\nfoo\nbob\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"00o\nf0o\nbob\n");
+        assert_eq!(
+            stats,
+            Stats {
+                replacements: 3,
+                lines: 2
+            }
+        );
+
+        // the tighter limit wins
+        replacer.max_per_line = None;
+        replacer.replace_all = true;
+        replacer.first_match_only = true;
+        out.clear();
+        replacer
+            .replace_stream(&mut &b"ooo\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"0oo\n");
+
+        replacer.first_match_only = false;
+        replacer.max_per_line = Some(0);
+        let mut buf = vec![];
+        assert_eq!(replacer.replace_into(&mut buf, b"foo").unwrap(), 0);
+        assert_eq!(buf, b"foo");
+    }
+
+    #[test]
+    fn test_address() {
+        let re = Regex::new(r"x").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("-"));
+        let text = b"x1\nx2 start\nx3\nx4 end\nx5\nx6 start\nx7\n";
+        let check = |replacer: &Replacer<Regex>, expected: &str| {
+            let mut out = vec![];
+            replacer.replace_stream(&mut &text[..], &mut out).unwrap();
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+        };
+
+        replacer.address = Address::new(
+            Some(LineRange {
+                start: 2,
+                end: Some(3),
+            }),
+            None,
+            None,
+        )
+        .unwrap();
+        check(&replacer, "x1\n-2 start\n-3\nx4 end\nx5\nx6 start\nx7\n");
+
+        replacer.address = Address::new(None, Some("start"), Some("end$")).unwrap();
+        check(&replacer, "x1\n-2 start\n-3\n-4 end\nx5\n-6 start\n-7\n");
+
+        replacer.address = Address::new(None, None, Some("start")).unwrap();
+        check(&replacer, "-1\n-2 start\nx3\nx4 end\nx5\nx6 start\nx7\n");
+
+        replacer.address = Address::new(
+            Some(LineRange {
+                start: 5,
+                end: None,
+            }),
+            Some("start"),
+            None,
+        )
+        .unwrap();
+        check(&replacer, "x1\nx2 start\nx3\nx4 end\n-5\n-6 start\n-7\n");
+
+        assert!(Address::new(None, Some("("), None).is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        let re = Regex::new(r"(?m)^b\nc$").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("bc"));
+        replacer.records = Records::Paragraphs;
+
+        let mut numbers = vec![];
+        let mut out = vec![];
+        let count = replacer
+            .for_each_line(&mut &b"a\nb\nc\n\n\r\nb\nc"[..], |line| {
+                numbers.push(line.number);
+                out.extend_from_slice(line.new);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            count,
+            Stats {
+                replacements: 2,
+                lines: 2
+            }
+        );
+        assert_eq!(out, b"a\nbc\n\n\r\nbc");
+        assert_eq!(numbers, [1, 4, 5, 6]);
+
+        replacer.records = Records::Lines;
+        let mut out = vec![];
+        replacer
+            .replace_stream(&mut &b"b\nc\n"[..], &mut out)
+            .unwrap();
+        assert_eq!(out, b"b\nc\n");
+
+        // whole-input mode matches across blank lines too
+        let re = Regex::new(r"(?s)a.*c").unwrap();
+        let mut replacer = Replacer::new(re, Template::literal("X"));
+        replacer.records = Records::Whole;
+        let mut out = vec![];
+        let count = replacer
+            .for_each_line(&mut &b"a\nb\n\nc\nd\n"[..], |line| {
+                assert_eq!(line.number, 1);
+                out.extend_from_slice(line.new);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            count,
+            Stats {
+                replacements: 1,
+                lines: 1
+            }
+        );
+        assert_eq!(out, b"X\nd\n");
+    }
+
+    #[test]
+    fn test_null_data() {
+        let opts = ReplaceOptions {
+            null_data: true,
+            output_delimiter: Some(b";".to_vec()),
+            ..Default::default()
+        };
+        let replacer = opts
+            .build_engine(Engine::Regex, "b.c$", "X", false, UnicodeMode::Auto)
+            .unwrap();
+        let mut out = vec![];
+        let count = replacer
+            .replace_stream(&mut &b"ab\nc\0b\nc\nd\0ab\nc"[..], &mut out)
+            .unwrap();
+        assert_eq!(
+            count,
+            Stats {
+                replacements: 2,
+                lines: 2
+            }
+        );
+        assert_eq!(out, b"aX;b\nc\nd;aX");
+    }
+
+    #[test]
+    fn test_max_output_line() {
+        let re = Regex::new(r"").unwrap();
+        let mut replacer = Replacer::new(re, Template::parse("--"));
+        replacer.replace_all = true;
+        replacer.max_output_line = Some(8);
+
+        let mut buf = b"prefix".to_vec();
+        assert_eq!(replacer.replace_into(&mut buf, b"ab").unwrap(), 3);
+        assert_eq!(buf, b"prefix--a--b--");
+        assert!(matches!(
+            replacer.replace_into(&mut buf, b"abc"),
+            Err(ReplaceError::OutputTooLarge { size: 11, limit: 8 })
+        ));
+        // the text after the last match counts too
+        replacer.replace_all = false;
+        assert!(matches!(
+            replacer.replace_into(&mut vec![], b"abcdefg"),
+            Err(ReplaceError::OutputTooLarge { size: 9, limit: 8 })
+        ));
+    }
+
+    #[test]
+    fn test_is_ascii_pattern() {
+        assert!(is_ascii_pattern("foo", false));
+        assert!(is_ascii_pattern("^foo[a-z]+(bar|baz)?$", false));
+        assert!(is_ascii_pattern(r"\x41\t", false));
+        assert!(is_ascii_pattern(r"[[:alpha:]]", false));
+        assert!(is_ascii_pattern(r"(?-u:\w)", false));
+        assert!(is_ascii_pattern("foo", true));
+
+        assert!(!is_ascii_pattern(".", false));
+        assert!(!is_ascii_pattern(r"\w+", false));
+        assert!(!is_ascii_pattern(r"\d", false));
+        assert!(!is_ascii_pattern(r"\bfoo", false));
+        assert!(!is_ascii_pattern("[^a]", false));
+        assert!(!is_ascii_pattern("é", false));
+        assert!(!is_ascii_pattern("é", true));
+        assert!(!is_ascii_pattern("(unclosed", false));
+        // only Unicode case folding knows that "k" also matches the Kelvin sign, and without it
+        // -I still has to work
+        assert_eq!(is_ascii_pattern("k", true), !cfg!(feature = "unicode"));
+        assert_eq!(is_ascii_pattern("(?i)k", false), !cfg!(feature = "unicode"));
+    }
 }
@@ -0,0 +1,235 @@
+//! `--rules-file`: read named pattern and replacement pairs from a file.
+//!
+//! The file is a small subset of TOML, with a table for each rule:
+//!
+//! ```toml
+//! # comments start with '#'
+//! [color]
+//! pattern = 'colou?r'
+//! replacement = "hue"
+//! ```
+//!
+//! Values are basic strings in double quotes, which support the usual TOML escapes, or literal
+//! strings in single quotes, which don't and are handy for regexes.
+
+use std::collections::HashSet;
+
+#[derive(Debug, thiserror::Error)]
+pub enum RulesError {
+    #[error("line {0}: expected '[name]' or 'key = \"value\"'")]
+    Syntax(usize),
+    #[error("line {0}: invalid string")]
+    InvalidString(usize),
+    #[error("line {0}: unknown key '{1}', expected 'pattern' or 'replacement'")]
+    UnknownKey(usize, String),
+    #[error("line {0}: '{1}' was already set")]
+    DuplicateKey(usize, String),
+    #[error("line {0}: key outside of a rule, add a '[name]' line before it")]
+    NoRule(usize),
+    #[error("line {0}: rule '{1}' was already defined")]
+    DuplicateRule(usize, String),
+    #[error("rule '{0}' has no {1}")]
+    Missing(String, &'static str),
+    #[error("no rules were found")]
+    Empty,
+}
+
+/// One pattern and replacement pair.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    pub name: String,
+    pub pattern: String,
+    pub replacement: String,
+}
+
+/// A rule which is still being parsed.
+struct PartialRule {
+    name: String,
+    pattern: Option<String>,
+    replacement: Option<String>,
+}
+
+impl PartialRule {
+    fn finish(self) -> Result<Rule, RulesError> {
+        match (self.pattern, self.replacement) {
+            (Some(pattern), Some(replacement)) => Ok(Rule {
+                name: self.name,
+                pattern,
+                replacement,
+            }),
+            (None, _) => Err(RulesError::Missing(self.name, "pattern")),
+            (_, None) => Err(RulesError::Missing(self.name, "replacement")),
+        }
+    }
+}
+
+/// Parse the text of a rules file. The rules are returned in the order they're defined.
+pub fn parse(text: &str) -> Result<Vec<Rule>, RulesError> {
+    let mut rules = vec![];
+    let mut names = HashSet::new();
+    let mut current: Option<PartialRule> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        if let Some(rest) = line.strip_prefix('[') {
+            let (name, rest) = rest.split_once(']').ok_or(RulesError::Syntax(number))?;
+            let name = name.trim();
+            if name.is_empty() || !is_comment(rest) {
+                return Err(RulesError::Syntax(number));
+            }
+            if !names.insert(name.to_owned()) {
+                return Err(RulesError::DuplicateRule(number, name.to_owned()));
+            }
+            if let Some(rule) = current.take() {
+                rules.push(rule.finish()?);
+            }
+            current = Some(PartialRule {
+                name: name.to_owned(),
+                pattern: None,
+                replacement: None,
+            });
+            continue;
+        }
+
+        let (key, value) = line.split_once('=').ok_or(RulesError::Syntax(number))?;
+        let key = key.trim();
+        let (value, rest) =
+            parse_string(value.trim_start()).ok_or(RulesError::InvalidString(number))?;
+        if !is_comment(rest) {
+            return Err(RulesError::Syntax(number));
+        }
+        let rule = current.as_mut().ok_or(RulesError::NoRule(number))?;
+        let slot = match key {
+            "pattern" => &mut rule.pattern,
+            "replacement" => &mut rule.replacement,
+            _ => return Err(RulesError::UnknownKey(number, key.to_owned())),
+        };
+        if slot.replace(value).is_some() {
+            return Err(RulesError::DuplicateKey(number, key.to_owned()));
+        }
+    }
+
+    if let Some(rule) = current {
+        rules.push(rule.finish()?);
+    }
+    if rules.is_empty() {
+        return Err(RulesError::Empty);
+    }
+    Ok(rules)
+}
+
+/// Whether the rest of a line is empty or a comment.
+fn is_comment(rest: &str) -> bool {
+    let rest = rest.trim_start();
+    rest.is_empty() || rest.starts_with('#')
+}
+
+/// Parse a quoted string at the start of `s`, returning its value and the text after it.
+fn parse_string(s: &str) -> Option<(String, &str)> {
+    if let Some(rest) = s.strip_prefix('\'') {
+        let (value, rest) = rest.split_once('\'')?;
+        return Some((value.to_owned(), rest));
+    }
+
+    let mut chars = s.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            // +1 for the opening quote and +1 for the closing one
+            '"' => return Some((value, &s[i + 2..])),
+            '\\' => {
+                let (_, escape) = chars.next()?;
+                let c = match escape {
+                    'b' => '\u{8}',
+                    't' => '\t',
+                    'n' => '\n',
+                    'f' => '\u{c}',
+                    'r' => '\r',
+                    '"' => '"',
+                    '\\' => '\\',
+                    'u' | 'U' => {
+                        let len = if escape == 'u' { 4 } else { 8 };
+                        let hex: String = chars.by_ref().take(len).map(|(_, c)| c).collect();
+                        if hex.len() != len {
+                            return None;
+                        }
+                        char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                    }
+                    _ => return None,
+                };
+                value.push(c);
+            }
+            _ => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(name: &str, pattern: &str, replacement: &str) -> Rule {
+        Rule {
+            name: name.to_owned(),
+            pattern: pattern.to_owned(),
+            replacement: replacement.to_owned(),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = r#"
+# spelling
+[color]
+pattern = 'colou?r\b'   # a literal string
+replacement = "hue"
+
+  [ tabs ]
+replacement = "\t\u00e9\\"
+pattern = "\\s{4}"
+"#;
+        assert_eq!(
+            parse(text).unwrap(),
+            [
+                rule("color", r"colou?r\b", "hue"),
+                rule("tabs", r"\s{4}", "\t\u{e9}\\"),
+            ]
+        );
+
+        let error = |text| parse(text).unwrap_err().to_string();
+        assert_eq!(error(""), "no rules were found");
+        assert_eq!(
+            error("pattern = 'x'"),
+            "line 1: key outside of a rule, add a '[name]' line before it"
+        );
+        assert_eq!(error("[a]\npattern = 'x'"), "rule 'a' has no replacement");
+        assert_eq!(
+            error("[a]\npattern = 'x'\nreplacement = ''\n[a]"),
+            "line 4: rule 'a' was already defined"
+        );
+        assert_eq!(
+            error("[a]\npattern = 'x'\npattern = 'y'"),
+            "line 3: 'pattern' was already set"
+        );
+        assert_eq!(
+            error("[a]\nflags = 'g'"),
+            "line 2: unknown key 'flags', expected 'pattern' or 'replacement'"
+        );
+        assert_eq!(error("[a]\npattern = 'x"), "line 2: invalid string");
+        assert_eq!(error("[a]\npattern = \"\\q\""), "line 2: invalid string");
+        assert_eq!(
+            error("[a]\npattern = 'x' 'y'"),
+            "line 2: expected '[name]' or 'key = \"value\"'"
+        );
+        assert_eq!(
+            error("[a] b"),
+            "line 1: expected '[name]' or 'key = \"value\"'"
+        );
+    }
+}
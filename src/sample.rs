@@ -0,0 +1,171 @@
+//! `--sample`: show a random sample of the replacements that would be made.
+
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use rp::replace::{Candidate, Pattern, Replacer, StreamError};
+
+use crate::prompt::write_candidate;
+
+/// One sampled replacement.
+#[derive(Debug)]
+struct Sample {
+    /// Which input the sample came from, as an index into `Sampler::paths`
+    input: usize,
+    number: usize,
+    /// The original line
+    text: Vec<u8>,
+    old: Range<usize>,
+    new: Vec<u8>,
+}
+
+/// Picks a uniformly random sample of the replacements in all inputs, without having to keep
+/// more than the sample in memory.
+#[derive(Debug)]
+pub struct Sampler {
+    size: usize,
+    rng: fastrand::Rng,
+    samples: Vec<Sample>,
+    paths: Vec<PathBuf>,
+    /// The number of replacements seen so far
+    seen: usize,
+}
+
+impl Sampler {
+    pub fn new(size: usize) -> Sampler {
+        Sampler::with_rng(size, fastrand::Rng::new())
+    }
+
+    fn with_rng(size: usize, rng: fastrand::Rng) -> Sampler {
+        Sampler {
+            size,
+            rng,
+            samples: vec![],
+            paths: vec![],
+            seen: 0,
+        }
+    }
+
+    /// Find the replacements in one input, named `path` in the output, and add them to the
+    /// sample. Nothing is written anywhere. Returns the number of replacements found.
+    pub fn add_input<P, R>(
+        &mut self,
+        replacer: &Replacer<P>,
+        input: &mut R,
+        path: &Path,
+    ) -> Result<usize, StreamError>
+    where
+        P: Pattern,
+        R: BufRead,
+    {
+        let index = self.paths.len();
+        self.paths.push(path.to_owned());
+        let stats = replacer.for_each_line(input, |line| {
+            for edit in line.edits {
+                self.seen += 1;
+                // reservoir sampling: the nth replacement replaces a random sample with
+                // probability size/n
+                let slot = if self.samples.len() < self.size {
+                    self.samples.len()
+                } else {
+                    match self.rng.usize(..self.seen) {
+                        slot if slot < self.size => slot,
+                        _ => continue,
+                    }
+                };
+                let sample = Sample {
+                    input: index,
+                    number: line.number,
+                    text: line.old.to_vec(),
+                    old: edit.old.clone(),
+                    new: line.new[edit.new.clone()].to_vec(),
+                };
+                if slot == self.samples.len() {
+                    self.samples.push(sample);
+                } else {
+                    self.samples[slot] = sample;
+                }
+            }
+            Ok(())
+        })?;
+        Ok(stats.replacements)
+    }
+
+    /// Write the sampled replacements in the order they were found, followed by a summary.
+    pub fn write<W: Write>(mut self, output: &mut W, color: bool) -> io::Result<()> {
+        self.samples
+            .sort_by_key(|sample| (sample.input, sample.number, sample.old.start));
+        for sample in &self.samples {
+            let candidate = Candidate {
+                number: sample.number,
+                text: &sample.text,
+                old: sample.old.clone(),
+                new: &sample.new,
+            };
+            write_candidate(output, &self.paths[sample.input], &candidate, color)?;
+        }
+        let plural = |n| if n == 1 { "" } else { "s" };
+        writeln!(
+            output,
+            "sampled {} of {} replacement{} in {} input{}",
+            self.samples.len(),
+            self.seen,
+            plural(self.seen),
+            self.paths.len(),
+            plural(self.paths.len()),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::bytes::Regex;
+
+    use rp::replace::ReplaceOptions;
+    use rp::template::Template;
+
+    #[test]
+    fn test_sampler() {
+        let opts = ReplaceOptions {
+            replace_all: true,
+            ..Default::default()
+        };
+        let replacer = opts.build(Regex::new("o").unwrap(), Template::literal("0"));
+        let text = "foo\nbar\n".repeat(100);
+
+        let mut sampler = Sampler::with_rng(3, fastrand::Rng::with_seed(1));
+        for name in ["a", "b"] {
+            let count = sampler
+                .add_input(&replacer, &mut text.as_bytes(), Path::new(name))
+                .unwrap();
+            assert_eq!(count, 200);
+        }
+        assert_eq!(sampler.samples.len(), 3);
+        assert_eq!(sampler.seen, 400);
+        for sample in &sampler.samples {
+            assert_eq!(sample.text, b"foo\n");
+            assert_eq!(sample.new, b"0");
+            assert_eq!(sample.number % 2, 1);
+        }
+
+        let mut out = vec![];
+        sampler.write(&mut out, false).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert_eq!(out.matches("\n-foo\n").count(), 3);
+        assert!(out.ends_with("sampled 3 of 400 replacements in 2 inputs\n"));
+
+        // everything is kept when there aren't enough replacements to sample
+        let mut sampler = Sampler::new(10);
+        sampler
+            .add_input(&replacer, &mut &b"bob\n"[..], Path::new("-"))
+            .unwrap();
+        let mut out = vec![];
+        sampler.write(&mut out, false).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "-:1:\n-bob\n+b0b\nsampled 1 of 1 replacement in 1 input\n"
+        );
+    }
+}
@@ -0,0 +1,112 @@
+//! `--sandbox`: only allow in-place edits of the files chosen before any work starts.
+
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The set of files which may be modified, pinned before any work starts.
+///
+/// Each file is identified by the canonical path of its directory plus its file name. Files are
+/// replaced by renaming a new file over them, so that's the directory entry which is written, even
+/// if the file itself is a symlink. Resolving the directory when the file is about to be replaced
+/// catches a directory which was swapped for a symlink after the sandbox was set up.
+#[derive(Debug, Default)]
+pub struct Sandbox {
+    allowed: HashSet<PathBuf>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SandboxError {
+    #[error("unable to resolve '{0}'")]
+    Resolve(PathBuf, #[source] io::Error),
+    #[error("'{0}' isn't one of the files the sandbox allows to be modified")]
+    NotAllowed(PathBuf),
+}
+
+impl Sandbox {
+    /// Create a sandbox which allows modifying only `files`, which must already exist.
+    pub fn new<P: AsRef<Path>>(files: &[P]) -> Result<Sandbox, SandboxError> {
+        let allowed = files
+            .iter()
+            .map(|path| pin(path.as_ref()))
+            .collect::<Result<_, _>>()?;
+        Ok(Sandbox { allowed })
+    }
+
+    /// Check that `path` may be modified.
+    pub fn check(&self, path: &Path) -> Result<(), SandboxError> {
+        if self.allowed.contains(&pin(path)?) {
+            Ok(())
+        } else {
+            Err(SandboxError::NotAllowed(path.to_owned()))
+        }
+    }
+}
+
+/// Get the canonical directory entry of a file which exists.
+fn pin(path: &Path) -> Result<PathBuf, SandboxError> {
+    let resolve_err = |err| SandboxError::Resolve(path.to_owned(), err);
+    // make sure the file exists, without following a final symlink
+    fs::symlink_metadata(path).map_err(resolve_err)?;
+    let name = path.file_name().ok_or_else(|| {
+        resolve_err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "not a file name",
+        ))
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    Ok(fs::canonicalize(dir).map_err(resolve_err)?.join(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sandbox() {
+        let dir = tempfile::tempdir().unwrap();
+        let allowed = dir.path().join("allowed");
+        let other = dir.path().join("other");
+        fs::write(&allowed, "").unwrap();
+        fs::write(&other, "").unwrap();
+
+        let sandbox = Sandbox::new(&[&allowed]).unwrap();
+        sandbox.check(&allowed).unwrap();
+        sandbox
+            .check(&dir.path().join(".").join("allowed"))
+            .unwrap();
+        assert!(matches!(
+            sandbox.check(&other),
+            Err(SandboxError::NotAllowed(_))
+        ));
+        assert!(matches!(
+            Sandbox::new(&[dir.path().join("missing")]),
+            Err(SandboxError::Resolve(..))
+        ));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_sandbox_symlink_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let sub = dir.path().join("sub");
+        let elsewhere = dir.path().join("elsewhere");
+        fs::create_dir(&sub).unwrap();
+        fs::create_dir(&elsewhere).unwrap();
+        fs::write(sub.join("file"), "").unwrap();
+        fs::write(elsewhere.join("file"), "").unwrap();
+
+        let sandbox = Sandbox::new(&[sub.join("file")]).unwrap();
+        // swap the directory for a symlink to somewhere else
+        fs::rename(&sub, dir.path().join("sub.orig")).unwrap();
+        std::os::unix::fs::symlink(&elsewhere, &sub).unwrap();
+        assert!(matches!(
+            sandbox.check(&sub.join("file")),
+            Err(SandboxError::NotAllowed(_))
+        ));
+    }
+}
@@ -0,0 +1,335 @@
+//! `rp --server`: make replacements requested as newline-delimited JSON on stdin.
+//!
+//! Each request is one line containing a JSON object:
+//!
+//! ```text
+//! {"id": 1, "pattern": "foo(\\d)", "replacement": "bar$1", "text": "foo1\n", "options": {"replace_all": true}}
+//! ```
+//!
+//! Exactly one of "text" or "file" gives the input. "id" is optional and is copied into the
+//! response unchanged. "options" is optional, its keys are the long names of the corresponding
+//! command line flags with underscores: fixed_strings, ignore_case, escape, no_expand,
+//! replace_all, only_matches, and in_place (which requires "file").
+//!
+//! Instead of "pattern" and "replacement", a request can have "rules", an array of objects which
+//! each have their own "pattern" and "replacement". All the patterns are searched for at once,
+//! and when several match at the same position the longest match wins, or the first rule with
+//! the leftmost_first option.
+//!
+//! Each response is also one line of JSON, either `{"id": 1, "ok": true, "replacements": 1,
+//! "text": "bar1\n"}` or `{"id": 1, "ok": false, "error": "..."}`. In-place requests have no
+//! "text" in their response. Output which isn't valid UTF-8 is converted lossily.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+use anyhow::Context;
+
+use crate::json::{self, Value};
+use rp::replace::{MatchKind, Pattern, Replacer, ReplacerBuilder, UnicodeMode};
+
+/// Flags which can be set in a request's "options" object.
+#[derive(Debug, Default)]
+struct Options {
+    fixed_strings: bool,
+    ignore_case: bool,
+    escape: bool,
+    no_expand: bool,
+    replace_all: bool,
+    only_matches: bool,
+    in_place: bool,
+    leftmost_first: bool,
+}
+
+impl Options {
+    fn from_json(value: Option<&Value>) -> anyhow::Result<Options> {
+        let mut opts = Options::default();
+        let fields = match value {
+            None | Some(Value::Null) => return Ok(opts),
+            Some(Value::Object(fields)) => fields,
+            Some(_) => anyhow::bail!("\"options\" must be an object"),
+        };
+        for (key, value) in fields {
+            let flag = match key.as_str() {
+                "fixed_strings" => &mut opts.fixed_strings,
+                "ignore_case" => &mut opts.ignore_case,
+                "escape" => &mut opts.escape,
+                "no_expand" => &mut opts.no_expand,
+                "replace_all" => &mut opts.replace_all,
+                "only_matches" => &mut opts.only_matches,
+                "in_place" => &mut opts.in_place,
+                "leftmost_first" => &mut opts.leftmost_first,
+                _ => anyhow::bail!("unknown option \"{key}\""),
+            };
+            *flag = value
+                .as_bool()
+                .with_context(|| format!("option \"{key}\" must be a boolean"))?;
+        }
+        Ok(opts)
+    }
+}
+
+/// Where a request's input comes from.
+enum Input<'a> {
+    Text(&'a str),
+    File(&'a Path),
+}
+
+/// Get a required string field from a request.
+fn get_str<'a>(req: &'a Value, key: &str) -> anyhow::Result<&'a str> {
+    req.get(key)
+        .with_context(|| format!("missing \"{key}\""))?
+        .as_str()
+        .with_context(|| format!("\"{key}\" must be a string"))
+}
+
+/// Handle one request line and build the response.
+fn handle_request(line: &str) -> Value {
+    let (id, result) = match json::parse(line) {
+        Ok(req) => (req.get("id").cloned(), process(&req)),
+        Err(err) => (None, Err(err.into())),
+    };
+
+    let mut response = vec![("id", id.unwrap_or(Value::Null))];
+    match result {
+        Ok((count, text)) => {
+            response.push(("ok", true.into()));
+            response.push(("replacements", count.into()));
+            if let Some(text) = text {
+                response.push(("text", String::from_utf8_lossy(&text).into_owned().into()));
+            }
+        }
+        Err(err) => {
+            response.push(("ok", false.into()));
+            response.push(("error", format!("{err:#}").into()));
+        }
+    }
+    Value::object(response)
+}
+
+/// Make the replacements for a request. Returns the replacement count and, unless the file was
+/// modified in-place, the output text.
+fn process(req: &Value) -> anyhow::Result<(usize, Option<Vec<u8>>)> {
+    if !matches!(req, Value::Object(_)) {
+        anyhow::bail!("request must be an object");
+    }
+
+    let opts = Options::from_json(req.get("options"))?;
+    let input = match (req.get("text"), req.get("file")) {
+        (Some(_), None) => Input::Text(get_str(req, "text")?),
+        (None, Some(_)) => Input::File(Path::new(get_str(req, "file")?)),
+        _ => anyhow::bail!("exactly one of \"text\" or \"file\" is required"),
+    };
+    if opts.in_place && !matches!(input, Input::File(_)) {
+        anyhow::bail!("in_place requires \"file\"");
+    }
+
+    let builder = ReplacerBuilder {
+        fixed_strings: opts.fixed_strings,
+        ignore_case: opts.ignore_case,
+        escape: opts.escape,
+        no_expand: opts.no_expand,
+        replace_all: opts.replace_all,
+        only_matches: opts.only_matches,
+        ..Default::default()
+    };
+    builder.validate()?;
+
+    if let Some(rules) = req.get("rules") {
+        if req.get("pattern").is_some() || req.get("replacement").is_some() {
+            anyhow::bail!("\"rules\" can't be used with \"pattern\" or \"replacement\"");
+        }
+        let rules = rules
+            .as_array()
+            .context("\"rules\" must be an array")?
+            .iter()
+            .map(|rule| {
+                let pattern = get_str(rule, "pattern")?;
+                let pattern = if opts.fixed_strings {
+                    regex::escape(pattern)
+                } else {
+                    pattern.to_owned()
+                };
+                Ok((pattern, builder.unescape(get_str(rule, "replacement")?)?))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let kind = if opts.leftmost_first {
+            MatchKind::LeftmostFirst
+        } else {
+            MatchKind::LeftmostLongest
+        };
+        let replacer =
+            builder
+                .options()
+                .build_multi(&rules, kind, opts.ignore_case, UnicodeMode::Auto)?;
+        return run_replacer(&replacer, &input, opts.in_place);
+    }
+
+    let pattern = get_str(req, "pattern")?;
+    let replacement = get_str(req, "replacement")?;
+    let replacer = builder.build(pattern, replacement)?;
+    run_replacer(&replacer, &input, opts.in_place)
+}
+
+fn run_replacer<P: Pattern>(
+    replacer: &Replacer<P>,
+    input: &Input,
+    in_place: bool,
+) -> anyhow::Result<(usize, Option<Vec<u8>>)> {
+    let mut output = vec![];
+    let stats = match *input {
+        Input::Text(text) => replacer.replace_stream(&mut text.as_bytes(), &mut output)?,
+        Input::File(path) if in_place => {
+            let count = crate::replace_one_inplace(replacer, path, &Default::default())
+                .with_context(|| path.display().to_string())?;
+            return Ok((count, None));
+        }
+        Input::File(path) => {
+            let file =
+                File::open(path).with_context(|| format!("unable to open '{}'", path.display()))?;
+            replacer
+                .replace_stream(&mut BufReader::new(file), &mut output)
+                .with_context(|| path.display().to_string())?
+        }
+    };
+    Ok((stats.replacements, Some(output)))
+}
+
+/// Serve requests from stdin until it's closed.
+pub fn run() -> anyhow::Result<()> {
+    let mut stdout = io::stdout().lock();
+    for line in io::stdin().lock().lines() {
+        let line = line.context("failed to read request")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        writeln!(stdout, "{}", handle_request(&line))?;
+        // flush after every response since the client is waiting on it
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn check(request: &str, expected: &str) {
+        assert_eq!(handle_request(request).to_string(), expected);
+    }
+
+    #[test]
+    fn test_text_requests() {
+        check(
+            r#"{"id": 1, "pattern": "o(\\w)", "replacement": "[$1]", "text": "foo bob\nyes\n"}"#,
+            r#"{"id":1,"ok":true,"replacements":1,"text":"f[o] bob\nyes\n"}"#,
+        );
+        check(
+            r#"{"id": "a", "pattern": "O", "replacement": "0", "text": "foo bob",
+                "options": {"ignore_case": true, "replace_all": true}}"#,
+            r#"{"id":"a","ok":true,"replacements":3,"text":"f00 b0b"}"#,
+        );
+        check(
+            r#"{"pattern": "$x", "replacement": "\\t", "text": "a$xb\nc\n",
+                "options": {"fixed_strings": true, "escape": true, "only_matches": true}}"#,
+            r#"{"id":null,"ok":true,"replacements":1,"text":"a\tb\n"}"#,
+        );
+    }
+
+    #[test]
+    fn test_rules_requests() {
+        check(
+            r#"{"rules": [{"pattern": "a", "replacement": "1"}, {"pattern": "ab", "replacement": "2"},
+                          {"pattern": "(c)", "replacement": "[$1]"}],
+                "text": "a ab abc", "options": {"replace_all": true}}"#,
+            r#"{"id":null,"ok":true,"replacements":4,"text":"1 2 2[c]"}"#,
+        );
+        check(
+            r#"{"rules": [{"pattern": "a", "replacement": "1"}, {"pattern": "ab", "replacement": "2"}],
+                "text": "ab", "options": {"leftmost_first": true}}"#,
+            r#"{"id":null,"ok":true,"replacements":1,"text":"1b"}"#,
+        );
+        check(
+            r#"{"rules": [{"pattern": "a.", "replacement": "$0"}], "text": "a. ab",
+                "options": {"fixed_strings": true}}"#,
+            r#"{"id":null,"ok":true,"replacements":1,"text":"$0 ab"}"#,
+        );
+    }
+
+    #[test]
+    fn test_file_requests() {
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        file.write_all(b"one two\n").unwrap();
+        let path = file.path().to_str().unwrap().replace('\\', "\\\\");
+
+        check(
+            &format!(r#"{{"pattern": "two", "replacement": "2", "file": "{path}"}}"#),
+            r#"{"id":null,"ok":true,"replacements":1,"text":"one 2\n"}"#,
+        );
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"one two\n");
+
+        check(
+            &format!(
+                r#"{{"pattern": "one", "replacement": "1", "file": "{path}",
+                     "options": {{"in_place": true}}}}"#
+            ),
+            r#"{"id":null,"ok":true,"replacements":1}"#,
+        );
+        assert_eq!(std::fs::read(file.path()).unwrap(), b"1 two\n");
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |request: &str| {
+            let response = handle_request(request);
+            assert_eq!(response.get("ok"), Some(&Value::Bool(false)), "{request}");
+            response.get("error").unwrap().as_str().unwrap().to_owned()
+        };
+
+        assert!(error("not json").starts_with("invalid JSON"));
+        assert_eq!(error("[]"), "request must be an object");
+        assert_eq!(
+            error(r#"{"replacement": "", "text": ""}"#),
+            "missing \"pattern\""
+        );
+        assert_eq!(
+            error(r#"{"pattern": "a", "replacement": "b"}"#),
+            "exactly one of \"text\" or \"file\" is required"
+        );
+        assert_eq!(
+            error(
+                r#"{"pattern": "a", "replacement": "b", "text": "", "options": {"bogus": true}}"#
+            ),
+            "unknown option \"bogus\""
+        );
+        assert_eq!(
+            error(
+                r#"{"pattern": "a", "replacement": "b", "text": "", "options": {"in_place": true}}"#
+            ),
+            "in_place requires \"file\""
+        );
+        assert!(error(r#"{"pattern": "(", "replacement": "", "text": ""}"#)
+            .starts_with("invalid pattern regex"));
+        assert_eq!(
+            error(
+                r#"{"pattern": "a", "replacement": "b", "text": "",
+                    "options": {"fixed_strings": true, "ignore_case": true}}"#
+            ),
+            "fixed_strings can't be used with ignore_case"
+        );
+        assert_eq!(
+            error(r#"{"rules": {}, "text": ""}"#),
+            "\"rules\" must be an array"
+        );
+        assert_eq!(
+            error(r#"{"rules": [{"pattern": "a", "replacement": "$1"}], "text": ""}"#),
+            "replacement refers to capture group ${1} which doesn't exist in the pattern"
+        );
+
+        // the id is still returned when the request is otherwise invalid
+        let response = handle_request(r#"{"id": 7, "pattern": 1}"#);
+        assert_eq!(response.get("id"), Some(&Value::from(7usize)));
+    }
+}
@@ -0,0 +1,344 @@
+//! `--spans-from`: replace spans of files found by another tool, rather than searching for them.
+
+use std::collections::BTreeMap;
+use std::io::{BufRead, Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+
+use crate::json::{self, Value};
+use crate::InPlaceOptions;
+use rp::replace::{EmptyMatches, Pattern, ReplaceOptions};
+
+/// Formats which spans can be read from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum SpanFormat {
+    /// The output of `rg --json`
+    RgJson,
+}
+
+/// A byte range of a file to replace, and the text which it should currently contain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Span {
+    pub range: Range<usize>,
+    pub expected: Option<Vec<u8>>,
+}
+
+/// A byte range of a file which is read from another tool's output to be replaced, like a span or
+/// an exported match.
+pub trait Region {
+    /// What to call it in error messages.
+    const NAME: &'static str;
+
+    fn range(&self) -> &Range<usize>;
+
+    /// The text which the range should currently contain, if known.
+    fn expected(&self) -> Option<&[u8]>;
+}
+
+/// Sort each file's regions, and check that they don't overlap.
+pub fn sort_regions<T: Region>(files: &mut BTreeMap<PathBuf, Vec<T>>) -> anyhow::Result<()> {
+    for (path, regions) in files {
+        regions.sort_by_key(|region| (region.range().start, region.range().end));
+        if let Some(pair) = regions
+            .windows(2)
+            .find(|pair| pair[0].range().end > pair[1].range().start)
+        {
+            anyhow::bail!(
+                "{}: {} {:?} overlaps {:?}",
+                path.display(),
+                T::NAME,
+                pair[0].range(),
+                pair[1].range()
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Check that every region is inside `text` and still contains what was expected.
+pub fn check_regions<T: Region>(regions: &[T], text: &[u8]) -> anyhow::Result<()> {
+    for region in regions {
+        let Some(current) = text.get(region.range().clone()) else {
+            anyhow::bail!(
+                "{} {:?} is past the end of the file",
+                T::NAME,
+                region.range()
+            );
+        };
+        if matches!(region.expected(), Some(expected) if expected != current) {
+            anyhow::bail!(
+                "{} {:?} has changed since it was found",
+                T::NAME,
+                region.range()
+            );
+        }
+    }
+    Ok(())
+}
+
+impl Region for Span {
+    const NAME: &'static str = "span";
+
+    fn range(&self) -> &Range<usize> {
+        &self.range
+    }
+
+    fn expected(&self) -> Option<&[u8]> {
+        self.expected.as_deref()
+    }
+}
+
+/// A pattern which matches a fixed, sorted list of spans.
+struct Spans(Vec<Range<usize>>);
+
+impl Pattern for Spans {
+    fn find_at(&self, _text: &[u8], start: usize) -> Option<Range<usize>> {
+        let index = self.0.partition_point(|span| span.start < start);
+        self.0.get(index).cloned()
+    }
+}
+
+/// Read spans in the given format, grouped by file. Each file's spans are sorted and don't
+/// overlap, and empty spans don't touch any other span, since the replacer would skip them.
+pub fn read_spans<R: BufRead>(
+    input: &mut R,
+    format: SpanFormat,
+) -> anyhow::Result<BTreeMap<PathBuf, Vec<Span>>> {
+    let mut files = match format {
+        SpanFormat::RgJson => read_rg_json(input)?,
+    };
+    sort_regions(&mut files)?;
+    for (path, spans) in &files {
+        if let Some(pair) = spans.windows(2).find(|pair| {
+            pair[0].range.end == pair[1].range.start
+                && (pair[0].range.is_empty() || pair[1].range.is_empty())
+        }) {
+            anyhow::bail!(
+                "{}: empty span {:?} touches span {:?}",
+                path.display(),
+                if pair[0].range.is_empty() {
+                    &pair[0]
+                } else {
+                    &pair[1]
+                }
+                .range,
+                if pair[0].range.is_empty() {
+                    &pair[1]
+                } else {
+                    &pair[0]
+                }
+                .range,
+            );
+        }
+    }
+    Ok(files)
+}
+
+/// Read the "match" messages from `rg --json` output. Other message types are ignored.
+fn read_rg_json<R: BufRead>(input: &mut R) -> anyhow::Result<BTreeMap<PathBuf, Vec<Span>>> {
+    let mut files: BTreeMap<PathBuf, Vec<Span>> = BTreeMap::new();
+    for (index, line) in input.lines().enumerate() {
+        let line = line.context("failed to read spans")?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = json::parse(&line).with_context(|| format!("line {}", index + 1))?;
+        if message.get("type").and_then(Value::as_str) != Some("match") {
+            continue;
+        }
+        let (path, spans) =
+            rg_match_spans(&message).with_context(|| format!("line {}", index + 1))?;
+        files.entry(path).or_default().extend(spans);
+    }
+    Ok(files)
+}
+
+/// Get the path and spans from the data of an `rg --json` match message.
+fn rg_match_spans(message: &Value) -> anyhow::Result<(PathBuf, Vec<Span>)> {
+    let data = message.get("data").context("missing \"data\"")?;
+    let path = data.get("path").context("missing \"path\"")?;
+    let path = match path.get("text").and_then(Value::as_str) {
+        Some(path) => PathBuf::from(path),
+        None if path.get("bytes").is_some() => anyhow::bail!("non-UTF-8 paths aren't supported"),
+        None => anyhow::bail!("invalid \"path\""),
+    };
+    let offset = data
+        .get("absolute_offset")
+        .and_then(Value::as_usize)
+        .context("missing \"absolute_offset\"")?;
+    let submatches = match data.get("submatches") {
+        Some(Value::Array(submatches)) => submatches,
+        _ => anyhow::bail!("missing \"submatches\""),
+    };
+
+    let spans = submatches
+        .iter()
+        .map(|submatch| {
+            let start = submatch.get("start").and_then(Value::as_usize);
+            let end = submatch.get("end").and_then(Value::as_usize);
+            let (Some(start), Some(end)) = (start, end) else {
+                anyhow::bail!("invalid submatch");
+            };
+            // the matched text is only given if it's valid UTF-8
+            let expected = submatch
+                .get("match")
+                .and_then(|m| m.get("text"))
+                .and_then(Value::as_str)
+                .map(|text| text.as_bytes().to_vec());
+            Ok(Span {
+                range: (offset + start)..(offset + end),
+                expected,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    Ok((path, spans))
+}
+
+/// Replace the given spans of `text`, after checking that they still contain what was expected.
+/// Returns the number of replacements.
+fn replace_spans(
+    opts: &ReplaceOptions,
+    replacement: &[u8],
+    spans: &[Span],
+    text: &[u8],
+    out: &mut Vec<u8>,
+) -> anyhow::Result<usize> {
+    check_regions(spans, text)?;
+    // the replacer doesn't match the empty string after the final line terminator
+    let terminator = if opts.null_data { b'\0' } else { b'\n' };
+    for span in spans {
+        if span.range.is_empty()
+            && span.range.start == text.len()
+            && text.last() == Some(&terminator)
+        {
+            anyhow::bail!(
+                "empty span {:?} is after the end of the last line",
+                span.range
+            );
+        }
+    }
+
+    // every span is replaced, including empty ones
+    let opts = ReplaceOptions {
+        replace_all: true,
+        empty_matches: EmptyMatches::Advance,
+        ..opts.clone()
+    };
+    let pattern = Spans(spans.iter().map(|span| span.range.clone()).collect());
+    let replacer = opts.build_template(pattern, replacement)?;
+    Ok(replacer.replace_into(out, text)?)
+}
+
+/// Replace the given spans of the file at `path` in-place. The file is left alone if any span
+/// doesn't contain the text that was expected.
+pub fn replace_file_spans(
+    opts: &ReplaceOptions,
+    replacement: &[u8],
+    path: &Path,
+    spans: &[Span],
+    inplace_opts: &InPlaceOptions,
+) -> anyhow::Result<usize> {
+    crate::rewrite_inplace(path, inplace_opts, |input, output| {
+        let mut text = vec![];
+        input.read_to_end(&mut text).context("failed to read")?;
+        let mut buf = vec![];
+        let count = replace_spans(opts, replacement, spans, &text, &mut buf)?;
+        output.write_all(&buf).context("failed to write")?;
+        Ok(count)
+    })
+}
+
+/// Check that `replacement` is valid for span replacements, which have only group 0.
+pub fn check_replacement(opts: &ReplaceOptions, replacement: &[u8]) -> anyhow::Result<()> {
+    opts.build_template(Spans(vec![]), replacement)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RG_JSON: &str = r#"
+{"type":"begin","data":{"path":{"text":"a.txt"}}}
+{"type":"match","data":{"path":{"text":"a.txt"},"lines":{"text":"foo bar foo\n"},"line_number":2,"absolute_offset":4,"submatches":[{"match":{"text":"foo"},"start":0,"end":3},{"match":{"text":"foo"},"start":8,"end":11}]}}
+{"type":"end","data":{"path":{"text":"a.txt"},"binary_offset":null,"stats":{}}}
+{"type":"match","data":{"path":{"text":"b.txt"},"lines":{"text":"x\n"},"line_number":1,"absolute_offset":0,"submatches":[{"match":{"bytes":"eA=="},"start":0,"end":1}]}}
+{"data":{"elapsed_total":{"human":"0s","nanos":1,"secs":0},"stats":{}},"type":"summary"}
+"#;
+
+    #[test]
+    fn test_read_rg_json() {
+        let files = read_spans(&mut RG_JSON.as_bytes(), SpanFormat::RgJson).unwrap();
+        let span = |range: Range<usize>, expected: Option<&str>| Span {
+            range,
+            expected: expected.map(|s| s.as_bytes().to_vec()),
+        };
+        assert_eq!(
+            files.into_iter().collect::<Vec<_>>(),
+            [
+                (
+                    PathBuf::from("a.txt"),
+                    vec![span(4..7, Some("foo")), span(12..15, Some("foo"))]
+                ),
+                (PathBuf::from("b.txt"), vec![span(0..1, None)]),
+            ]
+        );
+
+        let overlapping = r#"{"type":"match","data":{"path":{"text":"a"},"absolute_offset":0,"submatches":[{"start":0,"end":3},{"start":2,"end":4}]}}"#;
+        assert!(read_spans(&mut overlapping.as_bytes(), SpanFormat::RgJson).is_err());
+        // the replacer would skip over the second span after the empty one
+        let touching = r#"{"type":"match","data":{"path":{"text":"a"},"absolute_offset":0,"submatches":[{"start":3,"end":5},{"start":3,"end":3}]}}"#;
+        let err = read_spans(&mut touching.as_bytes(), SpanFormat::RgJson).unwrap_err();
+        assert_eq!(err.to_string(), "a: empty span 3..3 touches span 3..5");
+        let touching = r#"{"type":"match","data":{"path":{"text":"a"},"absolute_offset":0,"submatches":[{"start":1,"end":3},{"start":3,"end":3}]}}"#;
+        assert!(read_spans(&mut touching.as_bytes(), SpanFormat::RgJson).is_err());
+        let duplicate = r#"{"type":"match","data":{"path":{"text":"a"},"absolute_offset":0,"submatches":[{"start":3,"end":3},{"start":3,"end":3}]}}"#;
+        assert!(read_spans(&mut duplicate.as_bytes(), SpanFormat::RgJson).is_err());
+        let bad = r#"{"type":"match","data":{"path":{"text":"a"}}}"#;
+        assert!(read_spans(&mut bad.as_bytes(), SpanFormat::RgJson).is_err());
+    }
+
+    #[test]
+    fn test_replace_spans() {
+        let opts = ReplaceOptions::default();
+        let spans = [
+            Span {
+                range: 0..3,
+                expected: Some(b"foo".to_vec()),
+            },
+            Span {
+                range: 8..11,
+                expected: None,
+            },
+        ];
+        let mut out = vec![];
+        let count = replace_spans(&opts, b"<$0>", &spans, b"foo bar foo foo", &mut out).unwrap();
+        assert_eq!(count, 2);
+        assert_eq!(out, b"<foo> bar <foo> foo");
+
+        assert!(replace_spans(&opts, b"", &spans, b"fox bar foo", &mut vec![]).is_err());
+        assert!(replace_spans(&opts, b"", &spans, b"foo bar f", &mut vec![]).is_err());
+        assert!(check_replacement(&opts, b"$1").is_err());
+
+        // empty spans are replaced too, even with --empty-matches=skip
+        let opts = ReplaceOptions {
+            empty_matches: EmptyMatches::Skip,
+            ..Default::default()
+        };
+        let spans = [0..0, 1..3, 4..4].map(|range| Span {
+            range,
+            expected: None,
+        });
+        let mut out = vec![];
+        let count = replace_spans(&opts, b"<$0>", &spans, b"abcd\n", &mut out).unwrap();
+        assert_eq!(count, 3);
+        assert_eq!(out, b"<>a<bc>d<>\n");
+        let spans = [Span {
+            range: 5..5,
+            expected: None,
+        }];
+        assert!(replace_spans(&opts, b"", &spans, b"abcd\n", &mut vec![]).is_err());
+    }
+}
@@ -0,0 +1,134 @@
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// A file recording which inputs have been completed, so that an interrupted run can be resumed
+/// without processing those files again.
+///
+/// The state file has one path per line, exactly as it was given on the command line. Files are
+/// recorded as soon as they're finished so the state stays accurate if rp is killed.
+#[derive(Debug)]
+pub struct StateFile {
+    file: Mutex<File>,
+    done: HashSet<PathBuf>,
+}
+
+impl StateFile {
+    /// Open a state file. If `resume` is true, load the files already completed by a previous run
+    /// and append to it, otherwise start over with an empty state.
+    pub fn open(path: &Path, resume: bool) -> io::Result<StateFile> {
+        let done = if resume {
+            match fs::read(path) {
+                Ok(data) => parse_state(&data),
+                Err(err) if err.kind() == io::ErrorKind::NotFound => HashSet::new(),
+                Err(err) => return Err(err),
+            }
+        } else {
+            HashSet::new()
+        };
+
+        let mut opts = OpenOptions::new();
+        opts.create(true);
+        if resume {
+            opts.append(true);
+        } else {
+            opts.write(true).truncate(true);
+        }
+        let file = Mutex::new(opts.open(path)?);
+
+        Ok(StateFile { file, done })
+    }
+
+    /// Check whether a file was completed by a previous run.
+    pub fn is_done(&self, path: &Path) -> bool {
+        self.done.contains(path)
+    }
+
+    /// Record that a file has been completed.
+    pub fn mark_done(&self, path: &Path) -> io::Result<()> {
+        let mut line = path_bytes(path).into_owned();
+        line.push(b'\n');
+        // a poisoned lock just means another thread panicked, the file itself is still fine
+        let mut file = self.file.lock().unwrap_or_else(|err| err.into_inner());
+        file.write_all(&line)
+    }
+}
+
+/// Parse the contents of a state file. A final line without a newline is ignored since it was
+/// probably cut off in the middle of being written.
+fn parse_state(data: &[u8]) -> HashSet<PathBuf> {
+    let mut lines: Vec<&[u8]> = data.split(|b| *b == b'\n').collect();
+    // the last element is either empty or an incomplete line
+    lines.pop();
+    lines
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .map(path_from_bytes)
+        .collect()
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    path.as_os_str().as_bytes().into()
+}
+
+#[cfg(not(unix))]
+fn path_bytes(path: &Path) -> std::borrow::Cow<'_, [u8]> {
+    match path.to_string_lossy() {
+        std::borrow::Cow::Borrowed(s) => s.as_bytes().into(),
+        std::borrow::Cow::Owned(s) => s.into_bytes().into(),
+    }
+}
+
+/// Convert bytes read from a file back into a path.
+#[cfg(unix)]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    use std::os::unix::ffi::OsStrExt;
+    std::ffi::OsStr::from_bytes(bytes).into()
+}
+
+#[cfg(not(unix))]
+pub fn path_from_bytes(bytes: &[u8]) -> PathBuf {
+    String::from_utf8_lossy(bytes).into_owned().into()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_state() {
+        let state = parse_state(b"a.txt\ndir/b.txt\n\nc.t");
+        assert_eq!(state.len(), 2);
+        assert!(state.contains(Path::new("a.txt")));
+        assert!(state.contains(Path::new("dir/b.txt")));
+        assert!(!state.contains(Path::new("c.t")));
+    }
+
+    #[test]
+    fn test_state_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("state");
+
+        let state = StateFile::open(&path, true).unwrap();
+        assert!(!state.is_done(Path::new("a")));
+        state.mark_done(Path::new("a")).unwrap();
+        state.mark_done(Path::new("b")).unwrap();
+        drop(state);
+
+        let state = StateFile::open(&path, true).unwrap();
+        assert!(state.is_done(Path::new("a")));
+        assert!(state.is_done(Path::new("b")));
+        state.mark_done(Path::new("c")).unwrap();
+        drop(state);
+        assert_eq!(fs::read(&path).unwrap(), b"a\nb\nc\n");
+
+        let state = StateFile::open(&path, false).unwrap();
+        assert!(!state.is_done(Path::new("a")));
+        drop(state);
+        assert_eq!(fs::read(&path).unwrap(), b"");
+    }
+}
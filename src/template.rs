@@ -0,0 +1,771 @@
+use std::fmt;
+use std::ops::Range;
+
+use crate::replace::Captures;
+
+/// A reference to a capture group in a replacement template.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GroupRef {
+    /// A group by number, like `$1`
+    Index(usize),
+    /// A named group, like `$name`
+    Name(String),
+}
+
+impl fmt::Display for GroupRef {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GroupRef::Index(index) => write!(f, "${{{index}}}"),
+            GroupRef::Name(name) => write!(f, "${{{name}}}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(Vec<u8>),
+    Group(GroupRef),
+    /// A group re-cased with a function like `${snake(1)}`
+    Cased(Case, GroupRef),
+    /// Up to this many bytes of the line before the match, from `${before:N}`
+    Before(usize),
+    /// Up to this many bytes of the line after the match, from `${after:N}`
+    After(usize),
+    /// The whole original line containing the match, from `${LINE}`
+    Line,
+    /// Expands the template paired with the first of these groups which participated in the match
+    Branch(Vec<(usize, Template)>),
+}
+
+/// A function which changes the case of an identifier in a capture group.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Case {
+    /// `fooBarBaz`
+    Camel,
+    /// `FooBarBaz`
+    Pascal,
+    /// `foo_bar_baz`
+    Snake,
+    /// `Foo Bar Baz`
+    Title,
+}
+
+impl Case {
+    /// Parse a function call like `snake(1)` or `camel(name)`.
+    fn parse_call(s: &str) -> Option<(Case, GroupRef)> {
+        let (func, arg) = s.strip_suffix(')')?.split_once('(')?;
+        let case = match func {
+            "camel" => Case::Camel,
+            "pascal" => Case::Pascal,
+            "snake" => Case::Snake,
+            "title" => Case::Title,
+            _ => return None,
+        };
+        if arg.is_empty() || !arg.bytes().all(is_group_name_byte) {
+            return None;
+        }
+        let group = match arg.parse::<usize>() {
+            Ok(index) => GroupRef::Index(index),
+            Err(_) => GroupRef::Name(arg.to_owned()),
+        };
+        Some((case, group))
+    }
+
+    /// Re-case `text` and append it to `buf`. Text which isn't valid UTF-8 is copied unchanged.
+    fn apply(self, text: &[u8], buf: &mut Vec<u8>) {
+        let Ok(text) = std::str::from_utf8(text) else {
+            buf.extend_from_slice(text);
+            return;
+        };
+        let mut out = String::new();
+        for (i, word) in split_words(text).into_iter().enumerate() {
+            match (self, i) {
+                (Case::Snake, 0) | (Case::Camel, 0) => {
+                    out.extend(word.chars().flat_map(char::to_lowercase))
+                }
+                (Case::Snake, _) => {
+                    out.push('_');
+                    out.extend(word.chars().flat_map(char::to_lowercase));
+                }
+                (Case::Title, 1..) => {
+                    out.push(' ');
+                    capitalize_into(word, &mut out);
+                }
+                _ => capitalize_into(word, &mut out),
+            }
+        }
+        buf.extend_from_slice(out.as_bytes());
+    }
+}
+
+/// Split an identifier into words at non-alphanumeric characters like '_', '-', and spaces, and at
+/// changes of case. A run of capitals is one word, except that its last letter starts a new word if
+/// it's followed by a lowercase letter, so "HTTPServer" is "HTTP" and "Server". Digits stay with the
+/// word before them.
+pub fn split_words(text: &str) -> Vec<&str> {
+    let mut words = vec![];
+    let mut start = None;
+    let mut prev: Option<char> = None;
+    let mut iter = text.char_indices().peekable();
+    while let Some((i, c)) = iter.next() {
+        if !c.is_alphanumeric() {
+            if let Some(start) = start.take() {
+                words.push(&text[start..i]);
+            }
+            prev = None;
+            continue;
+        }
+        let next = iter.peek().map(|&(_, next)| next);
+        let boundary = match prev {
+            None => false,
+            Some(prev) => {
+                c.is_uppercase() && (!prev.is_uppercase() || next.is_some_and(char::is_lowercase))
+            }
+        };
+        match start {
+            Some(s) if boundary => {
+                words.push(&text[s..i]);
+                start = Some(i);
+            }
+            Some(_) => (),
+            None => start = Some(i),
+        }
+        prev = Some(c);
+    }
+    if let Some(start) = start {
+        words.push(&text[start..]);
+    }
+    words
+}
+
+/// Append `word` with its first letter uppercase and the rest lowercase.
+fn capitalize_into(word: &str, out: &mut String) {
+    let mut chars = word.chars();
+    if let Some(first) = chars.next() {
+        out.extend(first.to_uppercase());
+        out.extend(chars.flat_map(char::to_lowercase));
+    }
+}
+
+/// Change the case of the replacement at `buf[start..]` to follow the casing of the `matched` text,
+/// for `preserve_case`.
+///
+/// If the matched letters are all uppercase, the replacement is uppercased. If only the first one
+/// is, the replacement's first letter is uppercased. Otherwise the replacement is left as written,
+/// which includes when the match is all lowercase. Text which isn't valid UTF-8 is left alone.
+pub fn follow_case(matched: &[u8], buf: &mut Vec<u8>, start: usize) {
+    let (Ok(matched), Ok(rep)) = (
+        std::str::from_utf8(matched),
+        std::str::from_utf8(&buf[start..]),
+    ) else {
+        return;
+    };
+    let is_cased = |c: &char| c.is_uppercase() || c.is_lowercase();
+    let mut letters = matched.chars().filter(is_cased);
+    if !letters.next().is_some_and(char::is_uppercase) {
+        return;
+    }
+    let rest: Vec<char> = letters.collect();
+    let new = if !rest.is_empty() && rest.iter().all(|c| c.is_uppercase()) {
+        rep.to_uppercase()
+    } else if rest.iter().all(|c| c.is_lowercase()) {
+        let Some((i, first)) = rep.char_indices().find(|(_, c)| is_cased(c)) else {
+            return;
+        };
+        let mut new = rep[..i].to_owned();
+        new.extend(first.to_uppercase());
+        new.push_str(&rep[i + first.len_utf8()..]);
+        new
+    } else {
+        return;
+    };
+    buf.truncate(start);
+    buf.extend_from_slice(new.as_bytes());
+}
+
+/// The syntax used to parse a replacement string into a `Template`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum TemplateSyntax {
+    /// The regex crate's `$` syntax.
+    #[default]
+    Regex,
+    /// Also accept sed-style backslash sequences, see `Template::parse_sed`.
+    Sed,
+}
+
+/// A parsed replacement string.
+///
+/// Templates use the same syntax as the regex crate: `$N` or `${N}` for the group with index N,
+/// `$name` or `${name}` for a named group, and `$$` for a literal dollar sign. An unbraced name is
+/// always the longest run of `[0-9A-Za-z_]`, so `$1a` refers to the group named "1a" rather than
+/// group 1 followed by an 'a'. A '$' which doesn't start a valid reference is kept as-is.
+///
+/// A braced reference can also re-case the group's text, treating it as an identifier: `${camel(1)}`
+/// gives `fooBar`, `${pascal(1)}` gives `FooBar`, `${snake(1)}` gives `foo_bar`, and `${title(1)}`
+/// gives `Foo Bar`. Named groups work too, like `${snake(name)}`. `${before:N}` and `${after:N}`
+/// expand to up to N bytes of the line before and after the match, and `${LINE}` to the whole
+/// original line without its terminator, unless the pattern has a group named `LINE`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Create a template which always expands to the given bytes, with no group references.
+    pub fn literal(rep: impl Into<Vec<u8>>) -> Template {
+        let rep = rep.into();
+        let parts = if rep.is_empty() {
+            vec![]
+        } else {
+            vec![Part::Literal(rep)]
+        };
+        Template { parts }
+    }
+
+    /// Create a template which expands to different bytes depending on which capture group matched.
+    ///
+    /// Each element of `branches` is a group index and its replacement. If none of the groups
+    /// matched, the template expands to nothing. Named references in the replacements should
+    /// already be resolved.
+    pub fn branches(branches: Vec<(usize, Template)>) -> Template {
+        Template {
+            parts: vec![Part::Branch(branches)],
+        }
+    }
+
+    /// Parse a replacement string containing `$` group references.
+    pub fn parse(rep: impl AsRef<[u8]>) -> Template {
+        Template::parse_with(rep.as_ref(), TemplateSyntax::Regex)
+    }
+
+    /// Parse a replacement string which may also contain sed-style backslash sequences.
+    ///
+    /// `\0` through `\9` are aliases for `$0` through `$9`, and `&` is the whole match like `$0`.
+    /// `\n` and `\t` are a newline and tab, and `\\`, `\&`, and `\$` are a literal backslash,
+    /// ampersand, and dollar sign. Any other backslash is kept as-is. `$` references work the same
+    /// as in `parse`.
+    pub fn parse_sed(rep: impl AsRef<[u8]>) -> Template {
+        Template::parse_with(rep.as_ref(), TemplateSyntax::Sed)
+    }
+
+    fn parse_with(mut rep: &[u8], syntax: TemplateSyntax) -> Template {
+        let mut template = Template::default();
+        let mut lit = vec![];
+
+        let find_special = |rep: &[u8]| match syntax {
+            TemplateSyntax::Regex => memchr::memchr(b'$', rep),
+            TemplateSyntax::Sed => memchr::memchr3(b'$', b'\\', b'&', rep),
+        };
+
+        while let Some(pos) = find_special(rep) {
+            lit.extend_from_slice(&rep[..pos]);
+            rep = &rep[pos..];
+
+            if rep[0] == b'&' {
+                template.push_literal(&mut lit);
+                template.parts.push(Part::Group(GroupRef::Index(0)));
+                rep = &rep[1..];
+                continue;
+            }
+
+            if rep[0] == b'\\' {
+                match rep.get(1) {
+                    Some(&digit @ b'0'..=b'9') => {
+                        template.push_literal(&mut lit);
+                        let index = usize::from(digit - b'0');
+                        template.parts.push(Part::Group(GroupRef::Index(index)));
+                    }
+                    Some(b'n') => lit.push(b'\n'),
+                    Some(b't') => lit.push(b'\t'),
+                    Some(&c @ (b'\\' | b'&' | b'$')) => lit.push(c),
+                    _ => {
+                        lit.push(b'\\');
+                        rep = &rep[1..];
+                        continue;
+                    }
+                }
+                rep = &rep[2..];
+                continue;
+            }
+
+            if rep.get(1) == Some(&b'$') {
+                lit.push(b'$');
+                rep = &rep[2..];
+                continue;
+            }
+
+            match find_group_ref(rep) {
+                Some((group, len)) => {
+                    template.push_literal(&mut lit);
+                    let special = match &group {
+                        GroupRef::Name(name) if rep[1] == b'{' => parse_braced(name),
+                        _ => None,
+                    };
+                    template.parts.push(special.unwrap_or(Part::Group(group)));
+                    rep = &rep[len..];
+                }
+                None => {
+                    lit.push(b'$');
+                    rep = &rep[1..];
+                }
+            }
+        }
+
+        lit.extend_from_slice(rep);
+        template.push_literal(&mut lit);
+        template
+    }
+
+    /// Move any pending literal bytes into a new part, leaving `lit` empty.
+    fn push_literal(&mut self, lit: &mut Vec<u8>) {
+        if !lit.is_empty() {
+            self.parts.push(Part::Literal(std::mem::take(lit)));
+        }
+    }
+
+    /// If this template contains no group references, return the bytes it always expands to.
+    pub fn as_literal(&self) -> Option<&[u8]> {
+        match self.parts.as_slice() {
+            [] => Some(&[]),
+            [Part::Literal(lit)] => Some(lit),
+            _ => None,
+        }
+    }
+
+    /// Iterate over all the group references in this template.
+    pub fn group_refs(&self) -> impl Iterator<Item = &GroupRef> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Group(group) | Part::Cased(_, group) => Some(group),
+            Part::Literal(_) | Part::Before(_) | Part::After(_) | Part::Line | Part::Branch(_) => {
+                None
+            }
+        })
+    }
+
+    /// Convert named group references into indexes using the provided lookup function.
+    ///
+    /// Names which don't exist are removed, so that they expand to nothing. `${LINE}` refers to a
+    /// group named "LINE" if there is one.
+    pub fn resolve(&mut self, name_to_index: impl Fn(&str) -> Option<usize>) {
+        self.parts.retain_mut(|part| match part {
+            Part::Line => {
+                if let Some(index) = name_to_index("LINE") {
+                    *part = Part::Group(GroupRef::Index(index));
+                }
+                true
+            }
+            Part::Group(group) | Part::Cased(_, group) => match group {
+                GroupRef::Name(name) => match name_to_index(name) {
+                    Some(index) => {
+                        *group = GroupRef::Index(index);
+                        true
+                    }
+                    None => false,
+                },
+                GroupRef::Index(_) => true,
+            },
+            _ => true,
+        });
+    }
+
+    /// Add `offset` to the index of every group reference, for when the pattern's groups are
+    /// numbered as part of a larger set of groups.
+    pub fn shift_groups(&mut self, offset: usize) {
+        for part in &mut self.parts {
+            match part {
+                Part::Group(GroupRef::Index(index)) | Part::Cased(_, GroupRef::Index(index)) => {
+                    *index += offset
+                }
+                Part::Branch(branches) => {
+                    for (index, template) in branches {
+                        *index += offset;
+                        template.shift_groups(offset);
+                    }
+                }
+                Part::Literal(_)
+                | Part::Group(GroupRef::Name(_))
+                | Part::Cased(_, GroupRef::Name(_))
+                | Part::Before(_)
+                | Part::After(_)
+                | Part::Line => (),
+            }
+        }
+    }
+
+    /// Expand this template for a match in `text`, appending the result to `buf`.
+    ///
+    /// Groups which didn't participate in the match expand to nothing. Named references should be
+    /// resolved before calling this, any that remain also expand to nothing.
+    pub fn expand(&self, text: &[u8], caps: &Captures, buf: &mut Vec<u8>) {
+        for part in &self.parts {
+            match part {
+                Part::Literal(lit) => buf.extend_from_slice(lit),
+                Part::Group(GroupRef::Index(i)) => {
+                    if let Some(span) = caps.get(*i) {
+                        buf.extend_from_slice(&text[span]);
+                    }
+                }
+                Part::Cased(case, GroupRef::Index(i)) => {
+                    if let Some(span) = caps.get(*i) {
+                        case.apply(&text[span], buf);
+                    }
+                }
+                Part::Group(GroupRef::Name(_)) | Part::Cased(_, GroupRef::Name(_)) => (),
+                Part::Before(len) => {
+                    if let Some(span) = caps.get(0) {
+                        buf.extend_from_slice(context_before(text, span.start, *len));
+                    }
+                }
+                Part::After(len) => {
+                    if let Some(span) = caps.get(0) {
+                        buf.extend_from_slice(context_after(text, span.end, *len));
+                    }
+                }
+                Part::Line => {
+                    if let Some(span) = caps.get(0) {
+                        buf.extend_from_slice(&text[line_around(text, span)]);
+                    }
+                }
+                Part::Branch(branches) => {
+                    if let Some((_, rep)) = branches.iter().find(|(i, _)| caps.get(*i).is_some()) {
+                        rep.expand(text, caps, buf);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Parse the special forms which are only allowed inside braces: case functions like
+/// `${snake(1)}`, and context like `${before:20}` or `${LINE}`.
+fn parse_braced(name: &str) -> Option<Part> {
+    if name == "LINE" {
+        return Some(Part::Line);
+    }
+    if let Some((case, group)) = Case::parse_call(name) {
+        return Some(Part::Cased(case, group));
+    }
+    let (side, len) = name.split_once(':')?;
+    let len = len.parse().ok()?;
+    match side {
+        "before" => Some(Part::Before(len)),
+        "after" => Some(Part::After(len)),
+        _ => None,
+    }
+}
+
+/// Whether a byte ends the context around a match. NUL counts too for `-z`.
+fn is_context_end(b: u8) -> bool {
+    matches!(b, b'\n' | b'\r' | b'\0')
+}
+
+/// Up to `len` bytes of `text` before `pos`, stopping at a line break and without splitting a
+/// UTF-8 character.
+fn context_before(text: &[u8], pos: usize, len: usize) -> &[u8] {
+    let mut start = text[..pos]
+        .iter()
+        .rposition(|&b| is_context_end(b))
+        .map_or(0, |i| i + 1)
+        .max(pos.saturating_sub(len));
+    while start < pos && is_utf8_continuation(text[start]) {
+        start += 1;
+    }
+    &text[start..pos]
+}
+
+/// Up to `len` bytes of `text` after `pos`, stopping at a line break and without splitting a
+/// UTF-8 character.
+fn context_after(text: &[u8], pos: usize, len: usize) -> &[u8] {
+    let mut end = text[pos..]
+        .iter()
+        .position(|&b| is_context_end(b))
+        .map_or(text.len(), |i| pos + i)
+        .min(pos.saturating_add(len));
+    while end > pos && end < text.len() && is_utf8_continuation(text[end]) {
+        end -= 1;
+    }
+    &text[pos..end]
+}
+
+/// The span of the line in `text` containing `span`, or the lines if it contains line breaks,
+/// without the terminator. A match ending in a line break is on the line which that ends.
+fn line_around(text: &[u8], span: Range<usize>) -> Range<usize> {
+    let start = text[..span.start]
+        .iter()
+        .rposition(|&b| is_context_end(b))
+        .map_or(0, |i| i + 1);
+    let from = span.end.saturating_sub(1).max(span.start);
+    let end = text[from..]
+        .iter()
+        .position(|&b| is_context_end(b))
+        .map_or(text.len(), |i| from + i);
+    start..end
+}
+
+fn is_utf8_continuation(b: u8) -> bool {
+    b & 0xc0 == 0x80
+}
+
+/// Parse a group reference at the start of `rep`, which begins with '$'. Returns the reference
+/// and the number of bytes it used.
+fn find_group_ref(rep: &[u8]) -> Option<(GroupRef, usize)> {
+    let (name, len) = if rep.get(1) == Some(&b'{') {
+        let end = memchr::memchr(b'}', rep)?;
+        (std::str::from_utf8(&rep[2..end]).ok()?, end + 1)
+    } else {
+        let end = rep[1..]
+            .iter()
+            .position(|b| !is_group_name_byte(*b))
+            .map_or(rep.len(), |pos| pos + 1);
+        if end == 1 {
+            return None;
+        }
+        // only ASCII characters here so this is always valid UTF-8
+        (std::str::from_utf8(&rep[1..end]).ok()?, end)
+    };
+
+    let group = match name.parse::<usize>() {
+        Ok(index) => GroupRef::Index(index),
+        Err(_) => GroupRef::Name(name.to_owned()),
+    };
+    Some((group, len))
+}
+
+fn is_group_name_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_group_ref() {
+        #[track_caller]
+        fn check(rep: &str, expected: Option<(GroupRef, usize)>) {
+            assert_eq!(find_group_ref(rep.as_bytes()), expected);
+        }
+
+        let name = |n: &str| GroupRef::Name(n.to_owned());
+        check("$foo", Some((name("foo"), 4)));
+        check("${foo}", Some((name("foo"), 6)));
+        check("$0", Some((GroupRef::Index(0), 2)));
+        check("$10", Some((GroupRef::Index(10), 3)));
+        check("$1a", Some((name("1a"), 3)));
+        check("${1}a", Some((GroupRef::Index(1), 4)));
+        check("$foo-bar", Some((name("foo"), 4)));
+        check("${foo[bar].baz}", Some((name("foo[bar].baz"), 15)));
+        check("$", None);
+        check("$ foo", None);
+        check("${foo", None);
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(Template::parse("").as_literal(), Some(&b""[..]));
+        assert_eq!(Template::parse("foo").as_literal(), Some(&b"foo"[..]));
+        assert_eq!(Template::parse("a$$b").as_literal(), Some(&b"a$b"[..]));
+        assert_eq!(Template::parse("$ $").as_literal(), Some(&b"$ $"[..]));
+        assert_eq!(Template::parse("${x").as_literal(), Some(&b"${x"[..]));
+        assert_eq!(Template::literal("$1").as_literal(), Some(&b"$1"[..]));
+        assert_eq!(Template::parse("$1").as_literal(), None);
+
+        let t = Template::parse("<$2 ${name}>");
+        let refs: Vec<_> = t.group_refs().collect();
+        assert_eq!(
+            refs,
+            [&GroupRef::Index(2), &GroupRef::Name("name".to_owned())]
+        );
+    }
+
+    #[test]
+    fn test_parse_sed() {
+        let sed = Template::parse_sed;
+        assert_eq!(sed(r"a\\b\$1\n\t").as_literal(), Some(&b"a\\b$1\n\t"[..]));
+        assert_eq!(sed(r"\x\").as_literal(), Some(&br"\x\"[..]));
+        assert_eq!(sed(r"a\&b").as_literal(), Some(&b"a&b"[..]));
+        assert_eq!(Template::parse("a&b").as_literal(), Some(&b"a&b"[..]));
+        // regex syntax leaves backslashes alone
+        assert_eq!(Template::parse(r"\1\n").as_literal(), Some(&br"\1\n"[..]));
+
+        let t = sed(r"\2-\10-$1[&]");
+        let refs: Vec<_> = t.group_refs().collect();
+        assert_eq!(
+            refs,
+            [
+                &GroupRef::Index(2),
+                &GroupRef::Index(1),
+                &GroupRef::Index(1),
+                &GroupRef::Index(0)
+            ]
+        );
+
+        let mut caps = Captures::default();
+        caps.set([Some(0..3), Some(0..1), Some(2..3)]);
+        let mut buf = vec![];
+        t.expand(b"a-b", &caps, &mut buf);
+        assert_eq!(buf, b"b-a0-a[a-b]");
+    }
+
+    #[test]
+    fn test_expand() {
+        let text = b"hello world";
+        let mut caps = Captures::default();
+        caps.set([Some(0..11), Some(0..5), None, Some(6..11)]);
+
+        let mut t = Template::parse("$3 $1 [$2] ${name} $other $9");
+        t.resolve(|name| (name == "name").then_some(1));
+        let mut buf = vec![];
+        t.expand(text, &caps, &mut buf);
+        assert_eq!(buf, b"world hello [] hello  ");
+    }
+
+    #[test]
+    fn test_split_words() {
+        assert_eq!(split_words("fooBarBaz"), ["foo", "Bar", "Baz"]);
+        assert_eq!(split_words("FooBar"), ["Foo", "Bar"]);
+        assert_eq!(split_words("foo_bar-baz qux"), ["foo", "bar", "baz", "qux"]);
+        assert_eq!(split_words("__FOO_BAR__"), ["FOO", "BAR"]);
+        assert_eq!(split_words("HTTPServer"), ["HTTP", "Server"]);
+        assert_eq!(split_words("parseHTML5Doc"), ["parse", "HTML5", "Doc"]);
+        assert_eq!(split_words("utf8Len"), ["utf8", "Len"]);
+        assert_eq!(split_words("ÉcoleNormale"), ["École", "Normale"]);
+        assert!(split_words("_-_").is_empty());
+    }
+
+    #[test]
+    fn test_context() {
+        let text = "one\nfoo = bär(x);\r\nend".as_bytes();
+        let start = text.iter().position(|&b| b == b'x').unwrap();
+        let mut caps = Captures::default();
+        caps.set([Some(start..start + 1)]);
+
+        let t =
+            Template::parse("[${before:3}|${after:2}] [${before:4}] [${before:100}|${after:100}]");
+        assert_eq!(t.group_refs().count(), 0);
+        let mut buf = vec![];
+        t.expand(text, &caps, &mut buf);
+        // "ä" is two bytes, so it's left out rather than split
+        assert_eq!(
+            std::str::from_utf8(&buf).unwrap(),
+            "[r(|);] [är(] [foo = bär(|);]"
+        );
+
+        // not context without a number
+        let refs: Vec<_> = Template::parse("${before:} ${middle:2}")
+            .group_refs()
+            .cloned()
+            .collect();
+        assert_eq!(
+            refs,
+            [
+                GroupRef::Name("before:".to_owned()),
+                GroupRef::Name("middle:2".to_owned())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_line() {
+        let expand = |template: &Template, text: &str, span: Range<usize>| {
+            let mut caps = Captures::default();
+            caps.set([Some(span)]);
+            let mut buf = vec![];
+            template.expand(text.as_bytes(), &caps, &mut buf);
+            String::from_utf8(buf).unwrap()
+        };
+        let t = Template::parse("[${LINE}] $LINE");
+        assert_eq!(t.group_refs().count(), 1);
+        let text = "one\nfoo = bar;\r\nend";
+        assert_eq!(expand(&t, text, 10..13), "[foo = bar;] ");
+        // a match which includes the line break is still on its line
+        assert_eq!(expand(&t, "foo\nbar\n", 2..4), "[foo] ");
+        assert_eq!(expand(&t, "foo\nbar", 2..5), "[foo\nbar] ");
+        assert_eq!(expand(&t, "", 0..0), "[] ");
+
+        // a group named LINE takes precedence
+        let mut t = Template::parse("${LINE}");
+        t.resolve(|name| (name == "LINE").then_some(1));
+        let mut caps = Captures::default();
+        caps.set([Some(0..3), Some(1..2)]);
+        let mut buf = vec![];
+        t.expand(b"abc", &caps, &mut buf);
+        assert_eq!(buf, b"b");
+    }
+
+    #[test]
+    fn test_cased() {
+        let text = b"getHTTPResponse_code";
+        let mut caps = Captures::default();
+        caps.set([Some(0..text.len())]);
+
+        let mut t = Template::parse(
+            "${camel(0)} ${pascal(0)} ${snake(0)} ${title(name)} ${snake(missing)} ${upper(0)}",
+        );
+        let refs: Vec<_> = t.group_refs().collect();
+        assert_eq!(refs[3], &GroupRef::Name("name".to_owned()));
+        t.resolve(|name| (name == "name").then_some(0));
+        let mut buf = vec![];
+        t.expand(text, &caps, &mut buf);
+        assert_eq!(
+            String::from_utf8(buf).unwrap(),
+            "getHttpResponseCode GetHttpResponseCode get_http_response_code Get Http Response Code  "
+        );
+        // unknown functions are just group names
+        let refs: Vec<_> = Template::parse("${upper(0)}")
+            .group_refs()
+            .cloned()
+            .collect();
+        assert_eq!(refs, [GroupRef::Name("upper(0)".to_owned())]);
+
+        assert_eq!(
+            Case::parse_call("snake(1)"),
+            Some((Case::Snake, GroupRef::Index(1)))
+        );
+        assert_eq!(Case::parse_call("snake()"), None);
+        assert_eq!(Case::parse_call("snake(a b)"), None);
+        assert_eq!(Case::parse_call("snake"), None);
+    }
+
+    #[test]
+    fn test_follow_case() {
+        let follow = |matched: &str, rep: &str| {
+            let mut buf = b"x".to_vec();
+            buf.extend_from_slice(rep.as_bytes());
+            follow_case(matched.as_bytes(), &mut buf, 1);
+            String::from_utf8(buf[1..].to_vec()).unwrap()
+        };
+        assert_eq!(follow("foo", "bar"), "bar");
+        assert_eq!(follow("Foo", "bar"), "Bar");
+        assert_eq!(follow("FOO", "bar"), "BAR");
+        assert_eq!(follow("F", "bar"), "Bar");
+        assert_eq!(follow("fOO", "bar"), "bar");
+        assert_eq!(follow("FooBar", "bazQux"), "bazQux");
+        assert_eq!(follow("foo", "Bar"), "Bar");
+        assert_eq!(follow("Foo", "_bar"), "_Bar");
+        assert_eq!(follow("Foo_1", "bar_baz"), "Bar_baz");
+        assert_eq!(follow("FOO_1", "bar_baz"), "BAR_BAZ");
+        assert_eq!(follow("ÉTÉ", "straße"), "STRASSE");
+        assert_eq!(follow("123", "bar"), "bar");
+        assert_eq!(follow("Foo", "123"), "123");
+    }
+
+    #[test]
+    fn test_branches() {
+        let text = b"hello world";
+        let mut caps = Captures::default();
+        caps.set([Some(0..11), None, None, Some(6..11), Some(0..5)]);
+
+        let mut second = Template::parse("<$1 $0>");
+        second.shift_groups(3);
+        let t = Template::branches(vec![(1, Template::literal("one")), (3, second)]);
+        let mut buf = vec![];
+        t.expand(text, &caps, &mut buf);
+        assert_eq!(buf, b"<hello world>");
+
+        caps.set([Some(0..11), Some(0..11)]);
+        buf.clear();
+        t.expand(text, &caps, &mut buf);
+        assert_eq!(buf, b"one");
+    }
+}
@@ -1,3 +1,4 @@
+/// An invalid escape sequence, from `unescape_bytes`.
 #[derive(Debug, thiserror::Error)]
 #[error("invalid escape sequence at position {pos}: {kind}")]
 pub struct Error {
@@ -34,11 +35,11 @@ impl ErrorKind {
 
 /// Expand escape sequences in a string, producing a byte array.
 ///
-/// Supports the same set of escapes as Rust string literals[1], but additionally hex escapes for
+/// Supports the same set of escapes as [Rust string literals], but additionally hex escapes for
 /// non-ascii bytes (such as "\xFF") are allowed. The resulting byte array might not be valid
 /// UTF-8.
 ///
-/// [1] https://doc.rust-lang.org/reference/tokens.html#character-escapes
+/// [Rust string literals]: https://doc.rust-lang.org/reference/tokens.html#character-escapes
 #[inline]
 pub fn unescape_bytes(bytes: impl AsRef<[u8]>) -> Result<Vec<u8>, Error> {
     unescape_bytes_(bytes.as_ref())
@@ -87,6 +88,49 @@ fn unescape_bytes_(bytes: &[u8]) -> Result<Vec<u8>, Error> {
     Ok(out)
 }
 
+/// Escape control characters and invalid UTF-8 so that text is safe to print to a terminal,
+/// appending the result to `out`.
+///
+/// This uses the same escape syntax that [`unescape_bytes`] accepts. Newlines and tabs are left
+/// as-is, as are backslashes, so the result isn't necessarily reversible.
+pub fn escape_nonprintable_into(out: &mut Vec<u8>, bytes: &[u8]) {
+    escape_into(out, bytes, false)
+}
+
+/// Escape bytes so that [`unescape_bytes`] turns the result back into the same bytes.
+///
+/// Like [`escape_nonprintable_into`], but backslashes, newlines, and tabs are escaped too.
+pub fn escape_bytes(bytes: &[u8]) -> String {
+    let mut out = Vec::with_capacity(bytes.len());
+    escape_into(&mut out, bytes, true);
+    // only valid UTF-8 is passed through, everything else was escaped
+    String::from_utf8(out).unwrap()
+}
+
+fn escape_into(out: &mut Vec<u8>, bytes: &[u8], reversible: bool) {
+    use std::io::Write;
+
+    for chunk in bytes.utf8_chunks() {
+        for c in chunk.valid().chars() {
+            // writing to a Vec can't fail
+            let _ = match c {
+                '\\' if reversible => write!(out, "\\\\"),
+                '\n' if reversible => write!(out, "\\n"),
+                '\t' if reversible => write!(out, "\\t"),
+                '\n' | '\t' => write!(out, "{c}"),
+                '\0' => write!(out, "\\0"),
+                '\r' => write!(out, "\\r"),
+                c if c.is_ascii_control() => write!(out, "\\x{:02X}", c as u32),
+                c if c.is_control() => write!(out, "\\u{{{:X}}}", c as u32),
+                c => write!(out, "{c}"),
+            };
+        }
+        for b in chunk.invalid() {
+            let _ = write!(out, "\\x{b:02X}");
+        }
+    }
+}
+
 /// parse a single hex nibble from an ASCII byte
 fn nibble(b: u8) -> Result<u8, ErrorKind> {
     match b {
@@ -219,4 +263,39 @@ mod tests {
         assert!(unescape_bytes(r"\u{D800}").is_err()); // not a valid unicode codepoint
         assert!(unescape_bytes(r"bad escape \X10").is_err());
     }
+
+    #[test]
+    fn test_escape_nonprintable() {
+        use super::{escape_nonprintable_into, unescape_bytes};
+
+        #[track_caller]
+        fn check(input: impl AsRef<[u8]>, expected: &str) {
+            let mut out = vec![];
+            escape_nonprintable_into(&mut out, input.as_ref());
+            assert_eq!(String::from_utf8(out).unwrap(), expected);
+            // no backslashes in the input, so we should be able to round-trip
+            assert_eq!(unescape_bytes(expected).unwrap(), input.as_ref());
+        }
+
+        check("hello world\n", "hello world\n");
+        check("tab\there", "tab\there");
+        check("nul\0 cr\r esc\x1b del\x7f", r"nul\0 cr\r esc\x1B del\x7F");
+        check("smile 😀 c1\u{85}", r"smile 😀 c1\u{85}");
+        check(b"bad \xff\xfe utf8 \xe2\x82", r"bad \xFF\xFE utf8 \xE2\x82");
+    }
+
+    #[test]
+    fn test_escape_bytes() {
+        use super::{escape_bytes, unescape_bytes};
+
+        #[track_caller]
+        fn check(input: impl AsRef<[u8]>, expected: &str) {
+            assert_eq!(escape_bytes(input.as_ref()), expected);
+            assert_eq!(unescape_bytes(expected).unwrap(), input.as_ref());
+        }
+
+        check("plain é", "plain é");
+        check("a\\b\n\tc\r", r"a\\b\n\tc\r");
+        check(b"\\x41 \xff\0", r"\\x41 \xFF\0");
+    }
 }
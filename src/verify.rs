@@ -0,0 +1,253 @@
+//! `--verify`: check that an in-place edit can be undone before saving it, and `--paranoid`:
+//! check that it was saved correctly.
+
+use std::io::{self, BufRead, Read, Write};
+
+use rp::replace::{EnginePattern, Replacer, StreamError};
+
+#[derive(Debug, thiserror::Error)]
+pub enum VerifyError {
+    #[error("the inverse replacement doesn't reproduce the original, which differs on line {0}")]
+    Mismatch(usize),
+    #[error("unable to apply the inverse replacement: {0}")]
+    Stream(#[source] StreamError),
+    #[error("the new contents read back differ from the replaced text on line {0}")]
+    Corrupt(usize),
+    #[error("repeating the replacement made {found} replacements rather than {expected}")]
+    Count { expected: usize, found: usize },
+    #[error("unable to repeat the replacement: {0}")]
+    Replay(#[source] StreamError),
+}
+
+/// Applies the inverse of a replacement to the replaced text and compares the result with the
+/// original, to catch replacements which lose information, e.g. swapping tokens when one of them
+/// was already in the file.
+#[derive(Debug)]
+pub struct Verifier {
+    inverse: Replacer<EnginePattern>,
+}
+
+impl Verifier {
+    pub fn new(inverse: Replacer<EnginePattern>) -> Verifier {
+        Verifier { inverse }
+    }
+
+    /// Check that applying the inverse replacement to `replaced` reproduces `original`. Both are
+    /// streamed, so neither has to fit in memory.
+    pub fn check<A, B>(&self, original: &mut A, replaced: &mut B) -> Result<(), VerifyError>
+    where
+        A: Read,
+        B: BufRead,
+    {
+        match compare(original, |out| self.inverse.replace_stream(replaced, out)) {
+            Ok(Compared::Same(_)) => Ok(()),
+            Ok(Compared::Differs(line)) => Err(VerifyError::Mismatch(line)),
+            Err(err) => Err(VerifyError::Stream(err)),
+        }
+    }
+}
+
+/// Check that `saved`, the new contents of a file read back after writing them, is exactly what
+/// `replay` writes when it repeats the replacement, and that it makes `expected` replacements.
+/// `replay` returns the number of replacements it made.
+pub fn check_saved<R, F>(saved: &mut R, expected: usize, replay: F) -> Result<(), VerifyError>
+where
+    R: Read,
+    F: FnOnce(&mut dyn Write) -> Result<usize, StreamError>,
+{
+    match compare(saved, replay) {
+        Ok(Compared::Same(found)) if found == expected => Ok(()),
+        Ok(Compared::Same(found)) => Err(VerifyError::Count { expected, found }),
+        Ok(Compared::Differs(line)) => Err(VerifyError::Corrupt(line)),
+        Err(err) => Err(VerifyError::Replay(err)),
+    }
+}
+
+/// The result of comparing some output with what was expected.
+enum Compared<T> {
+    /// The output was the same, and this is what writing it returned
+    Same(T),
+    /// The output differed on this line
+    Differs(usize),
+}
+
+/// Call `write` with a writer which compares its output with the contents of `expected`.
+fn compare<R, T, F>(expected: &mut R, write: F) -> Result<Compared<T>, StreamError>
+where
+    R: Read,
+    F: FnOnce(&mut dyn Write) -> Result<T, StreamError>,
+{
+    let mut compare = Compare {
+        original: expected,
+        buf: vec![],
+        line: 1,
+        mismatch: false,
+    };
+    let result = write(&mut compare);
+    if compare.mismatch {
+        return Ok(Compared::Differs(compare.line));
+    }
+    let value = result?;
+    // anything left over in the original means the output was too short
+    match compare.original.read(&mut [0]) {
+        Ok(0) => Ok(Compared::Same(value)),
+        Ok(_) => Ok(Compared::Differs(compare.line)),
+        Err(err) => Err(StreamError::Read(err)),
+    }
+}
+
+/// A writer which compares everything written to it with the next bytes of `original`.
+struct Compare<'a, R> {
+    original: &'a mut R,
+    buf: Vec<u8>,
+    /// The line number of the next byte
+    line: usize,
+    /// Set when the written bytes differed from the original, and the write failed because of it
+    mismatch: bool,
+}
+
+impl<R: Read> Write for Compare<'_, R> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.buf.resize(data.len(), 0);
+        let len = read_fully(self.original, &mut self.buf)?;
+        let same = self.buf[..len]
+            .iter()
+            .zip(data)
+            .take_while(|(a, b)| a == b)
+            .count();
+        self.line += memchr::memchr_iter(b'\n', &data[..same]).count();
+        if same < data.len() {
+            self.mismatch = true;
+            return Err(io::Error::other("output differs from the original"));
+        }
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Read until `buf` is full or EOF, returning how much was read.
+fn read_fully<R: Read>(input: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match input.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(n) => len += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use rp::replace::ReplacerBuilder;
+
+    fn verifier(pattern: &str, replacement: &str) -> Verifier {
+        let builder = ReplacerBuilder {
+            replace_all: true,
+            ..Default::default()
+        };
+        Verifier::new(builder.build(pattern, replacement).unwrap())
+    }
+
+    fn check(verifier: &Verifier, original: &str, replaced: &str) -> Result<(), VerifyError> {
+        verifier.check(&mut original.as_bytes(), &mut replaced.as_bytes())
+    }
+
+    #[test]
+    fn test_verifier() {
+        // the inverse of foo -> bar
+        let verifier = verifier("bar", "foo");
+        assert!(check(&verifier, "foo\nx foo\n", "bar\nx bar\n").is_ok());
+        assert!(check(&verifier, "", "").is_ok());
+        // a 'bar' that was already there gets turned into 'foo' too
+        assert!(matches!(
+            check(&verifier, "a\nfoo bar\n", "a\nbar bar\n"),
+            Err(VerifyError::Mismatch(2))
+        ));
+        assert!(matches!(
+            check(&verifier, "foo\nfoo\n", "bar\n"),
+            Err(VerifyError::Mismatch(2))
+        ));
+        assert!(matches!(
+            check(&verifier, "foo\n", "bar\nbar\n"),
+            Err(VerifyError::Mismatch(2))
+        ));
+    }
+
+    #[test]
+    fn test_check_saved() {
+        let replacer = ReplacerBuilder::default().build("foo", "bar").unwrap();
+        let check = |original: &str, saved: &str, expected| {
+            check_saved(&mut saved.as_bytes(), expected, |out| {
+                Ok(replacer
+                    .replace_stream(&mut original.as_bytes(), out)?
+                    .replacements)
+            })
+        };
+        assert!(check(
+            "foo
+x
+foo
+",
+            "bar
+x
+bar
+",
+            2
+        )
+        .is_ok());
+        assert!(matches!(
+            check(
+                "foo
+x
+foo
+",
+                "bar
+x
+bar
+",
+                3
+            ),
+            Err(VerifyError::Count {
+                expected: 3,
+                found: 2
+            })
+        ));
+        // a byte which changed outside of the replacements
+        assert!(matches!(
+            check(
+                "foo
+x
+foo
+",
+                "bar
+y
+bar
+",
+                2
+            ),
+            Err(VerifyError::Corrupt(2))
+        ));
+        // a file which was cut short, e.g. by a full disk
+        assert!(matches!(
+            check(
+                "foo
+x
+foo
+",
+                "bar
+x
+",
+                2
+            ),
+            Err(VerifyError::Corrupt(3))
+        ));
+    }
+}
@@ -0,0 +1,446 @@
+//! Translate Vim regular expressions into the regex crate's syntax.
+
+/// Name of the capture group which holds the part of the match between `\zs` and `\ze`.
+pub const MATCH_GROUP: &str = "__rp_vim_match";
+
+#[derive(Debug, thiserror::Error)]
+pub enum VimError {
+    #[error("backreferences like '\\{0}' aren't supported")]
+    Backreference(char),
+    #[error("'{0}' isn't supported")]
+    Unsupported(String),
+    #[error("\\zs and \\ze must be outside of groups and alternations")]
+    NestedSubmatch,
+}
+
+/// A translated Vim pattern.
+#[derive(Debug, PartialEq, Eq)]
+pub struct VimPattern {
+    pub pattern: String,
+    /// Whether the pattern used `\zs` or `\ze`, in which case only the capture group named
+    /// `MATCH_GROUP` should be replaced rather than the whole match.
+    pub submatch: bool,
+}
+
+/// How many characters have special meaning without a backslash, set by `\v`, `\m`, `\M`, or
+/// `\V` in the pattern.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MagicLevel {
+    VeryMagic,
+    Magic,
+    NoMagic,
+    VeryNoMagic,
+}
+
+impl MagicLevel {
+    /// Whether `c` is special when it's not preceded by a backslash. A backslash toggles this.
+    fn is_special(self, c: char) -> bool {
+        match self {
+            MagicLevel::VeryMagic => c.is_ascii_punctuation() && c != '_',
+            MagicLevel::Magic => matches!(c, '^' | '$' | '.' | '*' | '[' | '~'),
+            MagicLevel::NoMagic => matches!(c, '^' | '$'),
+            MagicLevel::VeryNoMagic => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// A character which matches itself
+    Literal(char),
+    /// A punctuation character with its special meaning, e.g. '(' for a group
+    Special(char),
+    /// A backslash followed by a letter or digit, e.g. `\s`
+    Escape(char),
+    /// An already-translated bracket expression
+    Class(String),
+}
+
+/// Translate a Vim pattern into an equivalent regex crate pattern.
+///
+/// The default "magic" syntax is used, and `\v`, `\m`, `\M`, and `\V` switch between the magic
+/// levels like in Vim. `\c` and `\C` make the whole pattern case-insensitive or case-sensitive.
+/// Groups, `\%(` non-capturing groups, alternation, the `*`, `\+`, `\=`, `\?`, and `\{n,m}`
+/// multis (including lazy `\{-n,m}`), bracket expressions, character classes like `\s`, `\d`,
+/// `\w`, `\a`, `\l`, `\u`, `\x`, and `\h`, and `\zs` and `\ze` at the top level are supported.
+/// Backreferences, lookaround, `\<` and `\>` word boundaries, and other special atoms aren't.
+pub fn translate(pattern: &str) -> Result<VimPattern, VimError> {
+    let tokens = tokenize(pattern);
+    let mut out = String::with_capacity(pattern.len());
+    let mut ignore_case = None;
+    let mut depth = 0usize;
+    let mut top_alternation = false;
+    let mut zs = None;
+    let mut ze = None;
+    // At the start of the pattern or a group or alternative, where '*' is literal and '^' is an
+    // anchor
+    let mut at_start = true;
+
+    let mut i = 0;
+    while i < tokens.len() {
+        let token = &tokens[i];
+        let was_start = std::mem::replace(&mut at_start, false);
+        i += 1;
+
+        match *token {
+            Token::Literal(c) => push_literal(&mut out, c),
+            Token::Class(ref class) => out.push_str(class),
+            Token::Special(c) => match c {
+                '.' => out.push('.'),
+                '*' | '+' | '=' | '?' if was_start => push_literal(&mut out, c),
+                '*' | '+' => out.push(c),
+                '=' | '?' => out.push('?'),
+                '{' => {
+                    let end = tokens[i..]
+                        .iter()
+                        .position(|t| matches!(t, Token::Literal('}') | Token::Special('}')))
+                        .ok_or_else(|| VimError::Unsupported("unterminated \\{".to_owned()))?;
+                    let body = tokens[i..(i + end)]
+                        .iter()
+                        .map(|t| match t {
+                            Token::Literal(c) => Some(*c),
+                            _ => None,
+                        })
+                        .collect::<Option<String>>()
+                        .ok_or_else(|| VimError::Unsupported("invalid \\{}".to_owned()))?;
+                    out.push_str(&translate_interval(&body)?);
+                    i += end + 1;
+                }
+                '(' => {
+                    out.push('(');
+                    depth += 1;
+                    at_start = true;
+                }
+                '%' if matches!(
+                    tokens.get(i),
+                    Some(Token::Literal('(') | Token::Special('('))
+                ) =>
+                {
+                    out.push_str("(?:");
+                    depth += 1;
+                    at_start = true;
+                    i += 1;
+                }
+                ')' => {
+                    out.push(')');
+                    depth = depth.saturating_sub(1);
+                }
+                '|' => {
+                    out.push('|');
+                    top_alternation |= depth == 0;
+                    at_start = true;
+                }
+                '^' if was_start => {
+                    out.push('^');
+                    at_start = true;
+                }
+                '$' if matches!(tokens.get(i), None | Some(Token::Special('|' | ')'))) => {
+                    out.push('$')
+                }
+                // lookaround, word boundaries, and the other special atoms
+                '<' | '>' | '@' | '%' | '&' | '~' => {
+                    return Err(VimError::Unsupported(c.to_string()))
+                }
+                _ => push_literal(&mut out, c),
+            },
+            Token::Escape(e) => match e {
+                's' => out.push_str(r"[ \t]"),
+                'S' => out.push_str(r"[^ \t]"),
+                'd' => out.push_str("[0-9]"),
+                'D' => out.push_str("[^0-9]"),
+                'w' => out.push_str("[0-9A-Za-z_]"),
+                'W' => out.push_str("[^0-9A-Za-z_]"),
+                'a' => out.push_str("[A-Za-z]"),
+                'A' => out.push_str("[^A-Za-z]"),
+                'l' => out.push_str("[a-z]"),
+                'L' => out.push_str("[^a-z]"),
+                'u' => out.push_str("[A-Z]"),
+                'U' => out.push_str("[^A-Z]"),
+                'x' => out.push_str("[0-9A-Fa-f]"),
+                'X' => out.push_str("[^0-9A-Fa-f]"),
+                'o' => out.push_str("[0-7]"),
+                'O' => out.push_str("[^0-7]"),
+                'h' => out.push_str("[A-Za-z_]"),
+                'H' => out.push_str("[^A-Za-z_]"),
+                'n' => out.push_str(r"\n"),
+                't' => out.push_str(r"\t"),
+                'r' => out.push_str(r"\r"),
+                'e' => out.push_str(r"\x1B"),
+                'c' => ignore_case = Some(true),
+                'C' => ignore_case = Some(false),
+                '1'..='9' => return Err(VimError::Backreference(e)),
+                'z' if matches!(tokens.get(i), Some(Token::Literal('s' | 'e'))) => {
+                    if depth != 0 {
+                        return Err(VimError::NestedSubmatch);
+                    }
+                    let pos = Some(out.len());
+                    if tokens[i] == Token::Literal('s') {
+                        zs = pos;
+                    } else {
+                        ze = pos;
+                    }
+                    i += 1;
+                    // a multi right after \zs applies to nothing, like at the start
+                    at_start = was_start;
+                }
+                _ => return Err(VimError::Unsupported(format!("\\{e}"))),
+            },
+        }
+    }
+
+    let submatch = zs.is_some() || ze.is_some();
+    if submatch {
+        if top_alternation {
+            return Err(VimError::NestedSubmatch);
+        }
+        let start = zs.unwrap_or(0);
+        let end = ze.unwrap_or(out.len());
+        if end < start {
+            return Err(VimError::Unsupported("\\ze before \\zs".to_owned()));
+        }
+        out.insert(end, ')');
+        out.insert_str(start, &format!("(?P<{MATCH_GROUP}>"));
+    }
+
+    match ignore_case {
+        Some(true) => out.insert_str(0, "(?i)"),
+        Some(false) => out.insert_str(0, "(?-i)"),
+        None => (),
+    }
+
+    Ok(VimPattern {
+        pattern: out,
+        submatch,
+    })
+}
+
+/// Split a pattern into tokens, applying the magic level and translating bracket expressions.
+fn tokenize(pattern: &str) -> Vec<Token> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut tokens = vec![];
+    let mut magic = MagicLevel::Magic;
+    let mut i = 0;
+
+    while i < chars.len() {
+        let mut c = chars[i];
+        i += 1;
+
+        let special = if c == '\\' {
+            let Some(&next) = chars.get(i) else {
+                tokens.push(Token::Literal('\\'));
+                break;
+            };
+            i += 1;
+            match next {
+                'v' | 'm' | 'M' | 'V' => {
+                    magic = match next {
+                        'v' => MagicLevel::VeryMagic,
+                        'm' => MagicLevel::Magic,
+                        'M' => MagicLevel::NoMagic,
+                        _ => MagicLevel::VeryNoMagic,
+                    };
+                    continue;
+                }
+                '\\' => {
+                    tokens.push(Token::Literal('\\'));
+                    continue;
+                }
+                _ if next.is_ascii_alphanumeric() || next == '_' => {
+                    tokens.push(Token::Escape(next));
+                    continue;
+                }
+                _ => {
+                    // a backslash swaps whether punctuation is special
+                    c = next;
+                    !magic.is_special(c)
+                }
+            }
+        } else {
+            magic.is_special(c)
+        };
+
+        if !special {
+            tokens.push(Token::Literal(c));
+        } else if c != '[' {
+            tokens.push(Token::Special(c));
+        } else {
+            match translate_bracket(&chars[i..]) {
+                Some((class, len)) => {
+                    tokens.push(Token::Class(class));
+                    i += len;
+                }
+                // Vim treats a '[' without a matching ']' as a literal
+                None => tokens.push(Token::Literal('[')),
+            }
+        }
+    }
+
+    tokens
+}
+
+/// Translate the body of a `\{}` multi, e.g. "2,5" or "-1,".
+fn translate_interval(body: &str) -> Result<String, VimError> {
+    let (lazy, range) = match body.strip_prefix('-') {
+        Some(range) => (true, range),
+        None => (false, body),
+    };
+    if !range.bytes().all(|b| b.is_ascii_digit() || b == b',') {
+        return Err(VimError::Unsupported(format!("\\{{{body}}}")));
+    }
+
+    let mut out = match range.split_once(',') {
+        None if range.is_empty() => "*".to_owned(),
+        None => format!("{{{range}}}"),
+        Some((min, max)) => {
+            let min = if min.is_empty() { "0" } else { min };
+            format!("{{{min},{max}}}")
+        }
+    };
+    if lazy {
+        out.push('?');
+    }
+    Ok(out)
+}
+
+/// Translate a bracket expression whose contents start at `chars[0]`, just after the '['.
+/// Returns the translated class and the number of characters used, or None if there's no ']'.
+fn translate_bracket(chars: &[char]) -> Option<(String, usize)> {
+    let mut out = String::from("[");
+    let mut i = 0;
+    if chars.first() == Some(&'^') {
+        out.push('^');
+        i += 1;
+    }
+    // a ']' right at the start is a literal
+    if chars.get(i) == Some(&']') {
+        out.push_str(r"\]");
+        i += 1;
+    }
+
+    loop {
+        let c = *chars.get(i)?;
+        i += 1;
+        match c {
+            ']' => {
+                out.push(']');
+                return Some((out, i));
+            }
+            '\\' => match chars.get(i) {
+                Some(&next @ (']' | '^' | '-' | '\\')) => {
+                    out.push('\\');
+                    out.push(next);
+                    i += 1;
+                }
+                Some(&next @ ('n' | 't' | 'r' | 'e')) => {
+                    out.push_str(match next {
+                        'n' => r"\n",
+                        't' => r"\t",
+                        'r' => r"\r",
+                        _ => r"\x1B",
+                    });
+                    i += 1;
+                }
+                // any other backslash is a literal
+                _ => out.push_str(r"\\"),
+            },
+            '[' if chars.get(i) == Some(&':') => {
+                let len = chars[i..].windows(2).position(|w| w == [':', ']'])?;
+                out.push('[');
+                out.extend(&chars[i..(i + len + 2)]);
+                i += len + 2;
+            }
+            '[' | '&' | '~' => {
+                out.push('\\');
+                out.push(c);
+            }
+            _ => out.push(c),
+        }
+    }
+}
+
+/// Push a character which should match literally, escaping it if needed.
+fn push_literal(out: &mut String, c: char) {
+    if regex_syntax::is_meta_character(c) {
+        out.push('\\');
+    }
+    out.push(c);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[track_caller]
+    fn check(vim: &str, expected: &str) {
+        let translated = translate(vim).unwrap();
+        assert_eq!(translated.pattern, expected);
+        regex::Regex::new(expected).unwrap();
+    }
+
+    #[test]
+    fn test_translate_magic() {
+        check(r"\(foo\|bar\)\+", r"(foo|bar)+");
+        check(r"(a|b)+?=", r"\(a\|b\)\+\?=");
+        check(r"a\=b\?c*", r"a?b?c*");
+        check(r"*a\%(b\)", r"\*a(?:b)");
+        check(r"^a^b$c$", r"^a\^b\$c$");
+        check(
+            r"a\{2,3}b\{-}c\{-1,}d\{,4\}e\{2}",
+            r"a{2,3}b*?c{1,}?d{0,4}e{2}",
+        );
+        check(
+            r"\s\d\w\a\u\l\x\h\n",
+            r"[ \t][0-9][0-9A-Za-z_][A-Za-z][A-Z][a-z][0-9A-Fa-f][A-Za-z_]\n",
+        );
+        check(r"a\.b\*\[\/-#", r"a\.b\*\[/\-\#");
+        check(r"[a-z\]\\[:digit:]&]", r"[a-z\]\\[:digit:]\&]");
+        check(r"[\s]", r"[\\s]");
+        check(r"a[b", r"a\[b");
+        check(r"\cfoo", r"(?i)foo");
+    }
+
+    #[test]
+    fn test_translate_magic_levels() {
+        check(r"\v(foo|bar)+=x{2}\=", r"(foo|bar)+?x{2}=");
+        check(r"\v\(a\)%(b)", r"\(a\)(?:b)");
+        check(r"\Ma.b\.*\*", r"a\.b.\**");
+        check(r"\Va.b*[x]\[x]", r"a\.b\*\[x\][x]");
+        check(r"\Mx\[y]\v[z]", r"x[y][z]");
+    }
+
+    #[test]
+    fn test_translate_submatch() {
+        let t = translate(r"foo\zsbar\zebaz").unwrap();
+        assert!(t.submatch);
+        assert_eq!(t.pattern, format!("foo(?P<{MATCH_GROUP}>bar)baz"));
+
+        let t = translate(r"\vfoo\zs(\d+)").unwrap();
+        assert_eq!(t.pattern, format!("foo(?P<{MATCH_GROUP}>([0-9]+))"));
+        let t = translate(r"\(a\)\zeb").unwrap();
+        assert_eq!(t.pattern, format!("(?P<{MATCH_GROUP}>(a))b"));
+        assert!(!translate("abc").unwrap().submatch);
+
+        assert!(matches!(
+            translate(r"\(a\zsb\)"),
+            Err(VimError::NestedSubmatch)
+        ));
+        assert!(matches!(
+            translate(r"a\zsb\|c"),
+            Err(VimError::NestedSubmatch)
+        ));
+    }
+
+    #[test]
+    fn test_translate_errors() {
+        assert!(matches!(
+            translate(r"\(a\)\1"),
+            Err(VimError::Backreference('1'))
+        ));
+        for unsupported in [r"\<word\>", r"\vfoo@=", r"a~", r"\%V", r"a\{x}", r"\_s"] {
+            assert!(
+                matches!(translate(unsupported), Err(VimError::Unsupported(_))),
+                "{unsupported}"
+            );
+        }
+    }
+}
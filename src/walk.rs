@@ -0,0 +1,421 @@
+//! `-r`: find the files to process in directory trees.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, Read};
+use std::path::{Path, PathBuf};
+
+use crate::glob::GlobSet;
+use crate::ignore::IgnoreRules;
+
+/// How much of each file to check for NUL bytes when deciding whether it's binary, like grep.
+const BINARY_CHECK_SIZE: u64 = 8192;
+
+/// A path which was left out while walking.
+#[derive(Debug)]
+pub struct Skipped {
+    pub path: PathBuf,
+    pub reason: SkipReason,
+}
+
+impl Skipped {
+    fn unreadable(path: PathBuf, err: io::Error) -> Skipped {
+        Skipped {
+            path,
+            reason: SkipReason::Unreadable(err),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum SkipReason {
+    #[error("binary file")]
+    Binary,
+    #[error("unable to read: {0}")]
+    Unreadable(io::Error),
+    #[error("hidden file or directory")]
+    Hidden,
+    #[error("listed in an ignore file")]
+    Ignored,
+    #[error("not matched by the globs")]
+    Excluded,
+    #[error("symlinks aren't followed")]
+    Symlink,
+    #[error("not a regular file")]
+    Special,
+}
+
+impl SkipReason {
+    /// A short name for the reason, for machine-readable reports.
+    pub fn code(&self) -> &'static str {
+        match self {
+            SkipReason::Binary => "binary",
+            SkipReason::Unreadable(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+                "permission-denied"
+            }
+            SkipReason::Unreadable(_) => "unreadable",
+            SkipReason::Hidden => "hidden",
+            SkipReason::Ignored => "ignored",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Symlink => "symlink",
+            SkipReason::Special => "special",
+        }
+    }
+
+    /// Whether this is one of the reasons which are only recorded with `report_filtered`.
+    pub fn is_filtered(&self) -> bool {
+        !matches!(self, SkipReason::Binary | SkipReason::Unreadable(_))
+    }
+}
+
+/// Settings for which files are found while walking.
+#[derive(Debug, Default)]
+pub struct WalkOptions {
+    /// Only use files which match these
+    pub globs: GlobSet,
+    /// Also use hidden files and directories, whose names start with '.'
+    pub hidden: bool,
+    /// Don't skip the files listed in .gitignore and .ignore files
+    pub no_ignore: bool,
+    /// Also use files which look binary
+    pub binary: bool,
+    /// Add the hidden, ignored, and excluded paths, symlinks, and special files to `skipped` too
+    pub report_filtered: bool,
+}
+
+/// Expand each directory in `paths` to the regular files beneath it, in sorted order.
+///
+/// Other paths, including '-' for stdin, are kept as-is, so a symlink to a directory given
+/// directly is still walked. Symlinks found while walking are skipped like `grep -r`. Files which
+/// look binary, unless `opts.binary` is set, and files or directories which can't be read are
+/// added to `skipped` instead.
+///
+/// Files and directories found while walking are also left out, without being added to
+/// `skipped` unless `opts.report_filtered` is set, if they're hidden, listed in an ignore file in
+/// one of the directories walked, or not matched by the globs, depending on `opts`. Glob patterns
+/// are matched against the path relative to the directory given.
+pub fn expand(paths: &[PathBuf], opts: &WalkOptions, skipped: &mut Vec<Skipped>) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        if path.as_os_str() != "-" && path.is_dir() {
+            let walk = Walk { root: path, opts };
+            walk.dir(path, &mut vec![], &mut files, skipped);
+        } else {
+            files.push(path.clone());
+        }
+    }
+    files
+}
+
+/// Settings for walking one of the directories given to `expand`.
+struct Walk<'a> {
+    root: &'a Path,
+    opts: &'a WalkOptions,
+}
+
+impl Walk<'_> {
+    /// Walk `dir`. `ignores` has the ignore rules from each directory above it which has any.
+    fn dir(
+        &self,
+        dir: &Path,
+        ignores: &mut Vec<(PathBuf, IgnoreRules)>,
+        files: &mut Vec<PathBuf>,
+        skipped: &mut Vec<Skipped>,
+    ) {
+        let mut entries =
+            match fs::read_dir(dir).and_then(|entries| entries.collect::<Result<Vec<_>, _>>()) {
+                Ok(entries) => entries,
+                Err(err) => return skipped.push(Skipped::unreadable(dir.to_owned(), err)),
+            };
+        entries.sort_by_key(|entry| entry.file_name());
+
+        let ignores_len = ignores.len();
+        if !self.opts.no_ignore {
+            match IgnoreRules::from_dir(dir) {
+                Ok(rules) if rules.is_empty() => (),
+                Ok(rules) => ignores.push((dir.to_owned(), rules)),
+                Err((err, name)) => skipped.push(Skipped::unreadable(dir.join(name), err)),
+            }
+        }
+
+        for entry in entries {
+            let path = entry.path();
+            let file_type = match entry.file_type() {
+                Ok(file_type) => file_type,
+                Err(err) => {
+                    skipped.push(Skipped::unreadable(path, err));
+                    continue;
+                }
+            };
+            let is_dir = file_type.is_dir();
+            let mut filter = |reason| {
+                if self.opts.report_filtered {
+                    skipped.push(Skipped {
+                        path: path.clone(),
+                        reason,
+                    });
+                }
+            };
+            if !self.opts.hidden && entry.file_name().as_encoded_bytes().starts_with(b".") {
+                filter(SkipReason::Hidden);
+                continue;
+            }
+            // unwraps are ok because everything we find is under the root and those directories
+            let ignored = ignores.iter().rev().find_map(|(ignore_dir, rules)| {
+                rules.matched(path.strip_prefix(ignore_dir).unwrap(), is_dir)
+            });
+            if ignored == Some(true) {
+                filter(SkipReason::Ignored);
+                continue;
+            }
+            let relative = path.strip_prefix(self.root).unwrap();
+            if !self.opts.globs.is_match(relative, is_dir) {
+                filter(SkipReason::Excluded);
+                continue;
+            }
+            if !is_dir && !file_type.is_file() {
+                filter(if file_type.is_symlink() {
+                    SkipReason::Symlink
+                } else {
+                    SkipReason::Special
+                });
+                continue;
+            }
+            if is_dir {
+                self.dir(&path, ignores, files, skipped);
+            } else if self.opts.binary {
+                files.push(path);
+            } else {
+                match is_binary(&path) {
+                    Ok(false) => files.push(path),
+                    Ok(true) => skipped.push(Skipped {
+                        path,
+                        reason: SkipReason::Binary,
+                    }),
+                    Err(err) => skipped.push(Skipped::unreadable(path, err)),
+                }
+            }
+        }
+        ignores.truncate(ignores_len);
+    }
+}
+
+/// Remove the files which look binary from `files`, adding them to `skipped`. Stdin and files
+/// which can't be read are kept, so that they're reported as errors when they're processed.
+pub fn skip_binary(files: Vec<PathBuf>, skipped: &mut Vec<Skipped>) -> Vec<PathBuf> {
+    files
+        .into_iter()
+        .filter(|path| {
+            if path.as_os_str() == "-" || !matches!(is_binary(path), Ok(true)) {
+                return true;
+            }
+            skipped.push(Skipped {
+                path: path.clone(),
+                reason: SkipReason::Binary,
+            });
+            false
+        })
+        .collect()
+}
+
+/// Read a list of paths separated by `delimiter`, for --files-from. Empty entries are ignored, so
+/// a trailing delimiter or blank lines don't matter.
+pub fn read_list<R: BufRead + ?Sized>(input: &mut R, delimiter: u8) -> io::Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for entry in input.split(delimiter) {
+        let entry = entry?;
+        if !entry.is_empty() {
+            paths.push(crate::state::path_from_bytes(&entry));
+        }
+    }
+    Ok(paths)
+}
+
+/// Check whether the start of a file contains a NUL byte.
+fn is_binary(path: &Path) -> io::Result<bool> {
+    let mut buf = vec![];
+    File::open(path)?
+        .take(BINARY_CHECK_SIZE)
+        .read_to_end(&mut buf)?;
+    Ok(memchr::memchr(0, &buf).is_some())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_read_list() {
+        let list = |text: &[u8], delimiter| read_list(&mut &text[..], delimiter).unwrap();
+        assert_eq!(
+            list(b"a.txt\n\nsub/b c.txt\n", b'\n'),
+            [PathBuf::from("a.txt"), PathBuf::from("sub/b c.txt")]
+        );
+        assert_eq!(
+            list(b"a\nb\0c\0", 0),
+            [PathBuf::from("a\nb"), PathBuf::from("c")]
+        );
+        assert!(list(b"", b'\n').is_empty());
+    }
+
+    #[test]
+    fn test_expand() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("sub/deeper")).unwrap();
+        fs::write(root.join("b.txt"), "b").unwrap();
+        fs::write(root.join("a.txt"), "a").unwrap();
+        fs::write(root.join("sub/deeper/c.txt"), "c").unwrap();
+        fs::write(root.join("sub/binary"), b"x\0y").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("sub/link")).unwrap();
+
+        let mut skipped = vec![];
+        let files = expand(
+            &[root.to_owned(), PathBuf::from("-")],
+            &WalkOptions::default(),
+            &mut skipped,
+        );
+        assert_eq!(
+            files,
+            [
+                root.join("a.txt"),
+                root.join("b.txt"),
+                root.join("sub/deeper/c.txt"),
+                PathBuf::from("-"),
+            ]
+        );
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].path, root.join("sub/binary"));
+        assert!(matches!(skipped[0].reason, SkipReason::Binary));
+
+        // files given directly are kept even if they're binary
+        let binary = root.join("sub/binary");
+        let mut skipped = vec![];
+        assert_eq!(
+            expand(
+                std::slice::from_ref(&binary),
+                &WalkOptions::default(),
+                &mut skipped
+            ),
+            [binary]
+        );
+        assert!(skipped.is_empty());
+
+        let mut opts = WalkOptions::default();
+        opts.globs.add("*.txt").unwrap();
+        opts.globs.add("!deeper").unwrap();
+        assert_eq!(
+            expand(&[root.to_owned()], &opts, &mut vec![]),
+            [root.join("a.txt"), root.join("b.txt")]
+        );
+
+        let opts = WalkOptions {
+            binary: true,
+            ..Default::default()
+        };
+        let mut skipped = vec![];
+        assert_eq!(expand(&[root.join("sub")], &opts, &mut skipped).len(), 2);
+        assert!(skipped.is_empty());
+    }
+
+    #[test]
+    fn test_skip_binary() {
+        let dir = tempfile::tempdir().unwrap();
+        let (text, binary) = (dir.path().join("text"), dir.path().join("binary"));
+        fs::write(&text, "text\n").unwrap();
+        fs::write(&binary, b"\x7fELF\0").unwrap();
+        let missing = dir.path().join("missing");
+        let stdin = PathBuf::from("-");
+
+        let mut skipped = vec![];
+        let files = vec![text.clone(), binary.clone(), missing.clone(), stdin.clone()];
+        assert_eq!(skip_binary(files, &mut skipped), [text, missing, stdin]);
+        assert_eq!(skipped.len(), 1);
+        assert_eq!(skipped[0].path, binary);
+        assert!(matches!(skipped[0].reason, SkipReason::Binary));
+    }
+
+    #[test]
+    fn test_expand_ignore() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("sub/target")).unwrap();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        for file in [
+            "a.txt",
+            "a.log",
+            ".hidden",
+            ".git/config",
+            "sub/keep.log",
+            "sub/b.txt",
+            "sub/target/c",
+        ] {
+            fs::write(root.join(file), "x").unwrap();
+        }
+        fs::write(root.join(".gitignore"), "*.log\ntarget/\n").unwrap();
+        fs::write(root.join("sub/.ignore"), "!keep.log\n").unwrap();
+
+        let expand = |opts| expand(&[root.to_owned()], &opts, &mut vec![]);
+        assert_eq!(
+            expand(WalkOptions::default()),
+            [
+                root.join("a.txt"),
+                root.join("sub/b.txt"),
+                root.join("sub/keep.log")
+            ]
+        );
+        let all = expand(WalkOptions {
+            hidden: true,
+            no_ignore: true,
+            ..Default::default()
+        });
+        assert_eq!(all.len(), 9);
+    }
+
+    #[test]
+    fn test_report_filtered() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join(".git")).unwrap();
+        for file in ["a.txt", "b.log", "c.md", "d.bin"] {
+            fs::write(root.join(file), "x").unwrap();
+        }
+        fs::write(root.join("d.bin"), b"\0").unwrap();
+        fs::write(root.join(".gitignore"), "*.log\n").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(root.join("a.txt"), root.join("e.txt")).unwrap();
+
+        let mut opts = WalkOptions {
+            report_filtered: true,
+            ..Default::default()
+        };
+        opts.globs.add("!*.md").unwrap();
+        let mut skipped = vec![];
+        assert_eq!(
+            expand(&[root.to_owned()], &opts, &mut skipped),
+            [root.join("a.txt")]
+        );
+        let mut reasons: Vec<_> = skipped
+            .iter()
+            .map(|skip| {
+                let name = skip.path.strip_prefix(root).unwrap();
+                (name.to_str().unwrap(), skip.reason.code())
+            })
+            .collect();
+        reasons.sort();
+        let mut expected = vec![
+            (".git", "hidden"),
+            (".gitignore", "hidden"),
+            ("b.log", "ignored"),
+            ("c.md", "excluded"),
+            ("d.bin", "binary"),
+        ];
+        if cfg!(unix) {
+            expected.push(("e.txt", "symlink"));
+        }
+        assert_eq!(reasons, expected);
+        assert!(skipped
+            .iter()
+            .all(|skip| skip.reason.is_filtered() == !matches!(skip.reason, SkipReason::Binary)));
+    }
+}
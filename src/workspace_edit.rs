@@ -0,0 +1,117 @@
+//! Output replacements as an LSP `WorkspaceEdit`, for editors to apply themselves.
+//!
+//! The format is `{"changes": {URI: [TextEdit, ...], ...}}`, where each `TextEdit` is
+//! `{"range": {"start": POS, "end": POS}, "newText": TEXT}` and each position is
+//! `{"line": N, "character": N}`. Lines count from 0 and characters are UTF-16 code units, which
+//! is the LSP default. Text which isn't valid UTF-8 is converted lossily.
+
+use std::io::{self, BufRead};
+use std::path::Path;
+
+use crate::json::Value;
+use rp::replace::{Line, Pattern, Replacer, StreamError};
+
+/// Make replacements on each line of `input` and collect the resulting list of text edits.
+pub fn text_edits<P, R>(replacer: &Replacer<P>, input: &mut R) -> Result<Vec<Value>, StreamError>
+where
+    P: Pattern,
+    R: BufRead,
+{
+    let mut edits = vec![];
+    replacer.for_each_line(input, |line| {
+        for edit in line.edits {
+            let range = Value::object([
+                ("start", position(line, edit.old.start)),
+                ("end", position(line, edit.old.end)),
+            ]);
+            let new_text = String::from_utf8_lossy(&line.new[edit.new.clone()]);
+            edits.push(Value::object([
+                ("range", range),
+                ("newText", new_text.into_owned().into()),
+            ]));
+        }
+        Ok(())
+    })?;
+    Ok(edits)
+}
+
+/// Get the LSP position of a byte offset in the original text of a line, which may be several
+/// lines in paragraph mode.
+fn position(line: &Line, offset: usize) -> Value {
+    // a match that includes a newline ends at the start of the next line
+    let prefix = &line.old[..offset];
+    let line_start = prefix
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |pos| pos + 1);
+    let line_index = line.number - 1 + memchr::memchr_iter(b'\n', prefix).count();
+    let character = String::from_utf8_lossy(&prefix[line_start..])
+        .encode_utf16()
+        .count();
+    Value::object([("line", line_index.into()), ("character", character.into())])
+}
+
+/// Get a `file://` URI for a path, which is made absolute if it isn't already. Stdin ("-") is
+/// named "untitled:stdin".
+pub fn file_uri(path: &Path) -> io::Result<String> {
+    if path.to_str() == Some("-") {
+        return Ok("untitled:stdin".to_owned());
+    }
+
+    let path = std::env::current_dir()?.join(path);
+    let mut uri = String::from("file://");
+    let path = path.to_string_lossy();
+    // Windows paths need an extra slash before the drive letter, e.g. file:///C:/foo
+    if !path.starts_with('/') {
+        uri.push('/');
+    }
+    for b in path.bytes() {
+        match b {
+            b'\\' => uri.push('/'),
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' | b'/' => {
+                uri.push(b as char)
+            }
+            // the drive letter colon is conventionally left alone
+            b':' if cfg!(windows) => uri.push(':'),
+            _ => uri.push_str(&format!("%{b:02X}")),
+        }
+    }
+    Ok(uri)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::bytes::Regex;
+    use rp::template::Template;
+
+    #[test]
+    fn test_text_edits() {
+        let re = Regex::new(r"b\n?").unwrap();
+        let replacer = Replacer::new(re, Template::parse("[$0]"));
+        let input = "ab\né b\n";
+        let edits = text_edits(&replacer, &mut input.as_bytes()).unwrap();
+        assert_eq!(
+            Value::Array(edits).to_string(),
+            concat!(
+                r#"[{"range":{"start":{"line":0,"character":1},"end":{"line":1,"character":0}},"#,
+                r#""newText":"[b\n]"},"#,
+                r#"{"range":{"start":{"line":1,"character":2},"end":{"line":2,"character":0}},"#,
+                r#""newText":"[b\n]"}]"#,
+            )
+        );
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_file_uri() {
+        assert_eq!(
+            file_uri(Path::new("/tmp/a b/c%d.rs")).unwrap(),
+            "file:///tmp/a%20b/c%25d.rs"
+        );
+        assert_eq!(file_uri(Path::new("-")).unwrap(), "untitled:stdin");
+        assert!(file_uri(Path::new("rel.txt"))
+            .unwrap()
+            .ends_with("/rel.txt"));
+    }
+}